@@ -5,6 +5,7 @@ import {
   BatchTile,
   GameSettings,
   PublicUser,
+  RankedUser,
 } from "./types";
 import { webSocketHandler, WebSocketHandlersParams } from "./websocket";
 
@@ -20,6 +21,12 @@ export function initApi() {
 
   const fullUrl = (path: string) => `http://${host(path)}`;
 
+  // failed requests answer `{ error, message }`
+  const errorMessage = async (response: Response): Promise<string> => {
+    const body = await response.json().catch(() => null);
+    return body?.message ?? response.statusText;
+  };
+
   // TODO: store & restore from localStorage
   // state
   let state: LocalApiState = {
@@ -31,19 +38,40 @@ export function initApi() {
     return btoa(`${user.id}:${user.token}`);
   }
 
-  async function fetchBatch(batch: number): Promise<CoordsAndTile[]> {
+  // tiles of `batch` and the change sequence to resync them from
+  async function fetchBatch(
+    batch: number
+  ): Promise<{ seq: number; tiles: CoordsAndTile[] }> {
     const response = await fetch(fullUrl(`/tiles?batch=${batch}`), {
       method: "get",
     });
+    const seq = Number(response.headers.get("X-Change-Seq") ?? 0);
     let tiles = (await response.json()) as BatchTile[];
 
-    return tiles.map(
-      ([q, r, strength, user_id]) =>
-        [
-          { q, r },
-          { strength, user_id },
-        ] as CoordsAndTile
-    );
+    return {
+      seq,
+      tiles: tiles.map(
+        ([q, r, strength, user_id]) =>
+          [
+            { q, r },
+            { strength, user_id },
+          ] as CoordsAndTile
+      ),
+    };
+  }
+
+  // tiles changed after `since`, undefined when every batch must be downloaded again
+  async function fetchChangesSince(
+    since: number
+  ): Promise<{ seq: number; tiles: CoordsAndTile[] } | undefined> {
+    const response = await fetch(fullUrl(`/changes?since=${since}`), {
+      method: "get",
+    });
+    if (response.status === 410) {
+      return undefined;
+    }
+
+    return (await response.json()) as { seq: number; tiles: CoordsAndTile[] };
   }
 
   async function fetchBatchesList(): Promise<number[]> {
@@ -65,6 +93,29 @@ export function initApi() {
     return state.users;
   }
 
+  async function fetchLeaderboard(
+    offset: number = 0,
+    limit: number = 20
+  ): Promise<RankedUser[]> {
+    const response = await fetch(
+      fullUrl(`/leaderboard?offset=${offset}&limit=${limit}`),
+      { method: "get" }
+    );
+    return (await response.json()) as RankedUser[];
+  }
+
+  async function fetchUserRank(userId: string): Promise<RankedUser | null> {
+    const response = await fetch(fullUrl(`/users/${userId}/rank`), {
+      method: "get",
+    });
+
+    if (response.status === 404) {
+      return null;
+    }
+
+    return (await response.json()) as RankedUser;
+  }
+
   const clickAt = async (coords: AxialCoords): Promise<CoordsAndTile[]> => {
     console.log("[api/clickAt]", coords);
     if (state.user != null) {
@@ -76,16 +127,25 @@ export function initApi() {
       const response = await fetch(fullUrl(`/tile/${coords.q}/${coords.r}`), {
         method: "POST",
         headers,
-        body: state.user.id,
       });
 
+      if (response.status === 429) {
+        // still in cooldown, the websocket tells when the next click is allowed
+        return [];
+      }
+
+      if (response.status === 403) {
+        // the round is over, clicks are accepted again once the next one starts
+        return [];
+      }
+
       return (await response.json()) as CoordsAndTile[];
     }
 
     return [];
   };
 
-  const login = async (username: string): Promise<User> => {
+  const login = async (username: string, team?: string): Promise<User> => {
     const response = await fetch(fullUrl("/login"), {
       method: "POST",
       headers: {
@@ -93,9 +153,15 @@ export function initApi() {
       },
       body: JSON.stringify({
         username,
+        team,
       }),
     });
 
+    if (!response.ok) {
+      // 409 when the username is already taken, 400 for an unknown team
+      throw new Error(await errorMessage(response));
+    }
+
     const user = await response.json();
 
     state.user = user;
@@ -109,6 +175,41 @@ export function initApi() {
     return user as User;
   };
 
+  // rotates the session token before it expires
+  const refreshSession = async (): Promise<User | undefined> => {
+    if (state.user == null) {
+      return undefined;
+    }
+
+    const response = await fetch(fullUrl("/refresh"), {
+      method: "POST",
+      headers: { Authorization: `Basic ${getAuth(state.user)}` },
+    });
+
+    if (!response.ok) {
+      // 401 once the token expired or was revoked
+      state.user = undefined;
+      throw new Error(await errorMessage(response));
+    }
+
+    state.user = (await response.json()) as User;
+
+    return state.user;
+  };
+
+  const logout = async (): Promise<void> => {
+    if (state.user == null) {
+      return;
+    }
+
+    await fetch(fullUrl("/logout"), {
+      method: "POST",
+      headers: { Authorization: `Basic ${getAuth(state.user)}` },
+    });
+
+    state.user = undefined;
+  };
+
   function configureWebSocket(params: WebSocketHandlersParams): WebSocket {
     return webSocketHandler(`ws://${host("/ws")}`, params);
   }
@@ -118,9 +219,14 @@ export function initApi() {
     configureWebSocket,
     fetchBatch,
     fetchBatchesList,
+    fetchChangesSince,
     fetchGameSettings,
+    fetchLeaderboard,
+    fetchUserRank,
     fetchUsers,
     login,
+    logout,
+    refreshSession,
     state,
   };
 }
@@ -30,8 +30,14 @@ export function login(api: GameApi, onLogged: OnLoggedSuccess) {
     setLoading(true);
 
     const value = input.value;
-    // TODO: validation & error handling
-    await api.login(value);
+    // TODO: validation
+    try {
+      await api.login(value);
+    } catch (error) {
+      setLoading(false);
+      alert((error as Error).message);
+      return;
+    }
 
     login.classList.add("success");
     onLogged();
@@ -12,12 +12,37 @@ import {
   WebGLRenderer,
 } from "three";
 import { getTileName, ownerOf } from "./grid";
-import { CoordsAndTile, HexUserData, Tile, WithCallback } from "./types";
+import {
+  AxialCoords,
+  CoordsAndTile,
+  HexUserData,
+  Tile,
+  WithCallback,
+} from "./types";
 import { OrbitControls } from "three/examples/jsm/Addons.js";
 import { GameApi } from "./api";
 import { createHexMap } from "./shapes";
 import { hexagonColor } from "./colors";
+import { HEX_COLOR } from "./constants";
 import { wait } from "./utils";
+import { CloseCode, resyncFrame, subscribeFrame } from "./websocket";
+
+// milliseconds to wait before reconnecting after the server closed the socket
+// with `code`, undefined when reconnecting will not help
+function reconnectDelay(code: number): number | undefined {
+  switch (code) {
+    case CloseCode.UnsupportedVersion:
+      console.error("Server speaks another protocol version, reload the page");
+      return undefined;
+    case CloseCode.ServerFull:
+      return 10_000 + Math.random() * 10_000;
+    case CloseCode.TooManyConnections:
+      console.warn("Too many connections, close the game in other tabs");
+      return 30_000;
+    default:
+      return 1000;
+  }
+}
 
 function handleLights(scene: Scene) {
   const ambientLight = new AmbientLight(0xffffff, 0.95); // Soft global light
@@ -139,35 +164,101 @@ export async function render({ api, onReady }: RenderParams) {
 
   renderer.setAnimationLoop(animate);
 
-  api.configureWebSocket({
-    onOpen: () => {
-      wsConnected = true;
-    },
-    onClose: () => {
-      wsConnected = false;
-    },
-    onNewUser: (user) => {
-      api.state.users[user.id] = user;
-    },
-    onTileChange: (coords, tile) => {
-      const hex = hexMap.getObjectByName(getTileName(coords)) as Mesh;
-      if (hex) {
-        hex.userData.user_id = tile.user_id;
-
-        try {
-          const user = ownerOf(api.state.users, tile);
-          if (user) {
-            (hex.material as MeshPhongMaterial).color.set(
-              hexagonColor(parseInt(user.color.slice(1), 16), tile.strength)
-            );
-          }
-        } catch (e) {
-          console.error(`Did not found user for ${tile.user_id} ID`, e);
-          // fail silently
+  // batches rendered so far, their tile changes are asked once the websocket is open
+  const loadedBatches: number[] = [];
+  let requestId = 0;
+  // change sequence the rendered tiles are up to date with
+  let lastSeq: number | undefined = undefined;
+  let resyncRequestId: number | undefined = undefined;
+
+  function applyTileChange(coords: AxialCoords, tile: Tile) {
+    const hex = hexMap.getObjectByName(getTileName(coords)) as Mesh;
+    if (hex) {
+      hex.userData.user_id = tile.user_id;
+
+      try {
+        const user = ownerOf(api.state.users, tile);
+        if (user) {
+          (hex.material as MeshPhongMaterial).color.set(
+            hexagonColor(parseInt(user.color.slice(1), 16), tile.strength)
+          );
         }
+      } catch (e) {
+        console.error(`Did not found user for ${tile.user_id} ID`, e);
+        // fail silently
       }
-    },
-  });
+    }
+  }
+
+  // empties every tile, the grid of a new round starts empty
+  function clearTiles() {
+    hexMap.children.forEach((hex) => {
+      hex.userData.user_id = undefined;
+      ((hex as Mesh).material as MeshPhongMaterial).color.set(
+        hexagonColor(HEX_COLOR, 0)
+      );
+    });
+    tiles = {};
+  }
+
+  // downloads every loaded batch again, when the missed changes are not known anymore
+  async function reloadBatches() {
+    lastSeq = undefined;
+
+    for (const batch of loadedBatches) {
+      const { seq, tiles: batchTiles } = await api.fetchBatch(batch);
+      batchTiles.forEach(([coords, tile]) => applyTileChange(coords, tile));
+      lastSeq = lastSeq === undefined ? seq : Math.min(lastSeq, seq);
+    }
+  }
+
+  function connect(): WebSocket {
+    const ws = api.configureWebSocket({
+      onOpen: () => {
+        wsConnected = true;
+
+        if (loadedBatches.length > 0) {
+          ws.send(subscribeFrame(++requestId, loadedBatches));
+        }
+        // catch up with the changes made while disconnected
+        if (lastSeq !== undefined) {
+          resyncRequestId = ++requestId;
+          ws.send(resyncFrame(resyncRequestId, lastSeq));
+        }
+      },
+      onClose: (code) => {
+        wsConnected = false;
+
+        const delay = reconnectDelay(code);
+        if (delay === undefined) {
+          return;
+        }
+        setTimeout(() => {
+          socket = connect();
+        }, delay);
+      },
+      onReply: (id, error) => {
+        if (id === resyncRequestId && error?.code === 7) {
+          reloadBatches();
+        }
+      },
+      onNewUser: (user) => {
+        api.state.users[user.id] = user;
+      },
+      onSeq: (seq) => {
+        lastSeq = seq;
+      },
+      onRound: () => {
+        clearTiles();
+        reloadBatches();
+      },
+      onTileChange: applyTileChange,
+    });
+
+    return ws;
+  }
+
+  let socket = connect();
 
   window.onresize = function () {
     camera.aspect = window.innerWidth / window.innerHeight;
@@ -181,7 +272,9 @@ export async function render({ api, onReady }: RenderParams) {
   let res: CoordsAndTile[] = [];
 
   for (let batch of batches) {
-    let data = await api.fetchBatch(batch);
+    let { seq, tiles: data } = await api.fetchBatch(batch);
+    // keep the oldest sequence so that no change of a loaded batch is missed
+    lastSeq = lastSeq === undefined ? seq : Math.min(lastSeq, seq);
     data.forEach(([coords, tile]) => {
       let k = getTileName(coords);
       let hex = hexMap.getObjectByName(k) as Mesh;
@@ -198,6 +291,11 @@ export async function render({ api, onReady }: RenderParams) {
       tiles[k] = tile;
     });
 
+    loadedBatches.push(batch);
+    if (socket.readyState === WebSocket.OPEN) {
+      socket.send(subscribeFrame(++subscribeRequestId, [batch]));
+    }
+
     await wait(50);
 
     res = res.concat(data);
@@ -30,10 +30,21 @@ export interface User {
   token: string;
   color: string;
   id: string;
+  team?: string;
 }
 
 export type PublicUser = Omit<User, "token">;
 
+export type RankedUser = PublicUser & {
+  rank: number;
+  score: number;
+};
+
+export type LeaderboardEntry = {
+  id: string;
+  score: number;
+};
+
 export type UserWithAuth = User & {
   token: string;
 };
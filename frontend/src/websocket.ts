@@ -1,16 +1,172 @@
-import { AxialCoords, PublicUser, Tile } from "./types";
+import { AxialCoords, LeaderboardEntry, PublicUser, Tile } from "./types";
 
 export type WebSocketHandlersParams = {
   onTileChange: (coors: AxialCoords, tile: Tile) => void;
   onNewUser: (user: PublicUser) => void;
+  onLeaderboardChange?: (top: LeaderboardEntry[]) => void;
+  onCooldown?: (userId: string, remainingMs: number) => void;
+  // change sequence of the last tiles change, to send in `resyncFrame` after a reconnection
+  onSeq?: (seq: number) => void;
+  // a new round started on an empty grid, times are unix times in ms,
+  // endsAtMs is undefined when the round never ends
+  onRound?: (id: number, startsAtMs: number, endsAtMs?: number) => void;
+  // reply to a frame sent with `authenticateFrame`, `clickFrame`,
+  // `subscribeFrame` or `unsubscribeFrame`, error is
+  // undefined when the frame was acked
+  onReply?: (requestId: number, error?: FrameError) => void;
   onOpen: () => void;
-  onClose: () => void;
+  // code of the close frame, see `CloseCode`
+  onClose: (code: number) => void;
 };
 
+// Wire format is documented in server/src/protocol.rs, bump both together
+export const PROTOCOL_VERSION = 4;
+
+// Codes of the close frames sent by the server, see `CloseStatus` in
+// server/src/protocol.rs
+export const CloseCode = {
+  // hello announced another protocol version, reconnecting will not help
+  UnsupportedVersion: 1002,
+  // the client did not read its messages fast enough
+  TooManyPending: 1013,
+  // the client did not answer pings in time
+  Timeout: 4000,
+  // the server has too many clients connected
+  ServerFull: 4001,
+  // too many connections from the same address
+  TooManyConnections: 4002,
+} as const;
+
+export type FrameError = {
+  // 1: malformed frame, 2: unauthorized, 3: cooldown, 4: internal error,
+  // 5: hello not sent yet, 6: unsupported protocol version, 7: changes since
+  // the resync sequence are lost, every batch must be downloaded again,
+  // 8: click sent outside of the current round
+  code: number;
+  message: string;
+};
+
+// Client frame answering the server hello, must be sent before any other frame
+export function helloFrame(requestId: number, version: number): ArrayBuffer {
+  const data = new Uint8Array(1 + 4 + 1);
+  const view = new DataView(data.buffer);
+
+  data[0] = 0x00;
+  view.setUint32(1, requestId, true);
+  data[5] = version;
+
+  return data.buffer;
+}
+
+// Client frame authenticating the websocket as `userId`, needed before clicking
+export function authenticateFrame(
+  requestId: number,
+  userId: string,
+  token: string
+): ArrayBuffer {
+  const id = new TextEncoder().encode(userId);
+  const tok = new TextEncoder().encode(token);
+  const data = new Uint8Array(1 + 4 + 1 + id.length + 1 + tok.length);
+  const view = new DataView(data.buffer);
+
+  data[0] = 0x01;
+  view.setUint32(1, requestId, true);
+  data[5] = id.length;
+  data.set(id, 6);
+  data[6 + id.length] = tok.length;
+  data.set(tok, 7 + id.length);
+
+  return data.buffer;
+}
+
+// Client frame clicking on a tile, replaces `POST /tile/{q}/{r}`
+export function clickFrame(requestId: number, coords: AxialCoords): ArrayBuffer {
+  const data = new Uint8Array(1 + 4 + 4 + 4);
+  const view = new DataView(data.buffer);
+
+  data[0] = 0x02;
+  view.setUint32(1, requestId, true);
+  view.setInt32(5, coords.q, true);
+  view.setInt32(9, coords.r, true);
+
+  return data.buffer;
+}
+
+function batchesFrame(
+  type: number,
+  requestId: number,
+  batches: number[]
+): ArrayBuffer {
+  const data = new Uint8Array(1 + 4 + 2 + 2 * batches.length);
+  const view = new DataView(data.buffer);
+
+  data[0] = type;
+  view.setUint32(1, requestId, true);
+  view.setUint16(5, batches.length, true);
+  batches.forEach((batch, i) => view.setUint16(7 + 2 * i, batch, true));
+
+  return data.buffer;
+}
+
+// Client frame asking for the tile changes of `batches`, tile changes are only
+// sent for subscribed batches
+export function subscribeFrame(
+  requestId: number,
+  batches: number[]
+): ArrayBuffer {
+  return batchesFrame(0x03, requestId, batches);
+}
+
+// Client frame stopping the tile changes of `batches`
+export function unsubscribeFrame(
+  requestId: number,
+  batches: number[]
+): ArrayBuffer {
+  return batchesFrame(0x04, requestId, batches);
+}
+
+// Client frame asking the tile changes of subscribed batches made after `since`
+export function resyncFrame(requestId: number, since: number): ArrayBuffer {
+  const data = new Uint8Array(1 + 4 + 8);
+  const view = new DataView(data.buffer);
+
+  data[0] = 0x05;
+  view.setUint32(1, requestId, true);
+  view.setBigUint64(5, BigInt(since), true);
+
+  return data.buffer;
+}
+
 export function webSocketHandler(
   url: string,
-  { onOpen, onClose, onTileChange, onNewUser }: WebSocketHandlersParams
+  {
+    onOpen,
+    onClose,
+    onTileChange,
+    onNewUser,
+    onLeaderboardChange,
+    onCooldown,
+    onSeq,
+    onRound,
+    onReply,
+  }: WebSocketHandlersParams
 ): WebSocket {
+  function handleHelloMessage(data: Uint8Array) {
+    const version = data[1];
+
+    if (version !== PROTOCOL_VERSION) {
+      console.error("Unsupported protocol version", {
+        server: version,
+        client: PROTOCOL_VERSION,
+      });
+      socket.close();
+      return;
+    }
+
+    socket.send(helloFrame(0, PROTOCOL_VERSION));
+    onOpen();
+  }
+
   function handeTileChange(data: Uint8Array) {
     const view = new DataView(data.buffer);
     // Process the binary data (for example, extracting coordinates and tile data)
@@ -30,6 +186,46 @@ export function webSocketHandler(
     }
   }
 
+  function handleTilesChange(data: Uint8Array) {
+    const view = new DataView(data.buffer);
+
+    // Index 1: change sequence (u64), then number of tiles (u16), then each tile
+    // as in a tile change message
+    const seq = Number(view.getBigUint64(1, true));
+    const count = view.getUint16(9, true);
+
+    let offset = 11;
+    for (let i = 0; i < count; i++) {
+      const q = view.getInt32(offset, true);
+      const r = view.getInt32(offset + 4, true);
+      const strength = data[offset + 8];
+      const userIdLength = data[offset + 9];
+      const userId = new TextDecoder().decode(
+        data.slice(offset + 10, offset + 10 + userIdLength)
+      );
+      offset += 10 + userIdLength;
+
+      if (userIdLength > 0) {
+        onTileChange({ q, r }, { user_id: userId, strength });
+      }
+    }
+
+    onSeq?.(seq);
+  }
+
+  function handleRoundMessage(data: Uint8Array) {
+    const view = new DataView(data.buffer);
+
+    // round id (u32), starts at (u64), ends at (u64, 0 if the round never ends)
+    const id = view.getUint32(1, true);
+    const startsAtMs = Number(view.getBigUint64(5, true));
+    const endsAtMs = Number(view.getBigUint64(13, true));
+
+    console.log("[ws/handleRoundMessage]", { id, startsAtMs, endsAtMs });
+
+    onRound?.(id, startsAtMs, endsAtMs === 0 ? undefined : endsAtMs);
+  }
+
   function handleNewUserMessage(data: Uint8Array) {
     // Create a DataView instance for efficient reading of binary data
     const view = new DataView(data.buffer);
@@ -59,14 +255,22 @@ export function webSocketHandler(
       )
     );
 
+    // The last string is the team of the user, empty when it has none
+    const teamStart = 4 + idLength + usernameLength + colorLength;
+    const teamLength = data[teamStart];
+    const team = new TextDecoder().decode(
+      data.slice(teamStart + 1, teamStart + 1 + teamLength)
+    );
+
     console.log("[ws/handleNewUserMessage]", {
       username, // please help me parse those
       color,
       id,
+      team,
     });
 
     if (username.length > 0 && color.length > 0 && id.length > 0) {
-      onNewUser({ id, color, username });
+      onNewUser({ id, color, username, team: team || undefined });
     }
   }
 
@@ -74,6 +278,67 @@ export function webSocketHandler(
     // TODO
   }
 
+  function handleLeaderboardMessage(data: Uint8Array) {
+    const view = new DataView(data.buffer);
+
+    // Index 1: number of entries (u8), entries are sorted by rank
+    const count = view.getUint8(1);
+    const top: LeaderboardEntry[] = [];
+
+    let offset = 2;
+    for (let i = 0; i < count; i++) {
+      // user ID length (u8) then user ID bytes then score (u32)
+      const idLength = view.getUint8(offset);
+      const id = new TextDecoder().decode(
+        data.slice(offset + 1, offset + 1 + idLength)
+      );
+      const score = view.getUint32(offset + 1 + idLength, true);
+
+      top.push({ id, score });
+      offset += 1 + idLength + 4;
+    }
+
+    console.log("[ws/handleLeaderboardMessage]", top);
+
+    onLeaderboardChange?.(top);
+  }
+
+  function handleCooldownMessage(data: Uint8Array) {
+    const view = new DataView(data.buffer);
+
+    // Index 1: length of the user ID (u8), then user ID bytes
+    const idLength = view.getUint8(1);
+    const userId = new TextDecoder().decode(data.slice(2, 2 + idLength));
+
+    // Milliseconds before the user can click again (u32)
+    const remainingMs = view.getUint32(2 + idLength, true);
+
+    console.log("[ws/handleCooldownMessage]", { userId, remainingMs });
+
+    onCooldown?.(userId, remainingMs);
+  }
+
+  function handleAckMessage(data: Uint8Array) {
+    const view = new DataView(data.buffer);
+    const requestId = view.getUint32(1, true);
+
+    onReply?.(requestId);
+  }
+
+  function handleErrorMessage(data: Uint8Array) {
+    const view = new DataView(data.buffer);
+
+    // request id (u32), error code (u8), message length (u8), message bytes
+    const requestId = view.getUint32(1, true);
+    const code = view.getUint8(5);
+    const messageLength = view.getUint8(6);
+    const message = new TextDecoder().decode(data.slice(7, 7 + messageLength));
+
+    console.log("[ws/handleErrorMessage]", { requestId, code, message });
+
+    onReply?.(requestId, { code, message });
+  }
+
   // WEBSOCKET
   const socket = new WebSocket(url); // Adjust the URL if needed
   socket.binaryType = "arraybuffer";
@@ -91,6 +356,9 @@ export function webSocketHandler(
     const messageType = data[0];
 
     switch (messageType) {
+      case 0x00: // Server protocol version, sent on connect
+        handleHelloMessage(data);
+        break;
       case 0x01:
         handeTileChange(data);
         break;
@@ -100,6 +368,24 @@ export function webSocketHandler(
       case 0x03: // Score change message
         handleScoreChangeMessage(data);
         break;
+      case 0x04: // Top of the leaderboard changed
+        handleLeaderboardMessage(data);
+        break;
+      case 0x05: // Click cooldown of an user
+        handleCooldownMessage(data);
+        break;
+      case 0x06: // A client frame was accepted
+        handleAckMessage(data);
+        break;
+      case 0x07: // A client frame was refused
+        handleErrorMessage(data);
+        break;
+      case 0x08: // Tiles changed during the last broadcast tick
+        handleTilesChange(data);
+        break;
+      case 0x09: // A new round started
+        handleRoundMessage(data);
+        break;
       // Other cases for different message types (e.g., player login)
       default:
         console.error("Unknown message type:", messageType);
@@ -108,8 +394,8 @@ export function webSocketHandler(
 
   // Register WebSocket event listeners
   socket.addEventListener("open", function (event) {
+    // `onOpen` is called once the server hello is answered
     console.log("WebSocket is open now.");
-    onOpen();
   });
 
   socket.addEventListener("error", function (error) {
@@ -117,8 +403,8 @@ export function webSocketHandler(
   });
 
   socket.addEventListener("close", function (event) {
-    console.log("WebSocket is closed now.");
-    onClose();
+    console.log("WebSocket is closed now.", { code: event.code, reason: event.reason });
+    onClose(event.code);
   });
 
   return socket;
@@ -0,0 +1,68 @@
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use futures::future::LocalBoxFuture;
+
+use crate::{
+    error::{GameError, GameResult},
+    store::{GameStore, RedisHandler},
+    user::User,
+};
+
+/// User authenticated with its id and token sent as basic auth credentials.
+///
+/// Handlers taking an `AuthenticatedUser` only run for valid credentials,
+/// every other request is rejected with `GameError::Unauthorized`.
+pub struct AuthenticatedUser(pub User);
+
+impl FromRequest for AuthenticatedUser {
+    type Error = GameError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let credentials = BasicAuth::from_request(req, payload);
+        let store = req.app_data::<web::Data<GameStore>>().cloned();
+        let pool = req.app_data::<web::Data<deadpool_redis::Pool>>().cloned();
+
+        Box::pin(async move {
+            let credentials = credentials
+                .await
+                .map_err(|_| GameError::Unauthorized("Missing credentials".to_string()))?;
+
+            let (Some(store), Some(pool)) = (store, pool) else {
+                return Err(GameError::StoreUnavailable(
+                    "Store is not configured".to_string(),
+                ));
+            };
+
+            let user_id = credentials.user_id();
+            let token = credentials.password().unwrap_or("");
+
+            authenticate_user(&store, &pool, user_id, token)
+                .await
+                .map(AuthenticatedUser)
+        })
+    }
+}
+
+/// Returns the user matching `user_id` if `token` is its current session token
+pub async fn authenticate_user(
+    store: &GameStore,
+    pool: &deadpool_redis::Pool,
+    user_id: &str,
+    token: &str,
+) -> GameResult<User> {
+    let invalid_token = || GameError::Unauthorized("Invalid token".to_string());
+    let mut con = store.get_connection(pool).await?;
+
+    if !store
+        .is_valid_token_for_user(&mut con, token, user_id)
+        .await?
+    {
+        return Err(invalid_token());
+    }
+
+    store
+        .get_user_by_id(&mut con, user_id)
+        .await?
+        .ok_or_else(invalid_token)
+}
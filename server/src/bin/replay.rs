@@ -0,0 +1,227 @@
+//! Rebuilds the grid as it was at a given time from the click events stored in redis.
+//!
+//! Usage:
+//! - `replay [--until <unix time in ms>] [--output <file>]` writes the replayed tiles as
+//!   JSON, in the format of the `/tiles` route, to `--output` or to the standard output.
+//!   Without `--until` the whole history is replayed.
+//! - `replay timelapse --from <ms> --to <ms> --every <ms> --out-dir <dir> [--hex-size <px>]`
+//!   renders the grid every `--every` ms between `--from` and `--to` into numbered PNG
+//!   frames (`frame_000000.png`, `frame_000001.png`...) in `--out-dir`.
+//!
+//! Both replay the current round unless `--round <id>` is given.
+
+use std::{fs::File, io::Write, path::PathBuf};
+
+use pixelstratwar::{
+    config::GameConfig,
+    history::{self, Replay},
+    rules::ClassicRules,
+    snapshot::{self, DEFAULT_HEX_SIZE},
+    store::{self, RedisHandler, RedisStore},
+};
+
+const USAGE: &str = "Usage:
+  replay [--round <id>] [--until <unix time in ms>] [--output <file>]
+  replay timelapse [--round <id>] --from <ms> --to <ms> --every <ms> --out-dir <dir> [--hex-size <px>]";
+
+struct Args {
+    /// Current round when None
+    round: Option<u32>,
+    command: Command,
+}
+
+enum Command {
+    Grid {
+        until_ms: u64,
+        output: Option<String>,
+    },
+    Timelapse {
+        from_ms: u64,
+        to_ms: u64,
+        every_ms: u64,
+        out_dir: PathBuf,
+        hex_size: u32,
+    },
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{name} expects a number"))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {name} value {value}"))
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut argv = std::env::args().skip(1).peekable();
+    let mut round = None;
+
+    if argv.peek().is_some_and(|a| a == "timelapse") {
+        argv.next();
+
+        let (mut from_ms, mut to_ms, mut every_ms, mut out_dir) = (None, None, None, None);
+        let mut hex_size = DEFAULT_HEX_SIZE;
+
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--from" => from_ms = Some(parse_number(&arg, argv.next())?),
+                "--to" => to_ms = Some(parse_number(&arg, argv.next())?),
+                "--every" => every_ms = Some(parse_number(&arg, argv.next())?),
+                "--hex-size" => hex_size = parse_number(&arg, argv.next())?,
+                "--round" => round = Some(parse_number(&arg, argv.next())?),
+                "--out-dir" => {
+                    out_dir = Some(PathBuf::from(
+                        argv.next().ok_or("--out-dir expects a directory")?,
+                    ))
+                }
+                other => return Err(format!("Unknown argument {other}")),
+            }
+        }
+
+        let every_ms = every_ms.ok_or("--every is required")?;
+        if every_ms == 0 {
+            return Err("--every must be greater than 0".to_string());
+        }
+
+        return Ok(Args {
+            round,
+            command: Command::Timelapse {
+                from_ms: from_ms.ok_or("--from is required")?,
+                to_ms: to_ms.ok_or("--to is required")?,
+                every_ms,
+                out_dir: out_dir.ok_or("--out-dir is required")?,
+                hex_size,
+            },
+        });
+    }
+
+    let mut until_ms = u64::MAX;
+    let mut output = None;
+
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--until" => until_ms = parse_number(&arg, argv.next())?,
+            "--round" => round = Some(parse_number(&arg, argv.next())?),
+            "--output" => output = Some(argv.next().ok_or("--output expects a file path")?),
+            other => return Err(format!("Unknown argument {other}")),
+        }
+    }
+
+    Ok(Args {
+        round,
+        command: Command::Grid { until_ms, output },
+    })
+}
+
+fn report_divergences(replay: &Replay) {
+    for event in &replay.diverged {
+        eprintln!("Recorded outcome differs from the game rules: {event:?}");
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let Args { round, command } = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let app_config = GameConfig::read_config_from_env();
+    let (client, pool) = store::init_redis_client(&app_config)
+        .await
+        .expect("Could not connect to redis");
+    let client = RedisStore::new(client);
+    let mut con = pool.get().await.expect("Could not get a redis connection");
+
+    let round = match round {
+        Some(round) => round,
+        None => client
+            .get_current_round(&mut con)
+            .await
+            .expect("Could not read the current round")
+            .map_or(1, |round| round.id),
+    };
+    client
+        .use_round(&mut con, round)
+        .await
+        .expect("Could not switch to the round");
+
+    match command {
+        Command::Grid { until_ms, output } => {
+            let events = client
+                .get_click_events(&mut con, until_ms)
+                .await
+                .expect("Could not read click events");
+
+            let replay =
+                history::replay_with(ClassicRules::from_config(&app_config), &events, until_ms);
+
+            eprintln!(
+                "Replayed {} click events, {} tiles owned",
+                replay.applied,
+                replay.tiles.len()
+            );
+            report_divergences(&replay);
+
+            let json =
+                serde_json::to_string(&replay.computed_tiles()).expect("Could not serialize tiles");
+
+            match output {
+                Some(path) => {
+                    let mut file = File::create(&path).expect("Could not create output file");
+                    writeln!(file, "{json}").expect("Could not write output file");
+                }
+                None => println!("{json}"),
+            }
+        }
+        Command::Timelapse {
+            from_ms,
+            to_ms,
+            every_ms,
+            out_dir,
+            hex_size,
+        } => {
+            let events = client
+                .get_click_events(&mut con, to_ms)
+                .await
+                .expect("Could not read click events");
+            let users = client
+                .get_public_users(&mut con)
+                .await
+                .expect("Could not read users");
+            let colors = snapshot::user_colors(&users);
+
+            std::fs::create_dir_all(&out_dir).expect("Could not create output directory");
+
+            // frames are rendered in order, each one only replays the events since the previous one
+            let mut replay = Replay::new(ClassicRules::from_config(&app_config));
+            let mut events = events.iter().peekable();
+            let mut nb_frames = 0;
+
+            for frame_ms in (from_ms..=to_ms).step_by(every_ms as usize) {
+                while let Some(event) = events.next_if(|e| e.timestamp_ms <= frame_ms) {
+                    replay.apply(event);
+                }
+
+                let png =
+                    snapshot::render_png(&replay.tiles, &colors, app_config.grid_radius, hex_size)
+                        .expect("Could not encode frame");
+
+                let path = out_dir.join(format!("frame_{nb_frames:06}.png"));
+                std::fs::write(&path, png).expect("Could not write frame");
+                nb_frames += 1;
+            }
+
+            eprintln!(
+                "Rendered {nb_frames} frames from {} click events to {}",
+                replay.applied,
+                out_dir.display()
+            );
+            report_divergences(&replay);
+        }
+    }
+}
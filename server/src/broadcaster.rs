@@ -0,0 +1,135 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix::{Actor, AsyncContext, Context, Handler, Message, SpawnHandle};
+use actix_web::web;
+
+use crate::{
+    changes::ChangeLog,
+    clients::{ClientList, TilesByBatch},
+    coords::AxialCoords,
+    game::{GameData, TileData},
+    relay::Relay,
+};
+
+/// Tiles updated by a click, sent to the broadcaster
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TileChanges(pub Vec<(AxialCoords, TileData)>);
+
+/// Tiles updated by a click on another instance, see `Relay`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RelayedTileChanges(pub Vec<(AxialCoords, TileData)>);
+
+/// Collects tile changes for a tick then sends one `ServerMessage::TilesChange`
+/// per subscribed client, through the shards of the `ClientList`.
+///
+/// Changes are recorded in the `ChangeLog` as they are received, so the sequence
+/// sent with a tick covers every change sent until then.
+///
+/// The tick starts with the first change received after a flush, a tile updated
+/// several times during a tick is only sent with its last state.
+///
+/// With a `Relay`, changes of local clicks are also published to the other instances.
+pub struct TileBroadcaster {
+    clients: ClientList,
+    game_data: web::Data<GameData>,
+    change_log: web::Data<ChangeLog>,
+    tick: Duration,
+    pending: HashMap<AxialCoords, TileData>,
+    pending_seq: u64,
+    scheduled_flush: Option<SpawnHandle>,
+    relay: Option<Relay>,
+}
+
+impl TileBroadcaster {
+    /// A zero `tick` sends the changes of every click as soon as they are received
+    pub fn new(
+        clients: ClientList,
+        game_data: web::Data<GameData>,
+        change_log: web::Data<ChangeLog>,
+        tick: Duration,
+    ) -> Self {
+        Self {
+            clients,
+            game_data,
+            change_log,
+            tick,
+            pending: HashMap::new(),
+            pending_seq: 0,
+            scheduled_flush: None,
+            relay: None,
+        }
+    }
+
+    pub fn with_relay(self, relay: Relay) -> Self {
+        Self {
+            relay: Some(relay),
+            ..self
+        }
+    }
+
+    // Records `tiles` and sends them with the next tick
+    fn queue(&mut self, tiles: Vec<(AxialCoords, TileData)>, ctx: &mut Context<Self>) {
+        self.pending_seq = self.change_log.record(&tiles);
+
+        // later changes of a tile replace the pending ones
+        self.pending.extend(tiles);
+
+        if self.tick.is_zero() {
+            self.flush();
+        } else if self.scheduled_flush.is_none() {
+            self.scheduled_flush = Some(ctx.run_later(self.tick, |act, _ctx| act.flush()));
+        }
+    }
+
+    /// Sends the pending changes to the clients subscribed to their batch
+    fn flush(&mut self) {
+        self.scheduled_flush = None;
+
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let mut tiles_by_batch = TilesByBatch::new();
+        for (coords, tile) in self.pending.drain() {
+            if let Some(batch) = self.game_data.batch_of(&coords) {
+                tiles_by_batch
+                    .entry(batch)
+                    .or_default()
+                    .push((coords, tile));
+            }
+        }
+
+        self.clients.send_tiles(self.pending_seq, tiles_by_batch);
+    }
+}
+
+impl Actor for TileBroadcaster {
+    type Context = Context<Self>;
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::Running {
+        self.flush();
+        actix::Running::Stop
+    }
+}
+
+impl Handler<TileChanges> for TileBroadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: TileChanges, ctx: &mut Self::Context) {
+        if let Some(relay) = &self.relay {
+            relay.publish_tiles(&msg.0);
+        }
+
+        self.queue(msg.0, ctx);
+    }
+}
+
+impl Handler<RelayedTileChanges> for TileBroadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: RelayedTileChanges, ctx: &mut Self::Context) {
+        self.queue(msg.0, ctx);
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{coords::AxialCoords, game::TileData};
+
+/// Bits of a sequence counting the changes of a log, the bits above hold its epoch.
+/// Sequences stay below 2^53 so that javascript clients read them exactly.
+const COUNTER_BITS: u32 = 37;
+const EPOCH_BITS: u32 = 53 - COUNTER_BITS;
+
+/// Bounded log of the last tile changes, each change gets the next value of the
+/// sequence of the log so that reconnecting clients only fetch what they missed.
+///
+/// Every log counts its own changes, so each one tags its sequences with a random
+/// epoch. Sequences given by another instance, or before a restart, have another
+/// epoch and lead to a full resync instead of the changes of an unrelated log.
+pub struct ChangeLog {
+    capacity: usize,
+    inner: Mutex<ChangeLogInner>,
+}
+
+struct ChangeLogInner {
+    last_seq: u64,
+    entries: VecDeque<(u64, AxialCoords, TileData)>,
+}
+
+/// Result of `ChangeLog::since`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChangesSince {
+    /// Last state of every tile changed after the requested sequence
+    Changes {
+        seq: u64,
+        tiles: Vec<(AxialCoords, TileData)>,
+    },
+    /// Changes after the requested sequence are not in the log anymore, every
+    /// batch must be downloaded again
+    ResyncRequired { seq: u64 },
+}
+
+impl ChangeLog {
+    /// Log with a random epoch
+    pub fn new(capacity: usize) -> Self {
+        let epoch: u64 = rand::thread_rng().gen_range(1..1 << EPOCH_BITS);
+
+        Self::starting_at(epoch << COUNTER_BITS, capacity)
+    }
+
+    /// Log whose first change gets `seq + 1`, its epoch being the one of `seq`
+    pub fn starting_at(seq: u64, capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(ChangeLogInner {
+                last_seq: seq,
+                entries: VecDeque::with_capacity(capacity),
+            }),
+        }
+    }
+
+    /// Sequence of the last recorded change
+    pub fn last_seq(&self) -> u64 {
+        self.inner.lock().unwrap().last_seq
+    }
+
+    /// Gives a sequence to each of `tiles`, returns the last one
+    pub fn record(&self, tiles: &[(AxialCoords, TileData)]) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+
+        for (coords, tile) in tiles {
+            inner.last_seq += 1;
+            let seq = inner.last_seq;
+
+            if inner.entries.len() == self.capacity {
+                inner.entries.pop_front();
+            }
+            if self.capacity > 0 {
+                inner.entries.push_back((seq, *coords, tile.clone()));
+            }
+        }
+
+        inner.last_seq
+    }
+
+    /// Forgets every change, e.g. when a new round starts on an empty grid. Clients
+    /// resyncing from any sequence given so far have to download every batch again.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.entries.clear();
+        // the last given sequence must fall out of the log too
+        inner.last_seq += 1;
+    }
+
+    /// Tiles changed after `seq`, or `ResyncRequired` if some of these changes
+    /// were trimmed from the log or `seq` was never given by this log
+    pub fn since(&self, seq: u64) -> ChangesSince {
+        let inner = self.inner.lock().unwrap();
+        let last_seq = inner.last_seq;
+
+        // first sequence still in the log, `last_seq + 1` when the log is empty
+        let first_seq = last_seq + 1 - inner.entries.len() as u64;
+
+        let same_epoch = seq >> COUNTER_BITS == last_seq >> COUNTER_BITS;
+
+        if !same_epoch || seq > last_seq || seq + 1 < first_seq {
+            return ChangesSince::ResyncRequired { seq: last_seq };
+        }
+
+        // a tile changed several times is only sent with its last state
+        let mut latest: HashMap<AxialCoords, usize> = HashMap::new();
+        let mut tiles = Vec::new();
+
+        for (_, coords, tile) in inner.entries.iter().skip((seq + 1 - first_seq) as usize) {
+            match latest.get(coords) {
+                Some(&i) => tiles[i] = (*coords, tile.clone()),
+                None => {
+                    latest.insert(*coords, tiles.len());
+                    tiles.push((*coords, tile.clone()));
+                }
+            }
+        }
+
+        ChangesSince::Changes {
+            seq: last_seq,
+            tiles,
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::time::Instant;
+
+use actix::Addr;
+use actix_web::{dev::Payload, error, web, Error, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+
+use crate::{
+    broadcaster::{TileBroadcaster, TileChanges},
+    clients::ClientList,
+    config::GameConfig,
+    coords::{is_within_grid, AxialCoords},
+    error::{GameError, GameResult},
+    game::{ClickAction, GameData},
+    leaderboard::LeaderboardWatcher,
+    metrics::Metrics,
+    rounds::CurrentRound,
+    store::{GameStore, RedisHandler, StoreConnection},
+    user::User,
+    websocket::{notify_cooldown, notify_leaderboard_change, notify_score_change},
+};
+
+/// App data needed to play a click, shared by `POST /tile/{q}/{r}` and the websocket
+/// click frame so both go through the same cooldown and notifications.
+#[derive(Clone)]
+pub struct ClickContext {
+    pub app_config: web::Data<GameConfig>,
+    pub broadcaster: web::Data<Addr<TileBroadcaster>>,
+    pub clients: web::Data<ClientList>,
+    pub game_data: web::Data<GameData>,
+    pub leaderboard: web::Data<LeaderboardWatcher>,
+    pub metrics: web::Data<Metrics>,
+    pub pool: web::Data<deadpool_redis::Pool>,
+    pub round: web::Data<CurrentRound>,
+    pub store: web::Data<GameStore>,
+}
+
+impl ClickContext {
+    /// Plays a click of `user` on `coords` and notifies websocket clients of the changes
+    #[tracing::instrument(
+        name = "click",
+        skip_all,
+        fields(q = coords.q, r = coords.r, user_id = %user.id, outcome)
+    )]
+    pub async fn play(&self, user: &User, coords: &AxialCoords) -> GameResult<()> {
+        let started = Instant::now();
+        let played = self.play_click(user, coords).await;
+
+        let outcome = match &played {
+            Ok(action) => action.as_str(),
+            Err(e) => e.kind(),
+        };
+        tracing::Span::current().record("outcome", outcome);
+        self.metrics
+            .observe_click(&user.id, outcome, started.elapsed());
+
+        played.map(|_| ())
+    }
+
+    async fn play_click(&self, user: &User, coords: &AxialCoords) -> GameResult<ClickAction> {
+        if !self.round.is_open() {
+            return Err(GameError::RoundOver);
+        }
+
+        // checked before the cooldown so that a bad click does not use it up
+        if !is_within_grid(*coords, self.game_data.settings.radius) {
+            return Err(GameError::InvalidCoords(*coords));
+        }
+
+        let mut con = self.store.get_connection(&self.pool).await?;
+        let played = self.apply_click(&mut con, user, coords).await;
+        self.metrics.observe_round_trips("click", con.round_trips());
+
+        played
+    }
+
+    async fn apply_click(
+        &self,
+        con: &mut StoreConnection,
+        user: &User,
+        coords: &AxialCoords,
+    ) -> GameResult<ClickAction> {
+        let user_id = user.id.as_str();
+        let store = &**self.store;
+
+        let cooldown_ms = self.app_config.click_cooldown_ms;
+
+        if cooldown_ms > 0 {
+            let cooldown = store
+                .start_click_cooldown(con, user_id, cooldown_ms)
+                .await?;
+
+            if let Some(remaining_ms) = cooldown {
+                notify_cooldown(&self.clients, user_id, remaining_ms);
+                return Err(GameError::RateLimited(remaining_ms));
+            }
+
+            notify_cooldown(&self.clients, user_id, cooldown_ms);
+        }
+
+        let (outcome, updated_tiles) = self
+            .game_data
+            .resolve_click(store, con, coords, user_id, user.team.as_deref())
+            .await?;
+        let action = outcome.action;
+
+        self.broadcaster.do_send(TileChanges(updated_tiles));
+
+        // scores and ranking only move when a tile changes hands
+        if !matches!(action, ClickAction::Create | ClickAction::Capture) {
+            return Ok(action);
+        }
+
+        let new_score = store.count_tiles_by_user(con, user_id).await?;
+
+        notify_score_change(&self.clients, user_id, new_score as u32);
+
+        // a captured tile is lost by its previous owner
+        if let Some(previous_owner) = outcome
+            .previous_owner
+            .filter(|_| action == ClickAction::Capture)
+        {
+            let previous_score = store.count_tiles_by_user(con, &previous_owner).await?;
+
+            notify_score_change(&self.clients, &previous_owner, previous_score as u32);
+        }
+
+        let top = store
+            .get_leaderboard(con, 0, self.leaderboard.size())
+            .await?;
+
+        if self.leaderboard.has_changed(&top) {
+            notify_leaderboard_change(&self.clients, &top);
+        }
+
+        Ok(action)
+    }
+}
+
+fn app_data<T: 'static>(req: &HttpRequest) -> Result<web::Data<T>, Error> {
+    req.app_data::<web::Data<T>>().cloned().ok_or_else(|| {
+        error::ErrorInternalServerError(format!(
+            "{} is not registered as app data",
+            std::any::type_name::<T>()
+        ))
+    })
+}
+
+impl FromRequest for ClickContext {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let context = || -> Result<Self, Error> {
+            Ok(ClickContext {
+                app_config: app_data(req)?,
+                broadcaster: app_data(req)?,
+                clients: app_data(req)?,
+                game_data: app_data(req)?,
+                leaderboard: app_data(req)?,
+                metrics: app_data(req)?,
+                pool: app_data(req)?,
+                round: app_data(req)?,
+                store: app_data(req)?,
+            })
+        };
+
+        ready(context())
+    }
+}
@@ -0,0 +1,562 @@
+//! Websocket clients connected to this instance.
+//!
+//! Clients are spread over `ClientShard` actors, each one running on its own arbiter
+//! and owning its clients and their batch subscriptions, so that workers broadcasting
+//! at the same time do not contend on a lock. Connections, subscriptions and
+//! broadcasts are messages to the shards.
+//!
+//! Messages sent to a client and not written to its socket yet are counted. A client
+//! too slow to read them is disconnected once it has `queue_limit` of them pending
+//! instead of buffering without bound, it downloads or resyncs the grid when it
+//! reconnects.
+//!
+//! Connections are admitted before their websocket actor starts, up to a global and
+//! a per address limit, see `ClientList::admit`.
+//!
+//! Clients that authenticated are indexed by user id in their shard, so that messages
+//! meant for a single user, like its click cooldown, only reach its own sessions.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use actix::{Actor, Arbiter, Context, Handler, Message, Recipient};
+use bytes::Bytes;
+
+use crate::{
+    coords::AxialCoords,
+    game::TileData,
+    protocol::{CloseStatus, ServerMessage},
+    relay::Relay,
+    websocket::MyBinaryMessage,
+};
+
+/// Tiles changed during a broadcast tick, grouped by batch
+pub type TilesByBatch = HashMap<usize, Vec<(AxialCoords, TileData)>>;
+
+/// Messages pending for one client, shared by its shard and its websocket actor
+#[derive(Default)]
+struct ClientQueue {
+    pending: AtomicUsize,
+    overflowed: AtomicBool,
+}
+
+/// Registration of a client in its shard, returned by `ClientList::connect`
+#[derive(Clone)]
+pub struct ClientHandle {
+    id: u64,
+    shard: usize,
+    queue: Arc<ClientQueue>,
+}
+
+impl ClientHandle {
+    /// Must be called by the client for every `MyBinaryMessage` it handles. Returns
+    /// false once the client fell too far behind and has to close its connection.
+    pub fn delivered(&self) -> bool {
+        // the shard stops counting once the client overflowed
+        if self.queue.overflowed.load(Ordering::Acquire) {
+            return false;
+        }
+
+        self.queue.pending.fetch_sub(1, Ordering::AcqRel);
+        true
+    }
+}
+
+/// Depth of the queues of a shard, see `ClientList::metrics`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShardMetrics {
+    /// Clients connected to the shard
+    pub clients: usize,
+    /// Messages sent to the shard it did not handle yet
+    pub backlog: usize,
+    /// Messages sent to the clients of the shard they did not handle yet, as of the
+    /// last broadcast
+    pub pending: usize,
+    /// Highest number of messages pending for a single client, as of the last broadcast
+    pub max_pending: usize,
+    /// Clients disconnected for reaching the queue limit since the shard started
+    pub overflowed: u64,
+}
+
+#[derive(Default)]
+struct ShardStats {
+    clients: AtomicUsize,
+    backlog: AtomicUsize,
+    pending: AtomicUsize,
+    max_pending: AtomicUsize,
+    overflowed: AtomicU64,
+}
+
+impl ShardStats {
+    fn snapshot(&self) -> ShardMetrics {
+        ShardMetrics {
+            clients: self.clients.load(Ordering::Relaxed),
+            backlog: self.backlog.load(Ordering::Relaxed),
+            pending: self.pending.load(Ordering::Relaxed),
+            max_pending: self.max_pending.load(Ordering::Relaxed),
+            overflowed: self.overflowed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+enum ShardMessage {
+    Connect {
+        id: u64,
+        recipient: Recipient<MyBinaryMessage>,
+        queue: Arc<ClientQueue>,
+    },
+    Disconnect(u64),
+    /// Client authenticated as this user
+    Identify(u64, Arc<str>),
+    Subscribe(u64, Vec<usize>),
+    Unsubscribe(u64, Vec<usize>),
+    /// Encoded message sent to every client
+    Broadcast(Bytes),
+    /// Encoded message sent to the clients authenticated as this user
+    ToUser(Arc<str>, Bytes),
+    /// Tiles sent to the clients subscribed to their batch
+    Tiles {
+        seq: u64,
+        tiles: Arc<TilesByBatch>,
+    },
+}
+
+struct Client {
+    recipient: Recipient<MyBinaryMessage>,
+    queue: Arc<ClientQueue>,
+    batches: HashSet<usize>,
+    user_id: Option<Arc<str>>,
+}
+
+/// Actor owning a share of the clients, see the module documentation
+struct ClientShard {
+    clients: HashMap<u64, Client>,
+    clients_by_user: HashMap<Arc<str>, HashSet<u64>>,
+    queue_limit: usize,
+    stats: Arc<ShardStats>,
+}
+
+impl ClientShard {
+    // Sends `messages` to the clients returned by `messages_for`, disconnects the
+    // clients reaching the queue limit
+    fn deliver<F>(&mut self, mut messages_for: F)
+    where
+        F: FnMut(&Client) -> Vec<Bytes>,
+    {
+        let mut pending = 0;
+        let mut max_pending = 0;
+        let mut overflowed = Vec::new();
+
+        for (id, client) in &self.clients {
+            let messages = messages_for(client);
+            let queued = client.queue.pending.load(Ordering::Acquire);
+
+            if queued + messages.len() > self.queue_limit {
+                overflowed.push(*id);
+                continue;
+            }
+
+            for bytes in messages {
+                client.queue.pending.fetch_add(1, Ordering::AcqRel);
+                client.recipient.do_send(MyBinaryMessage(bytes));
+            }
+
+            let queued = client.queue.pending.load(Ordering::Acquire);
+            pending += queued;
+            max_pending = max_pending.max(queued);
+        }
+
+        self.overflow(overflowed);
+
+        self.stats.pending.store(pending, Ordering::Relaxed);
+        self.stats.max_pending.store(max_pending, Ordering::Relaxed);
+    }
+
+    // Sends `bytes` to the clients authenticated as `user_id`
+    fn deliver_to_user(&mut self, user_id: &str, bytes: Bytes) {
+        let Some(ids) = self.clients_by_user.get(user_id) else {
+            return;
+        };
+
+        let mut overflowed = Vec::new();
+
+        for id in ids {
+            let Some(client) = self.clients.get(id) else {
+                continue;
+            };
+
+            if client.queue.pending.load(Ordering::Acquire) + 1 > self.queue_limit {
+                overflowed.push(*id);
+                continue;
+            }
+
+            client.queue.pending.fetch_add(1, Ordering::AcqRel);
+            client.recipient.do_send(MyBinaryMessage(bytes.clone()));
+        }
+
+        self.overflow(overflowed);
+    }
+
+    // Disconnects clients that reached the queue limit
+    fn overflow(&mut self, ids: Vec<u64>) {
+        for id in ids {
+            if let Some(client) = self.remove(id) {
+                client.queue.overflowed.store(true, Ordering::Release);
+                self.stats.overflowed.fetch_add(1, Ordering::Relaxed);
+                log::warn!("Disconnecting client {id}, it has too many pending messages");
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Client> {
+        let client = self.clients.remove(&id)?;
+
+        if let Some(user_id) = &client.user_id {
+            self.forget_user(user_id, id);
+        }
+
+        Some(client)
+    }
+
+    fn forget_user(&mut self, user_id: &str, id: u64) {
+        if let Some(ids) = self.clients_by_user.get_mut(user_id) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.clients_by_user.remove(user_id);
+            }
+        }
+    }
+}
+
+impl Actor for ClientShard {
+    type Context = Context<Self>;
+}
+
+impl Handler<ShardMessage> for ClientShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: ShardMessage, _ctx: &mut Self::Context) {
+        self.stats.backlog.fetch_sub(1, Ordering::Relaxed);
+
+        match msg {
+            ShardMessage::Connect {
+                id,
+                recipient,
+                queue,
+            } => {
+                self.clients.insert(
+                    id,
+                    Client {
+                        recipient,
+                        queue,
+                        batches: HashSet::new(),
+                        user_id: None,
+                    },
+                );
+            }
+            ShardMessage::Disconnect(id) => {
+                self.remove(id);
+            }
+            ShardMessage::Identify(id, user_id) => {
+                if let Some(client) = self.clients.get_mut(&id) {
+                    if let Some(previous) = client.user_id.replace(user_id.clone()) {
+                        self.forget_user(&previous, id);
+                    }
+                    self.clients_by_user.entry(user_id).or_default().insert(id);
+                }
+            }
+            ShardMessage::Subscribe(id, batches) => {
+                if let Some(client) = self.clients.get_mut(&id) {
+                    client.batches.extend(batches);
+                }
+            }
+            ShardMessage::Unsubscribe(id, batches) => {
+                if let Some(client) = self.clients.get_mut(&id) {
+                    for batch in batches {
+                        client.batches.remove(&batch);
+                    }
+                }
+            }
+            ShardMessage::Broadcast(bytes) => self.deliver(|_| vec![bytes.clone()]),
+            ShardMessage::ToUser(user_id, bytes) => self.deliver_to_user(&user_id, bytes),
+            ShardMessage::Tiles { seq, tiles } => self.deliver(|client| {
+                let client_tiles: Vec<_> = client
+                    .batches
+                    .iter()
+                    .filter_map(|batch| tiles.get(batch))
+                    .flatten()
+                    .cloned()
+                    .collect();
+
+                client_tiles
+                    .chunks(u16::MAX as usize)
+                    .filter_map(|chunk| {
+                        let message = ServerMessage::TilesChange {
+                            seq,
+                            tiles: chunk.to_vec(),
+                        };
+
+                        message
+                            .encode()
+                            .inspect_err(|e| log::error!("Could not encode tiles change: {e}"))
+                            .map(Bytes::from)
+                            .ok()
+                    })
+                    .collect()
+            }),
+        }
+
+        self.stats
+            .clients
+            .store(self.clients.len(), Ordering::Relaxed);
+    }
+}
+
+struct Shard {
+    addr: actix::Addr<ClientShard>,
+    stats: Arc<ShardStats>,
+}
+
+struct Shards {
+    shards: Vec<Shard>,
+    arbiters: Vec<Arbiter>,
+    next_id: AtomicU64,
+}
+
+impl Drop for Shards {
+    fn drop(&mut self) {
+        for arbiter in &self.arbiters {
+            arbiter.stop();
+        }
+    }
+}
+
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Connection limits of the instance, 0 for no limit
+#[derive(Default)]
+struct Connections {
+    max: usize,
+    max_per_ip: usize,
+    counts: Mutex<ConnectionCounts>,
+}
+
+/// Connection admitted by `ClientList::admit`, it is released when dropped
+pub struct ConnectionPermit {
+    connections: Arc<Connections>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.connections.counts.lock().unwrap();
+        counts.total -= 1;
+
+        if let Some(ip) = self.ip {
+            if let Some(count) = counts.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Websocket clients connected to this instance. With a `Relay`, messages broadcast
+/// to them are also published to the clients of the other instances.
+#[derive(Clone)]
+pub struct ClientList {
+    shards: Arc<Shards>,
+    connections: Arc<Connections>,
+    relay: Option<Relay>,
+}
+
+impl ClientList {
+    /// Starts `shards` shards, each one on its own arbiter. Clients are disconnected
+    /// once they have `queue_limit` messages pending.
+    pub fn start(shards: usize, queue_limit: usize) -> Self {
+        let mut arbiters = Vec::new();
+        let mut started = Vec::new();
+
+        for _ in 0..shards.max(1) {
+            let arbiter = Arbiter::new();
+            let stats = Arc::new(ShardStats::default());
+            let shard_stats = stats.clone();
+            let addr = ClientShard::start_in_arbiter(&arbiter.handle(), move |_| ClientShard {
+                clients: HashMap::new(),
+                clients_by_user: HashMap::new(),
+                queue_limit,
+                stats: shard_stats,
+            });
+
+            arbiters.push(arbiter);
+            started.push(Shard { addr, stats });
+        }
+
+        Self {
+            shards: Arc::new(Shards {
+                shards: started,
+                arbiters,
+                next_id: AtomicU64::new(0),
+            }),
+            connections: Arc::new(Connections::default()),
+            relay: None,
+        }
+    }
+
+    /// Admits at most `max` connections at once and `max_per_ip` from the same
+    /// address, 0 for no limit
+    pub fn with_connection_limits(self, max: usize, max_per_ip: usize) -> Self {
+        Self {
+            connections: Arc::new(Connections {
+                max,
+                max_per_ip,
+                ..Connections::default()
+            }),
+            ..self
+        }
+    }
+
+    pub fn with_relay(self, relay: Relay) -> Self {
+        Self {
+            relay: Some(relay),
+            ..self
+        }
+    }
+
+    pub fn relay(&self) -> Option<&Relay> {
+        self.relay.as_ref()
+    }
+
+    fn send(&self, shard: usize, msg: ShardMessage) {
+        let shard = &self.shards.shards[shard];
+        shard.stats.backlog.fetch_add(1, Ordering::Relaxed);
+        shard.addr.do_send(msg);
+    }
+
+    fn send_all<F>(&self, msg: F)
+    where
+        F: Fn() -> ShardMessage,
+    {
+        for shard in 0..self.shards.shards.len() {
+            self.send(shard, msg());
+        }
+    }
+
+    /// Counts a new connection from `ip`, refused when it would exceed the connection
+    /// limits. Connections without a known address only count towards the global limit.
+    pub fn admit(&self, ip: Option<IpAddr>) -> Result<ConnectionPermit, CloseStatus> {
+        let connections = &self.connections;
+        let mut counts = connections.counts.lock().unwrap();
+
+        if connections.max > 0 && counts.total >= connections.max {
+            return Err(CloseStatus::ServerFull);
+        }
+
+        if let Some(ip) = ip {
+            let count = counts.per_ip.entry(ip).or_default();
+
+            if connections.max_per_ip > 0 && *count >= connections.max_per_ip {
+                return Err(CloseStatus::TooManyConnections);
+            }
+            *count += 1;
+        }
+        counts.total += 1;
+
+        Ok(ConnectionPermit {
+            connections: connections.clone(),
+            ip,
+        })
+    }
+
+    /// Connections currently admitted
+    pub fn connections(&self) -> usize {
+        self.connections.counts.lock().unwrap().total
+    }
+
+    /// Registers a client, it receives broadcasts until `disconnect` is called
+    pub fn connect(&self, recipient: Recipient<MyBinaryMessage>) -> ClientHandle {
+        let id = self.shards.next_id.fetch_add(1, Ordering::Relaxed);
+        let handle = ClientHandle {
+            id,
+            shard: id as usize % self.shards.shards.len(),
+            queue: Arc::new(ClientQueue::default()),
+        };
+
+        self.send(
+            handle.shard,
+            ShardMessage::Connect {
+                id,
+                recipient,
+                queue: handle.queue.clone(),
+            },
+        );
+
+        handle
+    }
+
+    pub fn disconnect(&self, handle: &ClientHandle) {
+        self.send(handle.shard, ShardMessage::Disconnect(handle.id));
+    }
+
+    /// Records that the client authenticated as `user_id`, see `send_to_user`
+    pub fn identify(&self, handle: &ClientHandle, user_id: &str) {
+        self.send(
+            handle.shard,
+            ShardMessage::Identify(handle.id, Arc::from(user_id)),
+        );
+    }
+
+    /// Sends the changes of `batches` to the client with the next ticks
+    pub fn subscribe(&self, handle: &ClientHandle, batches: Vec<usize>) {
+        self.send(handle.shard, ShardMessage::Subscribe(handle.id, batches));
+    }
+
+    pub fn unsubscribe(&self, handle: &ClientHandle, batches: Vec<usize>) {
+        self.send(handle.shard, ShardMessage::Unsubscribe(handle.id, batches));
+    }
+
+    /// Sends an encoded message to the clients of this instance only
+    pub fn send_local(&self, bytes: &[u8]) {
+        let bytes = Bytes::copy_from_slice(bytes);
+        self.send_all(|| ShardMessage::Broadcast(bytes.clone()));
+    }
+
+    /// Sends an encoded message to the clients of this instance authenticated as `user_id`
+    pub fn send_to_user(&self, user_id: &str, bytes: &[u8]) {
+        let user_id: Arc<str> = Arc::from(user_id);
+        let bytes = Bytes::copy_from_slice(bytes);
+        self.send_all(|| ShardMessage::ToUser(user_id.clone(), bytes.clone()));
+    }
+
+    /// Sends the tiles of a broadcast tick to the clients subscribed to their batch
+    pub fn send_tiles(&self, seq: u64, tiles: TilesByBatch) {
+        let tiles = Arc::new(tiles);
+        self.send_all(|| ShardMessage::Tiles {
+            seq,
+            tiles: tiles.clone(),
+        });
+    }
+
+    /// Depth of the queues of every shard
+    pub fn metrics(&self) -> Vec<ShardMetrics> {
+        self.shards
+            .shards
+            .iter()
+            .map(|shard| shard.stats.snapshot())
+            .collect()
+    }
+}
@@ -1,13 +1,74 @@
 use std::env;
 
+use crate::game::StrengthMode;
+
+/// Where the grid lives while the server runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GridStorage {
+    /// Every tile read and write goes to redis
+    Redis,
+    /// Tiles are kept in memory and periodically flushed to redis
+    InMemory,
+}
+
+/// How logs are written to stdout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per event, with the spans it happened in
+    Json,
+}
+
 /// All game configuration that can be done via env variables
 #[derive(Clone)]
 pub struct GameConfig {
+    /// Number of actors websocket clients are spread over, see `ClientList`
+    pub broadcast_shards: usize,
+    /// Number of tile changes kept for reconnecting clients, see `ChangeLog`
+    pub change_log_size: usize,
+    /// Minimum delay between two clicks of the same user, 0 disables the cooldown
+    pub click_cooldown_ms: u64,
+    /// Websocket clients with this many messages pending are disconnected, see `ClientList`
+    pub client_queue_limit: usize,
+    /// Websocket clients that sent nothing, pongs included, for this long are disconnected
+    pub client_timeout_ms: u64,
     pub front_end_url: String,
     pub grid_batch_div: u8,
+    pub grid_flush_interval_ms: u64,
     pub grid_radius: u32,
+    pub grid_storage: GridStorage,
+    /// Delay between two pings sent to websocket clients, 0 disables pings and timeouts
+    pub heartbeat_interval_ms: u64,
+    pub leaderboard_size: usize,
     pub locust_url: String,
+    pub log_format: LogFormat,
+    /// Websocket connections accepted at once by this instance, 0 for no limit
+    pub max_connections: usize,
+    /// Websocket connections accepted at once from the same address, 0 for no limit
+    pub max_connections_per_ip: usize,
+    /// Strength of a tile never goes above it however many tiles reinforce it, see `ClassicRules`
+    pub max_strength: u8,
+    /// OTLP collector traces are exported to, needs the `otlp` feature, see `Telemetry`
+    pub otlp_endpoint: Option<String>,
     pub redis_url: String,
+    /// Whether websocket events are relayed to the other instances through redis pub/sub,
+    /// needed when several instances run behind a load balancer, see `Relay`
+    pub relay_events: bool,
+    /// Time between the end of a round and the start of the next one, clicks are refused meanwhile
+    pub round_break_secs: u64,
+    /// Length of a round, 0 for a single round that never ends
+    pub round_duration_secs: u64,
+    /// Unix time in milliseconds at which the first round starts, at boot if not set
+    pub round_starts_at_ms: Option<u64>,
+    /// Lifetime of session tokens in seconds, refreshed by `POST /refresh`
+    pub session_ttl_secs: u64,
+    /// Whether teammates reinforce each other's tiles, see `GameData::with_strength_mode`
+    pub strength_mode: StrengthMode,
+    /// Teams users can join at login, any team name is accepted when empty
+    pub teams: Vec<String>,
+    /// Tile changes are sent to websocket clients once per tick, 0 sends them at once
+    pub tile_broadcast_tick_ms: u64,
     pub use_benchmark_data: bool,
     pub with_redis_tests: bool,
 }
@@ -38,6 +99,57 @@ impl GameConfig {
             Err(_) => 8,
         };
 
+        let grid_storage = match env::var("GRID_STORAGE") {
+            Ok(value) => match value.as_str() {
+                "redis" => GridStorage::Redis,
+                "memory" => GridStorage::InMemory,
+                _ => panic!("Failed to parse GRID_STORAGE. Expected `redis` or `memory`"),
+            },
+            Err(_) => GridStorage::Redis,
+        };
+
+        let grid_flush_interval_ms: u64 = match env::var("GRID_FLUSH_INTERVAL_MS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse GRID_FLUSH_INTERVAL_MS. Expected a valid u64"),
+            Err(_) => 1000,
+        };
+
+        let leaderboard_size: usize = match env::var("LEADERBOARD_SIZE") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse LEADERBOARD_SIZE. Expected a valid usize"),
+            Err(_) => 10,
+        };
+
+        let change_log_size: usize = match env::var("CHANGE_LOG_SIZE") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse CHANGE_LOG_SIZE. Expected a valid usize"),
+            Err(_) => 10_000,
+        };
+
+        let click_cooldown_ms: u64 = match env::var("CLICK_COOLDOWN_MS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse CLICK_COOLDOWN_MS. Expected a valid u64"),
+            Err(_) => 0,
+        };
+
+        let session_ttl_secs: u64 = match env::var("SESSION_TTL_SECS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse SESSION_TTL_SECS. Expected a valid u64"),
+            Err(_) => 24 * 60 * 60,
+        };
+
+        let tile_broadcast_tick_ms: u64 = match env::var("TILE_BROADCAST_TICK_MS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse TILE_BROADCAST_TICK_MS. Expected a valid u64"),
+            Err(_) => 50,
+        };
+
         let use_benchmark_data: bool = match env::var("USE_BENCHMARK_DATA") {
             Ok(value) => value
                 .parse()
@@ -52,17 +164,152 @@ impl GameConfig {
             Err(_) => false,
         };
 
+        let broadcast_shards: usize = match env::var("BROADCAST_SHARDS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse BROADCAST_SHARDS. Expected a valid usize"),
+            Err(_) => 4,
+        };
+
+        let client_queue_limit: usize = match env::var("CLIENT_QUEUE_LIMIT") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse CLIENT_QUEUE_LIMIT. Expected a valid usize"),
+            Err(_) => 1024,
+        };
+
+        let heartbeat_interval_ms: u64 = match env::var("HEARTBEAT_INTERVAL_MS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse HEARTBEAT_INTERVAL_MS. Expected a valid u64"),
+            Err(_) => 5000,
+        };
+
+        let client_timeout_ms: u64 = match env::var("CLIENT_TIMEOUT_MS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse CLIENT_TIMEOUT_MS. Expected a valid u64"),
+            Err(_) => 15_000,
+        };
+
+        let max_connections: usize = match env::var("MAX_CONNECTIONS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse MAX_CONNECTIONS. Expected a valid usize"),
+            Err(_) => 10_000,
+        };
+
+        let max_connections_per_ip: usize = match env::var("MAX_CONNECTIONS_PER_IP") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse MAX_CONNECTIONS_PER_IP. Expected a valid usize"),
+            Err(_) => 0,
+        };
+
+        let relay_events: bool = match env::var("RELAY_EVENTS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse RELAY_EVENTS. Expected a boolean"),
+            Err(_) => false,
+        };
+
+        // relayed tiles are not written to in-memory grids, each instance would resolve
+        // clicks against its own grid and write it over the tiles of the others
+        if relay_events && grid_storage == GridStorage::InMemory {
+            panic!("RELAY_EVENTS requires the redis grid storage. Expected GRID_STORAGE=redis");
+        }
+
+        let max_strength: u8 = match env::var("MAX_STRENGTH") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|max| *max > 0)
+                .expect("Failed to parse MAX_STRENGTH. Expected a u8 greater than 0"),
+            Err(_) => u8::MAX,
+        };
+
+        let log_format = match env::var("LOG_FORMAT") {
+            Ok(value) => match value.as_str() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                _ => panic!("Failed to parse LOG_FORMAT. Expected `text` or `json`"),
+            },
+            Err(_) => LogFormat::Text,
+        };
+
+        let otlp_endpoint = env::var("OTLP_ENDPOINT")
+            .ok()
+            .filter(|endpoint| !endpoint.is_empty());
+
         let redis_url = match env::var("REDIS_URL") {
             Ok(value) => value,
             Err(_) => "redis://127.0.0.1:6379".to_string(),
         };
 
+        let round_break_secs: u64 = match env::var("ROUND_BREAK_SECS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse ROUND_BREAK_SECS. Expected a valid u64"),
+            Err(_) => 0,
+        };
+
+        let round_duration_secs: u64 = match env::var("ROUND_DURATION_SECS") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse ROUND_DURATION_SECS. Expected a valid u64"),
+            Err(_) => 0,
+        };
+
+        let round_starts_at_ms: Option<u64> = env::var("ROUND_STARTS_AT_MS").ok().map(|value| {
+            value
+                .parse()
+                .expect("Failed to parse ROUND_STARTS_AT_MS. Expected a valid u64")
+        });
+
+        let strength_mode = match env::var("STRENGTH_MODE") {
+            Ok(value) => value
+                .parse()
+                .expect("Failed to parse STRENGTH_MODE. Expected `solo` or `team`"),
+            Err(_) => StrengthMode::Solo,
+        };
+
+        let teams: Vec<String> = match env::var("TEAMS") {
+            Ok(value) => value
+                .split(',')
+                .map(|team| team.trim().to_string())
+                .filter(|team| !team.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
         Self {
+            broadcast_shards,
+            change_log_size,
+            click_cooldown_ms,
+            client_queue_limit,
+            client_timeout_ms,
             front_end_url,
             grid_batch_div,
+            grid_flush_interval_ms,
             grid_radius,
+            grid_storage,
+            heartbeat_interval_ms,
+            leaderboard_size,
             locust_url,
+            log_format,
+            max_connections,
+            max_connections_per_ip,
+            max_strength,
+            otlp_endpoint,
             redis_url,
+            relay_events,
+            round_break_secs,
+            round_duration_secs,
+            round_starts_at_ms,
+            session_ttl_secs,
+            strength_mode,
+            teams,
+            tile_broadcast_tick_ms,
             use_benchmark_data,
             with_redis_tests,
         }
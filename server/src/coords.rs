@@ -69,7 +69,7 @@ pub fn cube_ring(center: &CubeCoords, radius: u32) -> Vec<CubeCoords> {
 
     for i in 0..6 {
         for _j in 0..radius {
-            results.push(coords.clone());
+            results.push(coords);
             coords = cube_neighbor(&coords, i)
         }
     }
@@ -164,8 +164,8 @@ pub fn direct_neighbors(center: &CubeCoords) -> [CubeCoords; 6] {
     let mut results = [CubeCoords::center(); 6];
     let mut coords = cube_add(center, &cube_scale(&cube_direction(4), 1));
 
-    for i in 0..6 {
-        results[i] = coords;
+    for (i, result) in results.iter_mut().enumerate() {
+        *result = coords;
         coords = cube_neighbor(&coords, i)
     }
 
@@ -176,7 +176,7 @@ pub fn direct_neighbors(center: &CubeCoords) -> [CubeCoords; 6] {
  * Does not include center countrary to red blob games' implementation
  */
 pub fn cube_spiral(center: &CubeCoords, radius: u32) -> Vec<CubeCoords> {
-    let mut results: Vec<CubeCoords> = vec![center.clone()];
+    let mut results: Vec<CubeCoords> = vec![*center];
 
     let max = radius + 1;
 
@@ -224,6 +224,46 @@ impl AxialCoords {
     }
 }
 
+/// Center of the pointy top hexagon at `coords`, for hexagons of `size` (center to corner).
+///
+/// See https://www.redblobgames.com/grids/hexagons/#hex-to-pixel, y grows downward.
+pub fn axial_to_pixel(coords: &AxialCoords, size: f64) -> (f64, f64) {
+    let x = size * (3f64.sqrt() * coords.q as f64 + 3f64.sqrt() / 2.0 * coords.r as f64);
+    let y = size * (3.0 / 2.0 * coords.r as f64);
+
+    (x, y)
+}
+
+/// Pointy top hexagon of `size` containing the pixel at `x`, `y`, inverse of `axial_to_pixel`.
+///
+/// See https://www.redblobgames.com/grids/hexagons/#pixel-to-hex
+pub fn pixel_to_axial(x: f64, y: f64, size: f64) -> AxialCoords {
+    let q = (3f64.sqrt() / 3.0 * x - 1.0 / 3.0 * y) / size;
+    let r = (2.0 / 3.0 * y) / size;
+
+    cube_round(q, r, -q - r).as_axial()
+}
+
+/// Rounds fractional cube coordinates to the nearest hexagon
+fn cube_round(q: f64, r: f64, s: f64) -> CubeCoords {
+    let (mut rq, mut rr, mut rs) = (q.round(), r.round(), s.round());
+
+    let q_diff = (rq - q).abs();
+    let r_diff = (rr - r).abs();
+    let s_diff = (rs - s).abs();
+
+    // the component with the largest rounding error is recomputed from the two others
+    if q_diff > r_diff && q_diff > s_diff {
+        rq = -rr - rs;
+    } else if r_diff > s_diff {
+        rr = -rq - rs;
+    } else {
+        rs = -rq - rr;
+    }
+
+    CubeCoords::new(rq as i32, rr as i32, rs as i32)
+}
+
 // implement hash for storage in HashMap
 impl Hash for AxialCoords {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -248,7 +288,7 @@ pub fn compute_neighboors(radius: u32) -> PrecomputedNeighbors {
             let mut results = [None; 6]; // Use an array of Option<AxialCoords>
             let mut index = 0;
 
-            for cc in direct_neighbors(&coords).iter() {
+            for cc in direct_neighbors(coords).iter() {
                 let ac = cc.as_axial();
                 if is_within_grid(ac, radius) {
                     results[index] = Some(ac);
@@ -0,0 +1,120 @@
+use std::fmt;
+
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use serde::Serialize;
+
+use crate::coords::AxialCoords;
+
+/// Every failure of a request, rendered as a JSON body `{"error": <kind>, "message": <text>}`
+/// with the matching status code (see `ResponseError`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameError {
+    /// Store or connection failure, the request may succeed later
+    StoreUnavailable(String),
+    /// Coordinates outside of the grid
+    InvalidCoords(AxialCoords),
+    /// Parameters or body the game does not accept
+    InvalidInput(String),
+    /// Missing or invalid credentials
+    Unauthorized(String),
+    /// User has to wait this many milliseconds before clicking again
+    RateLimited(u64),
+    /// Current round has not started yet or is over
+    RoundOver,
+    NotFound(String),
+    /// Resource already exists, e.g. a taken username
+    Conflict(String),
+}
+
+pub type GameResult<T> = Result<T, GameError>;
+
+impl GameError {
+    /// Stable name of the error, sent as `error` in JSON bodies
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GameError::StoreUnavailable(_) => "store_unavailable",
+            GameError::InvalidCoords(_) => "invalid_coords",
+            GameError::InvalidInput(_) => "invalid_input",
+            GameError::Unauthorized(_) => "unauthorized",
+            GameError::RateLimited(_) => "rate_limited",
+            GameError::RoundOver => "round_over",
+            GameError::NotFound(_) => "not_found",
+            GameError::Conflict(_) => "conflict",
+        }
+    }
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameError::StoreUnavailable(e) => write!(f, "Store unavailable: {e}"),
+            GameError::InvalidCoords(coords) => {
+                write!(f, "Coords ({}, {}) are out of the grid", coords.q, coords.r)
+            }
+            GameError::InvalidInput(message)
+            | GameError::Unauthorized(message)
+            | GameError::NotFound(message)
+            | GameError::Conflict(message) => write!(f, "{message}"),
+            GameError::RateLimited(remaining_ms) => {
+                write!(f, "Next click allowed in {remaining_ms}ms")
+            }
+            GameError::RoundOver => write!(f, "Clicks are not allowed outside of a round"),
+        }
+    }
+}
+
+impl std::error::Error for GameError {}
+
+impl From<redis::RedisError> for GameError {
+    fn from(e: redis::RedisError) -> Self {
+        GameError::StoreUnavailable(e.to_string())
+    }
+}
+
+impl From<deadpool_redis::PoolError> for GameError {
+    fn from(e: deadpool_redis::PoolError) -> Self {
+        GameError::StoreUnavailable(e.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: String,
+}
+
+impl ResponseError for GameError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            GameError::StoreUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            GameError::InvalidCoords(_) | GameError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            GameError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            GameError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            GameError::RoundOver => StatusCode::FORBIDDEN,
+            GameError::NotFound(_) => StatusCode::NOT_FOUND,
+            GameError::Conflict(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+
+        match self {
+            GameError::Unauthorized(_) => {
+                response.insert_header((header::WWW_AUTHENTICATE, "Basic"));
+            }
+            GameError::RateLimited(remaining_ms) => {
+                response.insert_header((header::RETRY_AFTER, remaining_ms.div_ceil(1000)));
+            }
+            _ => {}
+        }
+
+        response.json(ErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+        })
+    }
+}
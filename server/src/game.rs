@@ -1,7 +1,4 @@
-use std::{
-    cmp::max,
-    collections::{HashMap, HashSet},
-};
+use std::collections::{HashMap, HashSet};
 
 use rand::seq::SliceRandom; // you may need to adjust version depending on your Rust version
 
@@ -9,20 +6,59 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     config::GameConfig,
-    coords::{self, cube_spiral, is_within_grid, AxialCoords, PrecomputedNeighbors},
+    coords::{
+        self, cube_spiral, direct_neighbors, is_within_grid, AxialCoords, PrecomputedNeighbors,
+    },
+    error::{GameError, GameResult},
+    rules::{ClassicRules, GameRules},
     store::RedisHandler,
     user::{PublicUser, User},
     utils::create_benchmark_game_data,
 };
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct InnerTileData {
     pub user_id: String,
     pub damage: u8,
+    /// Team of the owner when the tile was taken, only set in `StrengthMode::Team`
+    #[serde(default)]
+    pub team: Option<String>,
+}
+
+impl InnerTileData {
+    /// Whether this tile counts as owned by `user_id`, tiles of its teammates
+    /// do too when `team` is set
+    pub fn is_allied_to(&self, user_id: &str, team: Option<&str>) -> bool {
+        self.user_id == user_id || team.is_some_and(|team| self.team.as_deref() == Some(team))
+    }
+}
+
+/// Which tiles reinforce each other, see `GameData::with_strength_mode`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StrengthMode {
+    /// Tiles are only reinforced by contiguous tiles of the same user
+    #[default]
+    Solo,
+    /// Tiles record the team of their owner and are reinforced by contiguous tiles
+    /// of the whole team. Teammates repair each other's tiles instead of damaging them.
+    Team,
+}
+
+impl std::str::FromStr for StrengthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "solo" => Ok(StrengthMode::Solo),
+            "team" => Ok(StrengthMode::Team),
+            other => Err(format!("Unknown strength mode {other}")),
+        }
+    }
 }
 
 /// Data associated to an hexagon in the grid
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct TileData {
     /// Owner of the tile, None => No owner yet
     pub user_id: String,
@@ -44,14 +80,210 @@ pub struct PublicGameData {
 
 pub type TileMap = HashMap<AxialCoords, InnerTileData>;
 
+/// What a click did to the clicked tile
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClickAction {
+    /// Tile had no owner and now belongs to the clicking user
+    Create,
+    /// Tile owned by someone else received one more damage
+    Damage,
+    /// Clicking user removed one damage from its own tile
+    Repair,
+    /// Tile owned by someone else reached a strength of 0 and changed ownership
+    Capture,
+    /// Clicking user clicked on its own undamaged tile, nothing changed
+    None,
+}
+
+impl ClickAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClickAction::Create => "create",
+            ClickAction::Damage => "damage",
+            ClickAction::Repair => "repair",
+            ClickAction::Capture => "capture",
+            ClickAction::None => "none",
+        }
+    }
+}
+
+impl std::str::FromStr for ClickAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(ClickAction::Create),
+            "damage" => Ok(ClickAction::Damage),
+            "repair" => Ok(ClickAction::Repair),
+            "capture" => Ok(ClickAction::Capture),
+            "none" => Ok(ClickAction::None),
+            other => Err(format!("Unknown click action {other}")),
+        }
+    }
+}
+
+/// Result of a click, as resolved atomically by the store
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClickOutcome {
+    pub action: ClickAction,
+    /// Owner of the tile before the click, None if the tile did not exist
+    pub previous_owner: Option<String>,
+    /// Team recorded on the tile before the click
+    pub previous_team: Option<String>,
+    /// State of the tile after the click
+    pub tile: InnerTileData,
+}
+
+/// Tiles owned by `user_id`, or by its `team` if any, that are contiguous to `coords`
+/// within `radius` steps, nearest first. Tiles outside of the grid are never in `tiles`,
+/// the walk does not need the precomputed neighbors of `GameData`.
+fn contiguous_coords(
+    tiles: &TileMap,
+    coords: &AxialCoords,
+    user_id: &str,
+    team: Option<&str>,
+    radius: u8,
+) -> Vec<AxialCoords> {
+    let mut processed_set: HashSet<AxialCoords> = HashSet::from([*coords]);
+    let mut results = Vec::new();
+    let mut to_check = vec![*coords];
+
+    for _ in 0..radius {
+        let mut next_to_check = Vec::new();
+
+        for coords_to_check in to_check.drain(..) {
+            for neighbor in direct_neighbors(&coords_to_check.as_cube()) {
+                let neighbor = neighbor.as_axial();
+
+                if processed_set.contains(&neighbor) {
+                    continue;
+                }
+
+                if tiles
+                    .get(&neighbor)
+                    .is_some_and(|t| t.is_allied_to(user_id, team))
+                {
+                    processed_set.insert(neighbor);
+                    next_to_check.push(neighbor);
+                }
+            }
+        }
+
+        results.extend_from_slice(&next_to_check);
+        to_check = next_to_check;
+    }
+
+    results
+}
+
+/// Number of tiles owned by `user_id`, or by its `team` if any, that are contiguous to
+/// `coords` within `radius` steps, see `GameData::contiguous_neighbors_of_tile`. It
+/// saturates at `u8::MAX`, above any strength, so it agrees with `apply_click.lua`.
+///
+/// It's used by stores that need to resolve a click on their own (see `GameRules::resolve_click`).
+pub fn contiguous_count(
+    tiles: &TileMap,
+    coords: &AxialCoords,
+    user_id: &str,
+    team: Option<&str>,
+    radius: u8,
+) -> u8 {
+    saturating_count(&contiguous_coords(tiles, coords, user_id, team, radius))
+}
+
+fn saturating_count<T>(tiles: &[T]) -> u8 {
+    u8::try_from(tiles.len()).unwrap_or(u8::MAX)
+}
+
 #[derive(Debug, Clone)]
-pub struct GameData {
+pub struct GameData<G: GameRules = ClassicRules> {
     pub precomputed_neighbors: PrecomputedNeighbors,
     precomputed_batches: Vec<Vec<AxialCoords>>,
+    /// Index in `precomputed_batches` of every coords of the grid
+    batch_by_coords: HashMap<AxialCoords, usize>,
     pub settings: GridSettings,
+    pub strength_mode: StrengthMode,
+    pub rules: G,
 }
 
 impl GameData {
+    /// Grid of `radius` played with the default `ClassicRules`, see `with_rules`
+    pub fn new(radius: u32, batch_rows_and_cols: u8) -> Self {
+        let precomputed_neighbors = coords::compute_neighboors(radius);
+        let precomputed_batches = coords::create_parallelogram_coords_batches(
+            batch_rows_and_cols,
+            batch_rows_and_cols,
+            radius,
+        );
+
+        let mut batch_by_coords = HashMap::new();
+        for (batch, batch_coords) in precomputed_batches.iter().enumerate() {
+            for c in batch_coords {
+                batch_by_coords.entry(*c).or_insert(batch);
+            }
+        }
+
+        Self {
+            precomputed_batches,
+            batch_by_coords,
+            settings: GridSettings { radius },
+            precomputed_neighbors,
+            strength_mode: StrengthMode::default(),
+            rules: ClassicRules::default(),
+        }
+    }
+
+    pub async fn init_from_config<R, C>(con: &mut C, redis_client: &R, config: &GameConfig) -> Self
+    where
+        C: redis::aio::ConnectionLike + Send,
+        R: RedisHandler,
+    {
+        if config.use_benchmark_data {
+            let user = match redis_client
+                .get_user_by_username(con, "benchmark-user")
+                .await
+                .unwrap()
+            {
+                Some(user) => user,
+                None => {
+                    let user = User::new("benchmark-user");
+                    let _ = redis_client
+                        .add_user(con, user.clone(), config.session_ttl_secs)
+                        .await
+                        .unwrap();
+                    user
+                }
+            };
+
+            return create_benchmark_game_data(
+                con,
+                redis_client,
+                &user,
+                config.grid_radius,
+                config.grid_batch_div,
+            )
+            .await
+            .with_strength_mode(config.strength_mode)
+            .with_rules(ClassicRules::from_config(config));
+        }
+
+        Self::new(config.grid_radius, config.grid_batch_div)
+            .with_strength_mode(config.strength_mode)
+            .with_rules(ClassicRules::from_config(config))
+    }
+}
+
+impl<G: GameRules> GameData<G> {
+    /// Batch containing `coords`, None if `coords` is out of the grid
+    pub fn batch_of(&self, coords: &AxialCoords) -> Option<usize> {
+        self.batch_by_coords.get(coords).copied()
+    }
+
+    pub fn batches_count(&self) -> usize {
+        self.precomputed_batches.len()
+    }
+
     pub fn get_batch_list(&self) -> Vec<usize> {
         let batches_len = self.precomputed_batches.len();
         let mut list = (0..batches_len).collect::<Vec<_>>();
@@ -60,148 +292,109 @@ impl GameData {
         list
     }
 
+    #[tracing::instrument(level = "debug", skip(self, redis_client, con))]
     pub async fn compute_batch<R, C>(
         &self,
         redis_client: &R,
         con: &mut C,
         batch: usize,
-    ) -> Result<Vec<(i32, i32, u8, String)>, String>
+    ) -> GameResult<Vec<(i32, i32, u8, String)>>
     where
         C: redis::aio::ConnectionLike + Send,
         R: RedisHandler,
     {
-        // Check if the batch exists
-        if let Some(batch_coords) = self.precomputed_batches.get(batch) {
-            let mut results = Vec::new();
+        let batch_coords = self
+            .precomputed_batches
+            .get(batch)
+            .ok_or_else(|| GameError::NotFound(format!("Batch {batch} does not exist")))?;
 
-            match redis_client
-                .batch_get_tiles(con, batch_coords.clone())
-                .await
-            {
-                Ok(tiles) => {
-                    let mut temp_fetched_map: HashMap<&AxialCoords, &InnerTileData> = HashMap::new();
-
-                    for (coords, tile) in tiles {
-                        match self
-                            .computed_tile(redis_client, con, &coords, &tile, &mut temp_fetched_map)
-                            .await
-                        {
-                            Ok(c) => {
-                                results.push((coords.q, coords.r, c.strength, c.user_id));
-                            }
-                            Err(e) => {
-                                log::error!("Encounted error while computing batch tile: {e}");
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::error!("Error encounted while fetching all coords: {e}");
-                    return Err(e.to_string());
-                }
-            }
+        let tiles = redis_client
+            .batch_get_tiles(con, batch_coords.clone())
+            .await
+            .inspect_err(|e| log::error!("Error encounted while fetching all coords: {e}"))?;
 
-            return Ok(results);
+        let mut results = Vec::new();
+        let mut temp_fetched_map = TileMap::new();
+
+        for (coords, tile) in tiles {
+            let c = self
+                .computed_tile(redis_client, con, &coords, &tile, &mut temp_fetched_map)
+                .await?;
+            results.push((coords.q, coords.r, c.strength, c.user_id));
         }
 
-        Err(format!("Batch {} does not exist", batch))
+        Ok(results)
     }
 
     pub fn all_grid_coords(&self) -> Vec<AxialCoords> {
         self.precomputed_neighbors.keys().cloned().collect()
     }
 
-    pub async fn init_from_config<R, C>(con: &mut C, redis_client: &R, config: &GameConfig) -> Self
-    where
-        C: redis::aio::ConnectionLike + Send,
-        R: RedisHandler,
-    {
-        if config.use_benchmark_data {
-            let user = User::new("benchmark-user");
+    /// Plays `rules` instead of the current ones, tiles are kept as they are
+    pub fn with_rules<H: GameRules>(self, rules: H) -> GameData<H> {
+        GameData {
+            precomputed_neighbors: self.precomputed_neighbors,
+            precomputed_batches: self.precomputed_batches,
+            batch_by_coords: self.batch_by_coords,
+            settings: self.settings,
+            strength_mode: self.strength_mode,
+            rules,
+        }
+    }
 
-            let _ = redis_client.add_user(con, user.clone()).await.unwrap();
+    /// Switches the rules to `strength_mode`. Tiles keep the team they were taken
+    /// with, so the mode should only change along with the round.
+    pub fn with_strength_mode(mut self, strength_mode: StrengthMode) -> Self {
+        self.strength_mode = strength_mode;
+        self
+    }
 
-            return create_benchmark_game_data(
-                con,
-                redis_client,
-                &user,
-                config.grid_radius,
-                config.grid_batch_div,
-            )
-            .await;
+    /// Team a click of a member of `team` is played for, None when playing solo
+    pub fn playing_team<'a>(&self, team: Option<&'a str>) -> Option<&'a str> {
+        match self.strength_mode {
+            StrengthMode::Solo => None,
+            StrengthMode::Team => team,
         }
-
-        Self::new(config.grid_radius, config.grid_batch_div)
     }
 
     /// Returns all tiles that are contiguous to the given `coords`, i.e., all "connected" tiles next to `coords`
-    /// that are owned by the specified `user_id`.
-    pub fn contiguous_neighbors_of_tile<'a, 'b, 'c>(
+    /// that are owned by the specified `user_id` or by a member of its `team`.
+    pub fn contiguous_neighbors_of_tile(
         &self,
-        prefetched: &'a HashMap<&'b AxialCoords, &'c InnerTileData>,
+        prefetched: &TileMap,
         tile_coords: &AxialCoords,
         user_id: &str,
+        team: Option<&str>,
         radius: u8,
-    ) -> (Vec<(&'b AxialCoords, &'c InnerTileData)>, u8) {
-        let mut count = 0;
-        let mut processed_set: HashSet<&AxialCoords> = HashSet::new();
-        let mut results = Vec::new();
-        let mut to_check = vec![tile_coords];
-
-        for _ in 0..radius {
-            let mut next_to_check = Vec::new();
-
-            for coords_to_check in to_check.drain(..) {
-                if let Some(ring) = self.precomputed_neighbors.get(&coords_to_check) {
-                    let filtered_neighbors: Vec<(&AxialCoords, &InnerTileData)> = ring
-                        .iter()
-                        .filter_map(|rc| {
-                            rc.and_then(|drc| {
-                                if &drc == tile_coords || processed_set.contains(&drc) {
-                                    return None;
-                                }
-
-                                if let Some(nb) = prefetched.get(&drc) {
-                                    if nb.user_id == user_id {
-                                        return Some((&drc, *nb));
-                                    }
-                                }
-
-                                return None;
-                            })
-                        })
-                        .collect();
-
-                    // Add valid neighbors to results and mark them as processed
-                    for (neighbor, tile_data) in filtered_neighbors {
-                        processed_set.insert(neighbor);
-                        results.push((neighbor, tile_data));
-                        count += 1;
-                        next_to_check.push(neighbor);
-                    }
-                }
-            }
-
-            to_check = next_to_check;
-        }
+    ) -> (Vec<(AxialCoords, InnerTileData)>, u8) {
+        let results: Vec<_> = contiguous_coords(prefetched, tile_coords, user_id, team, radius)
+            .into_iter()
+            .filter_map(|c| prefetched.get(&c).map(|tile| (c, tile.clone())))
+            .collect();
+        let count = saturating_count(&results);
 
         (results, count)
     }
 
-    /// helper fn to prefetch the HashMap<AxialCoords, InnerTileData>
-    /// that will be used by `contiguous_neighbors_of_tile`
-    pub async fn fetch_within<'a, 'b, R, C>(
+    /// helper fn to prefetch the `TileMap` that will be used by `contiguous_neighbors_of_tile`,
+    /// every tile within the influence radius of `coords` is fetched
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(q = coords.q, r = coords.r, prefetched = previously_fetched.len())
+    )]
+    pub async fn fetch_within<R, C>(
         &self,
-        redis_client: &'a R,
+        redis_client: &R,
         con: &mut C,
         coords: &AxialCoords,
-        previously_fetched: &'a mut HashMap<&'b AxialCoords, &InnerTileData>,
-    ) -> redis::RedisResult<bool>
+        previously_fetched: &mut TileMap,
+    ) -> GameResult<bool>
     where
         R: RedisHandler,
-        C: redis::aio::ConnectionLike + Send + 'a,
+        C: redis::aio::ConnectionLike + Send,
     {
-        let coords_to_fetch = cube_spiral(&coords.as_cube(), 2)
+        let coords_to_fetch = cube_spiral(&coords.as_cube(), self.rules.influence_radius() as u32)
             .iter()
             .filter_map(|c| {
                 let ac = c.as_axial();
@@ -209,7 +402,7 @@ impl GameData {
                 {
                     return Some(ac);
                 }
-                return None;
+                None
             })
             .collect();
 
@@ -222,27 +415,31 @@ impl GameData {
         Ok(true)
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(q = coords.q, r = coords.r))]
     pub async fn computed_tile<R, C>(
         &self,
         redis_client: &R,
         con: &mut C,
         coords: &AxialCoords,
         tile: &InnerTileData,
-        prefetched: &mut HashMap<&AxialCoords, &InnerTileData>,
-    ) -> redis::RedisResult<TileData>
+        prefetched: &mut TileMap,
+    ) -> GameResult<TileData>
     where
         R: RedisHandler,
         C: redis::aio::ConnectionLike + Send,
     {
-        let _ = self
-            .fetch_within(redis_client, con, coords, prefetched)
-            .await
-            .unwrap();
+        self.fetch_within(redis_client, con, coords, prefetched)
+            .await?;
 
-        let (_, nb_neighboors) =
-            self.contiguous_neighbors_of_tile(prefetched, coords, &tile.user_id, 2);
+        let (_, nb_neighboors) = self.contiguous_neighbors_of_tile(
+            prefetched,
+            coords,
+            &tile.user_id,
+            tile.team.as_deref(),
+            self.rules.influence_radius(),
+        );
 
-        let strength = 1 + nb_neighboors - tile.damage;
+        let strength = self.rules.strength(nb_neighboors, tile.damage);
 
         Ok(TileData {
             strength,
@@ -250,176 +447,123 @@ impl GameData {
         })
     }
 
-    pub fn new(radius: u32, batch_rows_and_cols: u8) -> Self {
-        let precomputed_neighbors = coords::compute_neighboors(radius);
-
-        Self {
-            precomputed_batches: coords::create_parallelogram_coords_batches(
-                batch_rows_and_cols,
-                batch_rows_and_cols,
-                radius,
-            ),
-            settings: GridSettings { radius },
-            precomputed_neighbors,
-        }
-    }
-
+    /// Resolves a click of `click_user_id`, member of `click_team`, atomically through
+    /// `RedisHandler::apply_click` then returns every tile whose strength changed because of it.
+    /// Coords out of the grid are refused with `GameError::InvalidCoords`.
     pub async fn handle_click<R, C>(
         &self,
         redis_client: &R,
         con: &mut C,
         click_coords: &AxialCoords,
         click_user_id: &str,
-    ) -> Result<Vec<(AxialCoords, TileData)>, redis::RedisError>
+        click_team: Option<&str>,
+    ) -> GameResult<Vec<(AxialCoords, TileData)>>
     where
         R: RedisHandler,
         C: redis::aio::ConnectionLike + Send,
     {
-        let mut updated_tiles: Vec<(&AxialCoords, &InnerTileData)> = Vec::new();
-
-        // helpful hashmap to recompute strength and avoir additionnal redis access
-        let mut tmp_hash = HashMap::new();
+        let (_, tiles) = self
+            .resolve_click(redis_client, con, click_coords, click_user_id, click_team)
+            .await?;
 
-        let _ = self
-            .fetch_within(redis_client, con, click_coords, &mut tmp_hash)
-            .await;
-
-        // If the tile exists (aka is owned by someone)
-        if let Some(current_tile) = tmp_hash.get(click_coords).cloned() {
-            let mut updated_tile = &current_tile;
-
-            let current_owner = current_tile.user_id.clone();
-            let mut damage = current_tile.clone().damage as i8;
-
-            let (_, nb_neighboors) =
-                { self.contiguous_neighbors_of_tile(&tmp_hash, click_coords, &current_owner, 2) };
-
-            // If the tile is not owned by the clicking user
-            if current_tile.user_id != click_user_id {
-                // raise damage only if on a tile owned by another user,
-                // do that to avoid issue with remaining_strength calculus below
-                let remaining_strength: i8;
-
-                // when clicking on a tile owned by someone => raise damage
-                damage += 1;
-                remaining_strength = max(0, 1 + nb_neighboors as i8 - damage);
-
-                // Handle the tile change in ownership
-                if remaining_strength == 0 {
-                    updated_tile.user_id = click_user_id.to_string();
-                    updated_tile.damage = 0;
+        Ok(tiles)
+    }
 
-                    // propagate change to redis shared state
-                    tmp_hash.insert(click_coords.clone(), updated_tile.clone());
+    /// Same as `handle_click`, also returns the outcome of the click
+    #[tracing::instrument(
+        name = "handle_click",
+        skip_all,
+        fields(q = click_coords.q, r = click_coords.r, user_id = %click_user_id, action)
+    )]
+    pub async fn resolve_click<R, C>(
+        &self,
+        redis_client: &R,
+        con: &mut C,
+        click_coords: &AxialCoords,
+        click_user_id: &str,
+        click_team: Option<&str>,
+    ) -> GameResult<(ClickOutcome, Vec<(AxialCoords, TileData)>)>
+    where
+        R: RedisHandler,
+        C: redis::aio::ConnectionLike + Send,
+    {
+        if !is_within_grid(*click_coords, self.settings.radius) {
+            return Err(GameError::InvalidCoords(*click_coords));
+        }
 
-                    // 0 => insert tile with new user_id (effectively write the data in shared state)
-                    let _ = redis_client
-                        .set_tile(con, click_coords, updated_tile.clone())
-                        .await
-                        .expect(&format!(
-                            "Could not update tile at {click_coords:?} with new user id"
-                        ));
-
-                    // 1. append former owner tiles to `update_tiles`
-                    let (tiles, _) = {
-                        self.contiguous_neighbors_of_tile(
-                            &tmp_hash,
-                            &click_coords,
-                            &current_owner,
-                            2,
-                        )
-                    };
+        let click_team = self.playing_team(click_team);
 
+        let outcome = redis_client
+            .apply_click(con, click_coords, click_user_id, click_team, &self.rules)
+            .await
+            .inspect_err(|e| {
+                log::error!("A redis error occured while clicking on {click_coords:?}: {e}");
+            })?;
+        tracing::Span::current().record("action", outcome.action.as_str());
+
+        // helpful hashmap to recompute strength and avoid additionnal redis access
+        let mut tmp_hash = TileMap::new();
+        tmp_hash.insert(*click_coords, outcome.tile.clone());
+
+        self.fetch_within(redis_client, con, click_coords, &mut tmp_hash)
+            .await?;
+
+        let mut updated_tiles: Vec<(AxialCoords, InnerTileData)> = Vec::new();
+
+        match outcome.action {
+            ClickAction::Create => {
+                updated_tiles.push((*click_coords, outcome.tile.clone()));
+
+                // append its neighboors to have new strength
+                let (mut tiles, _) = self.contiguous_neighbors_of_tile(
+                    &tmp_hash,
+                    click_coords,
+                    click_user_id,
+                    click_team,
+                    self.rules.influence_radius(),
+                );
+                updated_tiles.append(&mut tiles);
+            }
+            ClickAction::Capture => {
+                // 1. append former owner tiles to `update_tiles`
+                if let Some(former_owner) = &outcome.previous_owner {
+                    let (mut tiles, _) = self.contiguous_neighbors_of_tile(
+                        &tmp_hash,
+                        click_coords,
+                        former_owner,
+                        outcome.previous_team.as_deref(),
+                        self.rules.influence_radius(),
+                    );
                     updated_tiles.append(&mut tiles);
-
-                    // 2 => append new owner's tiles to `update_tiles` vec, will compute final strength at the end
-                    let (tiles, _) = {
-                        self.contiguous_neighbors_of_tile(
-                            &tmp_hash,
-                            &click_coords,
-                            click_user_id,
-                            2,
-                        )
-                    };
-                    updated_tiles.append(&mut tiles.clone());
-                } else {
-                    // Update current tile without changing ownership, not yet "destroyed"
-                    // but with augmented damage
-                    updated_tile.damage += 1;
-                    tmp_hash.insert(click_coords.clone(), updated_tile.clone());
-                    let _ = redis_client
-                        .set_tile(con, click_coords, updated_tile.clone())
-                        .await
-                        .expect(&format!(
-                            "Could not update tile at {click_coords:?} to raise damage"
-                        ));
-                }
-            } else {
-                // Clicking user clicks on its tile
-
-                // if has some damage => heals its tile
-                if current_tile.damage > 0 {
-                    updated_tile.damage -= 1;
-                    tmp_hash.insert(click_coords.clone(), updated_tile.clone());
-                    let _ = redis_client
-                        .set_tile(con, click_coords, updated_tile.clone())
-                        .await
-                        .expect(&format!(
-                            "Could not update tile at {click_coords:?} to decrease damage"
-                        ));
                 }
-            }
 
-            // append new_tile to updated tiles if changed (either damage change or ownership change)
-            if updated_tile.damage != current_tile.damage
-                || updated_tile.user_id != current_tile.user_id
-            {
-                updated_tiles.push((*click_coords, updated_tile))
+                // 2. append new owner's tiles to `update_tiles` vec, will compute final strength at the end
+                let (mut tiles, _) = self.contiguous_neighbors_of_tile(
+                    &tmp_hash,
+                    click_coords,
+                    click_user_id,
+                    click_team,
+                    self.rules.influence_radius(),
+                );
+                updated_tiles.append(&mut tiles);
+
+                updated_tiles.push((*click_coords, outcome.tile.clone()));
             }
-        } else {
-            // if not then we create the tile
-            let new_tile = InnerTileData {
-                user_id: click_user_id.to_string(),
-                damage: 0,
-            };
-            tmp_hash.insert(click_coords.clone(), new_tile.clone());
-
-            match redis_client
-                .set_tile(con, click_coords, new_tile.clone())
-                .await
-            {
-                Ok(_) => {
-                    updated_tiles.push((click_coords, new_tile));
-
-                    // append its neighboors to have new strength
-                    let (tiles, _) = self.contiguous_neighbors_of_tile(
-                        &mut tmp_hash,
-                        &click_coords,
-                        click_user_id,
-                        2,
-                    );
-
-                    updated_tiles.append(&mut tiles.clone());
-                }
-                Err(e) => {
-                    log::error!(
-                        "A redis error occured while updating tile at {click_coords:?}: {e}"
-                    );
-                    return Err(e);
-                }
+            ClickAction::Damage | ClickAction::Repair => {
+                updated_tiles.push((*click_coords, outcome.tile.clone()));
             }
+            ClickAction::None => {}
         }
 
         let mut res = Vec::new();
         for (coords, tile) in updated_tiles {
             let computed = self
                 .computed_tile(redis_client, con, &coords, &tile, &mut tmp_hash)
-                .await
-                .unwrap();
+                .await?;
 
-            res.push((coords.to_owned(), computed.clone()));
+            res.push((coords, computed));
         }
 
-        Ok(res)
+        Ok((outcome, res))
     }
 }
@@ -0,0 +1,661 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    coords::{cube_spiral, AxialCoords, CubeCoords},
+    error::GameResult,
+    game::{ClickAction, ClickOutcome, InnerTileData, TileMap},
+    history::{now_ms, ClickEvent},
+    rounds::{Round, RoundResult},
+    rules::GameRules,
+    store::RedisHandler,
+    user::{PublicUser, RankedUser, User},
+};
+
+/// Authoritative in-memory copy of the grid.
+///
+/// Tiles are read from and written to memory only, changed tiles are marked as
+/// dirty and written to the `backing` store by `flush` (see `spawn_write_behind`),
+/// along with the click events recorded since the last flush.
+/// Users and tokens are not cached and go straight to the `backing` store.
+///
+/// `use_round` switches rounds with the tiles locked, so no click is played while
+/// the grid of the previous round is saved and the one of the new round is loaded.
+pub struct GridState<R> {
+    backing: Arc<R>,
+    /// Radius of the grid loaded from the backing store
+    radius: u32,
+    /// Round whose grid is in memory, see `use_round`
+    round: Arc<AtomicU32>,
+    grid: Arc<RwLock<Grid>>,
+    dirty: Arc<Mutex<HashSet<AxialCoords>>>,
+    /// Click events not yet appended to the backing store, in the order clicks were applied
+    pending_events: Arc<Mutex<Vec<ClickEvent>>>,
+}
+
+/// Tiles of the grid and the number of tiles owned by each user, updated on every
+/// ownership change like the tile scores of the redis store
+#[derive(Default)]
+struct Grid {
+    tiles: TileMap,
+    scores: HashMap<String, usize>,
+}
+
+impl Grid {
+    fn new(tiles: TileMap) -> Self {
+        let mut scores: HashMap<String, usize> = HashMap::new();
+        for tile in tiles.values() {
+            *scores.entry(tile.user_id.clone()).or_default() += 1;
+        }
+
+        Self { tiles, scores }
+    }
+
+    fn insert(&mut self, coords: AxialCoords, tile: InnerTileData) {
+        *self.scores.entry(tile.user_id.clone()).or_default() += 1;
+
+        if let Some(previous) = self.tiles.insert(coords, tile) {
+            if let Some(score) = self.scores.get_mut(&previous.user_id) {
+                *score -= 1;
+                if *score == 0 {
+                    self.scores.remove(&previous.user_id);
+                }
+            }
+        }
+    }
+
+    /// Tiles owned by `user_id`
+    fn score(&self, user_id: &str) -> usize {
+        self.scores.get(user_id).copied().unwrap_or(0)
+    }
+
+    /// Ids and scores of the users owning tiles, ranked like `rank_public_users`
+    fn ranking(&self) -> Vec<(&str, usize)> {
+        let mut ranking: Vec<(&str, usize)> = self
+            .scores
+            .iter()
+            .map(|(id, score)| (id.as_str(), *score))
+            .collect();
+        ranking.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| b.0.cmp(a.0)));
+
+        ranking
+    }
+
+    /// Rank of `user_id` starting at 1, None if it owns no tile
+    fn rank(&self, user_id: &str) -> Option<usize> {
+        let score = self.score(user_id);
+        if score == 0 {
+            return None;
+        }
+
+        let ahead = self
+            .scores
+            .iter()
+            .filter(|(id, s)| (**s, id.as_str()) > (score, user_id))
+            .count();
+
+        Some(ahead + 1)
+    }
+}
+
+impl<R> Clone for GridState<R> {
+    fn clone(&self) -> Self {
+        Self {
+            backing: self.backing.clone(),
+            radius: self.radius,
+            round: self.round.clone(),
+            grid: self.grid.clone(),
+            dirty: self.dirty.clone(),
+            pending_events: self.pending_events.clone(),
+        }
+    }
+}
+
+impl<R> GridState<R>
+where
+    R: RedisHandler + Send + Sync + 'static,
+{
+    /// Empty grid of `radius`, see `load`
+    pub fn new(backing: R, radius: u32) -> Self {
+        Self {
+            backing: Arc::new(backing),
+            radius,
+            round: Arc::new(AtomicU32::new(0)),
+            grid: Arc::new(RwLock::new(Grid::default())),
+            dirty: Arc::new(Mutex::new(HashSet::new())),
+            pending_events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn backing(&self) -> &R {
+        &self.backing
+    }
+
+    /// Rebuilds the in-memory grid from the tiles saved in the backing store. Fails
+    /// without touching the grid if they cannot be read, an empty grid would be
+    /// taken for the truth and written over the saved tiles.
+    pub async fn load<C>(&self, con: &mut C) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let tiles = self.read_backing_grid(con).await?;
+        let nb_tiles = tiles.len();
+
+        *self.grid.write().await = Grid::new(tiles);
+
+        Ok(nb_tiles)
+    }
+
+    async fn read_backing_grid<C>(&self, con: &mut C) -> GameResult<TileMap>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let coords = cube_spiral(&CubeCoords::center(), self.radius)
+            .iter()
+            .map(|c| c.as_axial())
+            .collect();
+
+        Ok(self
+            .backing
+            .batch_get_tiles(con, coords)
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    /// Writes every dirty tile and pending click event to the backing store,
+    /// returns the number of written tiles
+    pub async fn flush<C>(&self, con: &mut C) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.flush_events(con).await?;
+
+        let dirty: Vec<AxialCoords> = self.dirty.lock().await.drain().collect();
+        let tiles = dirty_tiles(&self.grid.read().await.tiles, &dirty);
+
+        self.flush_tiles(con, dirty, tiles).await
+    }
+
+    // Same as `flush` with the tiles already locked by the caller
+    async fn flush_locked<C>(&self, con: &mut C, grid: &TileMap) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.flush_events(con).await?;
+
+        let dirty: Vec<AxialCoords> = self.dirty.lock().await.drain().collect();
+        let tiles = dirty_tiles(grid, &dirty);
+
+        self.flush_tiles(con, dirty, tiles).await
+    }
+
+    async fn flush_events<C>(&self, con: &mut C) -> GameResult<()>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let events: Vec<ClickEvent> = self.pending_events.lock().await.drain(..).collect();
+
+        if let Err(e) = self.backing.append_click_events(con, events.clone()).await {
+            // put events back in front of the ones recorded meanwhile so the order is kept
+            self.pending_events.lock().await.splice(0..0, events);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    async fn flush_tiles<C>(
+        &self,
+        con: &mut C,
+        dirty: Vec<AxialCoords>,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        if dirty.is_empty() {
+            return Ok(0);
+        }
+
+        let nb_tiles = tiles.len();
+
+        if let Err(e) = self.backing.batch_set_tiles(con, tiles).await {
+            // keep tiles dirty so the next flush retries them
+            self.dirty.lock().await.extend(dirty);
+            return Err(e);
+        }
+
+        Ok(nb_tiles)
+    }
+
+    /// Spawns a task flushing dirty tiles to the backing store every `interval`
+    pub fn spawn_write_behind(
+        &self,
+        pool: deadpool_redis::Pool,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                let mut con = match pool.get().await {
+                    Ok(con) => con,
+                    Err(e) => {
+                        log::error!("Could not get a redis connection to flush grid state: {e}");
+                        continue;
+                    }
+                };
+
+                if let Err(e) = state.flush(&mut con).await {
+                    log::error!("Failed to flush grid state to redis: {e}");
+                }
+            }
+        })
+    }
+
+    async fn mark_dirty(&self, coords: impl IntoIterator<Item = AxialCoords>) {
+        self.dirty.lock().await.extend(coords);
+    }
+}
+
+// Tiles of `grid` at `dirty` coords, to write to the backing store
+fn dirty_tiles(grid: &TileMap, dirty: &[AxialCoords]) -> Vec<(AxialCoords, InnerTileData)> {
+    dirty
+        .iter()
+        .filter_map(|c| grid.get(c).map(|t| (*c, t.clone())))
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl<R> RedisHandler for GridState<R>
+where
+    R: RedisHandler + Send + Sync + 'static,
+{
+    async fn flushdb(&self) -> GameResult<bool> {
+        *self.grid.write().await = Grid::default();
+        self.dirty.lock().await.clear();
+        self.pending_events.lock().await.clear();
+
+        self.backing.flushdb().await
+    }
+
+    async fn count_tiles_by_user<C>(&self, _con: &mut C, user_id: &str) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        Ok(self.grid.read().await.score(user_id))
+    }
+
+    async fn get_tile<C>(
+        &self,
+        _con: &mut C,
+        coords: &AxialCoords,
+    ) -> GameResult<Option<InnerTileData>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        Ok(self.grid.read().await.tiles.get(coords).cloned())
+    }
+
+    async fn set_tile<C>(
+        &self,
+        _con: &mut C,
+        coords: &AxialCoords,
+        data: InnerTileData,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        // marked under the tiles lock so that `use_round` flushes it to the round it was set in
+        let mut write = self.grid.write().await;
+        write.insert(*coords, data);
+        self.mark_dirty([*coords]).await;
+
+        Ok(true)
+    }
+
+    async fn batch_get_tiles<C>(
+        &self,
+        _con: &mut C,
+        coords: Vec<AxialCoords>,
+    ) -> GameResult<Vec<(AxialCoords, InnerTileData)>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let read = self.grid.read().await;
+
+        Ok(coords
+            .into_iter()
+            .filter_map(|c| read.tiles.get(&c).map(|t| (c, t.clone())))
+            .collect())
+    }
+
+    async fn batch_set_tiles<C>(
+        &self,
+        _con: &mut C,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let coords: Vec<AxialCoords> = tiles.iter().map(|(c, _)| *c).collect();
+
+        let mut write = self.grid.write().await;
+        for (coords, tile) in tiles {
+            write.insert(coords, tile);
+        }
+        self.mark_dirty(coords).await;
+
+        Ok(true)
+    }
+
+    async fn apply_click<C, G>(
+        &self,
+        _con: &mut C,
+        coords: &AxialCoords,
+        user_id: &str,
+        team: Option<&str>,
+        rules: &G,
+    ) -> GameResult<ClickOutcome>
+    where
+        C: redis::aio::ConnectionLike + Send,
+        G: GameRules,
+    {
+        let mut write = self.grid.write().await;
+        let outcome = rules.resolve_click(&write.tiles, coords, user_id, team);
+
+        if outcome.action != ClickAction::None {
+            write.insert(*coords, outcome.tile.clone());
+
+            // recorded under the tiles lock so events keep the order clicks were applied in,
+            // and so that `use_round` flushes the tile to the round it was clicked in
+            self.mark_dirty([*coords]).await;
+            self.pending_events.lock().await.push(ClickEvent::new(
+                now_ms(),
+                coords,
+                user_id,
+                team,
+                &outcome,
+            ));
+        }
+
+        Ok(outcome)
+    }
+
+    async fn append_click_events<C>(
+        &self,
+        _con: &mut C,
+        events: Vec<ClickEvent>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.pending_events.lock().await.extend(events);
+
+        Ok(true)
+    }
+
+    async fn get_click_events<C>(&self, con: &mut C, until_ms: u64) -> GameResult<Vec<ClickEvent>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let mut events = self.backing.get_click_events(con, until_ms).await?;
+
+        // events not flushed yet were applied after every event of the backing store
+        let pending = self.pending_events.lock().await;
+        events.extend(
+            pending
+                .iter()
+                .take_while(|e| e.timestamp_ms <= until_ms)
+                .cloned(),
+        );
+
+        Ok(events)
+    }
+
+    async fn add_user<C>(&self, con: &mut C, user: User, token_ttl: u64) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.add_user(con, user, token_ttl).await
+    }
+
+    async fn get_user_by_id<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.get_user_by_id(con, user_id).await
+    }
+
+    async fn get_user_by_username<C>(&self, con: &mut C, username: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.get_user_by_username(con, username).await
+    }
+
+    async fn get_users_by_ids<C>(
+        &self,
+        con: &mut C,
+        user_ids: &[String],
+    ) -> GameResult<Vec<Option<User>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.get_users_by_ids(con, user_ids).await
+    }
+
+    async fn get_public_users<C>(&self, con: &mut C) -> GameResult<Vec<PublicUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let mut users = self.backing.get_public_users(con).await?;
+
+        // backing store scores lag behind until the next flush, use the ones in memory instead
+        let read = self.grid.read().await;
+        for user in users.iter_mut() {
+            user.score = read.score(&user.id) as u32;
+        }
+
+        Ok(users)
+    }
+
+    async fn get_leaderboard<C>(
+        &self,
+        con: &mut C,
+        offset: usize,
+        limit: usize,
+    ) -> GameResult<Vec<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        // ranked from the scores in memory, only the users of the page are read
+        let page: Vec<(String, u32)> = {
+            let read = self.grid.read().await;
+            read.ranking()
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|(id, score)| (id.to_string(), score as u32))
+                .collect()
+        };
+
+        let ids: Vec<String> = page.iter().map(|(id, _)| id.clone()).collect();
+        let users = self.backing.get_users_by_ids(con, &ids).await?;
+
+        // tiles of unknown users still take their place in the ranking, like in redis
+        Ok(page
+            .into_iter()
+            .zip(users)
+            .enumerate()
+            .filter_map(|(i, ((_, score), user))| {
+                Some(RankedUser {
+                    rank: (offset + i) as u32 + 1,
+                    user: PublicUser::new(user?, score),
+                })
+            })
+            .collect())
+    }
+
+    async fn get_user_rank<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let (rank, score) = {
+            let read = self.grid.read().await;
+            match read.rank(user_id) {
+                Some(rank) => (rank, read.score(user_id)),
+                None => return Ok(None),
+            }
+        };
+
+        Ok(self
+            .backing
+            .get_user_by_id(con, user_id)
+            .await?
+            .map(|user| RankedUser {
+                rank: rank as u32,
+                user: PublicUser::new(user, score as u32),
+            }))
+    }
+
+    async fn is_valid_token_for_user<C>(
+        &self,
+        con: &mut C,
+        token: &str,
+        user_id: &str,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing
+            .is_valid_token_for_user(con, token, user_id)
+            .await
+    }
+
+    async fn set_user_token<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        token: &str,
+        token_ttl: u64,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing
+            .set_user_token(con, user_id, token, token_ttl)
+            .await
+    }
+
+    async fn revoke_user_token<C>(&self, con: &mut C, user_id: &str) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.revoke_user_token(con, user_id).await
+    }
+
+    async fn start_click_cooldown<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        cooldown_ms: u64,
+    ) -> GameResult<Option<u64>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        // kept in the backing store so that every worker shares the same cooldowns
+        self.backing
+            .start_click_cooldown(con, user_id, cooldown_ms)
+            .await
+    }
+
+    async fn get_current_round<C>(&self, con: &mut C) -> GameResult<Option<Round>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.get_current_round(con).await
+    }
+
+    async fn start_round<C>(&self, con: &mut C, previous_id: u32, round: &Round) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.start_round(con, previous_id, round).await
+    }
+
+    async fn use_round<C>(&self, con: &mut C, round_id: u32) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        // clicks wait for the new grid, otherwise they could be written to the wrong round
+        let mut grid = self.grid.write().await;
+
+        // the last changes of the previous round are saved under its own keys
+        self.flush_locked(con, &grid.tiles).await?;
+
+        let previous_id = self.round.load(Ordering::Relaxed);
+        self.backing.use_round(con, round_id).await?;
+
+        let tiles = match self.read_backing_grid(con).await {
+            Ok(tiles) => tiles,
+            Err(e) => {
+                // keep playing the grid in memory on the round it belongs to
+                self.backing.use_round(con, previous_id).await?;
+                return Err(e);
+            }
+        };
+
+        log::info!(
+            "Loaded {} tiles of round {round_id} from redis into memory",
+            tiles.len()
+        );
+        *grid = Grid::new(tiles);
+        self.round.store(round_id, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
+    async fn archive_round<C>(
+        &self,
+        con: &mut C,
+        result: &RoundResult,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.archive_round(con, result, tiles).await
+    }
+
+    async fn get_round_results<C>(&self, con: &mut C) -> GameResult<Vec<RoundResult>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.get_round_results(con).await
+    }
+
+    async fn get_round_grid<C>(
+        &self,
+        con: &mut C,
+        round_id: u32,
+    ) -> GameResult<Option<Vec<(AxialCoords, InnerTileData)>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.backing.get_round_grid(con, round_id).await
+    }
+}
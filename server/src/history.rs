@@ -0,0 +1,147 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coords::AxialCoords,
+    game::{contiguous_count, ClickAction, ClickOutcome, InnerTileData, TileMap},
+    rules::{ClassicRules, GameRules},
+};
+
+/// A click that changed the grid, as appended to the game history.
+///
+/// Clicks resolved as `ClickAction::None` change nothing and are not recorded.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClickEvent {
+    /// Unix time of the click in milliseconds
+    pub timestamp_ms: u64,
+    pub user_id: String,
+    /// Team the click was played for, None when playing solo
+    #[serde(default)]
+    pub team: Option<String>,
+    pub coords: AxialCoords,
+    pub action: ClickAction,
+    /// Owner of the tile before the click, None on creation
+    pub previous_owner: Option<String>,
+    /// State of the clicked tile after the click
+    pub tile: InnerTileData,
+}
+
+impl ClickEvent {
+    pub fn new(
+        timestamp_ms: u64,
+        coords: &AxialCoords,
+        user_id: &str,
+        team: Option<&str>,
+        outcome: &ClickOutcome,
+    ) -> Self {
+        Self {
+            timestamp_ms,
+            user_id: user_id.to_string(),
+            team: team.map(str::to_string),
+            coords: *coords,
+            action: outcome.action,
+            previous_owner: outcome.previous_owner.clone(),
+            tile: outcome.tile.clone(),
+        }
+    }
+}
+
+/// Current unix time in milliseconds
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Grid rebuilt from the game history, see `replay`
+#[derive(Debug, Default)]
+pub struct Replay<G: GameRules = ClassicRules> {
+    pub tiles: TileMap,
+    /// Number of replayed events
+    pub applied: usize,
+    /// Replayed events whose recorded outcome differs from the one given by the rules
+    pub diverged: Vec<ClickEvent>,
+    /// Rules events are resolved with
+    pub rules: G,
+}
+
+impl<G: GameRules> Replay<G> {
+    /// Empty grid on which events are resolved with `rules`
+    pub fn new(rules: G) -> Self {
+        Self {
+            tiles: TileMap::new(),
+            applied: 0,
+            diverged: Vec::new(),
+            rules,
+        }
+    }
+
+    /// Resolves `event` through `GameRules::resolve_click` on the replayed tiles
+    pub fn apply(&mut self, event: &ClickEvent) {
+        let outcome = self.rules.resolve_click(
+            &self.tiles,
+            &event.coords,
+            &event.user_id,
+            event.team.as_deref(),
+        );
+
+        if outcome.action != event.action || outcome.tile != event.tile {
+            self.diverged.push(event.clone());
+        }
+
+        if outcome.action != ClickAction::None {
+            self.tiles.insert(event.coords, outcome.tile);
+        }
+
+        self.applied += 1;
+    }
+
+    /// Replayed tiles with their strength, in the format of `GameData::compute_batch`
+    pub fn computed_tiles(&self) -> Vec<(i32, i32, u8, String)> {
+        let mut tiles: Vec<(i32, i32, u8, String)> = self
+            .tiles
+            .iter()
+            .map(|(coords, tile)| {
+                let nb_neighboors = contiguous_count(
+                    &self.tiles,
+                    coords,
+                    &tile.user_id,
+                    tile.team.as_deref(),
+                    self.rules.influence_radius(),
+                );
+                let strength = self.rules.strength(nb_neighboors, tile.damage);
+
+                (coords.q, coords.r, strength, tile.user_id.clone())
+            })
+            .collect();
+
+        tiles.sort();
+        tiles
+    }
+}
+
+/// Rebuilds the grid as it was at `until_ms` by resolving every event recorded
+/// up to then with the default `ClassicRules`, in the order they were applied.
+pub fn replay<'a>(events: impl IntoIterator<Item = &'a ClickEvent>, until_ms: u64) -> Replay {
+    replay_with(ClassicRules::default(), events, until_ms)
+}
+
+/// Same as `replay` but resolving events with `rules`
+pub fn replay_with<'a, G: GameRules>(
+    rules: G,
+    events: impl IntoIterator<Item = &'a ClickEvent>,
+    until_ms: u64,
+) -> Replay<G> {
+    let mut replay = Replay::new(rules);
+
+    for event in events
+        .into_iter()
+        .take_while(|e| e.timestamp_ms <= until_ms)
+    {
+        replay.apply(event);
+    }
+
+    replay
+}
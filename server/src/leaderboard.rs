@@ -0,0 +1,42 @@
+use std::sync::Mutex;
+
+use crate::user::RankedUser;
+
+/// Remembers the last top of the leaderboard pushed to websocket clients so that
+/// we only push a new one when the ranking actually shifts.
+pub struct LeaderboardWatcher {
+    size: usize,
+    last_top: Mutex<Vec<(String, u32)>>,
+}
+
+impl LeaderboardWatcher {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            last_top: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Number of users in the watched top
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Stores `top` and returns true if it differs from the previously stored one
+    pub fn has_changed(&self, top: &[RankedUser]) -> bool {
+        let new_top: Vec<(String, u32)> = top
+            .iter()
+            .take(self.size)
+            .map(|r| (r.user.id.clone(), r.user.score))
+            .collect();
+
+        let mut last_top = self.last_top.lock().unwrap();
+
+        if *last_top == new_top {
+            return false;
+        }
+
+        *last_top = new_top;
+        true
+    }
+}
@@ -1,7 +1,25 @@
+pub mod auth;
+pub mod broadcaster;
+pub mod changes;
+pub mod click;
+pub mod clients;
 pub mod config;
 pub mod coords;
+pub mod error;
 pub mod game;
+pub mod grid_state;
+pub mod history;
+pub mod leaderboard;
+pub mod metrics;
+pub mod protocol;
+pub mod relay;
+pub mod rounds;
+pub mod routes;
+pub mod rules;
+pub mod snapshot;
 pub mod store;
+pub mod telemetry;
+#[cfg(feature = "test-utils")]
 pub mod test_utils;
 pub mod user;
 pub mod utils;
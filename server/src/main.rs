@@ -1,150 +1,22 @@
+use actix::Actor;
 use actix_cors::Cors;
 use actix_web::middleware::Logger;
 use actix_web::web;
-use actix_web::{get, http, post, App, HttpResponse, HttpServer, Responder};
-use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web::{http, App, HttpServer};
+use pixelstratwar::broadcaster::TileBroadcaster;
+use pixelstratwar::changes::ChangeLog;
 use pixelstratwar::config::GameConfig;
-use pixelstratwar::coords::AxialCoords;
 use pixelstratwar::game::GameData;
-use pixelstratwar::store::{self, RedisHandler};
-use pixelstratwar::user::User;
-use pixelstratwar::websocket::{
-    init_clients, notify_new_user, notify_score_change, tile_change_message, ws_handler,
-    ClientList, MyBinaryMessage,
-};
-use serde::Deserialize;
-
-#[post("/tile/{q}/{r}")]
-async fn post_tile(
-    path: web::Path<AxialCoords>,
-    game_data: web::Data<GameData>,
-    clients: web::Data<ClientList>,
-    redis_client: web::Data<redis::Client>,
-    redis_pool: web::Data<deadpool_redis::Pool>,
-    user_id: String,
-    credentials: BasicAuth,
-) -> impl Responder {
-    let user_id_auth = credentials.user_id();
-    let token: &str = credentials.password().unwrap_or("");
-    let mut con = redis_pool.get().await.unwrap();
-
-    if redis_client
-        .is_valid_token_for_user(&mut con, token, user_id_auth)
-        .await
-        .unwrap()
-    {
-        let coords = path.into_inner();
-
-        let updated_tiles = match game_data
-            .handle_click(&**redis_client, &mut con, &coords, &user_id)
-            .await
-        {
-            Ok(value) => value,
-            Err(e) => {
-                return HttpResponse::InternalServerError().body(format!(
-                    "An error occured while handling click on {coords:?}.\nError: {e}"
-                ));
-            }
-        };
-
-        for client in clients.lock().unwrap().iter() {
-            updated_tiles.iter().for_each(|(coords, tile)| {
-                client.do_send(MyBinaryMessage(tile_change_message(&coords, &tile)));
-            });
-        }
-
-        let new_score = redis_client.count_tiles_by_user(&user_id).await.unwrap();
-
-        notify_score_change(&clients, &user_id, new_score as u32);
-
-        return HttpResponse::Ok().body("Tile updated");
-    } else {
-        return HttpResponse::Unauthorized().body("Invalid token");
-    }
-}
-
-#[get("/settings")]
-async fn get_game_settings(game_data: web::Data<GameData>) -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .json(game_data.settings)
-}
-
-#[derive(Deserialize)]
-struct BatchTilesQuery {
-    batch: usize,
-}
-
-#[get("/tiles")]
-async fn get_batch_tiles(
-    redis_client: web::Data<redis::Client>,
-    redis_pool: web::Data<deadpool_redis::Pool>,
-    game_data: web::Data<GameData>,
-    query: web::Query<BatchTilesQuery>,
-) -> impl Responder {
-    let mut con = redis_pool.get().await.unwrap();
-
-    match game_data
-        .compute_batch(&**redis_client, &mut con, query.batch)
-        .await
-    {
-        Ok(computed_batch) => HttpResponse::Ok()
-            .content_type("application/json")
-            .json(computed_batch),
-        Err(e) => HttpResponse::InternalServerError()
-            .content_type("text/plain")
-            .body(format!("Failed to compute batch: {}", e)),
-    }
-}
-
-#[get("/batches")]
-async fn get_batch_list(game_data: web::Data<GameData>) -> impl Responder {
-    let list = game_data.get_batch_list();
-
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .json(list)
-}
-
-#[get("/users")]
-async fn get_users(
-    redis_client: web::Data<redis::Client>,
-    redis_pool: web::Data<deadpool_redis::Pool>,
-) -> impl Responder {
-    let mut con = redis_pool.get().await.unwrap();
-    let users_public = redis_client.get_public_users(&mut con).await.unwrap();
-
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .json(users_public)
-}
-
-#[derive(Deserialize)]
-struct RegisterUserParams {
-    username: String,
-}
-
-#[post("/login")]
-async fn register_user(
-    redis_client: web::Data<redis::Client>,
-    redis_pool: web::Data<deadpool_redis::Pool>,
-    clients: web::Data<ClientList>,
-    post_params: web::Json<RegisterUserParams>,
-) -> impl Responder {
-    let username = post_params.into_inner().username;
-
-    let mut con = redis_pool.get().await.unwrap();
-
-    let user = User::new(&username);
-    match redis_client.add_user(&mut con, user.clone()).await {
-        Ok(_) => {
-            notify_new_user(&clients, &user.id, &user.username, &user.color);
-            // println!("[main.register_user] DONE, saved {} in DB", user.username);
-            return HttpResponse::Ok().json(user);
-        }
-        Err(_) => return HttpResponse::InternalServerError().body("Could not save user in DB"),
-    }
-}
+use pixelstratwar::leaderboard::LeaderboardWatcher;
+use pixelstratwar::metrics::Metrics;
+use pixelstratwar::relay::{Relay, RelayBus};
+use pixelstratwar::rounds::{self, CurrentRound, RoundScheduler};
+use pixelstratwar::routes;
+use pixelstratwar::snapshot::SnapshotCache;
+use pixelstratwar::store::{self, GameStore};
+use pixelstratwar::telemetry::Telemetry;
+use pixelstratwar::websocket::init_clients;
+use std::time::Duration;
 
 fn cors_middleware(app_config: &GameConfig) -> Cors {
     Cors::default()
@@ -156,30 +28,91 @@ fn cors_middleware(app_config: &GameConfig) -> Cors {
             http::header::CONTENT_TYPE,
             http::header::AUTHORIZATION,
         ])
+        .expose_headers(vec![routes::CHANGE_SEQ_HEADER])
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let app_config = GameConfig::read_config_from_env();
 
+    std::env::set_var("RUST_BACKTRACE", "1");
+    let telemetry = Telemetry::init(&app_config);
+
     let (redis_client, pool) = store::init_redis_client(&app_config).await.unwrap();
 
     let mut conn = pool.get().await.unwrap();
 
     let _ = store::init_redis_indices(&mut conn).await.unwrap();
 
-    std::env::set_var("RUST_LOG", "info");
-    std::env::set_var("RUST_BACKTRACE", "1");
-    env_logger::init();
+    let _ = store::init_redis_scripts(&mut conn).await.unwrap();
+
+    let _ = store::migrate_to_rounds(&mut conn).await.unwrap();
+
+    let relay = app_config
+        .relay_events
+        .then(|| Relay::start(RelayBus::Redis(redis_client.clone())));
+
+    let game_store = store::init_game_store(redis_client, &app_config);
+
+    // with the in-memory grid, serving clicks without the saved tiles would overwrite them
+    let round = rounds::init_round(&game_store, &mut conn, &app_config)
+        .await
+        .expect("Could not start or load the current round");
+
+    let _ = store::init_tile_scores(&mut conn, round.id).await.unwrap();
+
+    let game_data = GameData::init_from_config(&mut conn, &game_store, &app_config).await;
+
+    let mut clients = init_clients(&app_config);
+
+    if let GameStore::InMemory(state) = &game_store {
+        state.spawn_write_behind(
+            pool.clone(),
+            Duration::from_millis(app_config.grid_flush_interval_ms),
+        );
+    }
 
-    let game_data = GameData::init_from_config(&mut conn, &redis_client, &app_config).await;
+    let change_log = web::Data::new(ChangeLog::new(app_config.change_log_size));
+    let mut broadcaster = TileBroadcaster::new(
+        clients.clone(),
+        web::Data::new(game_data.clone()),
+        change_log.clone(),
+        Duration::from_millis(app_config.tile_broadcast_tick_ms),
+    );
+
+    if let Some(relay) = &relay {
+        clients = clients.with_relay(relay.clone());
+        broadcaster = broadcaster.with_relay(relay.clone());
+    }
+
+    let broadcaster = broadcaster.start();
+
+    if let Some(relay) = &relay {
+        relay.listen(clients.clone(), broadcaster.clone());
+    }
+
+    let broadcaster = web::Data::new(broadcaster);
 
-    let clients = init_clients();
+    let game_store = web::Data::new(game_store);
+    let current_round = web::Data::new(CurrentRound::new(round));
 
-    // let data_clone = data.clone();
-    // task::spawn(async move {
-    //     periodic_save(data_clone).await;
-    // });
+    RoundScheduler {
+        store: game_store.clone(),
+        pool: pool.clone(),
+        game_data: web::Data::new(game_data.clone()),
+        current: current_round.clone(),
+        change_log: change_log.clone(),
+        clients: clients.clone(),
+        config: app_config.clone(),
+    }
+    .spawn(Duration::from_secs(1));
+
+    let leaderboard = web::Data::new(LeaderboardWatcher::new(app_config.leaderboard_size));
+    let metrics = web::Data::new(Metrics::new());
+    let snapshot_cache = web::Data::new(SnapshotCache::new());
+    let server_config = app_config.clone();
+    let server_pool = pool.clone();
+    let server_store = game_store.clone();
 
     HttpServer::new(move || {
         let logger = Logger::default();
@@ -187,23 +120,32 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(web::Data::new(game_data.clone()))
             .app_data(web::Data::new(clients.clone()))
-            .app_data(web::Data::new(app_config.clone()))
-            .app_data(web::Data::new(redis_client.clone()))
-            .app_data(web::Data::new(pool.clone()))
-            // protected
-            .service(post_tile)
-            .service(get_batch_list)
-            .service(get_batch_tiles)
-            .service(get_game_settings)
-            .service(get_users)
-            .service(register_user)
-            .service(web::resource("/ws").to(ws_handler))
+            .app_data(broadcaster.clone())
+            .app_data(change_log.clone())
+            .app_data(web::Data::new(server_config.clone()))
+            .app_data(server_store.clone())
+            .app_data(leaderboard.clone())
+            .app_data(current_round.clone())
+            .app_data(metrics.clone())
+            .app_data(snapshot_cache.clone())
+            .app_data(web::Data::new(server_pool.clone()))
+            .configure(routes::configure)
             // .wrap(Compress::default())
             .wrap(logger)
-            .wrap(cors_middleware(&app_config))
+            .wrap(cors_middleware(&server_config))
     })
     .workers(512)
     .bind(("0.0.0.0", 8080))?
     .run()
-    .await
+    .await?;
+
+    // write the last dirty tiles before exiting
+    if let GameStore::InMemory(state) = &**game_store {
+        let mut conn = pool.get().await.unwrap();
+        state.flush(&mut conn).await.unwrap();
+    }
+
+    telemetry.shutdown();
+
+    Ok(())
 }
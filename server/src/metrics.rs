@@ -0,0 +1,263 @@
+//! Prometheus metrics of the server, served by `GET /metrics` in the text format.
+//!
+//! Request metrics are recorded where the requests are handled, see `ClickContext::play`
+//! and `GET /tiles`. Gauges describing the current state of the server, like the
+//! broadcast queues of `ClientList`, are read when the metrics are rendered.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+
+use crate::clients::ClientList;
+
+/// Users who clicked within this window are counted as active
+pub const ACTIVE_USER_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Last click of the users who clicked within `ACTIVE_USER_WINDOW`. Users are pruned
+/// at most once per window as clicks come in, so it keeps the users of the last two
+/// windows at most whether or not the metrics are rendered.
+#[derive(Debug)]
+pub struct ActiveUsers {
+    clicks_by_user: HashMap<String, Instant>,
+    pruned_at: Instant,
+}
+
+impl ActiveUsers {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            clicks_by_user: HashMap::new(),
+            pruned_at: now,
+        }
+    }
+
+    pub fn click(&mut self, user_id: &str, now: Instant) {
+        if now.duration_since(self.pruned_at) >= ACTIVE_USER_WINDOW {
+            self.prune(now);
+        }
+
+        match self.clicks_by_user.get_mut(user_id) {
+            Some(clicked_at) => *clicked_at = now,
+            None => {
+                self.clicks_by_user.insert(user_id.to_string(), now);
+            }
+        }
+    }
+
+    /// Users who clicked within the window before `now`
+    pub fn count(&mut self, now: Instant) -> usize {
+        self.prune(now);
+        self.clicks_by_user.len()
+    }
+
+    /// Users kept, including those whose last click expired since the last prune
+    pub fn len(&self) -> usize {
+        self.clicks_by_user.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clicks_by_user.is_empty()
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.clicks_by_user
+            .retain(|_, clicked_at| now.duration_since(*clicked_at) < ACTIVE_USER_WINDOW);
+        self.pruned_at = now;
+    }
+}
+
+/// Metrics of one server instance, registered as app data
+pub struct Metrics {
+    registry: Registry,
+    click_duration: HistogramVec,
+    batch_duration: Histogram,
+    redis_round_trips: HistogramVec,
+    websocket_clients: IntGauge,
+    broadcast_clients: IntGaugeVec,
+    broadcast_backlog: IntGaugeVec,
+    broadcast_pending: IntGaugeVec,
+    broadcast_max_pending: IntGaugeVec,
+    broadcast_overflowed: IntGaugeVec,
+    active_users: IntGauge,
+    clicks_by_user: Mutex<ActiveUsers>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("pixelstratwar".to_string()), None)
+            .expect("Metrics prefix should be valid");
+
+        let click_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "click_duration_seconds",
+                "Time to play a click, by outcome: the click action or the error kind",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        let batch_duration = Histogram::with_opts(HistogramOpts::new(
+            "batch_duration_seconds",
+            "Time to compute the tiles of a batch",
+        ))
+        .unwrap();
+        let redis_round_trips = HistogramVec::new(
+            HistogramOpts::new(
+                "redis_round_trips",
+                "Redis commands and pipelines sent to play a request",
+            )
+            .buckets(exponential_buckets(1.0, 2.0, 8).unwrap()),
+            &["request"],
+        )
+        .unwrap();
+        let websocket_clients = IntGauge::new(
+            "websocket_clients",
+            "Websocket clients connected to this instance",
+        )
+        .unwrap();
+
+        let shard_gauge =
+            |name: &str, help: &str| IntGaugeVec::new(Opts::new(name, help), &["shard"]).unwrap();
+        let broadcast_clients = shard_gauge(
+            "broadcast_clients",
+            "Websocket clients registered in a broadcaster shard",
+        );
+        let broadcast_backlog = shard_gauge(
+            "broadcast_backlog",
+            "Messages sent to a broadcaster shard it did not handle yet",
+        );
+        let broadcast_pending = shard_gauge(
+            "broadcast_pending",
+            "Messages sent to the clients of a shard they did not handle yet",
+        );
+        let broadcast_max_pending = shard_gauge(
+            "broadcast_max_pending",
+            "Highest number of messages pending for a single client of a shard",
+        );
+        let broadcast_overflowed = shard_gauge(
+            "broadcast_overflowed_clients",
+            "Clients of a shard disconnected for reaching the queue limit",
+        );
+
+        let active_users = IntGauge::new(
+            "active_users",
+            "Users who clicked within the last 5 minutes on this instance",
+        )
+        .unwrap();
+
+        registry.register(Box::new(click_duration.clone())).unwrap();
+        registry.register(Box::new(batch_duration.clone())).unwrap();
+        registry
+            .register(Box::new(redis_round_trips.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(websocket_clients.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_clients.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_backlog.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_pending.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_max_pending.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_overflowed.clone()))
+            .unwrap();
+        registry.register(Box::new(active_users.clone())).unwrap();
+
+        Self {
+            registry,
+            click_duration,
+            batch_duration,
+            redis_round_trips,
+            websocket_clients,
+            broadcast_clients,
+            broadcast_backlog,
+            broadcast_pending,
+            broadcast_max_pending,
+            broadcast_overflowed,
+            active_users,
+            clicks_by_user: Mutex::new(ActiveUsers::new(Instant::now())),
+        }
+    }
+
+    /// Records a click of `user_id` played in `duration`, `outcome` being the
+    /// `ClickAction` or the `GameError` kind of the click
+    pub fn observe_click(&self, user_id: &str, outcome: &str, duration: Duration) {
+        self.click_duration
+            .with_label_values(&[outcome])
+            .observe(duration.as_secs_f64());
+
+        self.clicks_by_user
+            .lock()
+            .unwrap()
+            .click(user_id, Instant::now());
+    }
+
+    pub fn observe_batch(&self, duration: Duration) {
+        self.batch_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Records the redis round trips a `request` needed, see `StoreConnection::round_trips`
+    pub fn observe_round_trips(&self, request: &str, round_trips: usize) {
+        self.redis_round_trips
+            .with_label_values(&[request])
+            .observe(round_trips as f64);
+    }
+
+    pub fn websocket_connected(&self) {
+        self.websocket_clients.inc();
+    }
+
+    pub fn websocket_disconnected(&self) {
+        self.websocket_clients.dec();
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self, clients: &ClientList) -> Result<String, prometheus::Error> {
+        for (shard, metrics) in clients.metrics().iter().enumerate() {
+            let shard = shard.to_string();
+            let labels = [shard.as_str()];
+
+            self.broadcast_clients
+                .with_label_values(&labels)
+                .set(metrics.clients as i64);
+            self.broadcast_backlog
+                .with_label_values(&labels)
+                .set(metrics.backlog as i64);
+            self.broadcast_pending
+                .with_label_values(&labels)
+                .set(metrics.pending as i64);
+            self.broadcast_max_pending
+                .with_label_values(&labels)
+                .set(metrics.max_pending as i64);
+            self.broadcast_overflowed
+                .with_label_values(&labels)
+                .set(metrics.overflowed as i64);
+        }
+
+        let active_users = self.clicks_by_user.lock().unwrap().count(Instant::now());
+        self.active_users.set(active_users as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,660 @@
+//! Binary websocket protocol shared by the server and `frontend/src/websocket.ts`.
+//!
+//! Every message starts with its type byte, numbers are little endian and
+//! strings are utf8 bytes prefixed by their length in bytes (u8), so ids,
+//! usernames and colors are limited to `MAX_STRING_LENGTH` bytes. Messages that
+//! do not fit are refused by `encode` instead of being truncated.
+//!
+//! On connect the server sends `ServerMessage::Hello` with its `PROTOCOL_VERSION`,
+//! the client answers with `ClientMessage::Hello` before sending anything else.
+//!
+//! Server messages:
+//!
+//! | type | message      | fields                                                        |
+//! |------|--------------|---------------------------------------------------------------|
+//! | 0x00 | hello        | version (u8)                                                  |
+//! | 0x01 | tile change  | q (i32), r (i32), strength (u8), user id                      |
+//! | 0x02 | new user     | user id, username, color, team (empty if none)                |
+//! | 0x03 | score change | user id, score (u32)                                          |
+//! | 0x04 | leaderboard  | count (u8) then user id, score (u32) sorted by rank           |
+//! | 0x05 | cooldown     | user id, remaining milliseconds (u32), own sessions only      |
+//! | 0x06 | ack          | request id (u32)                                              |
+//! | 0x07 | error        | request id (u32), code (u8), message                          |
+//! | 0x08 | tiles change | change sequence (u64), count (u16) then q (i32), r (i32),      |
+//! |      |              | strength (u8), user id                                        |
+//! | 0x09 | round        | round id (u32), starts at (u64), ends at (u64, 0 if never)    |
+//!
+//! Client messages, all of them carry a request id (u32) right after their type
+//! that the server echoes in the ack or error replying to it:
+//!
+//! | type | message      | fields                                      |
+//! |------|--------------|---------------------------------------------|
+//! | 0x00 | hello        | version (u8)                                |
+//! | 0x01 | authenticate | user id, token                              |
+//! | 0x02 | click        | q (i32), r (i32)                            |
+//! | 0x03 | subscribe    | count (u16) then batch indices (u16)        |
+//! | 0x04 | unsubscribe  | count (u16) then batch indices (u16)        |
+//! | 0x05 | resync       | change sequence (u64)                       |
+//!
+//! A resync is answered by the tiles of the subscribed batches changed after the
+//! given sequence, sent as tiles change messages before the ack, or by a
+//! `ResyncRequired` error when these changes are not known anymore.
+//!
+//! A round message is sent when a new round starts on an empty grid, clients have
+//! to download every batch again. Times are unix times in milliseconds.
+//!
+//! The server pings clients every `heartbeat_interval_ms` and closes connections
+//! it did not hear from for `client_timeout_ms`. Close frames sent by the server
+//! carry one of these codes (see `CloseStatus`):
+//!
+//! | code | reason                                     | client should              |
+//! |------|--------------------------------------------|----------------------------|
+//! | 1002 | hello announced another protocol version   | not reconnect              |
+//! | 1013 | too many messages pending for the client   | reconnect and resync       |
+//! | 4000 | no message nor pong before the timeout     | reconnect and resync       |
+//! | 4001 | server reached its connection limit        | reconnect after a while    |
+//! | 4002 | too many connections from the same address | close other connections    |
+
+use std::fmt;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::{coords::AxialCoords, game::TileData, rounds::Round, user::RankedUser};
+
+/// Version sent in hello messages, bumped on every incompatible change of the wire format
+pub const PROTOCOL_VERSION: u8 = 4;
+
+/// Maximum length in bytes of strings, they are prefixed by an u8 length
+pub const MAX_STRING_LENGTH: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// A string or a list does not fit in its length prefix
+    TooLong {
+        field: &'static str,
+        length: usize,
+        max: usize,
+    },
+    /// Message ended before all its fields were read
+    Truncated,
+    /// Bytes are left after the last field of the message
+    TrailingBytes(usize),
+    UnknownType(u8),
+    UnknownErrorCode(u8),
+    InvalidUtf8,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::TooLong { field, length, max } => {
+                write!(f, "{field} is {length} long, at most {max} is allowed")
+            }
+            ProtocolError::Truncated => write!(f, "Message is truncated"),
+            ProtocolError::TrailingBytes(count) => {
+                write!(f, "{count} unexpected bytes at the end of the message")
+            }
+            ProtocolError::UnknownType(t) => write!(f, "Unknown message type {t:#04x}"),
+            ProtocolError::UnknownErrorCode(c) => write!(f, "Unknown error code {c}"),
+            ProtocolError::InvalidUtf8 => write!(f, "String is not valid utf8"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Reason sent in an error message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Malformed = 1,
+    Unauthorized = 2,
+    Cooldown = 3,
+    Internal = 4,
+    /// Client sent a message before its hello
+    HandshakeRequired = 5,
+    /// Client hello announced another protocol version
+    UnsupportedVersion = 6,
+    /// Changes asked by a resync are not in the change log anymore
+    ResyncRequired = 7,
+    /// Click sent outside of the current round
+    RoundOver = 8,
+}
+
+impl TryFrom<u8> for ErrorCode {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(ErrorCode::Malformed),
+            2 => Ok(ErrorCode::Unauthorized),
+            3 => Ok(ErrorCode::Cooldown),
+            4 => Ok(ErrorCode::Internal),
+            5 => Ok(ErrorCode::HandshakeRequired),
+            6 => Ok(ErrorCode::UnsupportedVersion),
+            7 => Ok(ErrorCode::ResyncRequired),
+            8 => Ok(ErrorCode::RoundOver),
+            _ => Err(ProtocolError::UnknownErrorCode(value)),
+        }
+    }
+}
+
+/// Why the server closed a websocket connection, sent as the code of its close frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseStatus {
+    UnsupportedVersion,
+    /// Client did not read its messages fast enough, see `ClientList`
+    TooManyPending,
+    /// Client did not answer pings in time
+    Timeout,
+    /// Server has `max_connections` clients connected
+    ServerFull,
+    /// Address of the client has `max_connections_per_ip` connections
+    TooManyConnections,
+}
+
+impl CloseStatus {
+    pub fn code(self) -> u16 {
+        match self {
+            CloseStatus::UnsupportedVersion => 1002,
+            CloseStatus::TooManyPending => 1013,
+            CloseStatus::Timeout => 4000,
+            CloseStatus::ServerFull => 4001,
+            CloseStatus::TooManyConnections => 4002,
+        }
+    }
+
+    /// Description sent with the code
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseStatus::UnsupportedVersion => "Unsupported protocol version",
+            CloseStatus::TooManyPending => "Too many pending messages",
+            CloseStatus::Timeout => "Heartbeat timed out",
+            CloseStatus::ServerFull => "Too many connections",
+            CloseStatus::TooManyConnections => "Too many connections from this address",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderboardEntry {
+    pub user_id: String,
+    pub score: u32,
+}
+
+/// Messages sent by the server to websocket clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerMessage {
+    Hello {
+        version: u8,
+    },
+    TileChange {
+        coords: AxialCoords,
+        tile: TileData,
+    },
+    NewUser {
+        user_id: String,
+        username: String,
+        color: String,
+        /// Empty when the user plays alone
+        team: String,
+    },
+    ScoreChange {
+        user_id: String,
+        score: u32,
+    },
+    /// Top of the leaderboard, first entry is ranked 1st
+    Leaderboard {
+        top: Vec<LeaderboardEntry>,
+    },
+    /// Only sent to the sessions authenticated as `user_id`
+    Cooldown {
+        user_id: String,
+        remaining_ms: u32,
+    },
+    Ack {
+        request_id: u32,
+    },
+    Error {
+        request_id: u32,
+        code: ErrorCode,
+        message: String,
+    },
+    /// Tiles changed during a broadcast tick, at most u16::MAX of them. `seq` is
+    /// the last change sequence they include, to send in a later resync
+    TilesChange {
+        seq: u64,
+        tiles: Vec<(AxialCoords, TileData)>,
+    },
+    /// A new round started, `ends_at_ms` is 0 if the round never ends
+    Round {
+        id: u32,
+        starts_at_ms: u64,
+        ends_at_ms: u64,
+    },
+}
+
+impl ServerMessage {
+    /// Error reply, `message` is cut to `MAX_STRING_LENGTH` bytes as it is only
+    /// meant to be displayed
+    pub fn error(request_id: u32, code: ErrorCode, message: &str) -> Self {
+        let mut length = message.len().min(MAX_STRING_LENGTH);
+        while !message.is_char_boundary(length) {
+            length -= 1;
+        }
+
+        ServerMessage::Error {
+            request_id,
+            code,
+            message: message[..length].to_string(),
+        }
+    }
+
+    pub fn leaderboard(top: &[RankedUser]) -> Self {
+        ServerMessage::Leaderboard {
+            top: top
+                .iter()
+                .map(|r| LeaderboardEntry {
+                    user_id: r.user.id.clone(),
+                    score: r.user.score,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn round(round: &Round) -> Self {
+        ServerMessage::Round {
+            id: round.id,
+            starts_at_ms: round.starts_at_ms,
+            ends_at_ms: round.ends_at_ms.unwrap_or(0),
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut w = Writer::default();
+
+        match self {
+            ServerMessage::Hello { version } => {
+                w.u8(0x00);
+                w.u8(*version);
+            }
+            ServerMessage::TileChange { coords, tile } => {
+                w.u8(0x01);
+                w.tile(coords, tile)?;
+            }
+            ServerMessage::NewUser {
+                user_id,
+                username,
+                color,
+                team,
+            } => {
+                w.u8(0x02);
+                w.string("user id", user_id)?;
+                w.string("username", username)?;
+                w.string("color", color)?;
+                w.string("team", team)?;
+            }
+            ServerMessage::ScoreChange { user_id, score } => {
+                w.u8(0x03);
+                w.string("user id", user_id)?;
+                w.u32(*score);
+            }
+            ServerMessage::Leaderboard { top } => {
+                w.u8(0x04);
+                w.u8(count("leaderboard", top.len(), u8::MAX as usize)? as u8);
+                for entry in top {
+                    w.string("user id", &entry.user_id)?;
+                    w.u32(entry.score);
+                }
+            }
+            ServerMessage::Cooldown {
+                user_id,
+                remaining_ms,
+            } => {
+                w.u8(0x05);
+                w.string("user id", user_id)?;
+                w.u32(*remaining_ms);
+            }
+            ServerMessage::Ack { request_id } => {
+                w.u8(0x06);
+                w.u32(*request_id);
+            }
+            ServerMessage::Error {
+                request_id,
+                code,
+                message,
+            } => {
+                w.u8(0x07);
+                w.u32(*request_id);
+                w.u8(*code as u8);
+                w.string("error message", message)?;
+            }
+            ServerMessage::TilesChange { seq, tiles } => {
+                w.u8(0x08);
+                w.u64(*seq);
+                w.u16(count("tiles", tiles.len(), u16::MAX as usize)? as u16);
+                for (coords, tile) in tiles {
+                    w.tile(coords, tile)?;
+                }
+            }
+            ServerMessage::Round {
+                id,
+                starts_at_ms,
+                ends_at_ms,
+            } => {
+                w.u8(0x09);
+                w.u32(*id);
+                w.u64(*starts_at_ms);
+                w.u64(*ends_at_ms);
+            }
+        }
+
+        Ok(w.finish())
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut r = Reader::new(bytes);
+
+        let message = match r.u8()? {
+            0x00 => ServerMessage::Hello { version: r.u8()? },
+            0x01 => {
+                let (coords, tile) = r.tile()?;
+                ServerMessage::TileChange { coords, tile }
+            }
+            0x02 => ServerMessage::NewUser {
+                user_id: r.string()?,
+                username: r.string()?,
+                color: r.string()?,
+                team: r.string()?,
+            },
+            0x03 => ServerMessage::ScoreChange {
+                user_id: r.string()?,
+                score: r.u32()?,
+            },
+            0x04 => {
+                let count = r.u8()?;
+                let top = (0..count)
+                    .map(|_| {
+                        Ok(LeaderboardEntry {
+                            user_id: r.string()?,
+                            score: r.u32()?,
+                        })
+                    })
+                    .collect::<Result<_, ProtocolError>>()?;
+
+                ServerMessage::Leaderboard { top }
+            }
+            0x05 => ServerMessage::Cooldown {
+                user_id: r.string()?,
+                remaining_ms: r.u32()?,
+            },
+            0x06 => ServerMessage::Ack {
+                request_id: r.u32()?,
+            },
+            0x07 => ServerMessage::Error {
+                request_id: r.u32()?,
+                code: ErrorCode::try_from(r.u8()?)?,
+                message: r.string()?,
+            },
+            0x08 => {
+                let seq = r.u64()?;
+                let count = r.u16()?;
+                let tiles = (0..count)
+                    .map(|_| r.tile())
+                    .collect::<Result<_, ProtocolError>>()?;
+
+                ServerMessage::TilesChange { seq, tiles }
+            }
+            0x09 => ServerMessage::Round {
+                id: r.u32()?,
+                starts_at_ms: r.u64()?,
+                ends_at_ms: r.u64()?,
+            },
+            t => return Err(ProtocolError::UnknownType(t)),
+        };
+
+        r.finish()?;
+        Ok(message)
+    }
+}
+
+/// Messages sent by websocket clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// Must be the first message of the client
+    Hello { request_id: u32, version: u8 },
+    Authenticate {
+        request_id: u32,
+        user_id: String,
+        token: String,
+    },
+    Click {
+        request_id: u32,
+        coords: AxialCoords,
+    },
+    /// Tile changes of these batches are sent to the client from now on
+    Subscribe { request_id: u32, batches: Vec<u16> },
+    /// Stops tile changes of these batches
+    Unsubscribe { request_id: u32, batches: Vec<u16> },
+    /// Asks the changes of subscribed batches after `since`, the sequence of the
+    /// last tiles change received
+    Resync { request_id: u32, since: u64 },
+}
+
+impl ClientMessage {
+    pub fn request_id(&self) -> u32 {
+        match self {
+            ClientMessage::Hello { request_id, .. }
+            | ClientMessage::Authenticate { request_id, .. }
+            | ClientMessage::Click { request_id, .. }
+            | ClientMessage::Subscribe { request_id, .. }
+            | ClientMessage::Unsubscribe { request_id, .. }
+            | ClientMessage::Resync { request_id, .. } => *request_id,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut w = Writer::default();
+
+        let message_type = match self {
+            ClientMessage::Hello { .. } => 0x00,
+            ClientMessage::Authenticate { .. } => 0x01,
+            ClientMessage::Click { .. } => 0x02,
+            ClientMessage::Subscribe { .. } => 0x03,
+            ClientMessage::Unsubscribe { .. } => 0x04,
+            ClientMessage::Resync { .. } => 0x05,
+        };
+        w.u8(message_type);
+        w.u32(self.request_id());
+
+        match self {
+            ClientMessage::Hello { version, .. } => w.u8(*version),
+            ClientMessage::Authenticate { user_id, token, .. } => {
+                w.string("user id", user_id)?;
+                w.string("token", token)?;
+            }
+            ClientMessage::Click { coords, .. } => {
+                w.i32(coords.q);
+                w.i32(coords.r);
+            }
+            ClientMessage::Subscribe { batches, .. }
+            | ClientMessage::Unsubscribe { batches, .. } => {
+                w.u16(count("batches", batches.len(), u16::MAX as usize)? as u16);
+                for batch in batches {
+                    w.u16(*batch);
+                }
+            }
+            ClientMessage::Resync { since, .. } => w.u64(*since),
+        }
+
+        Ok(w.finish())
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut r = Reader::new(bytes);
+        let message_type = r.u8()?;
+        let request_id = r.u32()?;
+
+        let message = match message_type {
+            0x00 => ClientMessage::Hello {
+                request_id,
+                version: r.u8()?,
+            },
+            0x01 => ClientMessage::Authenticate {
+                request_id,
+                user_id: r.string()?,
+                token: r.string()?,
+            },
+            0x02 => ClientMessage::Click {
+                request_id,
+                coords: AxialCoords::new(r.i32()?, r.i32()?),
+            },
+            0x03 | 0x04 => {
+                let count = r.u16()?;
+                let batches = (0..count)
+                    .map(|_| r.u16())
+                    .collect::<Result<_, ProtocolError>>()?;
+
+                if message_type == 0x03 {
+                    ClientMessage::Subscribe {
+                        request_id,
+                        batches,
+                    }
+                } else {
+                    ClientMessage::Unsubscribe {
+                        request_id,
+                        batches,
+                    }
+                }
+            }
+            0x05 => ClientMessage::Resync {
+                request_id,
+                since: r.u64()?,
+            },
+            t => return Err(ProtocolError::UnknownType(t)),
+        };
+
+        r.finish()?;
+        Ok(message)
+    }
+}
+
+/// Request id of a client message that could not be decoded, 0 if there is none
+pub fn request_id_of(bytes: &[u8]) -> u32 {
+    bytes
+        .get(1..5)
+        .and_then(|b| b.try_into().ok())
+        .map(u32::from_le_bytes)
+        .unwrap_or(0)
+}
+
+// Checks that a list of `length` items fits in a `max` count prefix
+fn count(field: &'static str, length: usize, max: usize) -> Result<usize, ProtocolError> {
+    if length > max {
+        return Err(ProtocolError::TooLong { field, length, max });
+    }
+
+    Ok(length)
+}
+
+#[derive(Default)]
+struct Writer(BytesMut);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.put_u8(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.put_u16_le(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.put_u32_le(value);
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.put_u64_le(value);
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.0.put_i32_le(value);
+    }
+
+    fn string(&mut self, field: &'static str, value: &str) -> Result<(), ProtocolError> {
+        let length = count(field, value.len(), MAX_STRING_LENGTH)?;
+        self.u8(length as u8);
+        self.0.put_slice(value.as_bytes());
+        Ok(())
+    }
+
+    fn tile(&mut self, coords: &AxialCoords, tile: &TileData) -> Result<(), ProtocolError> {
+        self.i32(coords.q);
+        self.i32(coords.r);
+        self.u8(tile.strength);
+        self.string("user id", &tile.user_id)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.0.len() < length {
+            return Err(ProtocolError::Truncated);
+        }
+
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ProtocolError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, ProtocolError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let length = self.u8()? as usize;
+        let value = self.take(length)?;
+
+        String::from_utf8(value.to_vec()).map_err(|_| ProtocolError::InvalidUtf8)
+    }
+
+    fn tile(&mut self) -> Result<(AxialCoords, TileData), ProtocolError> {
+        let coords = AxialCoords::new(self.i32()?, self.i32()?);
+        let strength = self.u8()?;
+        let user_id = self.string()?;
+
+        Ok((coords, TileData { user_id, strength }))
+    }
+
+    fn finish(self) -> Result<(), ProtocolError> {
+        match self.0.len() {
+            0 => Ok(()),
+            count => Err(ProtocolError::TrailingBytes(count)),
+        }
+    }
+}
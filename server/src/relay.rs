@@ -0,0 +1,231 @@
+//! Fan-out of websocket events between server instances.
+//!
+//! Every instance sends its events to its own clients then publishes them on the
+//! `RELAY_CHANNEL` redis channel. Instances relay what the others published to their
+//! clients, so that several instances can run behind a load balancer. Delivery is
+//! best effort like redis pub/sub itself: events published while an instance is
+//! disconnected from redis are lost for its clients.
+//!
+//! Events are the encoded `ServerMessage` prefixed by the id of the instance that
+//! published them (u8 length then utf8 bytes). Tile changes are published as a
+//! `ServerMessage::TilesChange` with a 0 sequence, each instance records them in its
+//! own `ChangeLog` through its `TileBroadcaster`. Cooldowns are only relayed to the
+//! sessions of their user.
+
+use std::{sync::Arc, time::Duration};
+
+use actix::Addr;
+use futures::StreamExt;
+#[cfg(feature = "test-utils")]
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+use crate::{
+    broadcaster::{RelayedTileChanges, TileBroadcaster},
+    clients::ClientList,
+    coords::AxialCoords,
+    game::TileData,
+    protocol::ServerMessage,
+};
+
+/// Redis pub/sub channel every instance publishes and listens to
+pub const RELAY_CHANNEL: &str = "relay_events";
+
+/// Delay before connecting again to redis after a failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Transport shared by the instances
+#[derive(Clone)]
+pub enum RelayBus {
+    /// Redis pub/sub channel, instances may run on different hosts
+    Redis(redis::Client),
+    /// Channel of the current process, used to test several instances without redis
+    #[cfg(feature = "test-utils")]
+    Mock(broadcast::Sender<Vec<u8>>),
+}
+
+#[cfg(feature = "test-utils")]
+impl RelayBus {
+    pub fn mock() -> Self {
+        Self::Mock(broadcast::channel(1024).0)
+    }
+}
+
+/// Publishes the events of this instance to the other ones, see `Relay::listen` to
+/// receive theirs
+#[derive(Clone)]
+pub struct Relay {
+    instance_id: Arc<str>,
+    bus: RelayBus,
+    outbox: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl Relay {
+    /// Spawns the task publishing events on `bus`, must be called from a tokio runtime
+    pub fn start(bus: RelayBus) -> Self {
+        let (outbox, events) = mpsc::unbounded_channel();
+        tokio::spawn(publish_events(bus.clone(), events));
+
+        Self {
+            instance_id: uuid::Uuid::new_v4().simple().to_string().into(),
+            bus,
+            outbox,
+        }
+    }
+
+    /// Unique id of this instance, events it published are not relayed back to it
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// Publishes an encoded `ServerMessage` to the other instances
+    pub fn publish(&self, message: &[u8]) {
+        let mut event = Vec::with_capacity(1 + self.instance_id.len() + message.len());
+        event.push(self.instance_id.len() as u8);
+        event.extend_from_slice(self.instance_id.as_bytes());
+        event.extend_from_slice(message);
+
+        if self.outbox.send(event).is_err() {
+            log::error!("Relay publisher stopped, event not published");
+        }
+    }
+
+    /// Publishes tiles changed on this instance to the other ones
+    pub fn publish_tiles(&self, tiles: &[(AxialCoords, TileData)]) {
+        for chunk in tiles.chunks(u16::MAX as usize) {
+            let message = ServerMessage::TilesChange {
+                seq: 0,
+                tiles: chunk.to_vec(),
+            };
+
+            match message.encode() {
+                Ok(bytes) => self.publish(&bytes),
+                Err(e) => log::error!("Could not encode relayed tiles change: {e}"),
+            }
+        }
+    }
+
+    /// Spawns the task relaying events of the other instances to `clients`, tile changes
+    /// go through `broadcaster` so that only subscribed clients receive them
+    pub fn listen(
+        &self,
+        clients: ClientList,
+        broadcaster: Addr<TileBroadcaster>,
+    ) -> tokio::task::JoinHandle<()> {
+        let relay = self.clone();
+
+        tokio::spawn(async move {
+            match relay.bus.clone() {
+                RelayBus::Redis(client) => loop {
+                    match relay.listen_redis(&client, &clients, &broadcaster).await {
+                        Ok(()) => log::warn!("Relay channel closed, reconnecting"),
+                        Err(e) => log::error!("Lost relay channel, reconnecting: {e}"),
+                    }
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                },
+                #[cfg(feature = "test-utils")]
+                RelayBus::Mock(sender) => {
+                    let mut events = sender.subscribe();
+
+                    loop {
+                        match events.recv().await {
+                            Ok(event) => relay.dispatch(&event, &clients, &broadcaster),
+                            Err(broadcast::error::RecvError::Lagged(count)) => {
+                                log::warn!("Relay lagged, {count} events lost")
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn listen_redis(
+        &self,
+        client: &redis::Client,
+        clients: &ClientList,
+        broadcaster: &Addr<TileBroadcaster>,
+    ) -> redis::RedisResult<()> {
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(RELAY_CHANNEL).await?;
+
+        let mut events = pubsub.into_on_message();
+        while let Some(event) = events.next().await {
+            self.dispatch(event.get_payload_bytes(), clients, broadcaster);
+        }
+
+        Ok(())
+    }
+
+    // Sends an event published by another instance to the local clients
+    fn dispatch(&self, event: &[u8], clients: &ClientList, broadcaster: &Addr<TileBroadcaster>) {
+        let Some((instance_id, bytes)) = split_event(event) else {
+            log::error!("Malformed relayed event of {} bytes", event.len());
+            return;
+        };
+
+        if instance_id == self.instance_id.as_bytes() {
+            return;
+        }
+
+        match ServerMessage::decode(bytes) {
+            Ok(ServerMessage::TilesChange { tiles, .. }) => {
+                broadcaster.do_send(RelayedTileChanges(tiles))
+            }
+            Ok(ServerMessage::Cooldown { user_id, .. }) => clients.send_to_user(&user_id, bytes),
+            Ok(_) => clients.send_local(bytes),
+            Err(e) => log::error!("Could not decode relayed event: {e}"),
+        }
+    }
+}
+
+// Splits an event into the id of the instance that published it and the message
+fn split_event(event: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (length, rest) = event.split_first()?;
+    let length = *length as usize;
+
+    if rest.len() < length {
+        return None;
+    }
+
+    Some(rest.split_at(length))
+}
+
+async fn publish_events(bus: RelayBus, mut events: mpsc::UnboundedReceiver<Vec<u8>>) {
+    let mut con = None;
+
+    while let Some(event) = events.recv().await {
+        match &bus {
+            RelayBus::Redis(client) => {
+                if con.is_none() {
+                    con = client
+                        .get_multiplexed_async_connection()
+                        .await
+                        .inspect_err(|e| log::error!("Could not connect the relay publisher: {e}"))
+                        .ok();
+                }
+
+                let Some(connection) = con.as_mut() else {
+                    continue;
+                };
+
+                let published: redis::RedisResult<()> = redis::cmd("PUBLISH")
+                    .arg(RELAY_CHANNEL)
+                    .arg(event)
+                    .query_async(connection)
+                    .await;
+
+                if let Err(e) = published {
+                    log::error!("Could not publish relayed event: {e}");
+                    con = None;
+                }
+            }
+            #[cfg(feature = "test-utils")]
+            RelayBus::Mock(sender) => {
+                // fails when no instance listens, like a redis channel without subscribers
+                let _ = sender.send(event);
+            }
+        }
+    }
+}
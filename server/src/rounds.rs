@@ -0,0 +1,258 @@
+use std::{sync::Mutex, time::Duration};
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    changes::ChangeLog,
+    clients::ClientList,
+    config::GameConfig,
+    error::{GameError, GameResult},
+    game::GameData,
+    history::now_ms,
+    store::{GameStore, RedisHandler},
+    user::RankedUser,
+    websocket::notify_round,
+};
+
+/// Number of users kept in the leaderboard of archived rounds
+pub const ARCHIVED_LEADERBOARD_SIZE: usize = 100;
+
+/// A round of the game, every round is played on its own grid
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Round {
+    /// Starts at 1, namespaces the tiles of the round (`tile:{id}:{q}_{r}`)
+    pub id: u32,
+    /// Unix time in milliseconds, clicks are refused before it
+    pub starts_at_ms: u64,
+    /// Unix time in milliseconds, clicks are refused after it. None if the round never ends
+    pub ends_at_ms: Option<u64>,
+}
+
+impl Round {
+    /// First round of a game, starting at `ROUND_STARTS_AT_MS` or at `now_ms` if it's not set
+    pub fn first(config: &GameConfig, now_ms: u64) -> Self {
+        Self::scheduled(1, config.round_starts_at_ms.unwrap_or(now_ms), config)
+    }
+
+    /// Round following this one, starting after the configured break
+    pub fn next(&self, config: &GameConfig) -> Self {
+        let starts_at_ms = self.ends_at_ms.unwrap_or(0) + config.round_break_secs * 1000;
+
+        Self::scheduled(self.id + 1, starts_at_ms, config)
+    }
+
+    fn scheduled(id: u32, starts_at_ms: u64, config: &GameConfig) -> Self {
+        Self {
+            id,
+            starts_at_ms,
+            ends_at_ms: (config.round_duration_secs > 0)
+                .then(|| starts_at_ms + config.round_duration_secs * 1000),
+        }
+    }
+
+    /// Whether clicks are accepted at `now_ms`
+    pub fn is_open(&self, now_ms: u64) -> bool {
+        now_ms >= self.starts_at_ms && !self.is_over(now_ms)
+    }
+
+    pub fn is_over(&self, now_ms: u64) -> bool {
+        self.ends_at_ms
+            .is_some_and(|ends_at_ms| now_ms >= ends_at_ms)
+    }
+}
+
+/// Final leaderboard of an archived round, the final grid is kept apart
+/// (see `RedisHandler::get_round_grid`)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoundResult {
+    #[serde(flatten)]
+    pub round: Round,
+    pub leaderboard: Vec<RankedUser>,
+}
+
+/// Round played by this worker, checked before every click
+pub struct CurrentRound {
+    inner: Mutex<CurrentRoundInner>,
+}
+
+struct CurrentRoundInner {
+    round: Round,
+    /// Whether this worker already archived the round
+    archived: bool,
+}
+
+impl CurrentRound {
+    pub fn new(round: Round) -> Self {
+        Self {
+            inner: Mutex::new(CurrentRoundInner {
+                round,
+                archived: false,
+            }),
+        }
+    }
+
+    pub fn get(&self) -> Round {
+        self.inner.lock().unwrap().round.clone()
+    }
+
+    pub fn set(&self, round: Round) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.round.id != round.id {
+            inner.archived = false;
+        }
+        inner.round = round;
+    }
+
+    /// Whether clicks are accepted right now
+    pub fn is_open(&self) -> bool {
+        self.inner.lock().unwrap().round.is_open(now_ms())
+    }
+
+    fn is_archived(&self) -> bool {
+        self.inner.lock().unwrap().archived
+    }
+
+    fn mark_archived(&self) {
+        self.inner.lock().unwrap().archived = true;
+    }
+}
+
+/// Makes the store play the current round, starting the first one if needed.
+/// Must be called before serving any request.
+pub async fn init_round<C>(store: &GameStore, con: &mut C, config: &GameConfig) -> GameResult<Round>
+where
+    C: redis::aio::ConnectionLike + Send,
+{
+    let round = match store.get_current_round(con).await? {
+        Some(round) => round,
+        None => {
+            store
+                .start_round(con, 0, &Round::first(config, now_ms()))
+                .await?;
+            // e.g. the store was flushed meanwhile
+            store.get_current_round(con).await?.ok_or_else(|| {
+                GameError::StoreUnavailable("First round was not saved".to_string())
+            })?
+        }
+    };
+
+    store.use_round(con, round.id).await?;
+
+    Ok(round)
+}
+
+/// Moves the round lifecycle forward at `now_ms`:
+/// - archives the final leaderboard and grid of a round once it's over,
+/// - starts the next round once the break after it is over,
+/// - switches the store to the current round when another worker started it.
+///
+/// Returns the new round if the store switched to it.
+pub async fn advance_round<C>(
+    store: &GameStore,
+    con: &mut C,
+    game_data: &GameData,
+    current: &CurrentRound,
+    config: &GameConfig,
+    now_ms: u64,
+) -> GameResult<Option<Round>>
+where
+    C: redis::aio::ConnectionLike + Send,
+{
+    let Some(mut round) = store.get_current_round(con).await? else {
+        return Ok(None);
+    };
+
+    if round.id == current.get().id && round.is_over(now_ms) && !current.is_archived() {
+        // every worker may try, only the first archive is kept
+        let leaderboard = store
+            .get_leaderboard(con, 0, ARCHIVED_LEADERBOARD_SIZE)
+            .await?;
+        let tiles = store
+            .batch_get_tiles(con, game_data.all_grid_coords())
+            .await?;
+
+        let result = RoundResult {
+            round: round.clone(),
+            leaderboard,
+        };
+        if store.archive_round(con, &result, tiles).await? {
+            log::info!("Archived round {}", round.id);
+        }
+        current.mark_archived();
+    }
+
+    if round.id == current.get().id && round.is_over(now_ms) {
+        let next = round.next(config);
+        if now_ms >= next.starts_at_ms {
+            store.start_round(con, round.id, &next).await?;
+            round = store.get_current_round(con).await?.unwrap_or(next);
+        }
+    }
+
+    if round.id == current.get().id {
+        // the schedule of the round may have been changed by another worker
+        current.set(round);
+        return Ok(None);
+    }
+
+    store.use_round(con, round.id).await?;
+    current.set(round.clone());
+    log::info!("Round {} started", round.id);
+
+    Ok(Some(round))
+}
+
+/// Everything needed to advance rounds in the background, see `RoundScheduler::spawn`
+pub struct RoundScheduler {
+    pub store: web::Data<GameStore>,
+    pub pool: deadpool_redis::Pool,
+    pub game_data: web::Data<GameData>,
+    pub current: web::Data<CurrentRound>,
+    pub change_log: web::Data<ChangeLog>,
+    pub clients: ClientList,
+    pub config: GameConfig,
+}
+
+impl RoundScheduler {
+    /// Spawns a task advancing the round lifecycle every `interval`, websocket clients
+    /// are told about new rounds so that they download the new grid.
+    pub fn spawn(self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                let mut con = match self.store.get_connection(&self.pool).await {
+                    Ok(con) => con,
+                    Err(e) => {
+                        log::error!("Could not get a redis connection to advance rounds: {e}");
+                        continue;
+                    }
+                };
+
+                let advanced = advance_round(
+                    &self.store,
+                    &mut con,
+                    &self.game_data,
+                    &self.current,
+                    &self.config,
+                    now_ms(),
+                )
+                .await;
+
+                match advanced {
+                    Ok(Some(round)) => {
+                        // changes of the previous round must not be replayed on the new grid
+                        self.change_log.clear();
+                        notify_round(&self.clients, &round);
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::error!("Failed to advance rounds: {e}"),
+                }
+            }
+        })
+    }
+}
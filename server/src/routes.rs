@@ -0,0 +1,453 @@
+use std::time::Instant;
+
+use actix_web::{error::InternalError, get, http::StatusCode, post, web, HttpResponse, Responder};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::AuthenticatedUser,
+    changes::{ChangeLog, ChangesSince},
+    click::ClickContext,
+    clients::ClientList,
+    config::GameConfig,
+    coords::AxialCoords,
+    error::{GameError, GameResult},
+    game::{GameData, TileMap},
+    metrics::Metrics,
+    protocol::MAX_STRING_LENGTH,
+    rounds::{CurrentRound, Round, RoundResult},
+    snapshot::{self, SnapshotCache, SnapshotKey, DEFAULT_HEX_SIZE, MAX_HEX_SIZE},
+    store::{GameStore, RedisHandler},
+    user::{rank_teams, User},
+    websocket::{notify_new_user, ws_handler},
+};
+
+/// Registers every HTTP and websocket route of the game.
+///
+/// Handlers expect `GameConfig`, `GameData`, `ClientList`,
+/// `Addr<TileBroadcaster>`, `ChangeLog`, `GameStore`, `deadpool_redis::Pool`,
+/// `LeaderboardWatcher`, `CurrentRound`, `SnapshotCache` and `Metrics` to be registered
+/// as app data.
+/// Failures are answered with the JSON body of `GameError`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(post_tile)
+        .service(get_batch_list)
+        .service(get_batch_tiles)
+        .service(get_changes)
+        .service(get_snapshot)
+        .service(get_rounds)
+        .service(get_round_snapshot)
+        .service(get_game_settings)
+        .service(get_users)
+        .service(get_leaderboard)
+        .service(get_team_leaderboard)
+        .service(get_user_rank)
+        .service(register_user)
+        .service(refresh_token)
+        .service(logout)
+        .service(get_metrics)
+        .service(web::resource("/ws").to(ws_handler));
+}
+
+#[post("/tile/{q}/{r}")]
+#[tracing::instrument(skip_all, fields(q = path.q, r = path.r, user_id = %user.id))]
+async fn post_tile(
+    path: web::Path<AxialCoords>,
+    click_context: ClickContext,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> GameResult<HttpResponse> {
+    click_context.play(&user, &path.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().body("Tile updated"))
+}
+
+#[get("/settings")]
+async fn get_game_settings(game_data: web::Data<GameData>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(game_data.settings)
+}
+
+#[derive(Deserialize)]
+struct BatchTilesQuery {
+    batch: usize,
+}
+
+/// Header of `GET /tiles` responses holding the change sequence to resync from
+pub const CHANGE_SEQ_HEADER: &str = "X-Change-Seq";
+
+#[get("/tiles")]
+async fn get_batch_tiles(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    game_data: web::Data<GameData>,
+    change_log: web::Data<ChangeLog>,
+    metrics: web::Data<Metrics>,
+    query: web::Query<BatchTilesQuery>,
+) -> GameResult<HttpResponse> {
+    // read before the tiles so that changes made while reading them are replayed
+    let seq = change_log.last_seq();
+    let started = Instant::now();
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+
+    let computed_batch = game_data
+        .compute_batch(&**redis_client, &mut con, query.batch)
+        .await;
+    metrics.observe_round_trips("batch", con.round_trips());
+    let computed_batch = computed_batch?;
+    metrics.observe_batch(started.elapsed());
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((CHANGE_SEQ_HEADER, seq.to_string()))
+        .json(computed_batch))
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: u64,
+}
+
+/// Tiles changed after `since`, `410 Gone` when these changes are not known anymore
+/// and every batch must be downloaded again
+#[get("/changes")]
+async fn get_changes(
+    change_log: web::Data<ChangeLog>,
+    query: web::Query<ChangesQuery>,
+) -> impl Responder {
+    let changes = change_log.since(query.since);
+
+    match changes {
+        ChangesSince::Changes { .. } => HttpResponse::Ok().json(changes),
+        ChangesSince::ResyncRequired { .. } => HttpResponse::Gone().json(changes),
+    }
+}
+
+#[derive(Deserialize)]
+struct SnapshotQuery {
+    /// Size of the hexagons in pixels, see `snapshot::render_png`
+    size: Option<u32>,
+}
+
+impl SnapshotQuery {
+    fn hex_size(&self) -> u32 {
+        self.size.unwrap_or(DEFAULT_HEX_SIZE).clamp(1, MAX_HEX_SIZE)
+    }
+}
+
+/// Current grid rendered as a PNG image, the last render is reused until the grid changes
+#[get("/snapshot.png")]
+async fn get_snapshot(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    game_data: web::Data<GameData>,
+    change_log: web::Data<ChangeLog>,
+    current_round: web::Data<CurrentRound>,
+    snapshot_cache: web::Data<SnapshotCache>,
+    query: web::Query<SnapshotQuery>,
+) -> actix_web::Result<HttpResponse> {
+    // read before the tiles, a render including later changes is only cached as older
+    let key = SnapshotKey {
+        round_id: current_round.get().id,
+        seq: Some(change_log.last_seq()),
+        hex_size: query.hex_size(),
+    };
+
+    let png = snapshot_cache
+        .get_or_render(key, || async {
+            let mut con = redis_client
+                .get_connection(&redis_pool)
+                .await
+                .map_err(GameError::from)?;
+
+            let tiles = redis_client
+                .batch_get_tiles(&mut con, game_data.all_grid_coords())
+                .await?
+                .into_iter()
+                .collect();
+
+            render_snapshot(&redis_client, &mut con, tiles, &game_data, key.hex_size).await
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
+
+/// Final grid of an archived round rendered as a PNG image
+#[get("/rounds/{id}/snapshot.png")]
+async fn get_round_snapshot(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    game_data: web::Data<GameData>,
+    snapshot_cache: web::Data<SnapshotCache>,
+    path: web::Path<u32>,
+    query: web::Query<SnapshotQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let round_id = path.into_inner();
+    let key = SnapshotKey {
+        round_id,
+        seq: None,
+        hex_size: query.hex_size(),
+    };
+
+    let png = snapshot_cache
+        .get_or_render(key, || async {
+            let mut con = redis_client
+                .get_connection(&redis_pool)
+                .await
+                .map_err(GameError::from)?;
+
+            let tiles = redis_client
+                .get_round_grid(&mut con, round_id)
+                .await?
+                .ok_or_else(|| GameError::NotFound(format!("Round {round_id} is not archived")))?
+                .into_iter()
+                .collect();
+
+            render_snapshot(&redis_client, &mut con, tiles, &game_data, key.hex_size).await
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
+
+async fn render_snapshot<C>(
+    redis_client: &GameStore,
+    con: &mut C,
+    tiles: TileMap,
+    game_data: &GameData,
+    hex_size: u32,
+) -> actix_web::Result<Bytes>
+where
+    C: redis::aio::ConnectionLike + Send,
+{
+    let colors = snapshot::user_colors(&redis_client.get_public_users(con).await?);
+    let radius = game_data.settings.radius;
+
+    // rasterising the whole grid is CPU bound, keep it off the async workers
+    match web::block(move || snapshot::render_png(&tiles, &colors, radius, hex_size)).await {
+        Ok(Ok(png)) => Ok(Bytes::from(png)),
+        Ok(Err(e)) => Err(InternalError::new(
+            format!("Failed to encode snapshot: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into()),
+        Err(e) => Err(InternalError::new(
+            format!("Failed to render snapshot: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into()),
+    }
+}
+
+/// Metrics of this instance in the Prometheus text format, see `Metrics`
+#[get("/metrics")]
+async fn get_metrics(metrics: web::Data<Metrics>, clients: web::Data<ClientList>) -> HttpResponse {
+    match metrics.render(&clients) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("text/plain")
+            .body(format!("Failed to render metrics: {}", e)),
+    }
+}
+
+#[derive(Serialize)]
+struct RoundsHistory {
+    current: Round,
+    /// Newest first
+    archived: Vec<RoundResult>,
+}
+
+/// Schedule of the current round and final leaderboard of the archived ones
+#[get("/rounds")]
+async fn get_rounds(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    current_round: web::Data<CurrentRound>,
+) -> GameResult<HttpResponse> {
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+    let archived = redis_client.get_round_results(&mut con).await?;
+
+    Ok(HttpResponse::Ok().json(RoundsHistory {
+        current: current_round.get(),
+        archived,
+    }))
+}
+
+#[get("/batches")]
+async fn get_batch_list(game_data: web::Data<GameData>) -> impl Responder {
+    let list = game_data.get_batch_list();
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(list)
+}
+
+#[get("/users")]
+async fn get_users(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+) -> GameResult<HttpResponse> {
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+    let users_public = redis_client.get_public_users(&mut con).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(users_public))
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+const LEADERBOARD_MAX_LIMIT: usize = 100;
+
+#[get("/leaderboard")]
+async fn get_leaderboard(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    query: web::Query<LeaderboardQuery>,
+) -> GameResult<HttpResponse> {
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(20).min(LEADERBOARD_MAX_LIMIT);
+
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+    let ranking = redis_client
+        .get_leaderboard(&mut con, offset, limit)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(ranking))
+}
+
+/// Total number of tiles owned by the members of each team, highest first
+#[get("/leaderboard/teams")]
+async fn get_team_leaderboard(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+) -> GameResult<HttpResponse> {
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+    let users = redis_client.get_public_users(&mut con).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(rank_teams(&users)))
+}
+
+#[get("/users/{id}/rank")]
+async fn get_user_rank(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    path: web::Path<String>,
+) -> GameResult<HttpResponse> {
+    let user_id = path.into_inner();
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+
+    let ranked = redis_client
+        .get_user_rank(&mut con, &user_id)
+        .await?
+        .ok_or_else(|| GameError::NotFound(format!("User {user_id} is not ranked")))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(ranked))
+}
+
+#[derive(Deserialize)]
+struct RegisterUserParams {
+    username: String,
+    /// Team to join, one of `GameConfig::teams` when some are configured
+    #[serde(default)]
+    team: Option<String>,
+}
+
+#[post("/login")]
+async fn register_user(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    app_config: web::Data<GameConfig>,
+    clients: web::Data<ClientList>,
+    post_params: web::Json<RegisterUserParams>,
+) -> GameResult<HttpResponse> {
+    let RegisterUserParams { username, team } = post_params.into_inner();
+    let team = team.filter(|team| !team.is_empty());
+
+    // usernames and teams are sent to websocket clients with an u8 length
+    if username.len() > MAX_STRING_LENGTH {
+        return Err(GameError::InvalidInput(format!(
+            "Username must be at most {MAX_STRING_LENGTH} bytes long"
+        )));
+    }
+
+    if let Some(team) = &team {
+        if team.len() > MAX_STRING_LENGTH {
+            return Err(GameError::InvalidInput(format!(
+                "Team must be at most {MAX_STRING_LENGTH} bytes long"
+            )));
+        }
+
+        if !app_config.teams.is_empty() && !app_config.teams.contains(team) {
+            return Err(GameError::InvalidInput(format!(
+                "Unknown team {team}, expected one of {}",
+                app_config.teams.join(", ")
+            )));
+        }
+    }
+
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+
+    let user = User {
+        team,
+        ..User::new(&username)
+    };
+    let added = redis_client
+        .add_user(&mut con, user.clone(), app_config.session_ttl_secs)
+        .await?;
+
+    if !added {
+        return Err(GameError::Conflict(format!(
+            "Username {username} is already taken"
+        )));
+    }
+
+    notify_new_user(&clients, &user);
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Rotates the session token, the previous token stops being valid
+#[post("/refresh")]
+async fn refresh_token(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    app_config: web::Data<GameConfig>,
+    AuthenticatedUser(mut user): AuthenticatedUser,
+) -> GameResult<HttpResponse> {
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+
+    user.renew_token();
+
+    redis_client
+        .set_user_token(&mut con, &user.id, &user.token, app_config.session_ttl_secs)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[post("/logout")]
+async fn logout(
+    redis_client: web::Data<GameStore>,
+    redis_pool: web::Data<deadpool_redis::Pool>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> GameResult<HttpResponse> {
+    let mut con = redis_client.get_connection(&redis_pool).await?;
+
+    redis_client.revoke_user_token(&mut con, &user.id).await?;
+
+    Ok(HttpResponse::Ok().body("Logged out"))
+}
@@ -0,0 +1,177 @@
+use std::fmt::Debug;
+
+use crate::{
+    config::GameConfig,
+    coords::AxialCoords,
+    game::{contiguous_count, ClickAction, ClickOutcome, InnerTileData, TileMap},
+};
+
+/// Rules deciding the strength of tiles and the outcome of clicks, `GameData` is generic
+/// over them so that variants can be prototyped and played against the mock store.
+///
+/// Stores resolve clicks atomically on their own: the mock and in-memory stores call
+/// `resolve_click`, `RedisStore` runs `scripts/apply_click.lua` which is a port of the
+/// default `resolve_click` fed with `influence_radius`, `max_strength` and `repair_rate`.
+/// Rules overriding any other method can't be played against redis, see `SCRIPTED`.
+pub trait GameRules: Debug + Clone + Send + Sync + 'static {
+    /// Whether `scripts/apply_click.lua` resolves clicks as these rules do, i.e. they
+    /// only override the three parameters above. `RedisStore` refuses other rules.
+    const SCRIPTED: bool = false;
+
+    /// Tiles contiguous to a tile within this many steps reinforce it
+    fn influence_radius(&self) -> u8;
+
+    /// Strength of a tile never goes above it, however many tiles reinforce it
+    fn max_strength(&self) -> u8;
+
+    /// Damage removed from a tile by a click of its owner
+    fn repair_rate(&self) -> u8;
+
+    /// Strength of an undamaged tile reinforced by `allies` contiguous tiles, at least 1
+    fn full_strength(&self, allies: u8) -> u8 {
+        allies.saturating_add(1).min(self.max_strength()).max(1)
+    }
+
+    /// Damage of a tile reinforced by `allies` contiguous tiles. Damage is recorded against
+    /// the strength a tile had when it was clicked, so it's clamped when the tile lost
+    /// neighbors since then: a tile always needs at least one more click to be captured.
+    fn clamped_damage(&self, allies: u8, damage: u8) -> u8 {
+        damage.min(self.full_strength(allies) - 1)
+    }
+
+    /// Number of clicks needed to capture a tile reinforced by `allies` contiguous tiles,
+    /// between 1 and `max_strength`
+    fn strength(&self, allies: u8, damage: u8) -> u8 {
+        self.full_strength(allies) - self.clamped_damage(allies, damage)
+    }
+
+    /// Resolves a single click of `click_user_id`, member of `click_team` (None when playing
+    /// solo). `tiles` must contain every tile within `influence_radius` steps of `click_coords`.
+    fn resolve_click(
+        &self,
+        tiles: &TileMap,
+        click_coords: &AxialCoords,
+        click_user_id: &str,
+        click_team: Option<&str>,
+    ) -> ClickOutcome {
+        let click_tile = InnerTileData {
+            user_id: click_user_id.to_string(),
+            damage: 0,
+            team: click_team.map(str::to_string),
+        };
+
+        let Some(current_tile) = tiles.get(click_coords) else {
+            return ClickOutcome {
+                action: ClickAction::Create,
+                previous_owner: None,
+                previous_team: None,
+                tile: click_tile,
+            };
+        };
+
+        let previous_owner = Some(current_tile.user_id.clone());
+        let previous_team = current_tile.team.clone();
+
+        let allies = || {
+            contiguous_count(
+                tiles,
+                click_coords,
+                &current_tile.user_id,
+                current_tile.team.as_deref(),
+                self.influence_radius(),
+            )
+        };
+
+        if !current_tile.is_allied_to(click_user_id, click_team) {
+            let allies = allies();
+            let damage = self.clamped_damage(allies, current_tile.damage) + 1;
+
+            if damage >= self.full_strength(allies) {
+                return ClickOutcome {
+                    action: ClickAction::Capture,
+                    previous_owner,
+                    previous_team,
+                    tile: click_tile,
+                };
+            }
+
+            return ClickOutcome {
+                action: ClickAction::Damage,
+                previous_owner,
+                previous_team,
+                tile: InnerTileData {
+                    damage,
+                    ..current_tile.clone()
+                },
+            };
+        }
+
+        if current_tile.damage > 0 {
+            let damage = self.clamped_damage(allies(), current_tile.damage);
+
+            return ClickOutcome {
+                action: ClickAction::Repair,
+                previous_owner,
+                previous_team,
+                tile: InnerTileData {
+                    damage: damage.saturating_sub(self.repair_rate()),
+                    ..current_tile.clone()
+                },
+            };
+        }
+
+        ClickOutcome {
+            action: ClickAction::None,
+            previous_owner,
+            previous_team,
+            tile: current_tile.clone(),
+        }
+    }
+}
+
+/// Rules the game has always been played with: a tile has a strength of 1 plus one per
+/// contiguous tile of its owner within 2 steps, every click of another user damages it
+/// by one until it's captured and every click of its owner repairs one damage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassicRules {
+    // see the `GameRules` methods of the same name
+    pub influence_radius: u8,
+    pub max_strength: u8,
+    pub repair_rate: u8,
+}
+
+impl Default for ClassicRules {
+    fn default() -> Self {
+        Self {
+            influence_radius: 2,
+            max_strength: u8::MAX,
+            repair_rate: 1,
+        }
+    }
+}
+
+impl ClassicRules {
+    /// Default rules with the strength cap of `config`
+    pub fn from_config(config: &GameConfig) -> Self {
+        Self {
+            max_strength: config.max_strength,
+            ..Self::default()
+        }
+    }
+}
+
+impl GameRules for ClassicRules {
+    const SCRIPTED: bool = true;
+
+    fn influence_radius(&self) -> u8 {
+        self.influence_radius
+    }
+
+    fn max_strength(&self) -> u8 {
+        self.max_strength
+    }
+
+    fn repair_rate(&self) -> u8 {
+        self.repair_rate
+    }
+}
@@ -0,0 +1,23 @@
+-- Registers an user atomically, usernames must be unique.
+--
+-- KEYS[1]: hash of user ids by username
+-- KEYS[2]: user hash
+-- KEYS[3]: user token
+-- KEYS[4]: list of every user id
+-- ARGV[1]: user id
+-- ARGV[2]: username
+-- ARGV[3]: hashed session token
+-- ARGV[4]: session token time to live in seconds
+-- ARGV[5..]: fields and values of the user hash
+--
+-- Returns 1 if the user was added, 0 if the username is already taken.
+
+if redis.call('HSETNX', KEYS[1], ARGV[2], ARGV[1]) == 0 then
+    return 0
+end
+
+redis.call('HSET', KEYS[2], unpack(ARGV, 5))
+redis.call('SET', KEYS[3], ARGV[3], 'EX', ARGV[4])
+redis.call('RPUSH', KEYS[4], ARGV[1])
+
+return 1
@@ -0,0 +1,140 @@
+-- Atomically resolves a click, see `GameRules::resolve_click` for the reference implementation.
+--
+-- KEYS[1]: sorted set of tile counts by user id
+-- KEYS[2]: stream of click events, every click changing the clicked tile is appended to it
+-- KEYS[3..]: tile hashes of the clicked tile (KEYS[3]) and of every tile within the
+--   influence radius of it
+-- ARGV[1]: id of the clicking user
+-- ARGV[2]: team the click is played for, '' when playing solo
+-- ARGV[3]: influence radius, see `GameRules::influence_radius`
+-- ARGV[4]: max strength of a tile, see `GameRules::max_strength`
+-- ARGV[5]: damage removed by a repair, see `GameRules::repair_rate`
+-- ARGV[6..]: q and r of each tile in KEYS[3..], in the same order
+--
+-- Returns {action, previous_owner, previous_team, user_id, damage, team} where
+-- `user_id`, `damage` and `team` describe the clicked tile after the click and
+-- `previous_owner` is '' on creation. Missing teams are ''.
+
+local scores_key = KEYS[1]
+local events_key = KEYS[2]
+local click_user_id = ARGV[1]
+local click_team = ARGV[2]
+local influence_radius = tonumber(ARGV[3])
+local max_strength = tonumber(ARGV[4])
+local repair_rate = tonumber(ARGV[5])
+
+local directions = { { 1, 0 }, { 1, -1 }, { 0, -1 }, { -1, 0 }, { -1, 1 }, { 0, 1 } }
+
+local function coords_key(q, r)
+    return q .. '_' .. r
+end
+
+-- owner and team of every tile within reach, indexed by "q_r"
+local tiles = {}
+for i = 3, #KEYS do
+    local tile = redis.call('HMGET', KEYS[i], 'user_id', 'team')
+    if tile[1] then
+        tiles[coords_key(ARGV[2 * i], ARGV[2 * i + 1])] = { owner = tile[1], team = tile[2] or '' }
+    end
+end
+
+local click_q = tonumber(ARGV[6])
+local click_r = tonumber(ARGV[7])
+local click_key = KEYS[3]
+local click_coords = coords_key(click_q, click_r)
+
+-- whether `tile` counts as owned by `user_id`, tiles of its teammates do too when `team` is set
+local function is_allied(tile, user_id, team)
+    return tile.owner == user_id or (team ~= '' and tile.team == team)
+end
+
+-- number of tiles owned by `user_id` or its `team` contiguous to the clicked tile within
+-- the influence radius
+local function contiguous_count(user_id, team)
+    local processed = { [click_coords] = true }
+    local to_check = { { click_q, click_r } }
+    local count = 0
+
+    for _ = 1, influence_radius do
+        local next_to_check = {}
+
+        for _, coords in ipairs(to_check) do
+            for _, dir in ipairs(directions) do
+                local q = coords[1] + dir[1]
+                local r = coords[2] + dir[2]
+                local key = coords_key(q, r)
+
+                if not processed[key] and tiles[key] and is_allied(tiles[key], user_id, team) then
+                    processed[key] = true
+                    count = count + 1
+                    table.insert(next_to_check, { q, r })
+                end
+            end
+        end
+
+        to_check = next_to_check
+    end
+
+    return count
+end
+
+-- appends the click to the events stream, see `store::parse_click_event` for the fields
+local function record(action, previous_owner, previous_team, user_id, damage, team)
+    local time = redis.call('TIME')
+    -- concatenated rather than computed so that lua does not format it as a float
+    local ts = time[1] .. string.format('%03d', math.floor(tonumber(time[2]) / 1000))
+
+    redis.call('XADD', events_key, '*',
+        'ts', ts,
+        'user_id', click_user_id,
+        'team', click_team,
+        'q', click_q,
+        'r', click_r,
+        'action', action,
+        'previous_owner', previous_owner,
+        'owner', user_id,
+        'damage', damage,
+        'owner_team', team)
+
+    return { action, previous_owner, previous_team, user_id, damage, team }
+end
+
+local tile = tiles[click_coords]
+
+if not tile then
+    redis.call('HSET', click_key, 'user_id', click_user_id, 'damage', 0, 'team', click_team)
+    redis.call('ZINCRBY', scores_key, 1, click_user_id)
+    return record('create', '', '', click_user_id, 0, click_team)
+end
+
+local owner = tile.owner
+local damage = tonumber(redis.call('HGET', click_key, 'damage')) or 0
+
+-- strength of the tile without damage, see `GameRules::full_strength`
+local function full_strength()
+    return math.max(1, math.min(1 + contiguous_count(owner, tile.team), max_strength))
+end
+
+if not is_allied(tile, click_user_id, click_team) then
+    local full = full_strength()
+    -- the tile may have lost neighbors since it was damaged, see `GameRules::clamped_damage`
+    damage = math.min(damage, full - 1) + 1
+
+    if damage >= full then
+        redis.call('HSET', click_key, 'user_id', click_user_id, 'damage', 0, 'team', click_team)
+        redis.call('ZINCRBY', scores_key, -1, owner)
+        redis.call('ZINCRBY', scores_key, 1, click_user_id)
+        return record('capture', owner, tile.team, click_user_id, 0, click_team)
+    end
+
+    redis.call('HSET', click_key, 'damage', damage)
+    return record('damage', owner, tile.team, owner, damage, tile.team)
+end
+
+if damage > 0 then
+    damage = math.max(0, math.min(damage, full_strength() - 1) - repair_rate)
+    redis.call('HSET', click_key, 'damage', damage)
+    return record('repair', owner, tile.team, owner, damage, tile.team)
+end
+
+return { 'none', owner, tile.team, owner, damage, tile.team }
@@ -0,0 +1,20 @@
+-- Starts the click cooldown of an user unless it is already running.
+--
+-- KEYS[1]: user cooldown key
+-- ARGV[1]: cooldown duration in milliseconds
+--
+-- Returns 0 if the click is allowed, otherwise the milliseconds left before the next click.
+
+if redis.call('SET', KEYS[1], 1, 'PX', ARGV[1], 'NX') then
+    return 0
+end
+
+local ttl = redis.call('PTTL', KEYS[1])
+
+-- no expiry left on the key, restart the cooldown
+if ttl < 0 then
+    redis.call('SET', KEYS[1], 1, 'PX', ARGV[1])
+    return 0
+end
+
+return math.max(ttl, 1)
@@ -0,0 +1,27 @@
+-- Writes tiles while keeping the number of tiles owned by each user in sync.
+--
+-- KEYS[1]: sorted set of tile counts by user id
+-- KEYS[2..]: tile hashes to write
+-- ARGV: user_id, damage and team ('' if none) of each tile in KEYS[2..], in the same order
+--
+-- Returns the number of written tiles.
+
+local scores_key = KEYS[1]
+
+for i = 2, #KEYS do
+    local user_id = ARGV[3 * i - 5]
+    local damage = ARGV[3 * i - 4]
+    local team = ARGV[3 * i - 3]
+    local previous_owner = redis.call('HGET', KEYS[i], 'user_id')
+
+    redis.call('HSET', KEYS[i], 'user_id', user_id, 'damage', damage, 'team', team)
+
+    if previous_owner ~= user_id then
+        if previous_owner then
+            redis.call('ZINCRBY', scores_key, -1, previous_owner)
+        end
+        redis.call('ZINCRBY', scores_key, 1, user_id)
+    end
+end
+
+return #KEYS - 1
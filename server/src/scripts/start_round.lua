@@ -0,0 +1,18 @@
+-- Starts a round only if the current round is still the expected one, so that
+-- a single worker starts the next round.
+--
+-- KEYS[1]: hash of the current round
+-- ARGV[1]: id of the expected current round, 0 if no round was started yet
+-- ARGV[2..4]: id, starts_at_ms and ends_at_ms ('' if the round never ends) of the new round
+--
+-- Returns 1 if the round was started, 0 otherwise.
+
+local current_id = tonumber(redis.call('HGET', KEYS[1], 'id') or '0')
+
+if current_id ~= tonumber(ARGV[1]) then
+    return 0
+end
+
+redis.call('HSET', KEYS[1], 'id', ARGV[2], 'starts_at_ms', ARGV[3], 'ends_at_ms', ARGV[4])
+
+return 1
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, future::Future};
+
+use bytes::Bytes;
+use tokio::sync::Mutex;
+
+use crate::{
+    coords::{axial_to_pixel, is_within_grid, pixel_to_axial, AxialCoords},
+    game::TileMap,
+    user::PublicUser,
+    utils::{hex_to_color, string_to_color},
+};
+
+/// Size of the rendered hexagons in pixels (center to corner) when none is given
+pub const DEFAULT_HEX_SIZE: u32 = 4;
+
+/// Largest hexagon size accepted by `render_png`, bounds the size of the image
+pub const MAX_HEX_SIZE: u32 = 8;
+
+const EMPTY_TILE_COLOR: [u8; 4] = [0xdd, 0xdd, 0xdd, 0xff];
+
+const OUTSIDE_GRID_COLOR: [u8; 4] = [0, 0, 0, 0];
+
+/// Color of every user by id, parsed from `User.color`
+pub fn user_colors(users: &[PublicUser]) -> HashMap<String, (u8, u8, u8)> {
+    users
+        .iter()
+        .filter_map(|u| hex_to_color(&u.color).map(|c| (u.id.clone(), c)))
+        .collect()
+}
+
+/// Width and height of the image of a grid of `radius` with hexagons of `hex_size`
+pub fn image_size(radius: u32, hex_size: u32) -> (u32, u32) {
+    let size = hex_size as f64;
+    let diameter = 2.0 * radius as f64 + 1.0;
+
+    // a pointy top hexagon is sqrt(3) * size wide and 2 * size high, rows overlap by a quarter
+    let width = (3f64.sqrt() * size * diameter).ceil() as u32;
+    let height = (size * (1.5 * diameter + 0.5)).ceil() as u32;
+
+    (width, height)
+}
+
+/// Rasterises the grid of `radius` into a RGBA PNG, owned tiles are filled with the
+/// color of their owner (see `user_colors`), empty tiles in light grey and pixels
+/// outside the grid are transparent.
+///
+/// Tiles are laid out with `coords::axial_to_pixel`, the center tile being at the
+/// center of the image. `hex_size` is clamped to `1..=MAX_HEX_SIZE`.
+pub fn render_png(
+    tiles: &TileMap,
+    colors: &HashMap<String, (u8, u8, u8)>,
+    radius: u32,
+    hex_size: u32,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let hex_size = hex_size.clamp(1, MAX_HEX_SIZE);
+    let (width, height) = image_size(radius, hex_size);
+    let size = hex_size as f64;
+
+    // pixel position of the center tile
+    let (center_x, center_y) = axial_to_pixel(&AxialCoords::center(), size);
+    let offset_x = width as f64 / 2.0 - center_x;
+    let offset_y = height as f64 / 2.0 - center_y;
+
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+
+    for y in 0..height {
+        for x in 0..width {
+            let coords = pixel_to_axial(x as f64 + 0.5 - offset_x, y as f64 + 0.5 - offset_y, size);
+
+            let color = if !is_within_grid(coords, radius) {
+                OUTSIDE_GRID_COLOR
+            } else {
+                match tiles.get(&coords) {
+                    Some(tile) => {
+                        let (r, g, b) = colors
+                            .get(&tile.user_id)
+                            .copied()
+                            .unwrap_or_else(|| string_to_color(&tile.user_id));
+                        [r, g, b, 0xff]
+                    }
+                    None => EMPTY_TILE_COLOR,
+                }
+            };
+
+            pixels.extend_from_slice(&color);
+        }
+    }
+
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+
+    Ok(bytes)
+}
+
+/// Grid rendered by a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotKey {
+    pub round_id: u32,
+    /// Last change sequence of the current round, `None` for archived rounds which
+    /// do not change anymore
+    pub seq: Option<u64>,
+    pub hex_size: u32,
+}
+
+/// Last image rendered by the snapshot routes, requests for the same grid get it
+/// instead of rasterising the grid again. Renders run one at a time, concurrent
+/// requests wait for the image being rendered.
+#[derive(Default)]
+pub struct SnapshotCache {
+    last: Mutex<Option<(SnapshotKey, Bytes)>>,
+}
+
+impl SnapshotCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Image of `key`, the last one rendered if it has the same key or the one
+    /// returned by `render` which then replaces it
+    pub async fn get_or_render<F, Fut, E>(&self, key: SnapshotKey, render: F) -> Result<Bytes, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Bytes, E>>,
+    {
+        let mut last = self.last.lock().await;
+
+        if let Some((last_key, png)) = last.as_ref() {
+            if *last_key == key {
+                return Ok(png.clone());
+            }
+        }
+
+        let png = render().await?;
+        *last = Some((key, png.clone()));
+
+        Ok(png)
+    }
+}
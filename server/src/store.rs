@@ -1,12 +1,25 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        LazyLock,
+    },
+};
 
 use deadpool_redis::{Config, Runtime};
 
+#[cfg(feature = "test-utils")]
+use crate::test_utils::mocks::{MockRedisConnection, MockRedisHandler};
 use crate::{
-    config::GameConfig,
-    coords::AxialCoords,
-    game::InnerTileData,
-    user::{PublicUser, User},
+    config::{GameConfig, GridStorage},
+    coords::{cube_spiral, AxialCoords},
+    error::{GameError, GameResult},
+    game::{ClickOutcome, InnerTileData},
+    grid_state::GridState,
+    history::ClickEvent,
+    rounds::{Round, RoundResult},
+    rules::GameRules,
+    user::{hash_token, PublicUser, RankedUser, User},
 };
 
 /// Redis prefixes and keys
@@ -14,14 +27,70 @@ const USER_IDS_KEY: &str = "user_ids";
 
 const USER_PREFIX: &str = "user";
 
+/// Hash of user ids by username, enforces unique usernames
+const USERNAMES_KEY: &str = "usernames";
+
 const TILE_PREFIX: &str = "tile";
 
 const TOKEN_PREFIX: &str = "token";
 
+const COOLDOWN_PREFIX: &str = "cooldown";
+
 const TILE_INDEX: &str = "idx:tile";
 
-fn get_tile_key(coords: &AxialCoords) -> String {
-    format!("{}:{}", TILE_PREFIX, coords.as_redis_key())
+/// Prefix of the sorted set of the number of tiles owned by each user id in a round
+const TILE_SCORES_PREFIX: &str = "tile_scores";
+
+/// Prefix of the stream of every click that changed the grid of a round,
+/// in the order they were applied
+const CLICK_EVENTS_PREFIX: &str = "click_events";
+
+/// Number of events read at once from the click events stream
+const CLICK_EVENTS_PAGE_SIZE: usize = 1000;
+
+/// Hash of the current round, see `rounds::Round`
+const CURRENT_ROUND_KEY: &str = "round";
+
+/// Hash of the JSON encoded `RoundResult` of archived rounds by round id
+const ROUND_RESULTS_KEY: &str = "round_results";
+
+/// Prefix of the JSON encoded final tiles of archived rounds
+const ROUND_GRID_PREFIX: &str = "round_grid";
+
+/// Lua script resolving a click atomically, loaded at startup by `init_redis_scripts`
+static APPLY_CLICK_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(include_str!("scripts/apply_click.lua")));
+
+/// Lua script registering an user only if its username is free
+static ADD_USER_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(include_str!("scripts/add_user.lua")));
+
+/// Lua script starting the click cooldown of an user
+static CLICK_COOLDOWN_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(include_str!("scripts/click_cooldown.lua")));
+
+/// Lua script starting a round only if the current one did not change meanwhile
+static START_ROUND_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(include_str!("scripts/start_round.lua")));
+
+/// Lua script writing tiles and keeping the tile scores of the round in sync
+static SET_TILES_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(include_str!("scripts/set_tiles.lua")));
+
+fn get_tile_key(round: u32, coords: &AxialCoords) -> String {
+    format!("{}:{}:{}", TILE_PREFIX, round, coords.as_redis_key())
+}
+
+fn get_tile_scores_key(round: u32) -> String {
+    format!("{}:{}", TILE_SCORES_PREFIX, round)
+}
+
+fn get_click_events_key(round: u32) -> String {
+    format!("{}:{}", CLICK_EVENTS_PREFIX, round)
+}
+
+fn get_round_grid_key(round: u32) -> String {
+    format!("{}:{}", ROUND_GRID_PREFIX, round)
 }
 
 fn get_user_key(user: &User) -> String {
@@ -32,6 +101,10 @@ fn get_user_key_from_str(user_id: &str) -> String {
     format!("{}:{}", USER_PREFIX, user_id)
 }
 
+fn get_cooldown_key(user_id: &str) -> String {
+    format!("{}:{}", COOLDOWN_PREFIX, user_id)
+}
+
 fn get_token_key(user_id: &str) -> String {
     format!("{}:{}", TOKEN_PREFIX, user_id)
 }
@@ -54,23 +127,124 @@ fn parse_tile_hashmap(map: &HashMap<String, String>) -> redis::RedisResult<Optio
             redis::RedisError::from((redis::ErrorKind::TypeError, "Invalid damage value"))
         })?;
 
-    return Ok(Some(InnerTileData {
+    Ok(Some(InnerTileData {
         user_id: user_id.clone(),
         damage,
-    }));
+        team: map.get("team").and_then(|team| parse_team(team)),
+    }))
+}
+
+fn parse_click_event(map: &HashMap<String, String>) -> redis::RedisResult<ClickEvent> {
+    let field = |name: &str| {
+        map.get(name).ok_or_else(|| {
+            redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Missing click event field",
+                name.to_string(),
+            ))
+        })
+    };
+    let invalid = |name: &str| {
+        redis::RedisError::from((
+            redis::ErrorKind::TypeError,
+            "Invalid click event field",
+            name.to_string(),
+        ))
+    };
+
+    let previous_owner = field("previous_owner")?;
+
+    Ok(ClickEvent {
+        timestamp_ms: field("ts")?.parse().map_err(|_| invalid("ts"))?,
+        user_id: field("user_id")?.clone(),
+        // events recorded before teams existed have no team fields
+        team: map.get("team").and_then(|team| parse_team(team)),
+        coords: AxialCoords::new(
+            field("q")?.parse().map_err(|_| invalid("q"))?,
+            field("r")?.parse().map_err(|_| invalid("r"))?,
+        ),
+        action: field("action")?.parse().map_err(|_| invalid("action"))?,
+        previous_owner: (!previous_owner.is_empty()).then(|| previous_owner.clone()),
+        tile: InnerTileData {
+            user_id: field("owner")?.clone(),
+            damage: field("damage")?.parse().map_err(|_| invalid("damage"))?,
+            team: map.get("owner_team").and_then(|team| parse_team(team)),
+        },
+    })
+}
+
+fn parse_round(map: &HashMap<String, String>) -> redis::RedisResult<Option<Round>> {
+    if map.is_empty() {
+        return Ok(None);
+    }
+
+    let invalid = || redis::RedisError::from((redis::ErrorKind::TypeError, "Invalid round hash"));
+    let field = |name: &str| map.get(name).ok_or_else(invalid);
+
+    let ends_at_ms = field("ends_at_ms")?;
+
+    Ok(Some(Round {
+        id: field("id")?.parse().map_err(|_| invalid())?,
+        starts_at_ms: field("starts_at_ms")?.parse().map_err(|_| invalid())?,
+        ends_at_ms: match ends_at_ms.as_str() {
+            "" => None,
+            value => Some(value.parse().map_err(|_| invalid())?),
+        },
+    }))
+}
+
+fn json_error(e: serde_json::Error) -> redis::RedisError {
+    redis::RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Invalid JSON value",
+        e.to_string(),
+    ))
+}
+
+/// Teams are saved as empty strings when the tile has none
+fn parse_team(team: &str) -> Option<String> {
+    (!team.is_empty()).then(|| team.to_string())
+}
+
+fn parse_click_outcome(
+    (action, previous_owner, previous_team, user_id, damage, team): (
+        String,
+        String,
+        String,
+        String,
+        u8,
+        String,
+    ),
+) -> redis::RedisResult<ClickOutcome> {
+    let action = action.parse().map_err(|_| {
+        redis::RedisError::from((redis::ErrorKind::TypeError, "Invalid click action"))
+    })?;
+
+    Ok(ClickOutcome {
+        action,
+        previous_owner: (!previous_owner.is_empty()).then_some(previous_owner),
+        previous_team: parse_team(&previous_team),
+        tile: InnerTileData {
+            user_id,
+            damage,
+            team: parse_team(&team),
+        },
+    })
 }
 
 #[async_trait::async_trait]
 pub trait RedisHandler {
-    async fn flushdb(&self) -> redis::RedisResult<bool>;
+    async fn flushdb(&self) -> GameResult<bool>;
 
-    async fn count_tiles_by_user(&self, user_id: &str) -> redis::RedisResult<usize>;
+    async fn count_tiles_by_user<C>(&self, con: &mut C, user_id: &str) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send;
 
     async fn get_tile<C>(
         &self,
         con: &mut C,
         coords: &AxialCoords,
-    ) -> redis::RedisResult<Option<InnerTileData>>
+    ) -> GameResult<Option<InnerTileData>>
     where
         C: redis::aio::ConnectionLike + Send;
 
@@ -79,7 +253,7 @@ pub trait RedisHandler {
         con: &mut C,
         coords: &AxialCoords,
         data: InnerTileData,
-    ) -> redis::RedisResult<bool>
+    ) -> GameResult<bool>
     where
         C: redis::aio::ConnectionLike + Send;
 
@@ -87,15 +261,92 @@ pub trait RedisHandler {
         &self,
         con: &mut C,
         coords: Vec<AxialCoords>,
-    ) -> redis::RedisResult<Vec<(&AxialCoords, &InnerTileData)>>
+    ) -> GameResult<Vec<(AxialCoords, InnerTileData)>>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    async fn batch_set_tiles<C>(
+        &self,
+        con: &mut C,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Resolves a click on `coords` by `user_id` (damage, repair, capture or creation)
+    /// atomically, two concurrent clicks can never read the same tile state. Clicks
+    /// played for a `team` take tiles for it, see `GameRules::resolve_click`.
+    ///
+    /// Clicks that changed the tile are appended to the game history, see `get_click_events`.
+    async fn apply_click<C, G>(
+        &self,
+        con: &mut C,
+        coords: &AxialCoords,
+        user_id: &str,
+        team: Option<&str>,
+        rules: &G,
+    ) -> GameResult<ClickOutcome>
+    where
+        C: redis::aio::ConnectionLike + Send,
+        G: GameRules;
+
+    /// Appends clicks resolved elsewhere to the game history, in the given order
+    async fn append_click_events<C>(
+        &self,
+        con: &mut C,
+        events: Vec<ClickEvent>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Game history in the order clicks were applied, up to the first click after `until_ms`
+    async fn get_click_events<C>(&self, con: &mut C, until_ms: u64) -> GameResult<Vec<ClickEvent>>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Saves `user` with a session token expiring after `token_ttl` seconds,
+    /// returns false without saving anything if its username is already taken
+    async fn add_user<C>(&self, con: &mut C, user: User, token_ttl: u64) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    async fn get_user_by_id<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    async fn get_user_by_username<C>(
+        &self,
+        con: &mut C,
+        username: &str,
+    ) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Users of `user_ids` in the same order, None for unknown ids
+    async fn get_users_by_ids<C>(
+        &self,
+        con: &mut C,
+        user_ids: &[String],
+    ) -> GameResult<Vec<Option<User>>>
     where
         C: redis::aio::ConnectionLike + Send;
 
-    async fn add_user<C>(&self, con: &mut C, user: User) -> redis::RedisResult<bool>
+    async fn get_public_users<C>(&self, con: &mut C) -> GameResult<Vec<PublicUser>>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Users ranked by number of owned tiles, users without any tile are left out
+    async fn get_leaderboard<C>(
+        &self,
+        con: &mut C,
+        offset: usize,
+        limit: usize,
+    ) -> GameResult<Vec<RankedUser>>
     where
         C: redis::aio::ConnectionLike + Send;
 
-    async fn get_public_users<C>(&self, con: &mut C) -> redis::RedisResult<Vec<PublicUser>>
+    /// Rank of `user_id` in the leaderboard, None if the user does not own any tile
+    async fn get_user_rank<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<RankedUser>>
     where
         C: redis::aio::ConnectionLike + Send;
 
@@ -104,23 +355,117 @@ pub trait RedisHandler {
         con: &mut C,
         token: &str,
         user_id: &str,
-    ) -> redis::RedisResult<bool>
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Replaces the session token of `user_id`, the new token expires after `token_ttl` seconds
+    async fn set_user_token<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        token: &str,
+        token_ttl: u64,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Revokes the session token of `user_id`, returns false if there was none
+    async fn revoke_user_token<C>(&self, con: &mut C, user_id: &str) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Starts a `cooldown_ms` long click cooldown for `user_id`. Returns None if the
+    /// user could click, otherwise the milliseconds left on the running cooldown.
+    async fn start_click_cooldown<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        cooldown_ms: u64,
+    ) -> GameResult<Option<u64>>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Round currently played, None before the first round is started
+    async fn get_current_round<C>(&self, con: &mut C) -> GameResult<Option<Round>>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Makes `round` the current round if the current one is still `previous_id`
+    /// (0 before the first round), returns false if another worker started a round first.
+    ///
+    /// The store keeps playing its round until `use_round` is called.
+    async fn start_round<C>(
+        &self,
+        con: &mut C,
+        previous_id: u32,
+        round: &Round,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Switches tiles, tile scores and click events to those of `round_id`
+    async fn use_round<C>(&self, con: &mut C, round_id: u32) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Saves the final leaderboard and tiles of a round, returns false if it was already archived
+    async fn archive_round<C>(
+        &self,
+        con: &mut C,
+        result: &RoundResult,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Results of every archived round, latest first
+    async fn get_round_results<C>(&self, con: &mut C) -> GameResult<Vec<RoundResult>>
+    where
+        C: redis::aio::ConnectionLike + Send;
+
+    /// Final tiles of an archived round, None if the round is not archived
+    async fn get_round_grid<C>(
+        &self,
+        con: &mut C,
+        round_id: u32,
+    ) -> GameResult<Option<Vec<(AxialCoords, InnerTileData)>>>
     where
         C: redis::aio::ConnectionLike + Send;
 }
 
+/// Redis store of the game. Tiles, tile scores and click events of each round live
+/// under their own keys (`tile:{round}:{q}_{r}`), see `RedisHandler::use_round`.
+pub struct RedisStore {
+    client: redis::Client,
+    round: AtomicU32,
+}
+
+impl RedisStore {
+    /// Store playing no round until `use_round` is called
+    pub fn new(client: redis::Client) -> Self {
+        Self {
+            client,
+            round: AtomicU32::new(0),
+        }
+    }
+
+    pub fn client(&self) -> &redis::Client {
+        &self.client
+    }
+
+    /// Round whose tiles are read and written
+    pub fn round(&self) -> u32 {
+        self.round.load(Ordering::Relaxed)
+    }
+}
+
 #[async_trait::async_trait]
-impl RedisHandler for redis::Client {
-    async fn flushdb(&self) -> redis::RedisResult<bool> {
-        let mut con = self
-            .get_multiplexed_async_connection()
-            .await
-            .expect("Failed to create multiplexed async connection");
+impl RedisHandler for RedisStore {
+    async fn flushdb(&self) -> GameResult<bool> {
+        let mut con = self.client.get_multiplexed_async_connection().await?;
 
-        let _: () = redis::cmd("FLUSHDB")
-            .query_async(&mut con)
-            .await
-            .expect("Could not flush DB");
+        let _: () = redis::cmd("FLUSHDB").query_async(&mut con).await?;
         Ok(true)
     }
 
@@ -128,7 +473,7 @@ impl RedisHandler for redis::Client {
         &self,
         con: &mut C,
         coords: Vec<AxialCoords>,
-    ) -> redis::RedisResult<Vec<(&AxialCoords, &InnerTileData)>>
+    ) -> GameResult<Vec<(AxialCoords, InnerTileData)>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -138,57 +483,201 @@ impl RedisHandler for redis::Client {
 
         for c in coords.iter() {
             keys.push(c);
-            pipe.hgetall(get_tile_key(c));
+            pipe.hgetall(get_tile_key(self.round(), c));
         }
 
-        let query_res: Vec<HashMap<String, String>> =
-            pipe.query_async(con).await.unwrap_or(Vec::new());
+        let query_res: Vec<HashMap<String, String>> = pipe.query_async(con).await?;
 
         let mut res: Vec<(AxialCoords, InnerTileData)> = Vec::new();
-        let mut i = 0;
 
-        for hash in query_res.iter() {
-            let coord = *keys.get(i).unwrap();
-            match parse_tile_hashmap(hash) {
-                Ok(Some(tile)) => {
-                    res.push((coord.clone(), tile));
-                }
-                Ok(None) => {
-                    // do nothing
-                }
-                Err(_) => {
-                    // do nothing
-                }
+        for (coord, hash) in keys.into_iter().zip(query_res.iter()) {
+            if let Some(tile) = parse_tile_hashmap(hash)? {
+                res.push((*coord, tile));
             }
-            i += 1;
         }
 
         Ok(res)
     }
 
-    async fn count_tiles_by_user(&self, user_id: &str) -> Result<usize, redis::RedisError> {
-        // log::warn!("Not implemented count_tiles_by_user({user_id})");
+    async fn batch_set_tiles<C>(
+        &self,
+        con: &mut C,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        if tiles.is_empty() {
+            return Ok(true);
+        }
+
+        let round = self.round();
+        let mut invocation = SET_TILES_SCRIPT.prepare_invoke();
+        invocation.key(get_tile_scores_key(round));
+
+        for (coords, tile) in tiles {
+            invocation
+                .key(get_tile_key(round, &coords))
+                .arg(tile.user_id)
+                .arg(tile.damage)
+                .arg(tile.team.unwrap_or_default());
+        }
+
+        let _: usize = invocation.invoke_async(con).await?;
+
+        Ok(true)
+    }
+
+    async fn apply_click<C, G>(
+        &self,
+        con: &mut C,
+        coords: &AxialCoords,
+        user_id: &str,
+        team: Option<&str>,
+        rules: &G,
+    ) -> GameResult<ClickOutcome>
+    where
+        C: redis::aio::ConnectionLike + Send,
+        G: GameRules,
+    {
+        // the script is a port of the classic rules, it would ignore any other override
+        if !G::SCRIPTED {
+            return Err(GameError::InvalidInput(format!(
+                "Rules {rules:?} can't be played against redis"
+            )));
+        }
+
+        let round = self.round();
+        let mut invocation = APPLY_CLICK_SCRIPT.prepare_invoke();
+        invocation
+            .key(get_tile_scores_key(round))
+            .key(get_click_events_key(round))
+            .arg(user_id)
+            .arg(team.unwrap_or_default())
+            .arg(rules.influence_radius())
+            .arg(rules.max_strength())
+            .arg(rules.repair_rate());
+
+        // clicked tile comes first, see `scripts/apply_click.lua`
+        for c in cube_spiral(&coords.as_cube(), rules.influence_radius() as u32) {
+            let ac = c.as_axial();
+            invocation.key(get_tile_key(round, &ac)).arg(ac.q).arg(ac.r);
+        }
+
+        let res: (String, String, String, String, u8, String) =
+            invocation.invoke_async(con).await?;
+
+        Ok(parse_click_outcome(res)?)
+    }
+
+    async fn append_click_events<C>(&self, con: &mut C, events: Vec<ClickEvent>) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        if events.is_empty() {
+            return Ok(true);
+        }
+
+        // same fields as the events added by `scripts/apply_click.lua`
+        let events_key = get_click_events_key(self.round());
+        let mut pipe = redis::pipe();
+        for event in events {
+            pipe.cmd("XADD")
+                .arg(&events_key)
+                .arg("*")
+                .arg("ts")
+                .arg(event.timestamp_ms)
+                .arg("user_id")
+                .arg(event.user_id)
+                .arg("team")
+                .arg(event.team.unwrap_or_default())
+                .arg("q")
+                .arg(event.coords.q)
+                .arg("r")
+                .arg(event.coords.r)
+                .arg("action")
+                .arg(event.action.as_str())
+                .arg("previous_owner")
+                .arg(event.previous_owner.unwrap_or_default())
+                .arg("owner")
+                .arg(event.tile.user_id)
+                .arg("damage")
+                .arg(event.tile.damage)
+                .arg("owner_team")
+                .arg(event.tile.team.unwrap_or_default())
+                .ignore();
+        }
+
+        let _: () = pipe.query_async(con).await?;
+
+        Ok(true)
+    }
+
+    async fn get_click_events<C>(&self, con: &mut C, until_ms: u64) -> GameResult<Vec<ClickEvent>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let events_key = get_click_events_key(self.round());
+        let mut events = Vec::new();
+        let mut start = "-".to_string();
+
+        loop {
+            let page: Vec<(String, HashMap<String, String>)> = redis::cmd("XRANGE")
+                .arg(&events_key)
+                .arg(&start)
+                .arg("+")
+                .arg("COUNT")
+                .arg(CLICK_EVENTS_PAGE_SIZE)
+                .query_async(con)
+                .await?;
+
+            let page_len = page.len();
+
+            for (id, fields) in page {
+                let event = parse_click_event(&fields)?;
+
+                if event.timestamp_ms > until_ms {
+                    return Ok(events);
+                }
+
+                events.push(event);
+                start = format!("({id}");
+            }
+
+            if page_len < CLICK_EVENTS_PAGE_SIZE {
+                return Ok(events);
+            }
+        }
+    }
+
+    async fn count_tiles_by_user<C>(&self, con: &mut C, user_id: &str) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let score: Option<usize> = redis::Cmd::zscore(get_tile_scores_key(self.round()), user_id)
+            .query_async(con)
+            .await?;
 
-        Ok(0)
+        Ok(score.unwrap_or(0))
     }
 
     async fn get_tile<C>(
         &self,
         con: &mut C,
         coords: &AxialCoords,
-    ) -> Result<Option<InnerTileData>, redis::RedisError>
+    ) -> GameResult<Option<InnerTileData>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
-        let tile_k = get_tile_key(coords);
+        let tile_k = get_tile_key(self.round(), coords);
 
-        let res = match redis::Cmd::hgetall(tile_k).query_async(con).await {
-            Ok(Some(map)) => parse_tile_hashmap(&map).unwrap_or(None),
-            Ok(None) => return Ok(None),
-            Err(e) => return Err(e),
-        };
+        let map: Option<HashMap<String, String>> =
+            redis::Cmd::hgetall(tile_k).query_async(con).await?;
 
-        Ok(res)
+        match map {
+            Some(map) => Ok(parse_tile_hashmap(&map)?),
+            None => Ok(None),
+        }
     }
 
     async fn set_tile<C>(
@@ -196,61 +685,96 @@ impl RedisHandler for redis::Client {
         con: &mut C,
         coords: &AxialCoords,
         tile: InnerTileData,
-    ) -> redis::RedisResult<bool>
+    ) -> GameResult<bool>
     where
         C: redis::aio::ConnectionLike + Send,
     {
-        let key = get_tile_key(coords);
+        self.batch_set_tiles(con, vec![(*coords, tile)]).await
+    }
 
-        let () = redis::pipe()
-            .hset(key.clone(), "user_id", tile.user_id)
-            .hset(key.clone(), "damage", tile.damage)
-            .query_async(con)
+    async fn add_user<C>(&self, con: &mut C, user: User, token_ttl: u64) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let added: bool = ADD_USER_SCRIPT
+            .key(USERNAMES_KEY)
+            .key(get_user_key(&user))
+            .key(get_token_key(&user.id))
+            .key(USER_IDS_KEY)
+            .arg(&user.id)
+            .arg(&user.username)
+            .arg(hash_token(&user.token))
+            .arg(token_ttl)
+            .arg(&user)
+            .invoke_async(con)
             .await?;
 
-        Ok(true)
+        Ok(added)
     }
 
-    async fn add_user<C>(&self, con: &mut C, user: User) -> redis::RedisResult<bool>
+    async fn get_user_by_id<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<User>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
-        // println!("[RedisHandler.add_user] will add user into Redis DB");
-        let key = get_user_key(&user);
-        let mut pipe = redis::pipe();
+        let value: redis::Value = redis::Cmd::hgetall(get_user_key_from_str(user_id))
+            .query_async(con)
+            .await?;
 
-        let id = user.id.clone();
-        let username = user.username.clone();
-        let color = user.color.clone();
-        let token = user.token.clone();
+        let map: HashMap<String, String> = redis::from_redis_value(&value)?;
 
-        let token_key = get_token_key(&id);
-        // add token
-        pipe.set(token_key.clone(), &token);
+        if map.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(redis::from_redis_value(&value)?))
+    }
 
-        // println!(
-        //     "[redis::Client.add_user] Will set token ({}) at {})",
-        //     token,
-        //     &token_key.clone()
-        // );
+    async fn get_users_by_ids<C>(
+        &self,
+        con: &mut C,
+        user_ids: &[String],
+    ) -> GameResult<Vec<Option<User>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
 
-        pipe.cmd("hset").arg(&key).arg("id").arg(&id);
+        let mut pipe = redis::pipe();
+        for id in user_ids {
+            pipe.hgetall(get_user_key_from_str(id));
+        }
+        let values: Vec<redis::Value> = pipe.query_async(con).await?;
 
-        pipe.cmd("hset").arg(&key).arg("username").arg(&username);
+        let mut users = Vec::new();
+        for value in values.iter() {
+            let map: HashMap<String, String> = redis::from_redis_value(value)?;
 
-        pipe.cmd("hset").arg(&key).arg("color").arg(&color);
+            users.push(match map.is_empty() {
+                true => None,
+                false => Some(redis::from_redis_value(value)?),
+            });
+        }
 
-        pipe.cmd("hset").arg(&key).arg("token").arg(&token);
+        Ok(users)
+    }
 
-        let () = pipe
+    async fn get_user_by_username<C>(&self, con: &mut C, username: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let user_id: Option<String> = redis::Cmd::hget(USERNAMES_KEY, username)
             .query_async(con)
-            .await
-            .expect("Should be able to add user");
+            .await?;
 
-        Ok(true)
+        match user_id {
+            Some(id) => self.get_user_by_id(con, &id).await,
+            None => Ok(None),
+        }
     }
 
-    async fn get_public_users<C>(&self, con: &mut C) -> redis::RedisResult<Vec<PublicUser>>
+    async fn get_public_users<C>(&self, con: &mut C) -> GameResult<Vec<PublicUser>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -259,16 +783,12 @@ impl RedisHandler for redis::Client {
             .query_async(con)
             .await?;
 
+        let scores_key = get_tile_scores_key(self.round());
         let mut pipe = redis::pipe();
 
         for id in ids {
             pipe.hgetall(get_user_key_from_str(&id));
-            pipe.cmd("FT.SEARCH")
-                .arg(TILE_INDEX)
-                .arg(format!("\"@user_id:{id}\""))
-                .arg("LIMIT")
-                .arg(0)
-                .arg(0);
+            pipe.zscore(&scores_key, &id);
         }
 
         let mut results = Vec::new();
@@ -276,60 +796,881 @@ impl RedisHandler for redis::Client {
 
         let mut iter = pipe_res.iter();
 
-        while let (Some(hash_map_value), Some(nb_tiles_value)) = (iter.next(), iter.next()) {
-            let user: User = redis::from_redis_value(&hash_map_value)?;
+        while let (Some(hash_map_value), Some(score_value)) = (iter.next(), iter.next()) {
+            let user: User = redis::from_redis_value(hash_map_value)?;
 
-            let score: u32 = redis::from_redis_value(nb_tiles_value)?;
+            let score: Option<u32> = redis::from_redis_value(score_value)?;
 
-            results.push(PublicUser {
-                id: user.id,
-                color: user.color,
-                username: user.username,
-                score,
-            });
+            results.push(PublicUser::new(user, score.unwrap_or(0)));
         }
 
         Ok(results)
     }
 
-    async fn is_valid_token_for_user<C>(
+    async fn get_leaderboard<C>(
         &self,
         con: &mut C,
-        token: &str,
-        user_id: &str,
-    ) -> redis::RedisResult<bool>
+        offset: usize,
+        limit: usize,
+    ) -> GameResult<Vec<RankedUser>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
-        let token_key = get_token_key(user_id);
+        let scores: Vec<(String, u32)> = redis::cmd("ZREVRANGEBYSCORE")
+            .arg(get_tile_scores_key(self.round()))
+            .arg("+inf")
+            .arg(1)
+            .arg("WITHSCORES")
+            .arg("LIMIT")
+            .arg(offset)
+            .arg(limit)
+            .query_async(con)
+            .await?;
 
-        let r_token: Option<String> = redis::Cmd::get(token_key).query_async(con).await?;
+        if scores.is_empty() {
+            return Ok(Vec::new());
+        }
 
-        if let Some(t) = r_token {
-            return Ok(t == token.to_string());
-        } else {
-            return Ok(false);
+        let mut pipe = redis::pipe();
+        for (id, _) in scores.iter() {
+            pipe.hgetall(get_user_key_from_str(id));
         }
-    }
-}
+        let users: Vec<redis::Value> = pipe.query_async(con).await?;
 
-pub async fn has_index<C>(conn: &mut C, index_name: &str) -> redis::RedisResult<bool>
-where
-    C: redis::aio::ConnectionLike + Send,
-{
-    let indices: Vec<String> = redis::cmd("FT._LIST")
-        .query_async(conn)
-        .await
-        .expect("Unable to retrieve indices list");
+        let mut results = Vec::new();
 
-    Ok(indices.contains(&index_name.to_string()))
-}
+        for (i, ((_, score), user_value)) in scores.into_iter().zip(users.iter()).enumerate() {
+            // tiles of unknown users still take their place in the ranking
+            let Ok(user) = redis::from_redis_value::<User>(user_value) else {
+                continue;
+            };
 
-pub async fn init_redis_client(
-    app_config: &GameConfig,
-) -> redis::RedisResult<(redis::Client, deadpool_redis::Pool)> {
-    let client =
-        redis::Client::open(app_config.redis_url.clone()).expect("Failed to create redis client");
+            results.push(RankedUser {
+                rank: (offset + i) as u32 + 1,
+                user: PublicUser::new(user, score),
+            });
+        }
+
+        Ok(results)
+    }
+
+    async fn get_user_rank<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let scores_key = get_tile_scores_key(self.round());
+        let (rank, score, user_value): (Option<u32>, Option<u32>, redis::Value) = redis::pipe()
+            .zrevrank(&scores_key, user_id)
+            .zscore(&scores_key, user_id)
+            .hgetall(get_user_key_from_str(user_id))
+            .query_async(con)
+            .await?;
+
+        let (Some(rank), Some(score)) = (rank, score) else {
+            return Ok(None);
+        };
+
+        if score == 0 {
+            return Ok(None);
+        }
+
+        let Ok(user) = redis::from_redis_value::<User>(&user_value) else {
+            return Ok(None);
+        };
+
+        Ok(Some(RankedUser {
+            rank: rank + 1,
+            user: PublicUser::new(user, score),
+        }))
+    }
+
+    async fn is_valid_token_for_user<C>(
+        &self,
+        con: &mut C,
+        token: &str,
+        user_id: &str,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let token_key = get_token_key(user_id);
+
+        let r_token: Option<String> = redis::Cmd::get(token_key).query_async(con).await?;
+
+        if let Some(t) = r_token {
+            return Ok(t == hash_token(token));
+        } else {
+            return Ok(false);
+        }
+    }
+
+    async fn set_user_token<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        token: &str,
+        token_ttl: u64,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let _: () = redis::Cmd::set_ex(get_token_key(user_id), hash_token(token), token_ttl)
+            .query_async(con)
+            .await?;
+
+        Ok(true)
+    }
+
+    async fn revoke_user_token<C>(&self, con: &mut C, user_id: &str) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let deleted: usize = redis::Cmd::del(get_token_key(user_id))
+            .query_async(con)
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn start_click_cooldown<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        cooldown_ms: u64,
+    ) -> GameResult<Option<u64>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let remaining_ms: u64 = CLICK_COOLDOWN_SCRIPT
+            .key(get_cooldown_key(user_id))
+            .arg(cooldown_ms)
+            .invoke_async(con)
+            .await?;
+
+        Ok((remaining_ms > 0).then_some(remaining_ms))
+    }
+
+    async fn get_current_round<C>(&self, con: &mut C) -> GameResult<Option<Round>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let map: HashMap<String, String> = redis::Cmd::hgetall(CURRENT_ROUND_KEY)
+            .query_async(con)
+            .await?;
+
+        Ok(parse_round(&map)?)
+    }
+
+    async fn start_round<C>(&self, con: &mut C, previous_id: u32, round: &Round) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let started: bool = START_ROUND_SCRIPT
+            .key(CURRENT_ROUND_KEY)
+            .arg(previous_id)
+            .arg(round.id)
+            .arg(round.starts_at_ms)
+            .arg(round.ends_at_ms.map(|t| t.to_string()).unwrap_or_default())
+            .invoke_async(con)
+            .await?;
+
+        Ok(started)
+    }
+
+    async fn use_round<C>(&self, _con: &mut C, round_id: u32) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.round.store(round_id, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
+    async fn archive_round<C>(
+        &self,
+        con: &mut C,
+        result: &RoundResult,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let result_json = serde_json::to_string(result).map_err(json_error)?;
+        let tiles_json = serde_json::to_string(&tiles).map_err(json_error)?;
+
+        let (archived, _): (bool, bool) = redis::pipe()
+            .hset_nx(ROUND_RESULTS_KEY, result.round.id, result_json)
+            .set_nx(get_round_grid_key(result.round.id), tiles_json)
+            .query_async(con)
+            .await?;
+
+        Ok(archived)
+    }
+
+    async fn get_round_results<C>(&self, con: &mut C) -> GameResult<Vec<RoundResult>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let map: HashMap<u32, String> = redis::Cmd::hgetall(ROUND_RESULTS_KEY)
+            .query_async(con)
+            .await?;
+
+        let mut results = map
+            .values()
+            .map(|json| serde_json::from_str::<RoundResult>(json).map_err(json_error))
+            .collect::<redis::RedisResult<Vec<RoundResult>>>()?;
+
+        results.sort_by_key(|r| std::cmp::Reverse(r.round.id));
+
+        Ok(results)
+    }
+
+    async fn get_round_grid<C>(
+        &self,
+        con: &mut C,
+        round_id: u32,
+    ) -> GameResult<Option<Vec<(AxialCoords, InnerTileData)>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let json: Option<String> = redis::Cmd::get(get_round_grid_key(round_id))
+            .query_async(con)
+            .await?;
+
+        Ok(json
+            .map(|json| serde_json::from_str(&json).map_err(json_error))
+            .transpose()?)
+    }
+}
+
+/// Store used by the server, selected with `GameConfig::grid_storage`. Its
+/// `RedisHandler` methods run in debug spans named after them, whatever the backend.
+pub enum GameStore {
+    /// Every tile read and write goes to redis
+    Redis(RedisStore),
+    /// Tiles are served from memory and written behind to redis
+    InMemory(GridState<RedisStore>),
+    /// Everything is kept in memory, used to test the HTTP handlers without redis
+    #[cfg(feature = "test-utils")]
+    Mock(MockRedisHandler),
+}
+
+impl GameStore {
+    /// Connection to pass to the store methods, mock stores do not need the pool
+    pub async fn get_connection(
+        &self,
+        pool: &deadpool_redis::Pool,
+    ) -> Result<StoreConnection, deadpool_redis::PoolError> {
+        #[cfg(feature = "test-utils")]
+        if let GameStore::Mock(_) = self {
+            return Ok(StoreConnection {
+                inner: Connection::Mock(MockRedisConnection::new()),
+                round_trips: 0,
+            });
+        }
+
+        Ok(StoreConnection {
+            inner: Connection::Pooled(pool.get().await?),
+            round_trips: 0,
+        })
+    }
+}
+
+enum Connection {
+    Pooled(deadpool_redis::Connection),
+    #[cfg(feature = "test-utils")]
+    Mock(MockRedisConnection),
+}
+
+/// Connection returned by `GameStore::get_connection`, counting the round trips to redis
+pub struct StoreConnection {
+    inner: Connection,
+    round_trips: usize,
+}
+
+impl StoreConnection {
+    /// Commands and pipelines sent through this connection, each one is a round trip
+    pub fn round_trips(&self) -> usize {
+        self.round_trips
+    }
+}
+
+impl redis::aio::ConnectionLike for StoreConnection {
+    fn get_db(&self) -> i64 {
+        match &self.inner {
+            Connection::Pooled(conn) => conn.get_db(),
+            #[cfg(feature = "test-utils")]
+            Connection::Mock(conn) => conn.get_db(),
+        }
+    }
+
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        self.round_trips += 1;
+
+        match &mut self.inner {
+            Connection::Pooled(conn) => conn.req_packed_command(cmd),
+            #[cfg(feature = "test-utils")]
+            Connection::Mock(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        self.round_trips += 1;
+
+        match &mut self.inner {
+            Connection::Pooled(conn) => conn.req_packed_commands(cmd, offset, count),
+            #[cfg(feature = "test-utils")]
+            Connection::Mock(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RedisHandler for GameStore {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn flushdb(&self) -> GameResult<bool> {
+        match self {
+            GameStore::Redis(client) => client.flushdb().await,
+            GameStore::InMemory(state) => state.flushdb().await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.flushdb().await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(user_id = %user_id))]
+    async fn count_tiles_by_user<C>(&self, con: &mut C, user_id: &str) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.count_tiles_by_user(con, user_id).await,
+            GameStore::InMemory(state) => state.count_tiles_by_user(con, user_id).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.count_tiles_by_user(con, user_id).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(q = coords.q, r = coords.r))]
+    async fn get_tile<C>(
+        &self,
+        con: &mut C,
+        coords: &AxialCoords,
+    ) -> GameResult<Option<InnerTileData>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_tile(con, coords).await,
+            GameStore::InMemory(state) => state.get_tile(con, coords).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_tile(con, coords).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(q = coords.q, r = coords.r))]
+    async fn set_tile<C>(
+        &self,
+        con: &mut C,
+        coords: &AxialCoords,
+        data: InnerTileData,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.set_tile(con, coords, data).await,
+            GameStore::InMemory(state) => state.set_tile(con, coords, data).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.set_tile(con, coords, data).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(count = coords.len()))]
+    async fn batch_get_tiles<C>(
+        &self,
+        con: &mut C,
+        coords: Vec<AxialCoords>,
+    ) -> GameResult<Vec<(AxialCoords, InnerTileData)>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.batch_get_tiles(con, coords).await,
+            GameStore::InMemory(state) => state.batch_get_tiles(con, coords).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.batch_get_tiles(con, coords).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(count = tiles.len()))]
+    async fn batch_set_tiles<C>(
+        &self,
+        con: &mut C,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.batch_set_tiles(con, tiles).await,
+            GameStore::InMemory(state) => state.batch_set_tiles(con, tiles).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.batch_set_tiles(con, tiles).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(q = coords.q, r = coords.r, user_id = %user_id))]
+    async fn apply_click<C, G>(
+        &self,
+        con: &mut C,
+        coords: &AxialCoords,
+        user_id: &str,
+        team: Option<&str>,
+        rules: &G,
+    ) -> GameResult<ClickOutcome>
+    where
+        C: redis::aio::ConnectionLike + Send,
+        G: GameRules,
+    {
+        match self {
+            GameStore::Redis(client) => client.apply_click(con, coords, user_id, team, rules).await,
+            GameStore::InMemory(state) => {
+                state.apply_click(con, coords, user_id, team, rules).await
+            }
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.apply_click(con, coords, user_id, team, rules).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(count = events.len()))]
+    async fn append_click_events<C>(&self, con: &mut C, events: Vec<ClickEvent>) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.append_click_events(con, events).await,
+            GameStore::InMemory(state) => state.append_click_events(con, events).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.append_click_events(con, events).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(until_ms))]
+    async fn get_click_events<C>(&self, con: &mut C, until_ms: u64) -> GameResult<Vec<ClickEvent>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_click_events(con, until_ms).await,
+            GameStore::InMemory(state) => state.get_click_events(con, until_ms).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_click_events(con, until_ms).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(user_id = %user.id))]
+    async fn add_user<C>(&self, con: &mut C, user: User, token_ttl: u64) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.add_user(con, user, token_ttl).await,
+            GameStore::InMemory(state) => state.add_user(con, user, token_ttl).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.add_user(con, user, token_ttl).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(user_id = %user_id))]
+    async fn get_user_by_id<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_user_by_id(con, user_id).await,
+            GameStore::InMemory(state) => state.get_user_by_id(con, user_id).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_user_by_id(con, user_id).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(count = user_ids.len()))]
+    async fn get_users_by_ids<C>(
+        &self,
+        con: &mut C,
+        user_ids: &[String],
+    ) -> GameResult<Vec<Option<User>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_users_by_ids(con, user_ids).await,
+            GameStore::InMemory(state) => state.get_users_by_ids(con, user_ids).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_users_by_ids(con, user_ids).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(username = %username))]
+    async fn get_user_by_username<C>(&self, con: &mut C, username: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_user_by_username(con, username).await,
+            GameStore::InMemory(state) => state.get_user_by_username(con, username).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_user_by_username(con, username).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn get_public_users<C>(&self, con: &mut C) -> GameResult<Vec<PublicUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_public_users(con).await,
+            GameStore::InMemory(state) => state.get_public_users(con).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_public_users(con).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(offset, limit))]
+    async fn get_leaderboard<C>(
+        &self,
+        con: &mut C,
+        offset: usize,
+        limit: usize,
+    ) -> GameResult<Vec<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_leaderboard(con, offset, limit).await,
+            GameStore::InMemory(state) => state.get_leaderboard(con, offset, limit).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_leaderboard(con, offset, limit).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(user_id = %user_id))]
+    async fn get_user_rank<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_user_rank(con, user_id).await,
+            GameStore::InMemory(state) => state.get_user_rank(con, user_id).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_user_rank(con, user_id).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(user_id = %user_id))]
+    async fn is_valid_token_for_user<C>(
+        &self,
+        con: &mut C,
+        token: &str,
+        user_id: &str,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.is_valid_token_for_user(con, token, user_id).await,
+            GameStore::InMemory(state) => state.is_valid_token_for_user(con, token, user_id).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.is_valid_token_for_user(con, token, user_id).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(user_id = %user_id))]
+    async fn set_user_token<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        token: &str,
+        token_ttl: u64,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.set_user_token(con, user_id, token, token_ttl).await,
+            GameStore::InMemory(state) => {
+                state.set_user_token(con, user_id, token, token_ttl).await
+            }
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.set_user_token(con, user_id, token, token_ttl).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(user_id = %user_id))]
+    async fn revoke_user_token<C>(&self, con: &mut C, user_id: &str) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.revoke_user_token(con, user_id).await,
+            GameStore::InMemory(state) => state.revoke_user_token(con, user_id).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.revoke_user_token(con, user_id).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(user_id = %user_id))]
+    async fn start_click_cooldown<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        cooldown_ms: u64,
+    ) -> GameResult<Option<u64>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => {
+                client.start_click_cooldown(con, user_id, cooldown_ms).await
+            }
+            GameStore::InMemory(state) => {
+                state.start_click_cooldown(con, user_id, cooldown_ms).await
+            }
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.start_click_cooldown(con, user_id, cooldown_ms).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn get_current_round<C>(&self, con: &mut C) -> GameResult<Option<Round>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_current_round(con).await,
+            GameStore::InMemory(state) => state.get_current_round(con).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_current_round(con).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(previous_id, round_id = round.id))]
+    async fn start_round<C>(&self, con: &mut C, previous_id: u32, round: &Round) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.start_round(con, previous_id, round).await,
+            GameStore::InMemory(state) => state.start_round(con, previous_id, round).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.start_round(con, previous_id, round).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(round_id))]
+    async fn use_round<C>(&self, con: &mut C, round_id: u32) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.use_round(con, round_id).await,
+            GameStore::InMemory(state) => state.use_round(con, round_id).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.use_round(con, round_id).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(round_id = result.round.id))]
+    async fn archive_round<C>(
+        &self,
+        con: &mut C,
+        result: &RoundResult,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.archive_round(con, result, tiles).await,
+            GameStore::InMemory(state) => state.archive_round(con, result, tiles).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.archive_round(con, result, tiles).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn get_round_results<C>(&self, con: &mut C) -> GameResult<Vec<RoundResult>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_round_results(con).await,
+            GameStore::InMemory(state) => state.get_round_results(con).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_round_results(con).await,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(round_id))]
+    async fn get_round_grid<C>(
+        &self,
+        con: &mut C,
+        round_id: u32,
+    ) -> GameResult<Option<Vec<(AxialCoords, InnerTileData)>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            GameStore::Redis(client) => client.get_round_grid(con, round_id).await,
+            GameStore::InMemory(state) => state.get_round_grid(con, round_id).await,
+            #[cfg(feature = "test-utils")]
+            GameStore::Mock(mock) => mock.get_round_grid(con, round_id).await,
+        }
+    }
+}
+
+/// Creates the store selected in `app_config`, it plays no round until
+/// `RedisHandler::use_round` is called (see `rounds::init_round`)
+pub fn init_game_store(client: redis::Client, app_config: &GameConfig) -> GameStore {
+    let store = RedisStore::new(client);
+
+    match app_config.grid_storage {
+        GridStorage::Redis => GameStore::Redis(store),
+        GridStorage::InMemory => GameStore::InMemory(GridState::new(store, app_config.grid_radius)),
+    }
+}
+
+async fn scan_keys<C>(conn: &mut C, pattern: &str) -> redis::RedisResult<Vec<String>>
+where
+    C: redis::aio::ConnectionLike + Send,
+{
+    let mut keys = Vec::new();
+    let mut cursor = 0;
+
+    loop {
+        let (next_cursor, mut page): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(1000)
+            .query_async(conn)
+            .await?;
+
+        keys.append(&mut page);
+
+        if next_cursor == 0 {
+            return Ok(keys);
+        }
+        cursor = next_cursor;
+    }
+}
+
+/// Moves the tiles, tile scores and click events saved before rounds existed
+/// (`tile:{q}_{r}`) to the first round (`tile:1:{q}_{r}`)
+pub async fn migrate_to_rounds<C>(conn: &mut C) -> redis::RedisResult<bool>
+where
+    C: redis::aio::ConnectionLike + Send,
+{
+    let has_round: bool = redis::Cmd::exists(CURRENT_ROUND_KEY)
+        .query_async(conn)
+        .await?;
+
+    if has_round {
+        return Ok(false);
+    }
+
+    let mut pipe = redis::pipe();
+
+    for key in scan_keys(conn, &format!("{}:*", TILE_PREFIX)).await? {
+        let coords = &key[TILE_PREFIX.len() + 1..];
+
+        // keys of a round have one more segment
+        if !coords.contains(':') {
+            pipe.rename(&key, format!("{}:1:{}", TILE_PREFIX, coords))
+                .ignore();
+        }
+    }
+
+    for (legacy_key, key) in [
+        (TILE_SCORES_PREFIX, get_tile_scores_key(1)),
+        (CLICK_EVENTS_PREFIX, get_click_events_key(1)),
+    ] {
+        let exists: bool = redis::Cmd::exists(legacy_key).query_async(conn).await?;
+        if exists {
+            pipe.rename(legacy_key, key).ignore();
+        }
+    }
+
+    let () = pipe.query_async(conn).await?;
+
+    Ok(true)
+}
+
+/// Rebuilds the tile scores of `round` from its saved tiles, needed for grids saved
+/// before tile counts were maintained
+pub async fn init_tile_scores<C>(conn: &mut C, round: u32) -> redis::RedisResult<bool>
+where
+    C: redis::aio::ConnectionLike + Send,
+{
+    let scores_key = get_tile_scores_key(round);
+    let has_scores: bool = redis::Cmd::exists(&scores_key).query_async(conn).await?;
+
+    if has_scores {
+        return Ok(false);
+    }
+
+    let tile_keys = scan_keys(conn, &format!("{}:{}:*", TILE_PREFIX, round)).await?;
+
+    if tile_keys.is_empty() {
+        return Ok(false);
+    }
+
+    let mut pipe = redis::pipe();
+    for key in tile_keys.iter() {
+        pipe.hget(key, "user_id");
+    }
+    let owners: Vec<Option<String>> = pipe.query_async(conn).await?;
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for owner in owners.into_iter().flatten() {
+        *counts.entry(owner).or_default() += 1;
+    }
+
+    let members: Vec<(usize, String)> = counts.into_iter().map(|(id, c)| (c, id)).collect();
+    let () = redis::Cmd::zadd_multiple(&scores_key, &members)
+        .query_async(conn)
+        .await?;
+
+    Ok(true)
+}
+
+pub async fn has_index<C>(conn: &mut C, index_name: &str) -> redis::RedisResult<bool>
+where
+    C: redis::aio::ConnectionLike + Send,
+{
+    let indices: Vec<String> = redis::cmd("FT._LIST")
+        .query_async(conn)
+        .await
+        .expect("Unable to retrieve indices list");
+
+    Ok(indices.contains(&index_name.to_string()))
+}
+
+pub async fn init_redis_client(
+    app_config: &GameConfig,
+) -> redis::RedisResult<(redis::Client, deadpool_redis::Pool)> {
+    let client =
+        redis::Client::open(app_config.redis_url.clone()).expect("Failed to create redis client");
 
     let cfg = Config::from_url(app_config.redis_url.clone());
     let pool = cfg.create_pool(Some(Runtime::Tokio1)).unwrap();
@@ -337,15 +1678,33 @@ pub async fn init_redis_client(
     Ok((client, pool))
 }
 
+/// Registers Lua scripts so that later calls only need `EVALSHA`
+pub async fn init_redis_scripts<C>(conn: &mut C) -> redis::RedisResult<bool>
+where
+    C: redis::aio::ConnectionLike + Send,
+{
+    for script in [
+        &APPLY_CLICK_SCRIPT,
+        &SET_TILES_SCRIPT,
+        &ADD_USER_SCRIPT,
+        &CLICK_COOLDOWN_SCRIPT,
+        &START_ROUND_SCRIPT,
+    ] {
+        let _: String = script.prepare_invoke().load_async(conn).await?;
+    }
+
+    Ok(true)
+}
+
 pub async fn init_redis_indices<C>(conn: &mut C) -> redis::RedisResult<bool>
 where
     C: redis::aio::ConnectionLike + Send,
 {
-    let has_tile_index = has_index(conn, "idx:tile").await.unwrap();
+    let has_tile_index = has_index(conn, TILE_INDEX).await.unwrap();
 
     if has_tile_index {
         let () = redis::cmd("FT.DROPINDEX")
-            .arg("idx:tile")
+            .arg(TILE_INDEX)
             .arg("DD")
             .query_async(conn)
             .await
@@ -354,7 +1713,7 @@ where
 
     // create indices
     let () = redis::cmd("FT.CREATE")
-        .arg("idx:tile")
+        .arg(TILE_INDEX)
         .arg("ON")
         .arg("HASH")
         .arg("PREFIX")
@@ -365,6 +1724,8 @@ where
         .arg("TAG")
         .arg("damage")
         .arg("NUMERIC")
+        .arg("team")
+        .arg("TAG")
         .query_async(conn)
         .await?;
 
@@ -0,0 +1,113 @@
+//! Logs and traces of the server.
+//!
+//! Events, including the ones of the `log` macros, are written to stdout as text or
+//! JSON lines (`GameConfig::log_format`), filtered by `RUST_LOG` (`info` by default).
+//! Requests, game logic and store calls run in `tracing` spans, so JSON lines tell
+//! which click or batch an event belongs to.
+//!
+//! With the `otlp` feature and `GameConfig::otlp_endpoint` set, spans of this crate
+//! down to the debug level are also exported to an OTLP collector over gRPC, e.g.
+//! `http://localhost:4317`, to see where the time of a single click goes.
+
+use tracing_subscriber::{
+    layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+use crate::config::{GameConfig, LogFormat};
+
+/// Installed subscriber, `shutdown` flushes the spans not exported yet
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Telemetry {
+    /// Installs the global subscriber, must be called once from a tokio runtime
+    pub fn init(app_config: &GameConfig) -> Self {
+        let filter =
+            || EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+        let stdout = match app_config.log_format {
+            LogFormat::Text => tracing_subscriber::fmt::layer()
+                .with_filter(filter())
+                .boxed(),
+            LogFormat::Json => tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_filter(filter())
+                .boxed(),
+        };
+
+        #[cfg(feature = "otlp")]
+        {
+            let provider = app_config.otlp_endpoint.as_deref().map(otlp_provider);
+            let (provider, error) = match provider {
+                Some(Ok(provider)) => (Some(provider), None),
+                Some(Err(e)) => (None, Some(e)),
+                None => (None, None),
+            };
+
+            let traces = provider.as_ref().map(|provider| {
+                use opentelemetry::trace::TracerProvider as _;
+
+                tracing_opentelemetry::layer()
+                    .with_tracer(provider.tracer("pixelstratwar"))
+                    .with_filter(EnvFilter::new("pixelstratwar=debug"))
+            });
+
+            Registry::default().with(stdout).with(traces).init();
+
+            if let Some(endpoint) = &app_config.otlp_endpoint {
+                match error {
+                    Some(e) => log::error!("Could not export traces to {endpoint}: {e}"),
+                    None => log::info!("Exporting traces to {endpoint}"),
+                }
+            }
+
+            Self { provider }
+        }
+
+        #[cfg(not(feature = "otlp"))]
+        {
+            Registry::default().with(stdout).init();
+
+            if app_config.otlp_endpoint.is_some() {
+                log::warn!("OTLP_ENDPOINT is ignored, build the server with the `otlp` feature");
+            }
+
+            Self {}
+        }
+    }
+
+    /// Exports the remaining spans, to call before exiting
+    pub fn shutdown(self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                log::error!("Could not export the last traces: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_provider(endpoint: &str) -> Result<opentelemetry_sdk::trace::TracerProvider, String> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let resource = opentelemetry_sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+        "service.name",
+        "pixelstratwar",
+    )]);
+
+    Ok(opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(resource)
+        .build())
+}
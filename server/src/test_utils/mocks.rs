@@ -1,22 +1,31 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_trait;
 
 use crate::{
     config::GameConfig,
     coords::AxialCoords,
-    game::InnerTileData,
-    store::{self, RedisHandler},
-    user::{PublicUser, User},
+    error::GameResult,
+    game::{ClickAction, ClickOutcome, InnerTileData},
+    history::{now_ms, ClickEvent},
+    rounds::{Round, RoundResult},
+    rules::GameRules,
+    store::{self, RedisHandler, RedisStore},
+    user::{hash_token, rank_public_users, PublicUser, RankedUser, User},
 };
 use redis;
 
 use tokio::sync::RwLock;
 
+#[derive(Default)]
 pub struct MockRedisConnection;
 
 impl MockRedisConnection {
-    fn new() -> Self {
+    pub fn new() -> Self {
         MockRedisConnection
     }
 }
@@ -48,10 +57,39 @@ impl redis::aio::ConnectionLike for MockRedisConnection {
     }
 }
 
+/// Session token lifetime used by tests adding users
+pub const TOKEN_TTL: u64 = 60 * 60;
+
+fn mock_token(token: &str, token_ttl: u64) -> (String, Instant) {
+    (
+        hash_token(token),
+        Instant::now() + Duration::from_secs(token_ttl),
+    )
+}
+
+/// Result and final tiles of an archived round
+pub type RoundArchive = (RoundResult, Vec<(AxialCoords, InnerTileData)>);
+
 pub struct MockRedisHandler {
-    pub mock_tokens: Arc<RwLock<HashMap<String, String>>>,
+    /// Token hash and expiry by user id
+    pub mock_tokens: Arc<RwLock<HashMap<String, (String, Instant)>>>,
     pub mock_users: Arc<RwLock<HashMap<String, User>>>,
     pub mock_grid: Arc<RwLock<HashMap<AxialCoords, InnerTileData>>>,
+    /// End of the click cooldown by user id
+    pub mock_cooldowns: Arc<RwLock<HashMap<String, Instant>>>,
+    /// Game history in the order clicks were applied
+    pub mock_events: Arc<RwLock<Vec<ClickEvent>>>,
+    pub mock_current_round: Arc<RwLock<Option<Round>>>,
+    /// Round whose grid and events are kept, see `use_round`
+    pub mock_played_round: Arc<RwLock<u32>>,
+    /// Result and final tiles of archived rounds by round id
+    pub mock_round_archives: Arc<RwLock<HashMap<u32, RoundArchive>>>,
+}
+
+impl Default for MockRedisHandler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MockRedisHandler {
@@ -60,21 +98,36 @@ impl MockRedisHandler {
             mock_tokens: Arc::new(RwLock::new(HashMap::new())),
             mock_users: Arc::new(RwLock::new(HashMap::new())),
             mock_grid: Arc::new(RwLock::new(HashMap::new())),
+            mock_cooldowns: Arc::new(RwLock::new(HashMap::new())),
+            mock_events: Arc::new(RwLock::new(Vec::new())),
+            mock_current_round: Arc::new(RwLock::new(None)),
+            mock_played_round: Arc::new(RwLock::new(0)),
+            mock_round_archives: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 }
 
 #[async_trait::async_trait]
 impl RedisHandler for MockRedisHandler {
-    async fn flushdb(&self) -> redis::RedisResult<bool> {
+    async fn flushdb(&self) -> GameResult<bool> {
         let mut write = self.mock_grid.write().await;
 
         write.clear();
+        self.mock_users.write().await.clear();
+        self.mock_tokens.write().await.clear();
+        self.mock_cooldowns.write().await.clear();
+        self.mock_events.write().await.clear();
+        *self.mock_current_round.write().await = None;
+        *self.mock_played_round.write().await = 0;
+        self.mock_round_archives.write().await.clear();
 
         Ok(true)
     }
 
-    async fn count_tiles_by_user(&self, user_id: &str) -> redis::RedisResult<usize> {
+    async fn count_tiles_by_user<C>(&self, _con: &mut C, user_id: &str) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
         let read = self.mock_grid.read().await;
 
         let mut count = 0;
@@ -92,7 +145,7 @@ impl RedisHandler for MockRedisHandler {
         &self,
         _reuse_con: &mut C,
         coords: Vec<AxialCoords>,
-    ) -> redis::RedisResult<Vec<(AxialCoords, InnerTileData)>>
+    ) -> GameResult<Vec<(AxialCoords, InnerTileData)>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -101,7 +154,7 @@ impl RedisHandler for MockRedisHandler {
         for c in coords.iter() {
             match read.get(c) {
                 Some(t) => {
-                    results.push((c.clone(), t.clone()));
+                    results.push((*c, t.clone()));
                 }
                 None => {
                     // do nothing
@@ -112,16 +165,79 @@ impl RedisHandler for MockRedisHandler {
         Ok(results)
     }
 
+    async fn batch_set_tiles<C>(
+        &self,
+        _c: &mut C,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let mut write = self.mock_grid.write().await;
+        write.extend(tiles);
+        Ok(true)
+    }
+
+    async fn apply_click<C, G>(
+        &self,
+        _c: &mut C,
+        coords: &AxialCoords,
+        user_id: &str,
+        team: Option<&str>,
+        rules: &G,
+    ) -> GameResult<ClickOutcome>
+    where
+        C: redis::aio::ConnectionLike + Send,
+        G: GameRules,
+    {
+        // holding the write lock for the whole resolution mimics the atomicity of the lua script
+        let mut write = self.mock_grid.write().await;
+        let outcome = rules.resolve_click(&write, coords, user_id, team);
+
+        if outcome.action != ClickAction::None {
+            write.insert(*coords, outcome.tile.clone());
+            self.mock_events.write().await.push(ClickEvent::new(
+                now_ms(),
+                coords,
+                user_id,
+                team,
+                &outcome,
+            ));
+        }
+
+        Ok(outcome)
+    }
+
+    async fn append_click_events<C>(&self, _c: &mut C, events: Vec<ClickEvent>) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.mock_events.write().await.extend(events);
+        Ok(true)
+    }
+
+    async fn get_click_events<C>(&self, _c: &mut C, until_ms: u64) -> GameResult<Vec<ClickEvent>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let read = self.mock_events.read().await;
+        Ok(read
+            .iter()
+            .take_while(|e| e.timestamp_ms <= until_ms)
+            .cloned()
+            .collect())
+    }
+
     async fn get_tile<C>(
         &self,
         _c: &mut C,
         coords: &AxialCoords,
-    ) -> redis::RedisResult<Option<InnerTileData>>
+    ) -> GameResult<Option<InnerTileData>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
         let read = self.mock_grid.read().await;
-        Ok(read.get(&coords).cloned())
+        Ok(read.get(coords).cloned())
     }
 
     async fn set_tile<C>(
@@ -129,29 +245,71 @@ impl RedisHandler for MockRedisHandler {
         _c: &mut C,
         coords: &AxialCoords,
         tile: InnerTileData,
-    ) -> redis::RedisResult<bool>
+    ) -> GameResult<bool>
     where
         C: redis::aio::ConnectionLike + Send,
     {
         let mut write = self.mock_grid.write().await;
-        write.insert(coords.clone(), tile);
+        write.insert(*coords, tile);
         Ok(true)
     }
 
-    async fn add_user<C>(&self, _con: &mut C, user: User) -> redis::RedisResult<bool>
+    async fn add_user<C>(&self, _con: &mut C, user: User, token_ttl: u64) -> GameResult<bool>
     where
         C: redis::aio::ConnectionLike + Send,
     {
         let mut w_users = self.mock_users.write().await;
         let mut w_tokens = self.mock_tokens.write().await;
 
-        w_tokens.insert(user.id.clone(), user.token.clone());
-        w_users.insert(user.id.clone(), user.clone());
+        if w_users.values().any(|u| u.username == user.username) {
+            return Ok(false);
+        }
+
+        w_tokens.insert(user.id.clone(), mock_token(&user.token, token_ttl));
+        w_users.insert(user.id.clone(), user.without_token());
 
         Ok(true)
     }
 
-    async fn get_public_users<C>(&self, _con: &mut C) -> redis::RedisResult<Vec<PublicUser>>
+    async fn get_user_by_id<C>(&self, _con: &mut C, user_id: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let read_users = self.mock_users.read().await;
+        Ok(read_users.get(user_id).cloned())
+    }
+
+    async fn get_users_by_ids<C>(
+        &self,
+        _con: &mut C,
+        user_ids: &[String],
+    ) -> GameResult<Vec<Option<User>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let read_users = self.mock_users.read().await;
+        Ok(user_ids
+            .iter()
+            .map(|id| read_users.get(id).cloned())
+            .collect())
+    }
+
+    async fn get_user_by_username<C>(
+        &self,
+        _con: &mut C,
+        username: &str,
+    ) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let read_users = self.mock_users.read().await;
+        Ok(read_users
+            .values()
+            .find(|u| u.username == username)
+            .cloned())
+    }
+
+    async fn get_public_users<C>(&self, _con: &mut C) -> GameResult<Vec<PublicUser>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -166,44 +324,215 @@ impl RedisHandler for MockRedisHandler {
                 .filter(|(_, t)| t.user_id == user.id)
                 .count();
 
-            res.push(PublicUser {
-                color: user.color.clone(),
-                id: user.id.clone(),
-                username: user.username.clone(),
-                score: user_tiles as u32,
-            })
+            res.push(PublicUser::new(user.clone(), user_tiles as u32))
         }
 
         Ok(res)
     }
 
+    async fn get_leaderboard<C>(
+        &self,
+        con: &mut C,
+        offset: usize,
+        limit: usize,
+    ) -> GameResult<Vec<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let users = self.get_public_users(con).await?;
+
+        Ok(rank_public_users(users)
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect())
+    }
+
+    async fn get_user_rank<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let users = self.get_public_users(con).await?;
+
+        Ok(rank_public_users(users)
+            .into_iter()
+            .find(|r| r.user.id == user_id))
+    }
+
     async fn is_valid_token_for_user<C>(
         &self,
         _con: &mut C,
         token: &str,
         user_id: &str,
-    ) -> redis::RedisResult<bool>
+    ) -> GameResult<bool>
     where
         C: redis::aio::ConnectionLike + Send,
     {
         let r_tokens = self.mock_tokens.read().await;
 
         match r_tokens.get(user_id) {
-            Some(t) => Ok(t == token),
+            Some((hash, expires_at)) => {
+                Ok(*hash == hash_token(token) && Instant::now() < *expires_at)
+            }
             None => Ok(false),
         }
     }
+
+    async fn set_user_token<C>(
+        &self,
+        _con: &mut C,
+        user_id: &str,
+        token: &str,
+        token_ttl: u64,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        self.mock_tokens
+            .write()
+            .await
+            .insert(user_id.to_string(), mock_token(token, token_ttl));
+
+        Ok(true)
+    }
+
+    async fn revoke_user_token<C>(&self, _con: &mut C, user_id: &str) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        Ok(self.mock_tokens.write().await.remove(user_id).is_some())
+    }
+
+    async fn start_click_cooldown<C>(
+        &self,
+        _con: &mut C,
+        user_id: &str,
+        cooldown_ms: u64,
+    ) -> GameResult<Option<u64>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let mut w_cooldowns = self.mock_cooldowns.write().await;
+        let now = Instant::now();
+
+        if let Some(ends_at) = w_cooldowns.get(user_id) {
+            if *ends_at > now {
+                let remaining_ms = (*ends_at - now).as_millis() as u64;
+                return Ok(Some(remaining_ms.max(1)));
+            }
+        }
+
+        w_cooldowns.insert(
+            user_id.to_string(),
+            now + Duration::from_millis(cooldown_ms),
+        );
+
+        Ok(None)
+    }
+
+    async fn get_current_round<C>(&self, _con: &mut C) -> GameResult<Option<Round>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        Ok(self.mock_current_round.read().await.clone())
+    }
+
+    async fn start_round<C>(
+        &self,
+        _con: &mut C,
+        previous_id: u32,
+        round: &Round,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let mut current = self.mock_current_round.write().await;
+
+        if current.as_ref().map_or(0, |r| r.id) != previous_id {
+            return Ok(false);
+        }
+
+        *current = Some(round.clone());
+        Ok(true)
+    }
+
+    async fn use_round<C>(&self, _con: &mut C, round_id: u32) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let mut played = self.mock_played_round.write().await;
+
+        // only the grid of the played round is kept
+        if *played != round_id {
+            self.mock_grid.write().await.clear();
+            self.mock_events.write().await.clear();
+            *played = round_id;
+        }
+
+        Ok(true)
+    }
+
+    async fn archive_round<C>(
+        &self,
+        _con: &mut C,
+        result: &RoundResult,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let mut archives = self.mock_round_archives.write().await;
+
+        if archives.contains_key(&result.round.id) {
+            return Ok(false);
+        }
+
+        archives.insert(result.round.id, (result.clone(), tiles));
+        Ok(true)
+    }
+
+    async fn get_round_results<C>(&self, _con: &mut C) -> GameResult<Vec<RoundResult>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        let mut results: Vec<RoundResult> = self
+            .mock_round_archives
+            .read()
+            .await
+            .values()
+            .map(|(result, _)| result.clone())
+            .collect();
+
+        results.sort_by_key(|r| std::cmp::Reverse(r.round.id));
+        Ok(results)
+    }
+
+    async fn get_round_grid<C>(
+        &self,
+        _con: &mut C,
+        round_id: u32,
+    ) -> GameResult<Option<Vec<(AxialCoords, InnerTileData)>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        Ok(self
+            .mock_round_archives
+            .read()
+            .await
+            .get(&round_id)
+            .map(|(_, tiles)| tiles.clone()))
+    }
 }
 
 // Define the `RedisClient` enum
 pub enum TestRedisClient {
-    Real(redis::Client),
+    Real(RedisStore),
     Mock(MockRedisHandler),
 }
 
 #[async_trait::async_trait]
 impl RedisHandler for TestRedisClient {
-    async fn flushdb(&self) -> redis::RedisResult<bool> {
+    async fn flushdb(&self) -> GameResult<bool> {
         let res = match self {
             TestRedisClient::Real(client) => client.flushdb(),
             TestRedisClient::Mock(mock) => mock.flushdb(),
@@ -212,17 +541,20 @@ impl RedisHandler for TestRedisClient {
         res.await
     }
 
-    async fn count_tiles_by_user(&self, user_id: &str) -> redis::RedisResult<usize> {
+    async fn count_tiles_by_user<C>(&self, con: &mut C, user_id: &str) -> GameResult<usize>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
         match self {
-            TestRedisClient::Real(client) => client.count_tiles_by_user(user_id).await,
-            TestRedisClient::Mock(mock) => mock.count_tiles_by_user(user_id).await,
+            TestRedisClient::Real(client) => client.count_tiles_by_user(con, user_id).await,
+            TestRedisClient::Mock(mock) => mock.count_tiles_by_user(con, user_id).await,
         }
     }
     async fn batch_get_tiles<C>(
         &self,
         con: &mut C,
         coords: Vec<AxialCoords>,
-    ) -> redis::RedisResult<Vec<(AxialCoords, InnerTileData)>>
+    ) -> GameResult<Vec<(AxialCoords, InnerTileData)>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -232,11 +564,67 @@ impl RedisHandler for TestRedisClient {
         }
     }
 
+    async fn batch_set_tiles<C>(
+        &self,
+        con: &mut C,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.batch_set_tiles(con, tiles).await,
+            TestRedisClient::Mock(mock) => mock.batch_set_tiles(con, tiles).await,
+        }
+    }
+
+    async fn apply_click<C, G>(
+        &self,
+        con: &mut C,
+        coords: &AxialCoords,
+        user_id: &str,
+        team: Option<&str>,
+        rules: &G,
+    ) -> GameResult<ClickOutcome>
+    where
+        C: redis::aio::ConnectionLike + Send,
+        G: GameRules,
+    {
+        match self {
+            TestRedisClient::Real(client) => {
+                client.apply_click(con, coords, user_id, team, rules).await
+            }
+            TestRedisClient::Mock(mock) => {
+                mock.apply_click(con, coords, user_id, team, rules).await
+            }
+        }
+    }
+
+    async fn append_click_events<C>(&self, con: &mut C, events: Vec<ClickEvent>) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.append_click_events(con, events).await,
+            TestRedisClient::Mock(mock) => mock.append_click_events(con, events).await,
+        }
+    }
+
+    async fn get_click_events<C>(&self, con: &mut C, until_ms: u64) -> GameResult<Vec<ClickEvent>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_click_events(con, until_ms).await,
+            TestRedisClient::Mock(mock) => mock.get_click_events(con, until_ms).await,
+        }
+    }
+
     async fn get_tile<C>(
         &self,
         con: &mut C,
         coords: &AxialCoords,
-    ) -> redis::RedisResult<Option<InnerTileData>>
+    ) -> GameResult<Option<InnerTileData>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -251,7 +639,7 @@ impl RedisHandler for TestRedisClient {
         con: &mut C,
         coords: &AxialCoords,
         tile: InnerTileData,
-    ) -> redis::RedisResult<bool>
+    ) -> GameResult<bool>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -261,17 +649,51 @@ impl RedisHandler for TestRedisClient {
         }
     }
 
-    async fn add_user<C>(&self, con: &mut C, user: User) -> redis::RedisResult<bool>
+    async fn add_user<C>(&self, con: &mut C, user: User, token_ttl: u64) -> GameResult<bool>
     where
         C: redis::aio::ConnectionLike + Send,
     {
         match self {
-            TestRedisClient::Real(client) => client.add_user(con, user).await,
-            TestRedisClient::Mock(mock) => mock.add_user(con, user).await,
+            TestRedisClient::Real(client) => client.add_user(con, user, token_ttl).await,
+            TestRedisClient::Mock(mock) => mock.add_user(con, user, token_ttl).await,
         }
     }
 
-    async fn get_public_users<C>(&self, con: &mut C) -> redis::RedisResult<Vec<PublicUser>>
+    async fn get_user_by_id<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_user_by_id(con, user_id).await,
+            TestRedisClient::Mock(mock) => mock.get_user_by_id(con, user_id).await,
+        }
+    }
+
+    async fn get_users_by_ids<C>(
+        &self,
+        con: &mut C,
+        user_ids: &[String],
+    ) -> GameResult<Vec<Option<User>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_users_by_ids(con, user_ids).await,
+            TestRedisClient::Mock(mock) => mock.get_users_by_ids(con, user_ids).await,
+        }
+    }
+
+    async fn get_user_by_username<C>(&self, con: &mut C, username: &str) -> GameResult<Option<User>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_user_by_username(con, username).await,
+            TestRedisClient::Mock(mock) => mock.get_user_by_username(con, username).await,
+        }
+    }
+
+    async fn get_public_users<C>(&self, con: &mut C) -> GameResult<Vec<PublicUser>>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -281,12 +703,37 @@ impl RedisHandler for TestRedisClient {
         }
     }
 
+    async fn get_leaderboard<C>(
+        &self,
+        con: &mut C,
+        offset: usize,
+        limit: usize,
+    ) -> GameResult<Vec<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_leaderboard(con, offset, limit).await,
+            TestRedisClient::Mock(mock) => mock.get_leaderboard(con, offset, limit).await,
+        }
+    }
+
+    async fn get_user_rank<C>(&self, con: &mut C, user_id: &str) -> GameResult<Option<RankedUser>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_user_rank(con, user_id).await,
+            TestRedisClient::Mock(mock) => mock.get_user_rank(con, user_id).await,
+        }
+    }
+
     async fn is_valid_token_for_user<C>(
         &self,
         con: &mut C,
         token: &str,
         user_id: &str,
-    ) -> redis::RedisResult<bool>
+    ) -> GameResult<bool>
     where
         C: redis::aio::ConnectionLike + Send,
     {
@@ -297,6 +744,122 @@ impl RedisHandler for TestRedisClient {
             TestRedisClient::Mock(mock) => mock.is_valid_token_for_user(con, token, user_id).await,
         }
     }
+    async fn set_user_token<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        token: &str,
+        token_ttl: u64,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => {
+                client.set_user_token(con, user_id, token, token_ttl).await
+            }
+            TestRedisClient::Mock(mock) => {
+                mock.set_user_token(con, user_id, token, token_ttl).await
+            }
+        }
+    }
+
+    async fn revoke_user_token<C>(&self, con: &mut C, user_id: &str) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.revoke_user_token(con, user_id).await,
+            TestRedisClient::Mock(mock) => mock.revoke_user_token(con, user_id).await,
+        }
+    }
+
+    async fn start_click_cooldown<C>(
+        &self,
+        con: &mut C,
+        user_id: &str,
+        cooldown_ms: u64,
+    ) -> GameResult<Option<u64>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => {
+                client.start_click_cooldown(con, user_id, cooldown_ms).await
+            }
+            TestRedisClient::Mock(mock) => {
+                mock.start_click_cooldown(con, user_id, cooldown_ms).await
+            }
+        }
+    }
+    async fn get_current_round<C>(&self, con: &mut C) -> GameResult<Option<Round>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_current_round(con).await,
+            TestRedisClient::Mock(mock) => mock.get_current_round(con).await,
+        }
+    }
+
+    async fn start_round<C>(&self, con: &mut C, previous_id: u32, round: &Round) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.start_round(con, previous_id, round).await,
+            TestRedisClient::Mock(mock) => mock.start_round(con, previous_id, round).await,
+        }
+    }
+
+    async fn use_round<C>(&self, con: &mut C, round_id: u32) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.use_round(con, round_id).await,
+            TestRedisClient::Mock(mock) => mock.use_round(con, round_id).await,
+        }
+    }
+
+    async fn archive_round<C>(
+        &self,
+        con: &mut C,
+        result: &RoundResult,
+        tiles: Vec<(AxialCoords, InnerTileData)>,
+    ) -> GameResult<bool>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.archive_round(con, result, tiles).await,
+            TestRedisClient::Mock(mock) => mock.archive_round(con, result, tiles).await,
+        }
+    }
+
+    async fn get_round_results<C>(&self, con: &mut C) -> GameResult<Vec<RoundResult>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_round_results(con).await,
+            TestRedisClient::Mock(mock) => mock.get_round_results(con).await,
+        }
+    }
+
+    async fn get_round_grid<C>(
+        &self,
+        con: &mut C,
+        round_id: u32,
+    ) -> GameResult<Option<Vec<(AxialCoords, InnerTileData)>>>
+    where
+        C: redis::aio::ConnectionLike + Send,
+    {
+        match self {
+            TestRedisClient::Real(client) => client.get_round_grid(con, round_id).await,
+            TestRedisClient::Mock(mock) => mock.get_round_grid(con, round_id).await,
+        }
+    }
 }
 
 impl redis::aio::ConnectionLike for TestRedisConnection {
@@ -340,7 +903,7 @@ pub enum TestRedisConnection {
 pub async fn get_connection(client: &TestRedisClient) -> redis::RedisResult<TestRedisConnection> {
     match client {
         TestRedisClient::Real(c) => {
-            let conn = c.get_multiplexed_async_connection().await?;
+            let conn = c.client().get_multiplexed_async_connection().await?;
             Ok(TestRedisConnection::Real(conn))
         }
         TestRedisClient::Mock(_) => Ok(TestRedisConnection::Mock(MockRedisConnection::new())),
@@ -357,8 +920,9 @@ pub async fn redis_client_or_mock() -> redis::RedisResult<TestRedisClient> {
             Ok((client, pool)) => {
                 let mut conn = pool.get().await.unwrap();
                 let _ = store::init_redis_indices(&mut conn).await?;
+                let _ = store::init_redis_scripts(&mut conn).await?;
 
-                return Ok(TestRedisClient::Real(client));
+                return Ok(TestRedisClient::Real(RedisStore::new(client)));
             }
             Err(e) => {
                 return Err(e);
@@ -366,5 +930,24 @@ pub async fn redis_client_or_mock() -> redis::RedisResult<TestRedisClient> {
         }
     }
 
-    return Ok(TestRedisClient::Mock(MockRedisHandler::new()));
+    Ok(TestRedisClient::Mock(MockRedisHandler::new()))
+}
+
+/// Store and pool to register as app data when testing the HTTP handlers,
+/// the pool is never used by the mock store
+pub async fn game_store_or_mock() -> redis::RedisResult<(store::GameStore, deadpool_redis::Pool)> {
+    let _ = env_logger::try_init();
+
+    let app_config = GameConfig::read_config_from_env();
+    let (client, pool) = store::init_redis_client(&app_config).await?;
+
+    if app_config.with_redis_tests {
+        let mut conn = pool.get().await.unwrap();
+        let _ = store::init_redis_indices(&mut conn).await?;
+        let _ = store::init_redis_scripts(&mut conn).await?;
+
+        return Ok((store::GameStore::Redis(RedisStore::new(client)), pool));
+    }
+
+    Ok((store::GameStore::Mock(MockRedisHandler::new()), pool))
 }
@@ -1,7 +1,35 @@
-use crate::coords::AxialCoords;
+use std::time::Duration;
+
+use actix::Actor;
+use actix_web::{
+    body::BoxBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    http::header,
+    web, App, Error,
+};
+use actix_web_httpauth::headers::authorization::Basic;
+
+use crate::{
+    broadcaster::TileBroadcaster,
+    changes::ChangeLog,
+    config::GameConfig,
+    coords::AxialCoords,
+    game::GameData,
+    leaderboard::LeaderboardWatcher,
+    metrics::Metrics,
+    relay::{Relay, RelayBus},
+    rounds::{CurrentRound, Round},
+    routes,
+    snapshot::SnapshotCache,
+    store::{GameStore, RedisHandler},
+    user::User,
+    websocket::init_clients,
+};
+
+use super::mocks::TOKEN_TTL;
 
 pub fn are_coords_in_vec<T>(
-    tiles: &Vec<(AxialCoords, T)>,
+    tiles: &[(AxialCoords, T)],
     coords_to_check: &AxialCoords,
 ) -> Option<(AxialCoords, T)>
 where
@@ -12,3 +40,143 @@ where
         .find(|(coords, _)| coords == coords_to_check)
         .cloned()
 }
+
+/// App serving every route with the given store, to use with `actix_web::test::init_service`.
+///
+/// Must be called from a running actix system as it starts the tile broadcaster.
+/// Clicks are accepted, the endless round 1 being played.
+pub fn test_app(
+    store: web::Data<GameStore>,
+    pool: deadpool_redis::Pool,
+    app_config: GameConfig,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<BoxBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    test_app_in_round(store, pool, app_config, endless_round())
+}
+
+/// Same as `test_app` but relaying websocket events through `bus`, apps sharing the
+/// store and the bus behave as several instances of the server
+pub fn test_app_with_relay(
+    store: web::Data<GameStore>,
+    pool: deadpool_redis::Pool,
+    app_config: GameConfig,
+    bus: RelayBus,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<BoxBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    build_test_app(store, pool, app_config, endless_round(), Some(bus))
+}
+
+fn endless_round() -> web::Data<CurrentRound> {
+    web::Data::new(CurrentRound::new(Round {
+        id: 1,
+        starts_at_ms: 0,
+        ends_at_ms: None,
+    }))
+}
+
+/// Same as `test_app` but playing `round`
+pub fn test_app_in_round(
+    store: web::Data<GameStore>,
+    pool: deadpool_redis::Pool,
+    app_config: GameConfig,
+    round: web::Data<CurrentRound>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<BoxBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    build_test_app(store, pool, app_config, round, None)
+}
+
+fn build_test_app(
+    store: web::Data<GameStore>,
+    pool: deadpool_redis::Pool,
+    app_config: GameConfig,
+    round: web::Data<CurrentRound>,
+    bus: Option<RelayBus>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<BoxBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    let game_data = web::Data::new(GameData::new(10, 2));
+    let change_log = web::Data::new(ChangeLog::new(app_config.change_log_size));
+    let mut clients = init_clients(&app_config);
+    let mut broadcaster = TileBroadcaster::new(
+        clients.clone(),
+        game_data.clone(),
+        change_log.clone(),
+        Duration::from_millis(app_config.tile_broadcast_tick_ms),
+    );
+
+    let relay = bus.map(Relay::start);
+    if let Some(relay) = &relay {
+        clients = clients.with_relay(relay.clone());
+        broadcaster = broadcaster.with_relay(relay.clone());
+    }
+
+    let broadcaster = broadcaster.start();
+
+    if let Some(relay) = &relay {
+        relay.listen(clients.clone(), broadcaster.clone());
+    }
+
+    App::new()
+        .app_data(web::Data::new(LeaderboardWatcher::new(
+            app_config.leaderboard_size,
+        )))
+        .app_data(web::Data::new(app_config))
+        .app_data(game_data)
+        .app_data(web::Data::new(clients))
+        .app_data(web::Data::new(broadcaster))
+        .app_data(change_log)
+        .app_data(web::Data::new(pool))
+        .app_data(store)
+        .app_data(round)
+        .app_data(web::Data::new(Metrics::new()))
+        .app_data(web::Data::new(SnapshotCache::new()))
+        .configure(routes::configure)
+}
+
+/// Saves a new user in `store` and returns it with its session token
+pub async fn add_test_user(store: &GameStore, pool: &deadpool_redis::Pool, username: &str) -> User {
+    let mut con = store.get_connection(pool).await.unwrap();
+    let user = User::new(username);
+
+    store
+        .add_user(&mut con, user.clone(), TOKEN_TTL)
+        .await
+        .unwrap();
+
+    user
+}
+
+/// Authorization header with the credentials of `user`
+pub fn basic_auth(user: &User) -> (header::HeaderName, Basic) {
+    (
+        header::AUTHORIZATION,
+        Basic::new(user.id.clone(), Some(user.token.clone())),
+    )
+}
@@ -2,16 +2,23 @@ use std::collections::HashMap;
 
 use redis::{FromRedisValue, ToRedisArgs};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 use crate::utils::{color_to_hex, string_to_color};
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct User {
     pub color: String,
     pub id: String,
     pub username: String,
+    /// Session token, only known right after login or refresh. Stores keep a
+    /// hash of it (see `hash_token`) so users read back from a store have an empty token.
+    #[serde(default)]
     pub token: String,
+    /// Team joined at login, None when playing alone
+    #[serde(default)]
+    pub team: Option<String>,
 }
 
 impl FromRedisValue for User {
@@ -36,10 +43,6 @@ impl FromRedisValue for User {
             redis::RedisError::from((redis::ErrorKind::TypeError, "Missing color in user hashmap"))
         })?;
 
-        let token = raw_hashmap.get("token").ok_or_else(|| {
-            redis::RedisError::from((redis::ErrorKind::TypeError, "Missing token in user hashmap"))
-        })?;
-
         let username = raw_hashmap.get("username").ok_or_else(|| {
             redis::RedisError::from((
                 redis::ErrorKind::TypeError,
@@ -47,21 +50,103 @@ impl FromRedisValue for User {
             ))
         })?;
 
-        return Ok(User {
+        Ok(User {
             id: id.to_owned(),
             color: color.to_owned(),
-            token: token.to_owned(),
+            token: String::new(),
             username: username.to_owned(),
-        });
+            team: raw_hashmap.get("team").cloned(),
+        })
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PublicUser {
     pub id: String,
     pub username: String,
     pub color: String,
     pub score: u32,
+    #[serde(default)]
+    pub team: Option<String>,
+}
+
+impl PublicUser {
+    pub fn new(user: User, score: u32) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            color: user.color,
+            score,
+            team: user.team,
+        }
+    }
+}
+
+/// Position of an user in the leaderboard, `rank` starts at 1
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RankedUser {
+    pub rank: u32,
+    #[serde(flatten)]
+    pub user: PublicUser,
+}
+
+/// Ranks users by score, highest first. Users without any tile are not ranked and
+/// ties are broken by descending id to match redis' `ZREVRANGE` ordering.
+pub fn rank_public_users(mut users: Vec<PublicUser>) -> Vec<RankedUser> {
+    users.retain(|u| u.score > 0);
+    users.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| b.id.cmp(&a.id)));
+
+    users
+        .into_iter()
+        .enumerate()
+        .map(|(i, user)| RankedUser {
+            rank: i as u32 + 1,
+            user,
+        })
+        .collect()
+}
+
+/// Total score of the members of a team, `rank` starts at 1
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TeamScore {
+    pub rank: u32,
+    pub team: String,
+    pub score: u32,
+    pub members: u32,
+}
+
+/// Sums the scores of `users` by team, highest total first. Users without team are
+/// left out, ties are broken by team name.
+pub fn rank_teams(users: &[PublicUser]) -> Vec<TeamScore> {
+    let mut totals: HashMap<&str, (u32, u32)> = HashMap::new();
+
+    for user in users {
+        if let Some(team) = &user.team {
+            let (score, members) = totals.entry(team).or_default();
+            *score += user.score;
+            *members += 1;
+        }
+    }
+
+    let mut totals: Vec<(&str, (u32, u32))> = totals.into_iter().collect();
+    totals.sort_by(|(a, (a_score, _)), (b, (b_score, _))| b_score.cmp(a_score).then(a.cmp(b)));
+
+    totals
+        .into_iter()
+        .enumerate()
+        .map(|(i, (team, (score, members)))| TeamScore {
+            rank: i as u32 + 1,
+            team: team.to_string(),
+            score,
+            members,
+        })
+        .collect()
+}
+
+/// Hash of a session token as saved in the stores, tokens are random UUIDs so
+/// a plain SHA-256 is enough to not leak them from a dump
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
 impl User {
@@ -75,6 +160,20 @@ impl User {
             username: username.to_string(),
             token,
             color: color_to_hex(string_to_color(username)),
+            team: None,
+        }
+    }
+
+    /// Replaces the session token by a new random one
+    pub fn renew_token(&mut self) {
+        self.token = Uuid::new_v4().to_string();
+    }
+
+    /// Same user without its session token, as read back from a store
+    pub fn without_token(&self) -> Self {
+        Self {
+            token: String::new(),
+            ..self.clone()
         }
     }
 }
@@ -85,13 +184,16 @@ impl ToRedisArgs for User {
         W: ?Sized + redis::RedisWrite,
     {
         out.write_arg(b"id");
-        out.write_arg(self.username.as_bytes());
+        out.write_arg(self.id.as_bytes());
 
         out.write_arg(b"username");
         out.write_arg(self.username.as_bytes());
         out.write_arg(b"color");
         out.write_arg(self.color.as_bytes());
-        out.write_arg(b"token");
-        out.write_arg(self.token.as_bytes());
+
+        if let Some(team) = &self.team {
+            out.write_arg(b"team");
+            out.write_arg(team.as_bytes());
+        }
     }
 }
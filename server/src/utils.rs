@@ -18,14 +18,15 @@ where
 {
     let data = GameData::new(radius, grid_rows_and_cols);
 
-    for (coords, _) in &data.precomputed_neighbors {
+    for coords in data.precomputed_neighbors.keys() {
         redis_client
             .set_tile(
                 con,
-                &coords,
+                coords,
                 InnerTileData {
                     user_id: benchmark_user.id.clone(),
                     damage: 0,
+                    team: None,
                 },
             )
             .await
@@ -52,3 +53,16 @@ pub fn string_to_color(input: &str) -> (u8, u8, u8) {
 pub fn color_to_hex(color: (u8, u8, u8)) -> String {
     format!("#{:02X}{:02X}{:02X}", color.0, color.1, color.2)
 }
+
+/// Inverse of `color_to_hex`, None if `hex` is not a `#RRGGBB` color
+pub fn hex_to_color(hex: &str) -> Option<(u8, u8, u8)> {
+    let hex = hex.strip_prefix('#')?;
+
+    if hex.len() != 6 {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
@@ -1,36 +1,313 @@
-use bytes::BytesMut;
-
 use std::{
     collections::HashSet,
-    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use actix::{Actor, Addr, AsyncContext, Handler, Message, StreamHandler};
+use actix::{
+    fut, Actor, ActorContext, ActorFutureExt, AsyncContext, Handler, Message, StreamHandler,
+};
 use actix_web::{
-    web::{Data, Payload},
+    web::{self, Payload},
     Error, HttpRequest, HttpResponse,
 };
-use actix_web_actors::ws::{self, Message as WsMessage, ProtocolError, WebsocketContext};
-
-use crate::{coords::AxialCoords, game::TileData};
+use actix_web_actors::ws::{
+    self, CloseCode, CloseReason, Message as WsMessage, ProtocolError, WebsocketContext,
+};
+use bytes::Bytes;
+
+use crate::{
+    auth::authenticate_user,
+    changes::{ChangeLog, ChangesSince},
+    click::ClickContext,
+    clients::{ClientHandle, ClientList, ConnectionPermit},
+    config::GameConfig,
+    error::GameError,
+    protocol::{
+        request_id_of, ClientMessage, CloseStatus, ErrorCode, ServerMessage, PROTOCOL_VERSION,
+    },
+    rounds::Round,
+    user::{RankedUser, User},
+};
 
-/// Type alias for the list of WebSocket clients
-pub type ClientList = Arc<Mutex<HashSet<Addr<MyWebSocket>>>>;
+/// Starts the shards websocket clients are registered in, see `ClientList`
+pub fn init_clients(app_config: &GameConfig) -> ClientList {
+    ClientList::start(app_config.broadcast_shards, app_config.client_queue_limit)
+        .with_connection_limits(
+            app_config.max_connections,
+            app_config.max_connections_per_ip,
+        )
+}
 
-/// Function to initialize a new empty client list
-pub fn init_clients() -> ClientList {
-    Arc::new(Mutex::new(HashSet::new()))
+/// Closes the connection of a websocket actor with the code of `status`
+fn close<A>(ctx: &mut WebsocketContext<A>, status: CloseStatus)
+where
+    A: Actor<Context = WebsocketContext<A>>,
+{
+    ctx.close(Some(CloseReason {
+        code: CloseCode::from(status.code()),
+        description: Some(status.reason().to_string()),
+    }));
+    ctx.stop();
 }
 
 /// WebSocket actor to handle messages and manage connections
 pub struct MyWebSocket {
     clients: ClientList, // Shared client list for broadcasting messages
+    click_context: ClickContext,
+    change_log: web::Data<ChangeLog>, // Last tile changes, for resync messages
+    handshaken: bool,                 // Set once the client sent an hello with our protocol version
+    user: Option<User>,               // Set once the client sent a valid authenticate message
+    batches: HashSet<usize>,          // Batches this client receives tile changes for
+    handle: Option<ClientHandle>,     // Set once registered in the client list
+    last_heartbeat: Instant,          // Last time the client sent anything, pongs included
+    _permit: ConnectionPermit,        // Counts this connection until the actor is dropped
 }
 
 impl MyWebSocket {
     // Constructor to create a new instance of MyWebSocket
-    pub fn new(clients: ClientList) -> Self {
-        MyWebSocket { clients }
+    pub fn new(
+        clients: ClientList,
+        click_context: ClickContext,
+        change_log: web::Data<ChangeLog>,
+        permit: ConnectionPermit,
+    ) -> Self {
+        MyWebSocket {
+            clients,
+            click_context,
+            change_log,
+            handshaken: false,
+            user: None,
+            batches: HashSet::new(),
+            handle: None,
+            last_heartbeat: Instant::now(),
+            _permit: permit,
+        }
+    }
+
+    // Pings the client every `heartbeat_interval_ms`, closes the connection once it
+    // sent nothing for `client_timeout_ms`
+    fn start_heartbeat(&self, ctx: &mut WebsocketContext<Self>) {
+        let app_config = &self.click_context.app_config;
+        if app_config.heartbeat_interval_ms == 0 {
+            return;
+        }
+
+        let timeout = Duration::from_millis(app_config.client_timeout_ms);
+        let interval = Duration::from_millis(app_config.heartbeat_interval_ms);
+
+        ctx.run_interval(interval, move |act, ctx| {
+            if act.last_heartbeat.elapsed() > timeout {
+                close(ctx, CloseStatus::Timeout);
+                return;
+            }
+
+            ctx.ping(b"");
+        });
+    }
+
+    fn subscribe(&mut self, batches: &[u16]) {
+        let batches: Vec<usize> = batches.iter().map(|b| *b as usize).collect();
+        self.batches.extend(&batches);
+
+        if let Some(handle) = &self.handle {
+            self.clients.subscribe(handle, batches);
+        }
+    }
+
+    fn unsubscribe(&mut self, batches: &[u16]) {
+        let batches: Vec<usize> = batches.iter().map(|b| *b as usize).collect();
+        for batch in &batches {
+            self.batches.remove(batch);
+        }
+
+        if let Some(handle) = &self.handle {
+            self.clients.unsubscribe(handle, batches);
+        }
+    }
+
+    // Encodes and sends `message` to this client only
+    fn reply(ctx: &mut WebsocketContext<Self>, message: ServerMessage) {
+        if let Some(bytes) = encode_message(&message) {
+            ctx.binary(bytes);
+        }
+    }
+
+    fn handle_client_message(&mut self, bytes: &[u8], ctx: &mut WebsocketContext<Self>) {
+        let message = match ClientMessage::decode(bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                let message = format!("Malformed message: {e}");
+                Self::reply(
+                    ctx,
+                    ServerMessage::error(request_id_of(bytes), ErrorCode::Malformed, &message),
+                );
+                return;
+            }
+        };
+
+        if !self.handshaken && !matches!(message, ClientMessage::Hello { .. }) {
+            Self::reply(
+                ctx,
+                ServerMessage::error(
+                    message.request_id(),
+                    ErrorCode::HandshakeRequired,
+                    "Send a hello message first",
+                ),
+            );
+            return;
+        }
+
+        let context = self.click_context.clone();
+
+        match message {
+            ClientMessage::Hello {
+                request_id,
+                version,
+            } => {
+                if version != PROTOCOL_VERSION {
+                    Self::reply(
+                        ctx,
+                        ServerMessage::error(
+                            request_id,
+                            ErrorCode::UnsupportedVersion,
+                            &format!("Server speaks protocol version {PROTOCOL_VERSION}"),
+                        ),
+                    );
+                    close(ctx, CloseStatus::UnsupportedVersion);
+                    return;
+                }
+
+                self.handshaken = true;
+                Self::reply(ctx, ServerMessage::Ack { request_id });
+            }
+            ClientMessage::Subscribe {
+                request_id,
+                batches,
+            } => {
+                let batches_count = context.game_data.batches_count();
+
+                if let Some(unknown) = batches.iter().find(|b| **b as usize >= batches_count) {
+                    Self::reply(
+                        ctx,
+                        ServerMessage::error(
+                            request_id,
+                            ErrorCode::Malformed,
+                            &format!("Batch {unknown} does not exist"),
+                        ),
+                    );
+                    return;
+                }
+
+                self.subscribe(&batches);
+                Self::reply(ctx, ServerMessage::Ack { request_id });
+            }
+            ClientMessage::Unsubscribe {
+                request_id,
+                batches,
+            } => {
+                self.unsubscribe(&batches);
+                Self::reply(ctx, ServerMessage::Ack { request_id });
+            }
+            ClientMessage::Resync { request_id, since } => match self.change_log.since(since) {
+                ChangesSince::Changes { seq, tiles } => {
+                    let tiles: Vec<_> = tiles
+                        .into_iter()
+                        .filter(|(coords, _)| {
+                            context
+                                .game_data
+                                .batch_of(coords)
+                                .is_some_and(|batch| self.batches.contains(&batch))
+                        })
+                        .collect();
+
+                    // always send one message so that the client gets the current sequence
+                    let chunks = tiles.chunks(u16::MAX as usize);
+                    for chunk in chunks.chain(tiles.is_empty().then_some(&[][..])) {
+                        Self::reply(
+                            ctx,
+                            ServerMessage::TilesChange {
+                                seq,
+                                tiles: chunk.to_vec(),
+                            },
+                        );
+                    }
+                    Self::reply(ctx, ServerMessage::Ack { request_id });
+                }
+                ChangesSince::ResyncRequired { .. } => Self::reply(
+                    ctx,
+                    ServerMessage::error(
+                        request_id,
+                        ErrorCode::ResyncRequired,
+                        "Changes are too old, download every batch again",
+                    ),
+                ),
+            },
+            ClientMessage::Authenticate {
+                request_id,
+                user_id,
+                token,
+            } => {
+                let authenticate = async move {
+                    authenticate_user(&context.store, &context.pool, &user_id, &token).await
+                };
+
+                ctx.spawn(fut::wrap_future(authenticate).map(
+                    move |result, act: &mut Self, ctx| match result {
+                        Ok(user) => {
+                            // messages meant for this user only, like its cooldown
+                            if let Some(handle) = &act.handle {
+                                act.clients.identify(handle, &user.id);
+                            }
+                            act.user = Some(user);
+                            Self::reply(ctx, ServerMessage::Ack { request_id });
+                        }
+                        Err(e) => Self::reply(
+                            ctx,
+                            ServerMessage::error(request_id, error_code(&e), &e.to_string()),
+                        ),
+                    },
+                ));
+            }
+            ClientMessage::Click { request_id, coords } => {
+                let Some(user) = &self.user else {
+                    Self::reply(
+                        ctx,
+                        ServerMessage::error(
+                            request_id,
+                            ErrorCode::Unauthorized,
+                            "Authenticate before clicking",
+                        ),
+                    );
+                    return;
+                };
+
+                let user = user.clone();
+                let click = async move { context.play(&user, &coords).await };
+
+                ctx.spawn(
+                    fut::wrap_future(click).map(move |result, _act: &mut Self, ctx| match result {
+                        Ok(()) => Self::reply(ctx, ServerMessage::Ack { request_id }),
+                        Err(e) => Self::reply(
+                            ctx,
+                            ServerMessage::error(request_id, error_code(&e), &e.to_string()),
+                        ),
+                    }),
+                );
+            }
+        }
+    }
+}
+
+/// Error code sent to websocket clients for a failed request
+fn error_code(e: &GameError) -> ErrorCode {
+    match e {
+        GameError::Unauthorized(_) => ErrorCode::Unauthorized,
+        GameError::RateLimited(_) => ErrorCode::Cooldown,
+        GameError::RoundOver => ErrorCode::RoundOver,
+        GameError::InvalidCoords(_) | GameError::InvalidInput(_) => ErrorCode::Malformed,
+        GameError::StoreUnavailable(_) | GameError::NotFound(_) | GameError::Conflict(_) => {
+            ErrorCode::Internal
+        }
     }
 }
 
@@ -39,28 +316,48 @@ impl Actor for MyWebSocket {
     type Context = WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let addr = ctx.address();
-        // Insert the client address into the shared client list
-        self.clients.lock().unwrap().insert(addr);
+        // Register the client so that it receives broadcasts
+        self.handle = Some(self.clients.connect(ctx.address().recipient()));
+        self.click_context.metrics.websocket_connected();
+        self.start_heartbeat(ctx);
+
+        Self::reply(
+            ctx,
+            ServerMessage::Hello {
+                version: PROTOCOL_VERSION,
+            },
+        );
     }
 
-    fn stopped(&mut self, ctx: &mut Self::Context) {
-        let addr = ctx.address();
-        // Remove the client address from the shared client list when the connection stops
-        self.clients.lock().unwrap().remove(&addr);
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        // Remove the client and its subscriptions when the connection stops
+        if let Some(handle) = self.handle.take() {
+            self.clients.disconnect(&handle);
+            self.click_context.metrics.websocket_disconnected();
+        }
     }
 }
 
 // Define a message type for sending binary data to WebSocket clients
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct MyBinaryMessage(pub Vec<u8>); // A custom message type containing the binary data
+pub struct MyBinaryMessage(pub Bytes); // A custom message type containing the binary data
 
 // Implement the Handler trait for MyBinaryMessage
 impl Handler<MyBinaryMessage> for MyWebSocket {
     type Result = ();
 
     fn handle(&mut self, msg: MyBinaryMessage, ctx: &mut Self::Context) {
+        // Clients too slow to read their messages are disconnected, see `ClientList`
+        if self
+            .handle
+            .as_ref()
+            .is_some_and(|handle| !handle.delivered())
+        {
+            close(ctx, CloseStatus::TooManyPending);
+            return;
+        }
+
         // Handle sending the binary message to the WebSocket client
         ctx.binary(msg.0); // Send the binary message to the client
     }
@@ -69,99 +366,102 @@ impl Handler<MyBinaryMessage> for MyWebSocket {
 // Handle incoming WebSocket messages (e.g., text messages)
 impl StreamHandler<Result<WsMessage, ProtocolError>> for MyWebSocket {
     fn handle(&mut self, msg: Result<WsMessage, ProtocolError>, ctx: &mut Self::Context) {
+        if msg.is_ok() {
+            self.last_heartbeat = Instant::now();
+        }
+
         match msg {
+            Ok(WsMessage::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(WsMessage::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
             Ok(WsMessage::Text(text)) => {
                 // Echo the received text message back to the client
                 ctx.text(format!("Echo: {}", text));
             }
             Ok(WsMessage::Binary(bin)) => {
-                // Handle binary messages if needed
-                ctx.binary(bin); // Echo binary message (or process as needed)
+                // Client messages, see `ClientMessage`
+                self.handle_client_message(&bin, ctx);
             }
             Err(e) => {
-                // Handle errors (if necessary)
-                eprintln!("Error processing WebSocket message: {:?}", e);
+                log::warn!("Error processing websocket message: {e:?}");
             }
             _ => {}
         }
     }
 }
 
-// Function to construct a binary message from AxialCoords and TileData (Message type: 0x01 for tile change)
-pub fn tile_change_message(coords: &AxialCoords, tile: &TileData) -> Vec<u8> {
-    let user_id_bytes = tile.user_id.as_bytes();
-
-    // Create a buffer with enough space to hold all the data
-    let mut buffer = BytesMut::with_capacity(9 + user_id_bytes.len()); // 4 (q) + 4 (r) + 1 (strength) + user_id_length
-    buffer.extend_from_slice(&[0x01]); // Message type for tile change (0x01)
-    buffer.extend_from_slice(&coords.q.to_le_bytes()); // Serialize q (i32)
-    buffer.extend_from_slice(&coords.r.to_le_bytes()); // Serialize r (i32)
-    buffer.extend_from_slice(&[tile.strength]); // Serialize strength (u8)
-    buffer.extend_from_slice(&(user_id_bytes.len() as u8).to_le_bytes()); // Serialize length of user_id
-    buffer.extend_from_slice(user_id_bytes); // Add user_id bytes
-
-    buffer.to_vec()
+/// Encodes `message` for websocket clients, messages that cannot be encoded are logged
+fn encode_message(message: &ServerMessage) -> Option<Vec<u8>> {
+    message
+        .encode()
+        .inspect_err(|e| log::error!("Could not encode {message:?}: {e}"))
+        .ok()
 }
 
-// Function to construct a binary message for a new user (Message type: 0x02 for new user)
-pub fn new_user_message(user_id: &str, user_name: &str, user_color: &str) -> Vec<u8> {
-    let user_id_bytes = user_id.as_bytes();
-    let user_name_bytes = user_name.as_bytes();
-    let user_color_bytes = user_color.as_bytes();
-
-    let user_id_length = user_id_bytes.len();
-    let user_name_length = user_name_bytes.len();
-    let user_color_length = user_color_bytes.len();
-
-    let mut buffer = BytesMut::with_capacity(
-        1 + 4 + 1 + user_id_length + 1 + user_name_length + 1 + user_color_length + 4,
-    );
-
-    // Message type for new user
-    buffer.extend_from_slice(&[0x02]); // Message type for new user (0x02)
-
-    // Serialize user ID
-    buffer.extend_from_slice(&(user_id_length as u8).to_le_bytes()); // Length of user ID
-    buffer.extend_from_slice(user_id_bytes); // User ID bytes
-
-    // Serialize user Name
-    buffer.extend_from_slice(&(user_name_length as u8).to_le_bytes()); // Length of user Name
-    buffer.extend_from_slice(user_name_bytes); // User Name bytes
-
-    // Serialize user Color
-    buffer.extend_from_slice(&(user_color_length as u8).to_le_bytes()); // Length of user Color
-    buffer.extend_from_slice(user_color_bytes); // User Color bytes
-
-    buffer.to_vec()
-}
+/// Sends `message` to every connected client, including the ones of other instances
+pub fn broadcast(clients: &ClientList, message: &ServerMessage) {
+    let Some(bytes) = encode_message(message) else {
+        return;
+    };
 
-pub fn notify_new_user(clients: &ClientList, user_id: &str, user_name: &str, user_color: &str) {
-    let login_msg = new_user_message(user_id, user_name, user_color);
+    clients.send_local(&bytes);
 
-    // Send the login message to all connected clients
-    for client in clients.lock().unwrap().iter() {
-        client.do_send(MyBinaryMessage(login_msg.clone()));
+    if let Some(relay) = clients.relay() {
+        relay.publish(&bytes);
     }
 }
 
-pub fn score_change_message(user_id: &str, score: u32) -> Vec<u8> {
-    let user_id_bytes = user_id.as_bytes();
-    let user_id_length = user_id_bytes.len();
+pub fn notify_new_user(clients: &ClientList, user: &User) {
+    broadcast(
+        clients,
+        &ServerMessage::NewUser {
+            user_id: user.id.clone(),
+            username: user.username.clone(),
+            color: user.color.clone(),
+            team: user.team.clone().unwrap_or_default(),
+        },
+    );
+}
 
-    let mut buffer = BytesMut::with_capacity(1 + 1 + user_id_length + 4); // Type + user ID length + ID + score
-    buffer.extend_from_slice(&[0x03]); // Message type for score change
-    buffer.extend_from_slice(&(user_id_length as u8).to_le_bytes()); // Length of user ID
-    buffer.extend_from_slice(user_id_bytes); // User ID
-    buffer.extend_from_slice(&score.to_le_bytes()); // New score (u32)
+pub fn notify_score_change(clients: &ClientList, user_id: &str, score: u32) {
+    broadcast(
+        clients,
+        &ServerMessage::ScoreChange {
+            user_id: user_id.to_string(),
+            score,
+        },
+    );
+}
 
-    buffer.to_vec()
+pub fn notify_leaderboard_change(clients: &ClientList, top: &[RankedUser]) {
+    broadcast(clients, &ServerMessage::leaderboard(top));
 }
 
-pub fn notify_score_change(clients: &ClientList, user_id: &str, score: u32) {
-    let score_change_msg = score_change_message(user_id, score);
+/// Tells the sessions of `user_id` when it can click again, including its sessions on
+/// other instances. Other users are not told about it.
+pub fn notify_cooldown(clients: &ClientList, user_id: &str, remaining_ms: u64) {
+    let message = ServerMessage::Cooldown {
+        user_id: user_id.to_string(),
+        remaining_ms: remaining_ms.min(u32::MAX as u64) as u32,
+    };
+    let Some(bytes) = encode_message(&message) else {
+        return;
+    };
+
+    clients.send_to_user(user_id, &bytes);
+
+    if let Some(relay) = clients.relay() {
+        relay.publish(&bytes);
+    }
+}
 
-    for client in clients.lock().unwrap().iter() {
-        client.do_send(MyBinaryMessage(score_change_msg.clone()));
+/// Tells every client that `round` started, they have to download the new grid.
+/// Not relayed as every instance notices the new round on its own.
+pub fn notify_round(clients: &ClientList, round: &Round) {
+    if let Some(bytes) = encode_message(&ServerMessage::round(round)) {
+        clients.send_local(&bytes);
     }
 }
 
@@ -169,8 +469,37 @@ pub fn notify_score_change(clients: &ClientList, user_id: &str, score: u32) {
 pub async fn ws_handler(
     req: HttpRequest,
     stream: Payload,
-    clients: Data<ClientList>, // Shared client list
+    click_context: ClickContext, // Shared client list and everything needed to play clicks
+    change_log: web::Data<ChangeLog>,
 ) -> Result<HttpResponse, Error> {
+    let clients = click_context.clients.get_ref().clone();
+
+    // Behind a proxy every client has the address of the proxy
+    let permit = match clients.admit(req.peer_addr().map(|addr| addr.ip())) {
+        Ok(permit) => permit,
+        Err(status) => return ws::start(RefusedConnection(status), &req, stream),
+    };
+
     // Start the WebSocket actor with the provided client list
-    ws::start(MyWebSocket::new(clients.get_ref().clone()), &req, stream)
+    ws::start(
+        MyWebSocket::new(clients, click_context, change_log, permit),
+        &req,
+        stream,
+    )
+}
+
+/// Connection over the limits, closed with its `CloseStatus` as soon as it is open so
+/// that browsers, which do not see the status of failed upgrades, get the reason
+struct RefusedConnection(CloseStatus);
+
+impl Actor for RefusedConnection {
+    type Context = WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        close(ctx, self.0);
+    }
+}
+
+impl StreamHandler<Result<WsMessage, ProtocolError>> for RefusedConnection {
+    fn handle(&mut self, _msg: Result<WsMessage, ProtocolError>, _ctx: &mut Self::Context) {}
 }
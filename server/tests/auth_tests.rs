@@ -0,0 +1,224 @@
+use actix_web::{
+    http::{header, StatusCode},
+    test, web,
+};
+use actix_web_httpauth::headers::authorization::Basic;
+use pixelstratwar::{
+    config::GameConfig,
+    coords::AxialCoords,
+    store::RedisHandler,
+    test_utils::{
+        self,
+        utils::{add_test_user, basic_auth, test_app},
+    },
+    user::User,
+};
+
+#[actix_web::test]
+pub async fn click_without_credentials_is_unauthorized() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let app = test::init_service(test_app(
+        store.clone(),
+        pool,
+        GameConfig::read_config_from_env(),
+    ))
+    .await;
+
+    let req = test::TestRequest::post().uri("/tile/0/0").to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert!(
+        res.status() == StatusCode::UNAUTHORIZED,
+        "Click without credentials should be rejected, got {}",
+        res.status()
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn click_with_invalid_token_is_unauthorized() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+    let app = test::init_service(test_app(
+        store.clone(),
+        pool.clone(),
+        GameConfig::read_config_from_env(),
+    ))
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header((
+            header::AUTHORIZATION,
+            Basic::new(user.id.clone(), Some("not-the-token")),
+        ))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert!(
+        res.status() == StatusCode::UNAUTHORIZED,
+        "Click with a wrong token should be rejected, got {}",
+        res.status()
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header((
+            header::AUTHORIZATION,
+            Basic::new("unknown-user", Some(user.token.clone())),
+        ))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert!(
+        res.status() == StatusCode::UNAUTHORIZED,
+        "Click with another user's token should be rejected, got {}",
+        res.status()
+    );
+
+    let mut con = store.get_connection(&pool).await.unwrap();
+    let tile = store
+        .get_tile(&mut con, &AxialCoords::new(0, 0))
+        .await
+        .unwrap();
+    assert!(tile.is_none(), "Rejected clicks should not change the grid");
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn click_is_made_by_authenticated_user() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+    let other = add_test_user(&store, &pool, "other").await;
+    let app = test::init_service(test_app(
+        store.clone(),
+        pool.clone(),
+        GameConfig::read_config_from_env(),
+    ))
+    .await;
+
+    // the body used to carry the clicking user id, it must now be ignored
+    let req = test::TestRequest::post()
+        .uri("/tile/1/-1")
+        .insert_header((
+            header::AUTHORIZATION,
+            Basic::new(user.id.clone(), Some(user.token.clone())),
+        ))
+        .set_payload(other.id.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert!(
+        res.status() == StatusCode::OK,
+        "Click with valid credentials should succeed, got {}",
+        res.status()
+    );
+
+    let mut con = store.get_connection(&pool).await.unwrap();
+    let tile = store
+        .get_tile(&mut con, &AxialCoords::new(1, -1))
+        .await
+        .unwrap()
+        .expect("Tile should have been created");
+
+    assert!(
+        tile.user_id == user.id,
+        "Tile should belong to the authenticated user"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn refresh_rotates_token() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+    let app = test::init_service(test_app(
+        store.clone(),
+        pool.clone(),
+        GameConfig::read_config_from_env(),
+    ))
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/refresh")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let refreshed: User = test::call_and_read_body_json(&app, req).await;
+
+    assert!(
+        refreshed.id == user.id && refreshed.token != user.token,
+        "Refresh should return the same user with a new token, got {refreshed:?}"
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert!(
+        res.status() == StatusCode::UNAUTHORIZED,
+        "Previous token should be rejected after refresh, got {}",
+        res.status()
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header(basic_auth(&refreshed))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert!(
+        res.status() == StatusCode::OK,
+        "Refreshed token should be accepted, got {}",
+        res.status()
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn logout_revokes_token() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+    let app = test::init_service(test_app(
+        store.clone(),
+        pool.clone(),
+        GameConfig::read_config_from_env(),
+    ))
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/logout")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert!(
+        res.status() == StatusCode::OK,
+        "Logout should succeed, got {}",
+        res.status()
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/refresh")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert!(
+        res.status() == StatusCode::UNAUTHORIZED,
+        "Token should be rejected after logout, got {}",
+        res.status()
+    );
+
+    store.flushdb().await.unwrap();
+}
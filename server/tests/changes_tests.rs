@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, test as actix_test, web};
+use pixelstratwar::{
+    changes::{ChangeLog, ChangesSince},
+    config::GameConfig,
+    coords::AxialCoords,
+    game::TileData,
+    routes::CHANGE_SEQ_HEADER,
+    store::RedisHandler,
+    test_utils::{
+        self,
+        utils::{add_test_user, basic_auth, test_app},
+    },
+};
+use serde_json::Value;
+
+fn tile(user_id: &str, strength: u8) -> TileData {
+    TileData {
+        user_id: user_id.to_string(),
+        strength,
+    }
+}
+
+#[test]
+pub fn change_log_returns_last_state_of_changed_tiles() {
+    let log = ChangeLog::starting_at(100, 4);
+    let a = AxialCoords::new(0, 0);
+    let b = AxialCoords::new(1, 0);
+
+    let seq = log.record(&[(a, tile("user_a", 1)), (b, tile("user_a", 2))]);
+    assert!(
+        seq == 102,
+        "Each tile should get its own sequence, got {seq}"
+    );
+
+    let seq = log.record(&[(a, tile("user_b", 1))]);
+    assert!(seq == 103 && log.last_seq() == 103);
+
+    let changes = log.since(100);
+    assert!(
+        changes
+            == ChangesSince::Changes {
+                seq: 103,
+                tiles: vec![(a, tile("user_b", 1)), (b, tile("user_a", 2))],
+            },
+        "Tiles changed twice should only appear with their last state, got {changes:?}"
+    );
+
+    let changes = log.since(102);
+    assert!(
+        changes
+            == ChangesSince::Changes {
+                seq: 103,
+                tiles: vec![(a, tile("user_b", 1))],
+            },
+        "Only changes after the sequence should be returned, got {changes:?}"
+    );
+
+    assert!(
+        log.since(103)
+            == ChangesSince::Changes {
+                seq: 103,
+                tiles: vec![],
+            },
+        "Up to date clients should get no change"
+    );
+
+    assert!(
+        log.since(104) == ChangesSince::ResyncRequired { seq: 103 },
+        "Sequences never given by the log should require a resync"
+    );
+
+    // 105 pushes 101 out of the log
+    log.record(&[(b, tile("user_b", 1)), (a, tile("user_a", 1))]);
+
+    assert!(
+        log.since(100) == ChangesSince::ResyncRequired { seq: 105 },
+        "Trimmed changes should require a resync"
+    );
+    assert!(
+        matches!(log.since(101), ChangesSince::Changes { seq: 105, .. }),
+        "Changes still in the log should be returned"
+    );
+}
+
+#[test]
+pub fn sequences_of_other_change_logs_require_a_resync() {
+    let first = ChangeLog::new(16);
+    // logs starting at different sequences have different epochs
+    let second = loop {
+        let log = ChangeLog::new(16);
+        if log.last_seq() != first.last_seq() {
+            break log;
+        }
+    };
+
+    let changes: Vec<_> = (0..8)
+        .map(|q| (AxialCoords::new(q, 0), tile("user_a", 1)))
+        .collect();
+    let first_seq = first.record(&changes[..2]);
+    let second_start = second.last_seq();
+    second.record(&changes);
+
+    assert!(
+        matches!(second.since(second_start), ChangesSince::Changes { ref tiles, .. } if tiles.len() == 8),
+        "Sequences of the log should be resynced from it"
+    );
+    assert!(
+        matches!(second.since(first_seq), ChangesSince::ResyncRequired { .. }),
+        "Sequences of another instance should require a resync"
+    );
+    assert!(
+        first.last_seq() < 1 << 53 && second.last_seq() < 1 << 53,
+        "Sequences should be exact javascript numbers"
+    );
+}
+
+#[actix_web::test]
+pub async fn changes_since_batch_download() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.tile_broadcast_tick_ms = 0;
+    let app = actix_test::init_service(test_app(store.clone(), pool.clone(), app_config)).await;
+
+    let req = actix_test::TestRequest::get()
+        .uri("/tiles?batch=0")
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    let seq: u64 = res
+        .headers()
+        .get(CHANGE_SEQ_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .expect("Batch download should tell its change sequence");
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(res.status().is_success(), "Click should succeed");
+
+    // changes are recorded by the broadcaster once the click returned
+    let mut body = Value::Null;
+    for _ in 0..50 {
+        let req = actix_test::TestRequest::get()
+            .uri(&format!("/changes?since={seq}"))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert!(res.status() == StatusCode::OK);
+
+        body = actix_test::read_body_json(res).await;
+        if body["tiles"].as_array().is_some_and(|t| !t.is_empty()) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert!(
+        body["type"] == "changes" && body["seq"].as_u64().is_some_and(|s| s > seq),
+        "Changes should tell the new sequence, got {body}"
+    );
+    assert!(
+        body["tiles"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|t| t[0]["q"] == 0 && t[0]["r"] == 0 && t[1]["user_id"] == user.id),
+        "Clicked tile should be in the changes, got {body}"
+    );
+
+    let req = actix_test::TestRequest::get()
+        .uri("/changes?since=0")
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::GONE,
+        "Changes older than the log should require a resync, got {}",
+        res.status()
+    );
+    let body: Value = actix_test::read_body_json(res).await;
+    assert!(body["type"] == "resync_required", "Got {body}");
+
+    store.flushdb().await.unwrap();
+}
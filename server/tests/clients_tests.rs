@@ -0,0 +1,182 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix::{Actor, Context, Handler, Message};
+use pixelstratwar::{
+    clients::{ClientHandle, ClientList, ShardMetrics, TilesByBatch},
+    coords::AxialCoords,
+    game::TileData,
+    protocol::ServerMessage,
+    websocket::MyBinaryMessage,
+};
+
+/// Client counting the messages it receives, it only reads them once given its handle
+#[derive(Default)]
+struct TestClient {
+    handle: Option<ClientHandle>,
+    received: Arc<AtomicUsize>,
+    tiles: Arc<AtomicUsize>,
+}
+
+impl Actor for TestClient {
+    type Context = Context<Self>;
+}
+
+impl Handler<MyBinaryMessage> for TestClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: MyBinaryMessage, _ctx: &mut Self::Context) {
+        if let Some(handle) = &self.handle {
+            assert!(handle.delivered(), "Reading client should stay connected");
+        }
+
+        self.received.fetch_add(1, Ordering::SeqCst);
+        if let Ok(ServerMessage::TilesChange { tiles, .. }) = ServerMessage::decode(&msg.0) {
+            self.tiles.fetch_add(tiles.len(), Ordering::SeqCst);
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Reads(ClientHandle);
+
+impl Handler<Reads> for TestClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reads, _ctx: &mut Self::Context) {
+        self.handle = Some(msg.0);
+    }
+}
+
+async fn wait_until<F: Fn() -> bool>(condition: F) {
+    for _ in 0..200 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("Condition not met in time");
+}
+
+fn total(clients: &ClientList) -> ShardMetrics {
+    clients
+        .metrics()
+        .into_iter()
+        .fold(ShardMetrics::default(), |total, shard| ShardMetrics {
+            clients: total.clients + shard.clients,
+            backlog: total.backlog + shard.backlog,
+            pending: total.pending + shard.pending,
+            max_pending: total.max_pending.max(shard.max_pending),
+            overflowed: total.overflowed + shard.overflowed,
+        })
+}
+
+fn message(score: u32) -> Vec<u8> {
+    ServerMessage::ScoreChange {
+        user_id: "user_a".to_string(),
+        score,
+    }
+    .encode()
+    .unwrap()
+}
+
+#[actix_web::test]
+pub async fn slow_clients_are_disconnected_at_the_queue_limit() {
+    let clients = ClientList::start(2, 3);
+
+    let reading = TestClient::default();
+    let reading_received = reading.received.clone();
+    let reading = reading.start();
+    let handle = clients.connect(reading.clone().recipient());
+    reading.do_send(Reads(handle));
+
+    // never reads its messages, like a client that stopped reading its socket
+    let slow = TestClient::default();
+    let slow_received = slow.received.clone();
+    let slow_handle = clients.connect(slow.start().recipient());
+
+    wait_until(|| total(&clients).clients == 2).await;
+
+    for score in 1..=3 {
+        clients.send_local(&message(score));
+        wait_until(|| reading_received.load(Ordering::SeqCst) == score as usize).await;
+    }
+
+    // messages not read by the slow client are pending
+    wait_until(|| {
+        let metrics = total(&clients);
+        metrics.max_pending == 3 && metrics.overflowed == 0 && metrics.backlog == 0
+    })
+    .await;
+
+    clients.send_local(&message(4));
+    wait_until(|| reading_received.load(Ordering::SeqCst) == 4).await;
+
+    // the slow client is disconnected at the queue limit
+    wait_until(|| {
+        let metrics = total(&clients);
+        metrics.clients == 1 && metrics.overflowed == 1 && metrics.backlog == 0
+    })
+    .await;
+    assert!(
+        slow_received.load(Ordering::SeqCst) == 3,
+        "Messages past the limit should not be queued"
+    );
+    assert!(
+        !slow_handle.delivered(),
+        "Slow client should be told to close its connection"
+    );
+}
+
+#[actix_web::test]
+pub async fn tiles_are_sent_to_subscribers_of_their_batch() {
+    let clients = ClientList::start(3, 16);
+    let tile = TileData {
+        user_id: "user_a".to_string(),
+        strength: 1,
+    };
+
+    let mut received = Vec::new();
+    for batches in [vec![0], vec![1], vec![0, 1], vec![]] {
+        let client = TestClient::default();
+        received.push(client.tiles.clone());
+
+        let client = client.start();
+        let handle = clients.connect(client.clone().recipient());
+        clients.subscribe(&handle, batches);
+        client.do_send(Reads(handle));
+    }
+
+    let tiles = TilesByBatch::from([
+        (0, vec![(AxialCoords::new(0, 0), tile.clone())]),
+        (
+            1,
+            vec![
+                (AxialCoords::new(5, 0), tile.clone()),
+                (AxialCoords::new(6, 0), tile),
+            ],
+        ),
+    ]);
+    clients.send_tiles(1, tiles);
+
+    wait_until(|| {
+        received
+            .iter()
+            .map(|r| r.load(Ordering::SeqCst))
+            .sum::<usize>()
+            == 6
+    })
+    .await;
+    let counts: Vec<usize> = received.iter().map(|r| r.load(Ordering::SeqCst)).collect();
+    assert!(
+        counts == [1, 2, 3, 0],
+        "Clients should only receive the tiles of their batches, got {counts:?}"
+    );
+}
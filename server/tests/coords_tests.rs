@@ -1,4 +1,6 @@
-use pixelstratwar::coords::{cube_ring, cube_spiral, cube_substract, CubeCoords};
+use pixelstratwar::coords::{
+    axial_to_pixel, cube_ring, cube_spiral, cube_substract, pixel_to_axial, CubeCoords,
+};
 
 #[test]
 fn test_cube_ring() {
@@ -60,3 +62,33 @@ fn test_cube_subtract() {
         res
     );
 }
+
+#[test]
+fn pixel_to_axial_finds_hexagon_containing_pixel() {
+    let size = 10.0;
+
+    for coords in cube_spiral(&CubeCoords::center(), 5)
+        .iter()
+        .map(|c| c.as_axial())
+    {
+        let (x, y) = axial_to_pixel(&coords, size);
+
+        // inner radius of a pointy top hexagon is sqrt(3) / 2 * size
+        let inside = 0.8 * 3f64.sqrt() / 2.0 * size;
+        for (dx, dy) in [
+            (0.0, 0.0),
+            (inside, 0.0),
+            (-inside, 0.0),
+            (0.0, inside),
+            (0.0, -inside),
+        ] {
+            let found = pixel_to_axial(x + dx, y + dy, size);
+            assert!(
+                found == coords,
+                "Pixel ({}, {}) should be in {coords:?}, got {found:?}",
+                x + dx,
+                y + dy
+            );
+        }
+    }
+}
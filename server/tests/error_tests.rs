@@ -0,0 +1,267 @@
+use actix_web::{
+    http::{header, StatusCode},
+    test as actix_test, web,
+};
+use pixelstratwar::{
+    config::GameConfig,
+    coords::AxialCoords,
+    error::GameError,
+    store::{GameStore, RedisHandler, RedisStore},
+    test_utils::{
+        self,
+        utils::{add_test_user, basic_auth, test_app},
+    },
+    user::User,
+};
+use serde_json::{json, Value};
+
+/// Status code and JSON body of a response, the body being `{"error", "message"}`
+async fn error_of(res: actix_web::dev::ServiceResponse) -> (StatusCode, Value) {
+    let status = res.status();
+    let body: Value = actix_test::read_body_json(res).await;
+
+    assert!(
+        body["message"].as_str().is_some_and(|m| !m.is_empty()),
+        "Errors should be explained, got {body}"
+    );
+
+    (status, body)
+}
+
+#[actix_web::test]
+pub async fn unreachable_store_is_unavailable() {
+    let url = "redis://127.0.0.1:1";
+    let store = GameStore::Redis(RedisStore::new(redis::Client::open(url).unwrap()));
+    let pool = deadpool_redis::Config::from_url(url)
+        .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+        .unwrap();
+
+    let app_config = GameConfig::read_config_from_env();
+    let app = actix_test::init_service(test_app(web::Data::new(store), pool, app_config)).await;
+
+    for uri in ["/users", "/leaderboard", "/tiles?batch=0"] {
+        let req = actix_test::TestRequest::get().uri(uri).to_request();
+        let (status, body) = error_of(actix_test::call_service(&app, req).await).await;
+        assert!(
+            status == StatusCode::SERVICE_UNAVAILABLE && body["error"] == "store_unavailable",
+            "{uri} should fail without panicking when redis is down, got {status} {body}"
+        );
+    }
+
+    let req = actix_test::TestRequest::post()
+        .uri("/login")
+        .set_json(json!({ "username": "nobody" }))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::SERVICE_UNAVAILABLE,
+        "Users can't register while redis is down, got {}",
+        res.status()
+    );
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header(basic_auth(&User::new("nobody")))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::SERVICE_UNAVAILABLE,
+        "Credentials can't be checked while redis is down, got {}",
+        res.status()
+    );
+}
+
+/// Address of a server speaking the redis protocol that answers every command with
+/// an error, to test failures happening after the connection succeeded
+async fn failing_redis_url() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(answer_with_errors(socket));
+        }
+    });
+
+    format!("redis://{addr}")
+}
+
+async fn answer_with_errors(mut socket: tokio::net::TcpStream) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut received = Vec::new();
+    let mut buffer = [0; 4096];
+
+    while let Ok(read) = socket.read(&mut buffer).await {
+        if read == 0 {
+            return;
+        }
+        received.extend_from_slice(&buffer[..read]);
+
+        while let Some(length) = command_length(&received) {
+            received.drain(..length);
+            if socket.write_all(b"-ERR failing store\r\n").await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Length in bytes of the first command of `bytes` if it was fully received, commands
+/// being arrays of bulk strings
+fn command_length(bytes: &[u8]) -> Option<usize> {
+    let line = |from: usize| -> Option<(usize, usize)> {
+        let end = from + bytes[from..].windows(2).position(|w| w == b"\r\n")?;
+        let value = std::str::from_utf8(&bytes[from + 1..end])
+            .ok()?
+            .parse()
+            .ok()?;
+        Some((value, end + 2))
+    };
+
+    let (count, mut position) = line(0)?;
+    for _ in 0..count {
+        let (length, data) = line(position)?;
+        position = data + length + 2;
+        if position > bytes.len() {
+            return None;
+        }
+    }
+
+    Some(position)
+}
+
+#[actix_web::test]
+pub async fn failing_tile_reads_are_unavailable() {
+    let url = failing_redis_url().await;
+    let store = GameStore::Redis(RedisStore::new(redis::Client::open(url.as_str()).unwrap()));
+    let pool = deadpool_redis::Config::from_url(url)
+        .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+        .unwrap();
+
+    let app_config = GameConfig::read_config_from_env();
+    let app = actix_test::init_service(test_app(web::Data::new(store), pool, app_config)).await;
+
+    // connections succeed, then reading the tiles fails
+    for uri in ["/tiles?batch=0", "/snapshot.png"] {
+        let req = actix_test::TestRequest::get().uri(uri).to_request();
+        let (status, body) = error_of(actix_test::call_service(&app, req).await).await;
+        assert!(
+            status == StatusCode::SERVICE_UNAVAILABLE && body["error"] == "store_unavailable",
+            "{uri} should not be served from an empty grid when redis fails, got {status} {body}"
+        );
+    }
+}
+
+#[actix_web::test]
+pub async fn request_errors_have_matching_status_codes() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+
+    let app_config = GameConfig {
+        click_cooldown_ms: 60_000,
+        ..GameConfig::read_config_from_env()
+    };
+    let app = actix_test::init_service(test_app(store.clone(), pool.clone(), app_config)).await;
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/100/0")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let (status, body) = error_of(actix_test::call_service(&app, req).await).await;
+    assert!(
+        status == StatusCode::BAD_REQUEST && body["error"] == "invalid_coords",
+        "Clicks out of the grid should be refused, got {status} {body}"
+    );
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header(basic_auth(&User::new("intruder")))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.headers().contains_key(header::WWW_AUTHENTICATE),
+        "Unauthorized responses should ask for credentials"
+    );
+    let (status, body) = error_of(res).await;
+    assert!(
+        status == StatusCode::UNAUTHORIZED && body["error"] == "unauthorized",
+        "Unknown tokens should be refused, got {status} {body}"
+    );
+
+    let click = || {
+        actix_test::TestRequest::post()
+            .uri("/tile/0/0")
+            .insert_header(basic_auth(&user))
+            .to_request()
+    };
+    let res = actix_test::call_service(&app, click()).await;
+    assert!(res.status() == StatusCode::OK, "Got {}", res.status());
+
+    let res = actix_test::call_service(&app, click()).await;
+    assert!(
+        res.headers().contains_key(header::RETRY_AFTER),
+        "Rate limited responses should tell when to retry"
+    );
+    let (status, body) = error_of(res).await;
+    assert!(
+        status == StatusCode::TOO_MANY_REQUESTS && body["error"] == "rate_limited",
+        "Second click should wait for the cooldown, got {status} {body}"
+    );
+
+    let req = actix_test::TestRequest::get()
+        .uri("/users/unknown/rank")
+        .to_request();
+    let (status, body) = error_of(actix_test::call_service(&app, req).await).await;
+    assert!(
+        status == StatusCode::NOT_FOUND && body["error"] == "not_found",
+        "Unknown users have no rank, got {status} {body}"
+    );
+
+    let req = actix_test::TestRequest::get()
+        .uri("/tiles?batch=9999")
+        .to_request();
+    let (status, body) = error_of(actix_test::call_service(&app, req).await).await;
+    assert!(
+        status == StatusCode::NOT_FOUND && body["error"] == "not_found",
+        "Unknown batches should not be computed, got {status} {body}"
+    );
+
+    let req = actix_test::TestRequest::post()
+        .uri("/login")
+        .set_json(json!({ "username": "player" }))
+        .to_request();
+    let (status, body) = error_of(actix_test::call_service(&app, req).await).await;
+    assert!(
+        status == StatusCode::CONFLICT && body["error"] == "conflict",
+        "Usernames should be unique, got {status} {body}"
+    );
+
+    let req = actix_test::TestRequest::post()
+        .uri("/login")
+        .set_json(json!({ "username": "x".repeat(1000) }))
+        .to_request();
+    let (status, body) = error_of(actix_test::call_service(&app, req).await).await;
+    assert!(
+        status == StatusCode::BAD_REQUEST && body["error"] == "invalid_input",
+        "Oversized usernames should be refused, got {status} {body}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[test]
+pub fn store_failures_are_unavailable_errors() {
+    let redis_error = redis::RedisError::from((redis::ErrorKind::IoError, "connection refused"));
+    let error = GameError::from(redis_error);
+
+    assert!(
+        matches!(error, GameError::StoreUnavailable(_)),
+        "Redis failures should be reported as an unavailable store, got {error:?}"
+    );
+    assert!(
+        GameError::InvalidCoords(AxialCoords::new(100, 0)).to_string()
+            == "Coords (100, 0) are out of the grid"
+    );
+}
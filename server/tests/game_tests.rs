@@ -1,8 +1,12 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
+
+use proptest::prelude::*;
 
 use pixelstratwar::{
-    coords::AxialCoords,
-    game::GameData,
+    config::GameConfig,
+    coords::{cube_spiral, AxialCoords, CubeCoords},
+    game::{ClickAction, GameData, InnerTileData, StrengthMode, TileMap},
+    rules::{ClassicRules, GameRules},
     store::RedisHandler,
     test_utils::{self, utils::are_coords_in_vec},
 };
@@ -22,10 +26,10 @@ pub async fn contiguous_neighbors_of_tile_empty() {
         .fetch_within(&mock_redis, &mut con_arc, &coords, &mut prefetch)
         .await
         .unwrap();
-    let (tiles, nb) = game_data.contiguous_neighbors_of_tile(&prefetch, &coords, "toto", 2);
+    let (tiles, nb) = game_data.contiguous_neighbors_of_tile(&prefetch, &coords, "toto", None, 2);
 
     assert!(
-        tiles.len() == 0 && nb == 0,
+        tiles.is_empty() && nb == 0,
         "When user didn't click, it should not have any neighbors tile"
     );
 }
@@ -42,7 +46,7 @@ pub async fn test_fetch_within() {
         .unwrap();
 
     let updated_tiles = game_data
-        .handle_click(&mock_redis, &mut con, &center, "first_user_id")
+        .handle_click(&mock_redis, &mut con, &center, "first_user_id", None)
         .await
         .expect("Should be able to click on (0,0)");
 
@@ -59,6 +63,7 @@ pub async fn test_fetch_within() {
             &mut con,
             &AxialCoords::new(0, 1),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0,1)");
@@ -69,6 +74,7 @@ pub async fn test_fetch_within() {
             &mut con,
             &AxialCoords::new(0, 2),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0, 2)");
@@ -78,6 +84,7 @@ pub async fn test_fetch_within() {
             &mut con,
             &AxialCoords::new(0, 3),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0, 3)");
@@ -93,16 +100,16 @@ pub async fn test_fetch_within() {
         .get(&center)
         .expect("Fetched tiles hashmap should contain (0,0)");
     assert!(
-        center_t.user_id == "first_user_id".to_string() && center_t.damage == 0,
+        center_t.user_id == "first_user_id" && center_t.damage == 0,
         "Prefetched data should contain center with expected data"
     );
 
-    let zero_one_t = prefetched.get(&AxialCoords::new(0, 1)).expect(&format!(
-        "Prefeteched should contain (0,1),\n\tcurrent state: {prefetched:?}"
-    ));
+    let zero_one_t = prefetched.get(&AxialCoords::new(0, 1)).unwrap_or_else(|| {
+        panic!("Prefeteched should contain (0,1),\n\tcurrent state: {prefetched:?}")
+    });
 
     assert!(
-        zero_one_t.user_id == "first_user_id".to_string() && zero_one_t.damage == 0,
+        zero_one_t.user_id == "first_user_id" && zero_one_t.damage == 0,
         "(0, 1) should be owned by first user and have 0 damage, got {zero_one_t:?}"
     );
 
@@ -110,12 +117,12 @@ pub async fn test_fetch_within() {
         .get(&AxialCoords::new(0, 2))
         .expect("Prefetched should contain (0,2)");
     assert!(
-        zero_two_t.user_id == "first_user_id".to_string() && zero_two_t.damage == 0,
+        zero_two_t.user_id == "first_user_id" && zero_two_t.damage == 0,
         "(0,2) should be owned by first user and have 0 damage, got {zero_two_t:?}"
     );
 
     assert!(
-        prefetched.get(&AxialCoords::new(0, 3)).is_none(),
+        !prefetched.contains_key(&AxialCoords::new(0, 3)),
         "Prefeteched hashmap should not contain (0,3) because not in 2 radius"
     );
 
@@ -146,7 +153,7 @@ pub async fn contiguous_neighbors_of_tile_with_clicks() {
     let center = AxialCoords::center();
 
     let updated_tiles = game_data
-        .handle_click(&mock_redis, &mut con, &center, "first_user_id")
+        .handle_click(&mock_redis, &mut con, &center, "first_user_id", None)
         .await
         .expect("Should be able to click on (0,0)");
 
@@ -164,6 +171,7 @@ pub async fn contiguous_neighbors_of_tile_with_clicks() {
             &mut con,
             &AxialCoords::new(0, -1),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0,-1)");
@@ -174,6 +182,7 @@ pub async fn contiguous_neighbors_of_tile_with_clicks() {
             &mut con,
             &AxialCoords::new(0, -2),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0, -2)");
@@ -185,7 +194,7 @@ pub async fn contiguous_neighbors_of_tile_with_clicks() {
         .expect("Should be able to fetch within 2 for (0,0)");
 
     let (contiguous_tiles, nb) =
-        game_data.contiguous_neighbors_of_tile(&prefetched, &center, "first_user_id", 2);
+        game_data.contiguous_neighbors_of_tile(&prefetched, &center, "first_user_id", None, 2);
 
     assert!(
         nb == 2,
@@ -203,6 +212,7 @@ pub async fn contiguous_neighbors_of_tile_with_clicks() {
             &mut con,
             &AxialCoords::new(0, -3),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0, -1)");
@@ -213,6 +223,7 @@ pub async fn contiguous_neighbors_of_tile_with_clicks() {
             &mut con,
             &AxialCoords::new(0, 1),
             "second_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0,1)");
@@ -223,6 +234,7 @@ pub async fn contiguous_neighbors_of_tile_with_clicks() {
             &mut con,
             &AxialCoords::new(1, 0),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (1,0)");
@@ -236,7 +248,7 @@ pub async fn contiguous_neighbors_of_tile_with_clicks() {
         .expect("Should be able to fetch all tiles");
 
     let (tiles, nb) =
-        game_data.contiguous_neighbors_of_tile(&prefetch, &coords, "first_user_id", 2);
+        game_data.contiguous_neighbors_of_tile(&prefetch, &coords, "first_user_id", None, 2);
 
     // check proper tiles in vec
 
@@ -292,6 +304,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::center(),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0,0)");
@@ -302,6 +315,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(1, 0),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (1,0)");
@@ -312,6 +326,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(0, -1),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0, 1)");
@@ -322,6 +337,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(0, -2),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to click on (0, -1)");
@@ -332,6 +348,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(0, -3),
             "first_user_id",
+            None,
         )
         .await
         .unwrap();
@@ -343,6 +360,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(0, 1),
             "second_user_id",
+            None,
         )
         .await
         .unwrap();
@@ -353,6 +371,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(0, 2),
             "second_user_id",
+            None,
         )
         .await
         .unwrap();
@@ -367,7 +386,7 @@ pub async fn game_behavior_taking_ownership() {
         .expect("Should have tile a (0,0)");
 
     assert!(
-        tile_to_check.damage == 0 && tile_to_check.user_id == String::from("first_user_id"),
+        tile_to_check.damage == 0 && tile_to_check.user_id == "first_user_id",
         "(0,0) should be owned by first user and have no damage"
     );
 
@@ -385,8 +404,7 @@ pub async fn game_behavior_taking_ownership() {
         .expect("Failed to compute tile to check");
 
     assert!(
-        computed_tile_to_check.strength == 4
-            && computed_tile_to_check.user_id == "first_user_id".to_string(),
+        computed_tile_to_check.strength == 4 && computed_tile_to_check.user_id == "first_user_id",
         "(0,0) has no damage + 3 contiguous neighbors in a radius of 2 => strengh should eq 4"
     );
 
@@ -398,7 +416,7 @@ pub async fn game_behavior_taking_ownership() {
         .expect("Should find tile at (1,0)");
 
     assert!(
-        tile_to_check.damage == 0 && tile_to_check.user_id == String::from("first_user_id"),
+        tile_to_check.damage == 0 && tile_to_check.user_id == "first_user_id",
         "(1,0) should be owned by first user and have no damage"
     );
 
@@ -410,7 +428,7 @@ pub async fn game_behavior_taking_ownership() {
         .expect("Should find tile at (0, -1)");
 
     assert!(
-        tile_to_check.damage == 0 && tile_to_check.user_id == String::from("first_user_id"),
+        tile_to_check.damage == 0 && tile_to_check.user_id == "first_user_id",
         "(0,-1) should be owned by first user and have no damage"
     );
     // check (0,-2)
@@ -421,7 +439,7 @@ pub async fn game_behavior_taking_ownership() {
         .expect("Should find tile at (0,-2)");
 
     assert!(
-        tile_to_check.damage == 0 && tile_to_check.user_id == String::from("first_user_id"),
+        tile_to_check.damage == 0 && tile_to_check.user_id == "first_user_id",
         "(0,-2) should be owned by first user and have no damage"
     );
     // check (0,-3)
@@ -431,7 +449,7 @@ pub async fn game_behavior_taking_ownership() {
         .unwrap()
         .expect("Should find tile at (0,-3)");
     assert!(
-        tile_to_check.damage == 0 && tile_to_check.user_id == String::from("first_user_id"),
+        tile_to_check.damage == 0 && tile_to_check.user_id == "first_user_id",
         "(0,-3) should be owned by first user and have no damage"
     );
     // check (0,1)
@@ -441,7 +459,7 @@ pub async fn game_behavior_taking_ownership() {
         .unwrap()
         .expect("Should find tile at (0,1)");
     assert!(
-        tile_to_check.damage == 0 && tile_to_check.user_id == String::from("second_user_id"),
+        tile_to_check.damage == 0 && tile_to_check.user_id == "second_user_id",
         "(0,1) should be owned by second user and have no damage"
     );
     // check (0,2)
@@ -451,7 +469,7 @@ pub async fn game_behavior_taking_ownership() {
         .unwrap()
         .expect("should find tile at (0,2)");
     assert!(
-        tile_to_check.damage == 0 && tile_to_check.user_id == String::from("second_user_id"),
+        tile_to_check.damage == 0 && tile_to_check.user_id == "second_user_id",
         "(0,2) should be owned by second user and have no damage"
     );
 
@@ -470,8 +488,7 @@ pub async fn game_behavior_taking_ownership() {
         .unwrap();
 
     assert!(
-        computed_tile_to_check.strength == 2
-            && computed_tile_to_check.user_id == "second_user_id".to_string(),
+        computed_tile_to_check.strength == 2 && computed_tile_to_check.user_id == "second_user_id",
         "(0,2) has no damage + 1 contiguous neighbors in a radius of 2 => strengh should eq 2"
     );
 
@@ -482,6 +499,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(0, 1),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should update tile properly");
@@ -492,11 +510,11 @@ pub async fn game_behavior_taking_ownership() {
         .unwrap()
         .expect("Should find tile at (0,1)");
     assert!(
-        tile_to_check.damage == 1 && tile_to_check.user_id == String::from("second_user_id"),
+        tile_to_check.damage == 1 && tile_to_check.user_id == "second_user_id",
         "(0,1) should still be owned by second user but with 1 damage"
     );
 
-    let (first_updated_tile_coords, first_updated_tile_data) = updated_tiles.get(0).unwrap();
+    let (first_updated_tile_coords, first_updated_tile_data) = updated_tiles.first().unwrap();
     assert!(
         updated_tiles.len() == 1
             && first_updated_tile_coords == &AxialCoords::new(0, 1)
@@ -518,8 +536,7 @@ pub async fn game_behavior_taking_ownership() {
         .await
         .unwrap();
     assert!(
-        computed_tile_to_check.strength == 1
-            && computed_tile_to_check.user_id == "second_user_id".to_string(),
+        computed_tile_to_check.strength == 1 && computed_tile_to_check.user_id == "second_user_id",
         "(0,1) has 1 damage + 1 contiguous neighbors in a radius of 2 => strengh should eq 1"
     );
 
@@ -530,6 +547,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(0, 1),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should update tile properly");
@@ -540,7 +558,7 @@ pub async fn game_behavior_taking_ownership() {
         .unwrap()
         .expect("Should find tile at (0,1)");
     assert!(
-        tile_to_check.damage == 0 && tile_to_check.user_id == String::from("first_user_id"),
+        tile_to_check.damage == 0 && tile_to_check.user_id == "first_user_id",
         "(0,1) should now be owned by first user and have no damage anymore, got {tile_to_check:?}"
     );
 
@@ -550,33 +568,33 @@ pub async fn game_behavior_taking_ownership() {
     // check (0,1) in updated tiles and have proper owner
     match updated_tiles
         .iter()
+        .find(|&(coords, _)| coords == &AxialCoords::new(0, 1))
         .cloned()
-        .find(|(coords, _)| coords == &AxialCoords::new(0, 1))
     {
         Some((_, tile)) => {
             assert!(
-                tile.user_id == "first_user_id".to_string() && tile.strength == 4,
+                tile.user_id == "first_user_id" && tile.strength == 4,
                 "(0,1) should have a strength of 4"
             );
         }
         None => {
-            assert!(false, "(0,1) should be in updated tiles vector");
+            panic!("(0,1) should be in updated tiles vector");
         }
     };
     // check (0,2) in updated tiles, still owned by second user with strength of 1
     match updated_tiles
         .iter()
+        .find(|&(coords, _)| coords == &AxialCoords::new(0, 2))
         .cloned()
-        .find(|(coords, _)| coords == &AxialCoords::new(0, 2))
     {
         Some((_, tile)) => {
             assert!(
-                tile.user_id == "second_user_id".to_string() && tile.strength == 1,
+                tile.user_id == "second_user_id" && tile.strength == 1,
                 "(0,2) should still be owned by second user and have a strength of 1"
             );
         }
         None => {
-            assert!(false, "(0,2) should be in updated tiles vector");
+            panic!("(0,2) should be in updated tiles vector");
         }
     };
 
@@ -588,6 +606,7 @@ pub async fn game_behavior_taking_ownership() {
             &mut con,
             &AxialCoords::new(-2, 0),
             "first_user_id",
+            None,
         )
         .await
         .expect("Should be able to update tiles");
@@ -599,8 +618,8 @@ pub async fn game_behavior_taking_ownership() {
 
     match updated_tiles
         .iter()
+        .find(|&(coords, _)| coords == &AxialCoords::new(-2, 0))
         .cloned()
-        .find(|(coords, _)| coords == &AxialCoords::new(-2, 0))
     {
         Some((_, tile)) => {
             assert!(
@@ -610,7 +629,7 @@ pub async fn game_behavior_taking_ownership() {
         }
 
         None => {
-            assert!(false, "(-2,0) should be in updated tiles");
+            panic!("(-2,0) should be in updated tiles");
         }
     }
 
@@ -640,3 +659,487 @@ pub async fn game_behavior_taking_ownership() {
 
     let _ = mock_redis.flushdb().await.unwrap();
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+/// Hammers a single tile owned by A (surrounded by 18 of its tiles => strength of 19) with
+/// 30 concurrent clicks from B. Each click must see the result of the previous one, so we
+/// expect exactly 18 damages, a single capture and 11 clicks of B on its own tile.
+pub async fn concurrent_clicks_on_same_tile() {
+    let mock_redis = Arc::new(test_utils::mocks::redis_client_or_mock().await.unwrap());
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let center = AxialCoords::center();
+
+    for c in cube_spiral(&center.as_cube(), 2) {
+        mock_redis
+            .set_tile(
+                &mut con,
+                &c.as_axial(),
+                InnerTileData {
+                    user_id: "first_user_id".to_string(),
+                    damage: 0,
+                    team: None,
+                },
+            )
+            .await
+            .unwrap();
+    }
+
+    let tasks: Vec<_> = (0..30)
+        .map(|_| {
+            let mock_redis = mock_redis.clone();
+            tokio::spawn(async move {
+                let mut con = test_utils::mocks::get_connection(&mock_redis)
+                    .await
+                    .unwrap();
+
+                mock_redis
+                    .apply_click(
+                        &mut con,
+                        &center,
+                        "second_user_id",
+                        None,
+                        &ClassicRules::default(),
+                    )
+                    .await
+                    .expect("Should be able to click on (0,0)")
+            })
+        })
+        .collect();
+
+    let mut actions = Vec::new();
+    for task in tasks {
+        actions.push(task.await.unwrap().action);
+    }
+
+    let count = |action: ClickAction| actions.iter().filter(|a| **a == action).count();
+
+    assert!(
+        count(ClickAction::Damage) == 18,
+        "Tile should have been damaged 18 times, got {actions:?}"
+    );
+    assert!(
+        count(ClickAction::Capture) == 1,
+        "Tile should have been captured exactly once, got {actions:?}"
+    );
+    assert!(
+        count(ClickAction::None) == 11,
+        "Remaining clicks should be on an owned and undamaged tile, got {actions:?}"
+    );
+
+    let tile = mock_redis
+        .get_tile(&mut con, &center)
+        .await
+        .unwrap()
+        .expect("Should find tile at (0,0)");
+
+    assert!(
+        tile.user_id == "second_user_id" && tile.damage == 0,
+        "(0,0) should be owned by second user without damage, got {tile:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+/// 1. [A, (0,0)] => A owns 1 tile
+/// 2. [B, (1,0)] => B owns 1 tile
+/// 3. [B, (0,0)] => capture, A owns 0 tile and B owns 2 tiles
+pub async fn tile_counts_follow_ownership() {
+    let game_data = GameData::new(10, 2);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let clicks = [
+        (AxialCoords::center(), "first_user_id"),
+        (AxialCoords::new(1, 0), "second_user_id"),
+    ];
+
+    for (coords, user_id) in clicks.iter() {
+        game_data
+            .handle_click(&mock_redis, &mut con, coords, user_id, None)
+            .await
+            .unwrap();
+    }
+
+    let first_count = mock_redis
+        .count_tiles_by_user(&mut con, "first_user_id")
+        .await
+        .unwrap();
+    let second_count = mock_redis
+        .count_tiles_by_user(&mut con, "second_user_id")
+        .await
+        .unwrap();
+
+    assert!(
+        first_count == 1 && second_count == 1,
+        "Both users should own one tile, got {first_count} and {second_count}"
+    );
+
+    game_data
+        .handle_click(
+            &mock_redis,
+            &mut con,
+            &AxialCoords::center(),
+            "second_user_id",
+            None,
+        )
+        .await
+        .unwrap();
+
+    let first_count = mock_redis
+        .count_tiles_by_user(&mut con, "first_user_id")
+        .await
+        .unwrap();
+    let second_count = mock_redis
+        .count_tiles_by_user(&mut con, "second_user_id")
+        .await
+        .unwrap();
+
+    assert!(
+        first_count == 0 && second_count == 2,
+        "Second user should have captured (0,0), got {first_count} and {second_count}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[test]
+pub fn every_tile_belongs_to_a_batch() {
+    let game_data = GameData::new(10, 3);
+
+    for c in cube_spiral(&CubeCoords::center(), 10) {
+        let coords = c.as_axial();
+
+        assert!(
+            game_data
+                .batch_of(&coords)
+                .is_some_and(|b| b < game_data.batches_count()),
+            "{coords:?} should belong to a batch"
+        );
+    }
+
+    assert!(
+        game_data.batch_of(&AxialCoords::new(11, 0)).is_none(),
+        "Coords out of the grid should not belong to any batch"
+    );
+}
+
+#[tokio::test]
+pub async fn teammates_share_territory_strength() {
+    let game_data = GameData::new(10, 2).with_strength_mode(StrengthMode::Team);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let center = AxialCoords::center();
+    let clicks = [
+        (center, "red_a", "red"),
+        (AxialCoords::new(1, 0), "red_b", "red"),
+    ];
+    for (coords, user_id, team) in clicks {
+        game_data
+            .handle_click(&mock_redis, &mut con, &coords, user_id, Some(team))
+            .await
+            .unwrap();
+    }
+
+    let tile = mock_redis.get_tile(&mut con, &center).await.unwrap();
+    assert!(
+        tile.is_some_and(|t| t.team.as_deref() == Some("red")),
+        "Tiles should belong to the team of their owner"
+    );
+
+    // the tile of red_b holds (0,0), a single click only damages it
+    let updated = game_data
+        .handle_click(&mock_redis, &mut con, &center, "blue_a", Some("blue"))
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.user_id == "red_a" && tile.strength == 1,
+        "Teammate tiles should add up to the strength of a tile, got {tile:?}"
+    );
+
+    // red_b repairs the tile of its teammate instead of damaging it
+    let updated = game_data
+        .handle_click(&mock_redis, &mut con, &center, "red_b", Some("red"))
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.user_id == "red_a" && tile.strength == 2,
+        "Teammates should repair each other's tiles, got {tile:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn teams_are_ignored_in_solo_mode() {
+    let game_data = GameData::new(10, 2);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let center = AxialCoords::center();
+    let clicks = [
+        (center, "red_a", "red"),
+        (AxialCoords::new(1, 0), "red_b", "red"),
+    ];
+    for (coords, user_id, team) in clicks {
+        game_data
+            .handle_click(&mock_redis, &mut con, &coords, user_id, Some(team))
+            .await
+            .unwrap();
+    }
+
+    // red_b is on its own, its click captures the lone tile of red_a
+    let updated = game_data
+        .handle_click(&mock_redis, &mut con, &center, "red_b", Some("red"))
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.user_id == "red_b",
+        "Teams should not protect tiles in solo mode, got {tile:?}"
+    );
+
+    let tile = mock_redis.get_tile(&mut con, &center).await.unwrap();
+    assert!(
+        tile.is_some_and(|t| t.team.is_none()),
+        "Tiles should not record teams in solo mode"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+/// A owns (0,0) damaged twice, (1,0) and (2,0) => (0,0) has a strength of 1.
+/// B captures (1,0), (0,0) loses its neighbors while its damage is above its new
+/// strength: it must be clamped so that (0,0) still needs one click to be captured.
+pub async fn captured_neighbors_clamp_damage() {
+    let game_data = GameData::new(10, 2);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let center = AxialCoords::center();
+    let neighbor = AxialCoords::new(1, 0);
+
+    for (coords, damage) in [(center, 2), (neighbor, 0), (AxialCoords::new(2, 0), 0)] {
+        mock_redis
+            .set_tile(
+                &mut con,
+                &coords,
+                InnerTileData {
+                    user_id: "first_user_id".to_string(),
+                    damage,
+                    team: None,
+                },
+            )
+            .await
+            .unwrap();
+    }
+
+    let mut updated = Vec::new();
+    for _ in 0..3 {
+        updated = game_data
+            .handle_click(&mock_redis, &mut con, &neighbor, "second_user_id", None)
+            .await
+            .expect("Should be able to click on (1,0)");
+    }
+
+    let (_, tile) = are_coords_in_vec(&updated, &neighbor).unwrap();
+    assert!(
+        tile.user_id == "second_user_id",
+        "(1,0) should have been captured, got {tile:?}"
+    );
+
+    let (_, tile) = are_coords_in_vec(&updated, &center).expect("(0,0) lost a neighbor");
+    assert!(
+        tile.user_id == "first_user_id" && tile.strength == 1,
+        "(0,0) should keep a strength of 1 after losing its neighbors, got {tile:?}"
+    );
+
+    // repairing starts from the clamped damage
+    let updated = game_data
+        .handle_click(&mock_redis, &mut con, &center, "first_user_id", None)
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    let stored = mock_redis.get_tile(&mut con, &center).await.unwrap();
+    assert!(
+        tile.strength == 1 && stored.is_some_and(|t| t.damage == 0),
+        "Repair should start from the clamped damage, got {tile:?}"
+    );
+
+    let updated = game_data
+        .handle_click(&mock_redis, &mut con, &center, "second_user_id", None)
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.user_id == "second_user_id",
+        "A tile of strength 1 should be captured in one click, got {tile:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+/// A owns (0,0) with 6 neighbors, damaged 5 times by B. A loses 4 neighbors to C:
+/// (0,0) drops from a strength of 2 to 1 and B captures it with its next click.
+pub async fn lost_neighbors_never_leave_a_tile_without_strength() {
+    let game_data = GameData::new(10, 2);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let center = AxialCoords::center();
+    for c in cube_spiral(&center.as_cube(), 1) {
+        game_data
+            .handle_click(&mock_redis, &mut con, &c.as_axial(), "first_user_id", None)
+            .await
+            .unwrap();
+    }
+
+    for _ in 0..5 {
+        game_data
+            .handle_click(&mock_redis, &mut con, &center, "second_user_id", None)
+            .await
+            .unwrap();
+    }
+
+    // C captures 4 neighbors, (0,0) is left with the 2 others
+    let lost = [
+        AxialCoords::new(1, 0),
+        AxialCoords::new(1, -1),
+        AxialCoords::new(0, -1),
+        AxialCoords::new(-1, 0),
+    ];
+    for coords in lost {
+        let mut captured = false;
+        for _ in 0..u8::MAX {
+            let updated = game_data
+                .handle_click(&mock_redis, &mut con, &coords, "third_user_id", None)
+                .await
+                .expect("Losing neighbors should not break the damaged center");
+
+            if let Some((_, tile)) = are_coords_in_vec(&updated, &center) {
+                assert!(
+                    tile.strength >= 1,
+                    "(0,0) should always need a click to be captured, got {tile:?}"
+                );
+            }
+
+            let tile = mock_redis.get_tile(&mut con, &coords).await.unwrap();
+            if tile.is_some_and(|t| t.user_id == "third_user_id") {
+                captured = true;
+                break;
+            }
+        }
+        assert!(captured, "{coords:?} should have been captured");
+    }
+
+    let mut prefetched = TileMap::new();
+    let tile = mock_redis
+        .get_tile(&mut con, &center)
+        .await
+        .unwrap()
+        .unwrap();
+    let computed = game_data
+        .computed_tile(&mock_redis, &mut con, &center, &tile, &mut prefetched)
+        .await
+        .unwrap();
+    assert!(
+        tile.damage == 5 && computed.strength == 1,
+        "(0,0) with 2 neighbors left and 5 damages should have a strength of 1, got {computed:?}"
+    );
+
+    let outcome = mock_redis
+        .apply_click(
+            &mut con,
+            &center,
+            "second_user_id",
+            None,
+            &ClassicRules::default(),
+        )
+        .await
+        .unwrap();
+    assert!(
+        outcome.action == ClickAction::Capture,
+        "Next click should capture (0,0), got {outcome:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn strength_is_capped_by_config() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+    let config = GameConfig {
+        max_strength: 3,
+        use_benchmark_data: false,
+        ..GameConfig::read_config_from_env()
+    };
+    let game_data = GameData::init_from_config(&mut con, &mock_redis, &config).await;
+
+    let center = AxialCoords::center();
+    let mut updated = Vec::new();
+    for c in cube_spiral(&center.as_cube(), 1) {
+        updated = game_data
+            .handle_click(&mock_redis, &mut con, &c.as_axial(), "first_user_id", None)
+            .await
+            .unwrap();
+    }
+
+    assert!(
+        updated.iter().all(|(_, tile)| tile.strength == 3),
+        "Every tile should be capped to a strength of 3, got {updated:?}"
+    );
+
+    for _ in 0..2 {
+        game_data
+            .handle_click(&mock_redis, &mut con, &center, "second_user_id", None)
+            .await
+            .unwrap();
+    }
+    let updated = game_data
+        .handle_click(&mock_redis, &mut con, &center, "second_user_id", None)
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.user_id == "second_user_id",
+        "A capped tile should be captured in 3 clicks, got {tile:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+proptest! {
+    #[test]
+    fn strength_stays_between_one_and_the_cap(
+        max_strength in 1..=u8::MAX,
+        allies in any::<u8>(),
+        damage in any::<u8>(),
+    ) {
+        let rules = ClassicRules { max_strength, ..ClassicRules::default() };
+        let strength = rules.strength(allies, damage);
+
+        prop_assert!((1..=max_strength).contains(&strength));
+        prop_assert!(strength <= rules.full_strength(allies));
+    }
+}
@@ -0,0 +1,344 @@
+use std::sync::Arc;
+
+use pixelstratwar::{
+    coords::{cube_spiral, AxialCoords},
+    game::{GameData, InnerTileData},
+    grid_state::GridState,
+    rules::ClassicRules,
+    store::{RedisHandler, RedisStore},
+    test_utils,
+};
+
+/// Connection whose pipelines fail, so that reading tiles fails while single
+/// commands, like the script writing tiles, succeed. Keeps the commands it received.
+#[derive(Default)]
+struct FailingReads {
+    commands: Vec<String>,
+}
+
+impl redis::aio::ConnectionLike for FailingReads {
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        self.commands
+            .push(String::from_utf8_lossy(&cmd.get_packed_command()).into_owned());
+        Box::pin(async { Ok(redis::Value::Int(1)) })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        _cmd: &'a redis::Pipeline,
+        _offset: usize,
+        _count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        Box::pin(async {
+            Err(redis::RedisError::from((
+                redis::ErrorKind::IoError,
+                "connection reset",
+            )))
+        })
+    }
+}
+
+/// Connection reading empty tiles and accepting every write, keeps the commands it
+/// received in a log shared by its clones
+#[derive(Clone, Default)]
+struct RecordingConnection {
+    commands: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl redis::aio::ConnectionLike for RecordingConnection {
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        self.commands
+            .lock()
+            .unwrap()
+            .push(String::from_utf8_lossy(&cmd.get_packed_command()).into_owned());
+        Box::pin(async { Ok(redis::Value::Int(1)) })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        _cmd: &'a redis::Pipeline,
+        _offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        Box::pin(async move { Ok(vec![redis::Value::Array(Vec::new()); count]) })
+    }
+}
+
+#[tokio::test]
+pub async fn grid_state_loads_from_backing_store() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    mock_redis
+        .set_tile(
+            &mut con,
+            &AxialCoords::new(1, -1),
+            InnerTileData {
+                user_id: "first_user_id".to_string(),
+                damage: 1,
+                team: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let state = GridState::new(mock_redis, 10);
+    let nb_loaded = state.load(&mut con).await.unwrap();
+
+    assert!(nb_loaded == 1, "Should load one tile, loaded {nb_loaded}");
+
+    let tile = state
+        .get_tile(&mut con, &AxialCoords::new(1, -1))
+        .await
+        .unwrap()
+        .expect("Should find tile at (1,-1)");
+
+    assert!(
+        tile.user_id == "first_user_id" && tile.damage == 1,
+        "(1,-1) should be loaded as is from the backing store, got {tile:?}"
+    );
+
+    let _ = state.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn grid_state_writes_behind() {
+    let game_data = GameData::new(10, 2);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let state = GridState::new(mock_redis, 10);
+
+    game_data
+        .handle_click(
+            &state,
+            &mut con,
+            &AxialCoords::center(),
+            "first_user_id",
+            None,
+        )
+        .await
+        .expect("Should be able to click on (0,0)");
+
+    game_data
+        .handle_click(
+            &state,
+            &mut con,
+            &AxialCoords::new(0, 1),
+            "first_user_id",
+            None,
+        )
+        .await
+        .expect("Should be able to click on (0,1)");
+
+    let count = state
+        .count_tiles_by_user(&mut con, "first_user_id")
+        .await
+        .unwrap();
+    assert!(count == 2, "First user should own 2 tiles, got {count}");
+
+    assert!(
+        state
+            .backing()
+            .get_tile(&mut con, &AxialCoords::center())
+            .await
+            .unwrap()
+            .is_none(),
+        "Clicks should not reach the backing store before a flush"
+    );
+
+    let nb_flushed = state.flush(&mut con).await.unwrap();
+    assert!(
+        nb_flushed == 2,
+        "Should flush 2 dirty tiles, flushed {nb_flushed}"
+    );
+
+    let nb_flushed = state.flush(&mut con).await.unwrap();
+    assert!(
+        nb_flushed == 0,
+        "Nothing changed since last flush, flushed {nb_flushed}"
+    );
+
+    let tile = state
+        .backing()
+        .get_tile(&mut con, &AxialCoords::new(0, 1))
+        .await
+        .unwrap()
+        .expect("Backing store should contain (0,1) after a flush");
+
+    assert!(
+        tile.user_id == "first_user_id" && tile.damage == 0,
+        "(0,1) should be owned by first user, got {tile:?}"
+    );
+
+    let _ = state.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn grid_state_is_kept_when_loading_fails() {
+    let client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+    let state = GridState::new(RedisStore::new(client), 10);
+    let mut con = FailingReads::default();
+    let coords = AxialCoords::new(1, -1);
+
+    let switched = state.use_round(&mut con, 1).await;
+    assert!(
+        switched.is_err() && state.backing().round() == 0,
+        "Round should not change when its grid can't be loaded, got {switched:?}"
+    );
+
+    state
+        .set_tile(
+            &mut con,
+            &coords,
+            InnerTileData {
+                user_id: "first_user_id".to_string(),
+                damage: 0,
+                team: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    assert!(
+        state.load(&mut con).await.is_err(),
+        "Loading should fail when tiles can't be read"
+    );
+    assert!(
+        state.get_tile(&mut con, &coords).await.unwrap().is_some(),
+        "Failed load should not empty the grid"
+    );
+
+    let switched = state.use_round(&mut con, 2).await;
+    assert!(
+        switched.is_err() && state.backing().round() == 0,
+        "Round should not change when its grid can't be loaded, got {switched:?}"
+    );
+    assert!(
+        state.get_tile(&mut con, &coords).await.unwrap().is_some(),
+        "Grid should be kept when the next round can't be loaded"
+    );
+    assert!(
+        con.commands.iter().any(|c| c.contains("tile:0:1_m1")),
+        "Dirty tiles should be written to their own round, got {:?}",
+        con.commands
+    );
+}
+
+#[tokio::test]
+pub async fn grid_state_counts_tiles_on_ownership_changes() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+    let owned_by = |user_id: &str| InnerTileData {
+        user_id: user_id.to_string(),
+        damage: 0,
+        team: None,
+    };
+
+    let state = GridState::new(mock_redis, 10);
+
+    state
+        .batch_set_tiles(
+            &mut con,
+            vec![
+                (AxialCoords::new(0, 0), owned_by("user_a")),
+                (AxialCoords::new(0, 1), owned_by("user_a")),
+                (AxialCoords::new(1, 0), owned_by("user_b")),
+            ],
+        )
+        .await
+        .unwrap();
+
+    // captured tile
+    state
+        .set_tile(&mut con, &AxialCoords::new(0, 1), owned_by("user_b"))
+        .await
+        .unwrap();
+    // same owner
+    state
+        .set_tile(&mut con, &AxialCoords::new(1, 0), owned_by("user_b"))
+        .await
+        .unwrap();
+
+    for (user_id, expected) in [("user_a", 1), ("user_b", 2), ("user_c", 0)] {
+        let count = state.count_tiles_by_user(&mut con, user_id).await.unwrap();
+        assert!(
+            count == expected,
+            "{user_id} should own {expected} tiles, got {count}"
+        );
+    }
+
+    state.flush(&mut con).await.unwrap();
+    state.load(&mut con).await.unwrap();
+
+    let count = state.count_tiles_by_user(&mut con, "user_b").await.unwrap();
+    assert!(
+        count == 2,
+        "Counts should be rebuilt from the loaded tiles, got {count}"
+    );
+
+    let _ = state.flushdb().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+pub async fn grid_state_flushes_clicks_to_their_round() {
+    let client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+    let state = Arc::new(GridState::new(RedisStore::new(client), 10));
+    let con = RecordingConnection::default();
+    let clicked: Vec<AxialCoords> = cube_spiral(&AxialCoords::center().as_cube(), 10)
+        .iter()
+        .map(|c| c.as_axial())
+        .collect();
+
+    // every round is loaded empty, clicks of a round are only kept by the flush of `use_round`
+    let rounds = tokio::spawn({
+        let (state, mut con) = (state.clone(), con.clone());
+        async move {
+            for round_id in 1..=100 {
+                state.use_round(&mut con, round_id).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+
+    let rules = ClassicRules::default();
+    for coords in &clicked {
+        state
+            .apply_click(&mut con.clone(), coords, "first_user_id", None, &rules)
+            .await
+            .unwrap();
+    }
+    rounds.await.unwrap();
+    state.flush(&mut con.clone()).await.unwrap();
+
+    let commands = con.commands.lock().unwrap();
+    for coords in &clicked {
+        let key_suffix = format!(":{}", coords.as_redis_key());
+        assert!(
+            commands.iter().any(|c| c
+                .split("\r\n")
+                .any(|part| part.starts_with("tile:") && part.ends_with(&key_suffix))),
+            "Click on {coords:?} should be saved to the round it was played in"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use pixelstratwar::{
+    coords::{cube_spiral, AxialCoords, CubeCoords},
+    game::{ClickAction, GameData, InnerTileData, TileMap},
+    grid_state::GridState,
+    history::{now_ms, replay, ClickEvent},
+    rules::ClassicRules,
+    store::RedisHandler,
+    test_utils::{
+        self,
+        mocks::{MockRedisConnection, MockRedisHandler},
+    },
+};
+
+async fn grid<R, C>(store: &R, con: &mut C) -> TileMap
+where
+    R: RedisHandler,
+    C: redis::aio::ConnectionLike + Send,
+{
+    let coords = cube_spiral(&CubeCoords::center(), 10)
+        .iter()
+        .map(|c| c.as_axial())
+        .collect();
+
+    store
+        .batch_get_tiles(con, coords)
+        .await
+        .unwrap()
+        .into_iter()
+        .collect()
+}
+
+#[tokio::test]
+pub async fn replay_rebuilds_grid_at_any_time() {
+    let store = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&store).await.unwrap();
+    let game_data = GameData::new(10, 2);
+
+    let a = AxialCoords::new(0, 0);
+    let b = AxialCoords::new(1, 0);
+
+    for (coords, user_id) in [(a, "user_a"), (b, "user_a"), (a, "user_a")] {
+        game_data
+            .handle_click(&store, &mut con, &coords, user_id, None)
+            .await
+            .unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let middle_ms = now_ms();
+    let middle_grid = grid(&store, &mut con).await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    // damages then captures `b`, which leaves `a` alone and captured at once
+    for (coords, user_id) in [(b, "user_b"), (b, "user_b"), (a, "user_b")] {
+        game_data
+            .handle_click(&store, &mut con, &coords, user_id, None)
+            .await
+            .unwrap();
+    }
+
+    let events = store.get_click_events(&mut con, u64::MAX).await.unwrap();
+    let actions: Vec<ClickAction> = events.iter().map(|e| e.action).collect();
+    assert!(
+        actions
+            == [
+                ClickAction::Create,
+                ClickAction::Create,
+                ClickAction::Damage,
+                ClickAction::Capture,
+                ClickAction::Capture,
+            ],
+        "Clicks changing nothing should not be recorded, got {actions:?}"
+    );
+
+    let full = replay(&events, u64::MAX);
+    assert!(
+        full.applied == 5 && full.diverged.is_empty(),
+        "Every event should be replayed as recorded, diverged: {:?}",
+        full.diverged
+    );
+    assert!(
+        full.tiles == grid(&store, &mut con).await,
+        "Replaying the whole history should give the current grid"
+    );
+
+    let middle = replay(&events, middle_ms);
+    assert!(
+        middle.applied == 2 && middle.tiles == middle_grid,
+        "Replaying until a time should give the grid at that time, got {:?}",
+        middle.tiles
+    );
+
+    let partial = store.get_click_events(&mut con, middle_ms).await.unwrap();
+    assert!(
+        partial == events[..2],
+        "Reading the history until a time should stop at that time"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[test]
+pub fn replay_reports_diverging_events() {
+    let coords = AxialCoords::new(0, 0);
+    let event = |action, user_id: &str, damage| ClickEvent {
+        timestamp_ms: 1,
+        user_id: "user_a".to_string(),
+        team: None,
+        coords,
+        action,
+        previous_owner: None,
+        tile: InnerTileData {
+            user_id: user_id.to_string(),
+            damage,
+            team: None,
+        },
+    };
+
+    let events = [
+        event(ClickAction::Create, "user_a", 0),
+        // own undamaged tile, the rules do not repair anything
+        event(ClickAction::Repair, "user_a", 1),
+    ];
+
+    let replayed = replay(&events, u64::MAX);
+    assert!(
+        replayed.diverged == events[1..],
+        "Events the rules resolve differently should be reported, got {:?}",
+        replayed.diverged
+    );
+    assert!(
+        replayed.tiles.get(&coords).is_some_and(|t| t.damage == 0),
+        "Replay should follow the rules rather than the recorded outcome"
+    );
+}
+
+#[tokio::test]
+pub async fn grid_state_appends_click_events_on_flush() {
+    let state = GridState::new(MockRedisHandler::new(), 10);
+    let mut con = MockRedisConnection::new();
+
+    let a = AxialCoords::new(0, 0);
+    state
+        .apply_click(&mut con, &a, "user_a", None, &ClassicRules::default())
+        .await
+        .unwrap();
+    state
+        .apply_click(&mut con, &a, "user_b", None, &ClassicRules::default())
+        .await
+        .unwrap();
+
+    assert!(
+        state.backing().mock_events.read().await.is_empty(),
+        "Events should not reach the backing store before a flush"
+    );
+
+    let events = state.get_click_events(&mut con, u64::MAX).await.unwrap();
+    assert!(
+        events.len() == 2,
+        "Pending events should be part of the history, got {events:?}"
+    );
+
+    state.flush(&mut con).await.unwrap();
+
+    let flushed = state.backing().mock_events.read().await.clone();
+    assert!(
+        flushed == events,
+        "Flush should append pending events in the order clicks were applied, got {flushed:?}"
+    );
+    assert!(
+        state.get_click_events(&mut con, u64::MAX).await.unwrap() == events,
+        "Flushed events should not be read twice"
+    );
+}
@@ -0,0 +1,313 @@
+use actix_web::{http::StatusCode, test as actix_test, web};
+use pixelstratwar::{
+    config::GameConfig,
+    coords::AxialCoords,
+    game::GameData,
+    grid_state::GridState,
+    leaderboard::LeaderboardWatcher,
+    protocol::ServerMessage,
+    store::RedisHandler,
+    test_utils::{
+        self,
+        utils::{basic_auth, test_app},
+    },
+    user::{rank_public_users, RankedUser, TeamScore, User},
+};
+use serde_json::json;
+
+#[tokio::test]
+/// 1. [A, (0,0)], [A, (1,0)], [A, (2,0)] => A owns 3 tiles
+/// 2. [B, (0,2)], [B, (1,2)] => B owns 2 tiles
+/// 3. [C, (-3,0)] => C owns 1 tile
+/// 4. D never clicks => not ranked
+pub async fn leaderboard_ranks_users_by_tiles() {
+    let game_data = GameData::new(10, 2);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let users: Vec<User> = ["a", "b", "c", "d"].iter().map(|u| User::new(u)).collect();
+
+    for user in users.iter() {
+        mock_redis
+            .add_user(&mut con, user.clone(), test_utils::mocks::TOKEN_TTL)
+            .await
+            .unwrap();
+    }
+
+    let clicks = [
+        (AxialCoords::new(0, 0), &users[0]),
+        (AxialCoords::new(1, 0), &users[0]),
+        (AxialCoords::new(2, 0), &users[0]),
+        (AxialCoords::new(0, 2), &users[1]),
+        (AxialCoords::new(1, 2), &users[1]),
+        (AxialCoords::new(-3, 0), &users[2]),
+    ];
+
+    for (coords, user) in clicks.iter() {
+        game_data
+            .handle_click(&mock_redis, &mut con, coords, &user.id, None)
+            .await
+            .unwrap();
+    }
+
+    let ranking = mock_redis.get_leaderboard(&mut con, 0, 10).await.unwrap();
+
+    let ranked: Vec<(u32, &str, u32)> = ranking
+        .iter()
+        .map(|r| (r.rank, r.user.id.as_str(), r.user.score))
+        .collect();
+
+    assert!(
+        ranked
+            == vec![
+                (1, users[0].id.as_str(), 3),
+                (2, users[1].id.as_str(), 2),
+                (3, users[2].id.as_str(), 1),
+            ],
+        "Leaderboard should rank a, b then c and leave d out, got {ranked:?}"
+    );
+
+    let page = mock_redis.get_leaderboard(&mut con, 1, 1).await.unwrap();
+    assert!(
+        page.len() == 1 && page[0].rank == 2 && page[0].user.id == users[1].id,
+        "Second page of size 1 should only contain b ranked 2nd, got {page:?}"
+    );
+
+    let rank = mock_redis
+        .get_user_rank(&mut con, &users[2].id)
+        .await
+        .unwrap()
+        .expect("c should be ranked");
+    assert!(
+        rank.rank == 3 && rank.user.score == 1,
+        "c should be ranked 3rd with a score of 1, got {rank:?}"
+    );
+
+    let rank = mock_redis
+        .get_user_rank(&mut con, &users[3].id)
+        .await
+        .unwrap();
+    assert!(rank.is_none(), "d should not be ranked, got {rank:?}");
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn grid_state_ranks_users_from_its_counts() {
+    let game_data = GameData::new(10, 2);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let users: Vec<User> = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|u| User::new(u))
+        .collect();
+
+    for user in users.iter() {
+        mock_redis
+            .add_user(&mut con, user.clone(), test_utils::mocks::TOKEN_TTL)
+            .await
+            .unwrap();
+    }
+
+    let state = GridState::new(mock_redis, 10);
+
+    // b and c tie, e captures one of the tiles of a
+    let clicks = [
+        (AxialCoords::new(0, 0), &users[0]),
+        (AxialCoords::new(1, 0), &users[0]),
+        (AxialCoords::new(2, 0), &users[0]),
+        (AxialCoords::new(0, 2), &users[1]),
+        (AxialCoords::new(-3, 0), &users[2]),
+        (AxialCoords::new(5, -5), &users[4]),
+        (AxialCoords::new(5, -5), &users[4]),
+        (AxialCoords::new(2, 0), &users[4]),
+        (AxialCoords::new(2, 0), &users[4]),
+    ];
+
+    for (coords, user) in clicks.iter() {
+        game_data
+            .handle_click(&state, &mut con, coords, &user.id, None)
+            .await
+            .unwrap();
+    }
+
+    let ranked = |ranking: &[RankedUser]| -> Vec<(u32, String, u32)> {
+        ranking
+            .iter()
+            .map(|r| (r.rank, r.user.id.clone(), r.user.score))
+            .collect()
+    };
+
+    let public_users = state.get_public_users(&mut con).await.unwrap();
+    let expected = ranked(&rank_public_users(public_users));
+
+    let ranking = ranked(&state.get_leaderboard(&mut con, 0, 10).await.unwrap());
+    assert!(
+        ranking == expected,
+        "Ranking from the counts should match the one of every user, got {ranking:?} instead of {expected:?}"
+    );
+
+    let page = ranked(&state.get_leaderboard(&mut con, 1, 2).await.unwrap());
+    assert!(
+        page == expected[1..3],
+        "Page should be a slice of the ranking, got {page:?}"
+    );
+
+    for expected_rank in expected.iter() {
+        let rank = state
+            .get_user_rank(&mut con, &expected_rank.1)
+            .await
+            .unwrap()
+            .map(|r| ranked(&[r]).remove(0));
+        assert!(
+            rank.as_ref() == Some(expected_rank),
+            "Rank should be {expected_rank:?}, got {rank:?}"
+        );
+    }
+
+    let rank = state.get_user_rank(&mut con, &users[3].id).await.unwrap();
+    assert!(rank.is_none(), "d owns no tile, got {rank:?}");
+
+    let _ = state.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn leaderboard_watcher_detects_shifts() {
+    let game_data = GameData::new(10, 2);
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let first = User::new("first");
+    let second = User::new("second");
+    mock_redis
+        .add_user(&mut con, first.clone(), test_utils::mocks::TOKEN_TTL)
+        .await
+        .unwrap();
+    mock_redis
+        .add_user(&mut con, second.clone(), test_utils::mocks::TOKEN_TTL)
+        .await
+        .unwrap();
+
+    let watcher = LeaderboardWatcher::new(1);
+
+    for coords in [AxialCoords::new(0, 0), AxialCoords::new(1, 0)] {
+        game_data
+            .handle_click(&mock_redis, &mut con, &coords, &first.id, None)
+            .await
+            .unwrap();
+    }
+
+    let top = mock_redis.get_leaderboard(&mut con, 0, 1).await.unwrap();
+    assert!(watcher.has_changed(&top), "First ranking should be pushed");
+    assert!(
+        !watcher.has_changed(&top),
+        "Same ranking should not be pushed twice"
+    );
+
+    // second user clicks far away, its score stays below first user's one
+    game_data
+        .handle_click(
+            &mock_redis,
+            &mut con,
+            &AxialCoords::new(5, 0),
+            &second.id,
+            None,
+        )
+        .await
+        .unwrap();
+
+    let top = mock_redis.get_leaderboard(&mut con, 0, 1).await.unwrap();
+    assert!(
+        !watcher.has_changed(&top),
+        "Top 1 did not change, got {top:?}"
+    );
+
+    let message = ServerMessage::leaderboard(&top).encode().unwrap();
+    let id_len = first.id.len();
+    assert!(
+        message[0] == 0x04
+            && message[1] == 1
+            && message[2] as usize == id_len
+            && &message[3..3 + id_len] == first.id.as_bytes()
+            && message[3 + id_len..] == 2u32.to_le_bytes(),
+        "Leaderboard message should encode a single entry for first user, got {message:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn team_leaderboard_sums_members_scores() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let app_config = GameConfig {
+        teams: vec!["red".to_string(), "blue".to_string()],
+        click_cooldown_ms: 0,
+        ..GameConfig::read_config_from_env()
+    };
+    let app = actix_test::init_service(test_app(store.clone(), pool.clone(), app_config)).await;
+
+    let req = actix_test::TestRequest::post()
+        .uri("/login")
+        .set_json(json!({ "username": "yellow_a", "team": "yellow" }))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::BAD_REQUEST,
+        "Teams outside of the configured ones should be refused, got {}",
+        res.status()
+    );
+
+    let mut users = Vec::new();
+    for (username, team) in [("red_a", "red"), ("red_b", "red"), ("blue_a", "blue")] {
+        let req = actix_test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({ "username": username, "team": team }))
+            .to_request();
+        let user: User = actix_test::call_and_read_body_json(&app, req).await;
+        assert!(
+            user.team.as_deref() == Some(team),
+            "User should join its team, got {:?}",
+            user.team
+        );
+        users.push(user);
+    }
+
+    let clicks = [
+        ("/tile/0/0", &users[0]),
+        ("/tile/0/2", &users[1]),
+        ("/tile/5/0", &users[2]),
+        ("/tile/6/0", &users[2]),
+        ("/tile/-5/0", &users[2]),
+    ];
+    for (uri, user) in clicks {
+        let req = actix_test::TestRequest::post()
+            .uri(uri)
+            .insert_header(basic_auth(user))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert!(res.status().is_success(), "Got {}", res.status());
+    }
+
+    let req = actix_test::TestRequest::get()
+        .uri("/leaderboard/teams")
+        .to_request();
+    let teams: Vec<TeamScore> = actix_test::call_and_read_body_json(&app, req).await;
+    let ranked: Vec<(u32, &str, u32, u32)> = teams
+        .iter()
+        .map(|t| (t.rank, t.team.as_str(), t.score, t.members))
+        .collect();
+    assert!(
+        ranked == [(1, "blue", 3, 1), (2, "red", 2, 2)],
+        "Teams should be ranked by the tiles of their members, got {ranked:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
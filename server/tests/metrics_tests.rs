@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use futures::StreamExt;
+use pixelstratwar::{
+    config::GameConfig,
+    metrics::{ActiveUsers, ACTIVE_USER_WINDOW},
+    store::RedisHandler,
+    test_utils::{
+        self,
+        utils::{add_test_user, basic_auth, test_app},
+    },
+};
+
+#[actix_web::test]
+pub async fn metrics_describe_clicks_batches_and_clients() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let first = add_test_user(&store, &pool, "first").await;
+    let second = add_test_user(&store, &pool, "second").await;
+
+    let app_config = GameConfig {
+        click_cooldown_ms: 60_000,
+        ..GameConfig::read_config_from_env()
+    };
+    let app_store = store.clone();
+    let app_pool = pool.clone();
+    let mut srv = actix_test::start(move || {
+        test_app(app_store.clone(), app_pool.clone(), app_config.clone())
+    });
+
+    // created by the first user, captured by the second one then refused by the cooldown
+    for user in [&first, &second, &second] {
+        srv.post("/tile/0/0")
+            .insert_header(basic_auth(user))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    let res = srv.get("/tiles?batch=0").send().await.unwrap();
+    assert!(res.status().is_success());
+
+    let mut framed = srv.ws_at("/ws").await.unwrap();
+    assert!(framed.next().await.is_some(), "Websocket should say hello");
+
+    let mut res = srv.get("/metrics").send().await.unwrap();
+    assert!(res.status().is_success());
+    let body = String::from_utf8(res.body().await.unwrap().to_vec()).unwrap();
+
+    for expected in [
+        "pixelstratwar_click_duration_seconds_count{outcome=\"create\"} 1",
+        "pixelstratwar_click_duration_seconds_count{outcome=\"capture\"} 1",
+        "pixelstratwar_click_duration_seconds_count{outcome=\"rate_limited\"} 1",
+        "pixelstratwar_redis_round_trips_count{request=\"click\"} 3",
+        "pixelstratwar_redis_round_trips_count{request=\"batch\"} 1",
+        "pixelstratwar_batch_duration_seconds_count 1",
+        "pixelstratwar_websocket_clients 1",
+        "pixelstratwar_active_users 2",
+        "pixelstratwar_broadcast_clients{shard=\"0\"}",
+    ] {
+        assert!(
+            body.lines().any(|line| line.starts_with(expected)),
+            "Metrics should contain `{expected}`, got:\n{body}"
+        );
+    }
+
+    store.flushdb().await.unwrap();
+}
+
+#[test]
+pub fn active_users_are_pruned_without_rendering() {
+    let start = Instant::now();
+    let mut active_users = ActiveUsers::new(start);
+
+    for user in 0..100 {
+        active_users.click(&format!("user_{user}"), start);
+    }
+    active_users.click("user_0", start + ACTIVE_USER_WINDOW / 2);
+    assert!(
+        active_users.len() == 100,
+        "Users should be kept within the window, got {}",
+        active_users.len()
+    );
+
+    active_users.click(
+        "late_user",
+        start + ACTIVE_USER_WINDOW + Duration::from_secs(1),
+    );
+    assert!(
+        active_users.len() == 2,
+        "Clicks should prune users who did not click within the window, got {}",
+        active_users.len()
+    );
+
+    let count = active_users.count(start + 2 * ACTIVE_USER_WINDOW);
+    assert!(
+        count == 1,
+        "Only the late user is still active, got {count}"
+    );
+}
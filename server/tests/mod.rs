@@ -1,3 +1,19 @@
 #[cfg(test)]
+pub mod auth_tests;
+pub mod changes_tests;
+pub mod clients_tests;
 pub mod coords_tests;
+pub mod error_tests;
 pub mod game_tests;
+pub mod grid_state_tests;
+pub mod history_tests;
+pub mod leaderboard_tests;
+pub mod metrics_tests;
+pub mod protocol_tests;
+pub mod rate_limit_tests;
+pub mod rounds_tests;
+pub mod rules_tests;
+pub mod snapshot_tests;
+pub mod telemetry_tests;
+pub mod user_tests;
+pub mod websocket_tests;
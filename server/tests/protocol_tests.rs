@@ -0,0 +1,261 @@
+use pixelstratwar::{
+    coords::AxialCoords,
+    game::TileData,
+    protocol::{
+        ClientMessage, ErrorCode, LeaderboardEntry, ProtocolError, ServerMessage, MAX_STRING_LENGTH,
+    },
+};
+use proptest::{collection::vec, prelude::*};
+
+// Strings of at most 63 chars, so at most 252 bytes once utf8 encoded
+fn short_string() -> impl Strategy<Value = String> {
+    "\\PC{0,63}"
+}
+
+fn coords() -> impl Strategy<Value = AxialCoords> {
+    (any::<i32>(), any::<i32>()).prop_map(|(q, r)| AxialCoords::new(q, r))
+}
+
+fn tile() -> impl Strategy<Value = (AxialCoords, TileData)> {
+    (coords(), any::<u8>(), short_string())
+        .prop_map(|(coords, strength, user_id)| (coords, TileData { user_id, strength }))
+}
+
+fn error_code() -> impl Strategy<Value = ErrorCode> {
+    prop_oneof![
+        Just(ErrorCode::Malformed),
+        Just(ErrorCode::Unauthorized),
+        Just(ErrorCode::Cooldown),
+        Just(ErrorCode::Internal),
+        Just(ErrorCode::HandshakeRequired),
+        Just(ErrorCode::UnsupportedVersion),
+        Just(ErrorCode::ResyncRequired),
+        Just(ErrorCode::RoundOver),
+    ]
+}
+
+fn server_message() -> impl Strategy<Value = ServerMessage> {
+    prop_oneof![
+        any::<u8>().prop_map(|version| ServerMessage::Hello { version }),
+        tile().prop_map(|(coords, tile)| ServerMessage::TileChange { coords, tile }),
+        (
+            short_string(),
+            short_string(),
+            short_string(),
+            short_string()
+        )
+            .prop_map(|(user_id, username, color, team)| ServerMessage::NewUser {
+                user_id,
+                username,
+                color,
+                team,
+            }),
+        (short_string(), any::<u32>())
+            .prop_map(|(user_id, score)| ServerMessage::ScoreChange { user_id, score }),
+        vec((short_string(), any::<u32>()), 0..20).prop_map(|entries| {
+            ServerMessage::Leaderboard {
+                top: entries
+                    .into_iter()
+                    .map(|(user_id, score)| LeaderboardEntry { user_id, score })
+                    .collect(),
+            }
+        }),
+        (short_string(), any::<u32>()).prop_map(|(user_id, remaining_ms)| {
+            ServerMessage::Cooldown {
+                user_id,
+                remaining_ms,
+            }
+        }),
+        any::<u32>().prop_map(|request_id| ServerMessage::Ack { request_id }),
+        (any::<u32>(), error_code(), short_string()).prop_map(|(request_id, code, message)| {
+            ServerMessage::Error {
+                request_id,
+                code,
+                message,
+            }
+        }),
+        (any::<u64>(), vec(tile(), 0..20))
+            .prop_map(|(seq, tiles)| ServerMessage::TilesChange { seq, tiles }),
+        (any::<u32>(), any::<u64>(), any::<u64>()).prop_map(|(id, starts_at_ms, ends_at_ms)| {
+            ServerMessage::Round {
+                id,
+                starts_at_ms,
+                ends_at_ms,
+            }
+        }),
+    ]
+}
+
+fn client_message() -> impl Strategy<Value = ClientMessage> {
+    prop_oneof![
+        (any::<u32>(), any::<u8>()).prop_map(|(request_id, version)| ClientMessage::Hello {
+            request_id,
+            version
+        }),
+        (any::<u32>(), short_string(), short_string()).prop_map(|(request_id, user_id, token)| {
+            ClientMessage::Authenticate {
+                request_id,
+                user_id,
+                token,
+            }
+        }),
+        (any::<u32>(), coords())
+            .prop_map(|(request_id, coords)| ClientMessage::Click { request_id, coords }),
+        (any::<u32>(), vec(any::<u16>(), 0..50)).prop_map(|(request_id, batches)| {
+            ClientMessage::Subscribe {
+                request_id,
+                batches,
+            }
+        }),
+        (any::<u32>(), vec(any::<u16>(), 0..50)).prop_map(|(request_id, batches)| {
+            ClientMessage::Unsubscribe {
+                request_id,
+                batches,
+            }
+        }),
+        (any::<u32>(), any::<u64>())
+            .prop_map(|(request_id, since)| ClientMessage::Resync { request_id, since }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn server_messages_round_trip(message in server_message()) {
+        let bytes = message.encode().unwrap();
+        prop_assert_eq!(ServerMessage::decode(&bytes), Ok(message));
+    }
+
+    #[test]
+    fn client_messages_round_trip(message in client_message()) {
+        let bytes = message.encode().unwrap();
+        prop_assert_eq!(ClientMessage::decode(&bytes), Ok(message));
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected(message in server_message(), cut in any::<prop::sample::Index>()) {
+        let bytes = message.encode().unwrap();
+        let cut = cut.index(bytes.len());
+
+        prop_assert!(ServerMessage::decode(&bytes[..cut]).is_err());
+    }
+}
+
+#[test]
+pub fn too_long_strings_are_refused() {
+    let long_id = "a".repeat(MAX_STRING_LENGTH + 1);
+
+    let message = ServerMessage::NewUser {
+        user_id: "user_a".to_string(),
+        username: long_id.clone(),
+        color: "#ffffff".to_string(),
+        team: String::new(),
+    };
+    assert!(
+        matches!(
+            message.encode(),
+            Err(ProtocolError::TooLong {
+                field: "username",
+                length: 256,
+                max: 255
+            })
+        ),
+        "Username over 255 bytes should not be truncated"
+    );
+
+    let message = ClientMessage::Authenticate {
+        request_id: 1,
+        user_id: long_id,
+        token: "token".to_string(),
+    };
+    assert!(
+        matches!(message.encode(), Err(ProtocolError::TooLong { .. })),
+        "User id over 255 bytes should not be truncated"
+    );
+
+    let message = ServerMessage::Leaderboard {
+        top: vec![
+            LeaderboardEntry {
+                user_id: "user_a".to_string(),
+                score: 1,
+            };
+            256
+        ],
+    };
+    assert!(
+        matches!(message.encode(), Err(ProtocolError::TooLong { .. })),
+        "Leaderboard over 255 entries should not be truncated"
+    );
+}
+
+#[test]
+pub fn error_messages_are_cut_on_char_boundary() {
+    let message = ServerMessage::error(1, ErrorCode::Internal, &"é".repeat(200));
+
+    let ServerMessage::Error { message, .. } = &message else {
+        panic!("Expected an error message");
+    };
+    assert!(
+        message.len() == 254 && message.chars().all(|c| c == 'é'),
+        "Error message should be cut before the character crossing 255 bytes"
+    );
+}
+
+#[test]
+pub fn new_user_message_layout() {
+    let bytes = ServerMessage::NewUser {
+        user_id: "id".to_string(),
+        username: "bob".to_string(),
+        color: "#fff".to_string(),
+        team: "red".to_string(),
+    }
+    .encode()
+    .unwrap();
+
+    assert!(
+        bytes
+            == [
+                &[0x02, 2][..],
+                b"id",
+                &[3],
+                b"bob",
+                &[4],
+                b"#fff",
+                &[3],
+                b"red"
+            ]
+            .concat(),
+        "New user message should hold its four strings and nothing else, got {bytes:?}"
+    );
+}
+
+#[test]
+pub fn malformed_client_messages_are_rejected() {
+    let click = ClientMessage::Click {
+        request_id: 1,
+        coords: AxialCoords::new(1, 1),
+    }
+    .encode()
+    .unwrap();
+
+    let malformed: [(&[u8], ProtocolError); 6] = [
+        (&[], ProtocolError::Truncated),
+        (&[0x02, 1, 0], ProtocolError::Truncated),
+        (&click[..click.len() - 1], ProtocolError::Truncated),
+        (
+            &[click.as_slice(), &[0]].concat(),
+            ProtocolError::TrailingBytes(1),
+        ),
+        (&[0x0a, 1, 0, 0, 0], ProtocolError::UnknownType(0x0a)),
+        // announces 2 batches but carries only one
+        (&[0x03, 1, 0, 0, 0, 2, 0, 1, 0], ProtocolError::Truncated),
+    ];
+
+    for (bytes, error) in malformed {
+        let decoded = ClientMessage::decode(bytes);
+
+        assert!(
+            decoded.as_ref() == Err(&error),
+            "{bytes:?} should be rejected with {error:?}, got {decoded:?}"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use actix_web::{
+    http::{header, StatusCode},
+    test as actix_test, web,
+};
+use pixelstratwar::{
+    config::GameConfig,
+    protocol::ServerMessage,
+    store::RedisHandler,
+    test_utils::{
+        self,
+        utils::{add_test_user, basic_auth, test_app},
+    },
+};
+use std::time::Duration;
+
+#[tokio::test]
+pub async fn click_cooldown_expires() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let first = mock_redis
+        .start_click_cooldown(&mut con, "user_a", 200)
+        .await
+        .unwrap();
+    assert!(first.is_none(), "First click should be allowed");
+
+    let second = mock_redis
+        .start_click_cooldown(&mut con, "user_a", 200)
+        .await
+        .unwrap();
+    assert!(
+        second.is_some_and(|ms| ms > 0 && ms <= 200),
+        "Second click should wait for the running cooldown, got {second:?}"
+    );
+
+    let other = mock_redis
+        .start_click_cooldown(&mut con, "user_b", 200)
+        .await
+        .unwrap();
+    assert!(other.is_none(), "Cooldowns should be per user");
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let after = mock_redis
+        .start_click_cooldown(&mut con, "user_a", 200)
+        .await
+        .unwrap();
+    assert!(
+        after.is_none(),
+        "Click should be allowed once the cooldown ended"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn clicks_during_cooldown_are_rejected() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+    let other = add_test_user(&store, &pool, "other").await;
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.click_cooldown_ms = 60_000;
+    let app = actix_test::init_service(test_app(store.clone(), pool.clone(), app_config)).await;
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::OK,
+        "First click should succeed, got {}",
+        res.status()
+    );
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/1/0")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::TOO_MANY_REQUESTS,
+        "Click during cooldown should be rejected, got {}",
+        res.status()
+    );
+
+    let retry_after = res
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    assert!(
+        retry_after.is_some_and(|secs| secs > 0 && secs <= 60),
+        "Rejected click should tell when to retry, got {retry_after:?}"
+    );
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/2/0")
+        .insert_header(basic_auth(&other))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::OK,
+        "Cooldown of an user should not affect others, got {}",
+        res.status()
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn zero_cooldown_disables_rate_limit() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.click_cooldown_ms = 0;
+    let app = actix_test::init_service(test_app(store.clone(), pool.clone(), app_config)).await;
+
+    for q in 0..3 {
+        let req = actix_test::TestRequest::post()
+            .uri(&format!("/tile/{q}/0"))
+            .insert_header(basic_auth(&user))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+
+        assert!(
+            res.status() == StatusCode::OK,
+            "Click {q} should succeed without cooldown, got {}",
+            res.status()
+        );
+    }
+
+    store.flushdb().await.unwrap();
+}
+
+#[test]
+pub fn cooldown_message_encoding() {
+    let message = ServerMessage::Cooldown {
+        user_id: "user_a".to_string(),
+        remaining_ms: 1500,
+    }
+    .encode()
+    .unwrap();
+
+    assert!(message[0] == 0x05, "Cooldown message type should be 0x05");
+    assert!(message[1] == 6, "User id length should follow the type");
+    assert!(
+        &message[2..8] == b"user_a",
+        "User id should follow its length"
+    );
+    assert!(
+        u32::from_le_bytes(message[8..12].try_into().unwrap()) == 1500,
+        "Remaining milliseconds should end the message"
+    );
+}
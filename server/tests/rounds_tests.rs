@@ -0,0 +1,302 @@
+use actix_web::{http::StatusCode, test as actix_test, web};
+use pixelstratwar::{
+    changes::{ChangeLog, ChangesSince},
+    config::GameConfig,
+    coords::AxialCoords,
+    error::GameError,
+    game::{GameData, TileData},
+    rounds::{advance_round, init_round, CurrentRound, Round},
+    store::{GameStore, RedisHandler, RedisStore},
+    test_utils::{
+        self,
+        utils::{add_test_user, basic_auth, test_app, test_app_in_round},
+    },
+};
+use serde_json::Value;
+
+/// Rounds of a minute with a 10 seconds break, the first one starting at 1s
+fn scheduled_config() -> GameConfig {
+    GameConfig {
+        round_break_secs: 10,
+        round_duration_secs: 60,
+        round_starts_at_ms: Some(1_000),
+        ..GameConfig::read_config_from_env()
+    }
+}
+
+#[test]
+pub fn rounds_follow_the_schedule() {
+    let config = scheduled_config();
+
+    let first = Round::first(&config, 0);
+    assert!(
+        first
+            == Round {
+                id: 1,
+                starts_at_ms: 1_000,
+                ends_at_ms: Some(61_000),
+            },
+        "First round should start at the configured time, got {first:?}"
+    );
+    assert!(!first.is_open(999) && first.is_open(1_000) && first.is_open(60_999));
+    assert!(!first.is_open(61_000) && first.is_over(61_000));
+
+    let next = first.next(&config);
+    assert!(
+        next == Round {
+            id: 2,
+            starts_at_ms: 71_000,
+            ends_at_ms: Some(131_000),
+        },
+        "Next round should start after the break, got {next:?}"
+    );
+
+    let endless = Round::first(
+        &GameConfig {
+            round_duration_secs: 0,
+            round_starts_at_ms: None,
+            ..config
+        },
+        5_000,
+    );
+    assert!(
+        endless.starts_at_ms == 5_000 && endless.ends_at_ms.is_none(),
+        "Rounds without duration should start now and never end, got {endless:?}"
+    );
+    assert!(endless.is_open(u64::MAX));
+}
+
+/// Connection accepting every write but never keeping the current round
+struct ForgetfulConnection;
+
+impl redis::aio::ConnectionLike for ForgetfulConnection {
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        let is_read = cmd.get_packed_command().windows(7).any(|w| w == b"HGETALL");
+
+        Box::pin(async move {
+            Ok(match is_read {
+                true => redis::Value::Array(Vec::new()),
+                false => redis::Value::Int(1),
+            })
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        _cmd: &'a redis::Pipeline,
+        _offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        Box::pin(async move { Ok(vec![redis::Value::Int(1); count]) })
+    }
+}
+
+#[tokio::test]
+pub async fn lost_first_round_fails_the_init() {
+    let client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+    let store = GameStore::Redis(RedisStore::new(client));
+
+    let res = init_round(&store, &mut ForgetfulConnection, &scheduled_config()).await;
+    assert!(
+        matches!(res, Err(GameError::StoreUnavailable(_))),
+        "First round missing after being started should be an error, got {res:?}"
+    );
+}
+
+#[actix_web::test]
+pub async fn ended_round_is_archived_before_the_next_one() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let mut con = store.get_connection(&pool).await.unwrap();
+    let config = scheduled_config();
+    let game_data = GameData::new(10, 2);
+
+    let round = init_round(&store, &mut con, &config).await.unwrap();
+    assert!(
+        round.id == 1,
+        "First round should be started, got {round:?}"
+    );
+    let current = CurrentRound::new(round.clone());
+    let user = add_test_user(&store, &pool, "winner").await;
+
+    let coords = AxialCoords::new(0, 0);
+    game_data
+        .handle_click(&store, &mut con, &coords, &user.id, None)
+        .await
+        .unwrap();
+
+    let advanced = advance_round(&store, &mut con, &game_data, &current, &config, 30_000)
+        .await
+        .unwrap();
+    assert!(advanced.is_none(), "Round should go on until its end");
+    assert!(store.get_round_results(&mut con).await.unwrap().is_empty());
+
+    // round is over, the next one starts after the break
+    let advanced = advance_round(&store, &mut con, &game_data, &current, &config, 61_000)
+        .await
+        .unwrap();
+    assert!(advanced.is_none(), "Next round should wait for the break");
+
+    let results = store.get_round_results(&mut con).await.unwrap();
+    assert!(
+        results.len() == 1
+            && results[0].round == round
+            && results[0].leaderboard.len() == 1
+            && results[0].leaderboard[0].user.id == user.id,
+        "Final leaderboard should be archived once the round is over, got {results:?}"
+    );
+    let grid = store.get_round_grid(&mut con, 1).await.unwrap();
+    assert!(
+        grid.is_some_and(|tiles| tiles.len() == 1 && tiles[0].0 == coords),
+        "Final grid should be archived with the leaderboard"
+    );
+
+    let advanced = advance_round(&store, &mut con, &game_data, &current, &config, 71_000)
+        .await
+        .unwrap();
+    assert!(
+        advanced == Some(round.next(&config)) && current.get() == round.next(&config),
+        "Next round should start after the break, got {advanced:?}"
+    );
+
+    let tiles = store
+        .batch_get_tiles(&mut con, game_data.all_grid_coords())
+        .await
+        .unwrap();
+    assert!(tiles.is_empty(), "New round should start on an empty grid");
+    assert!(
+        store.get_round_results(&mut con).await.unwrap().len() == 1,
+        "Previous rounds should stay archived"
+    );
+
+    let advanced = advance_round(&store, &mut con, &game_data, &current, &config, 72_000)
+        .await
+        .unwrap();
+    assert!(advanced.is_none(), "Round should only start once");
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn clicks_are_refused_outside_of_the_round() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "late").await;
+
+    let round = web::Data::new(CurrentRound::new(Round {
+        id: 1,
+        starts_at_ms: 0,
+        ends_at_ms: Some(1),
+    }));
+    let app_config = GameConfig::read_config_from_env();
+    let app = actix_test::init_service(test_app_in_round(
+        store.clone(),
+        pool.clone(),
+        app_config,
+        round,
+    ))
+    .await;
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::FORBIDDEN,
+        "Click after the end of the round should be refused, got {}",
+        res.status()
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn rounds_route_lists_current_and_archived_rounds() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let mut con = store.get_connection(&pool).await.unwrap();
+    let config = scheduled_config();
+    let game_data = GameData::new(10, 2);
+
+    let round = init_round(&store, &mut con, &config).await.unwrap();
+    let current = CurrentRound::new(round);
+    advance_round(&store, &mut con, &game_data, &current, &config, 61_000)
+        .await
+        .unwrap();
+
+    let app_config = GameConfig::read_config_from_env();
+    let app = actix_test::init_service(test_app(store.clone(), pool.clone(), app_config)).await;
+
+    let req = actix_test::TestRequest::get().uri("/rounds").to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(res.status().is_success(), "Got {}", res.status());
+
+    let body: Value = actix_test::read_body_json(res).await;
+    assert!(
+        body["current"]["id"] == 1 && body["archived"][0]["id"] == 1,
+        "Rounds should list the current round and the archived ones, got {body}"
+    );
+    assert!(
+        body["archived"][0]["ends_at_ms"] == 61_000,
+        "Archived rounds should keep their schedule, got {body}"
+    );
+
+    let req = actix_test::TestRequest::get()
+        .uri("/rounds/1/snapshot.png")
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status().is_success(),
+        "Archived grid should be rendered, got {}",
+        res.status()
+    );
+
+    let req = actix_test::TestRequest::get()
+        .uri("/rounds/2/snapshot.png")
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(
+        res.status() == StatusCode::NOT_FOUND,
+        "Rounds not archived have no snapshot, got {}",
+        res.status()
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[test]
+pub fn cleared_change_log_requires_a_resync() {
+    let log = ChangeLog::new(8);
+    let tile = TileData {
+        user_id: "user_a".to_string(),
+        strength: 1,
+    };
+
+    let seq = log.record(&[(AxialCoords::new(0, 0), tile)]);
+    log.clear();
+
+    for since in [0, seq] {
+        let changes = log.since(since);
+        assert!(
+            matches!(changes, ChangesSince::ResyncRequired { .. }),
+            "Changes of a previous round should not be replayed, got {changes:?}"
+        );
+    }
+
+    let changes = log.since(log.last_seq());
+    assert!(
+        changes
+            == ChangesSince::Changes {
+                seq: log.last_seq(),
+                tiles: vec![],
+            },
+        "Sequence given after the clear should be valid, got {changes:?}"
+    );
+}
@@ -0,0 +1,254 @@
+use pixelstratwar::{
+    coords::{cube_spiral, AxialCoords},
+    error::GameError,
+    game::{ClickAction, GameData, InnerTileData},
+    rules::{ClassicRules, GameRules},
+    store::{RedisHandler, RedisStore},
+    test_utils::{
+        self,
+        mocks::{MockRedisConnection, MockRedisHandler, TestRedisClient, TestRedisConnection},
+        utils::are_coords_in_vec,
+    },
+};
+
+/// Gives every tile within `radius` steps of the center to `user_id`
+async fn own_around_center(
+    store: &TestRedisClient,
+    con: &mut TestRedisConnection,
+    user_id: &str,
+    radius: u32,
+) {
+    for c in cube_spiral(&AxialCoords::center().as_cube(), radius) {
+        store
+            .set_tile(
+                con,
+                &c.as_axial(),
+                InnerTileData {
+                    user_id: user_id.to_string(),
+                    damage: 0,
+                    team: None,
+                },
+            )
+            .await
+            .unwrap();
+    }
+}
+
+/// Clicks of `user_id` on the center until it's captured, returns the number of clicks
+async fn clicks_to_capture<G: GameRules>(
+    game_data: &GameData<G>,
+    store: &TestRedisClient,
+    con: &mut TestRedisConnection,
+    user_id: &str,
+) -> usize {
+    let center = AxialCoords::center();
+
+    for clicks in 1..=u8::MAX as usize {
+        game_data
+            .handle_click(store, con, &center, user_id, None)
+            .await
+            .unwrap();
+
+        let tile = store.get_tile(con, &center).await.unwrap().unwrap();
+        if tile.user_id == user_id {
+            return clicks;
+        }
+    }
+
+    panic!("Center should have been captured");
+}
+
+#[tokio::test]
+pub async fn classic_rules_are_parametrized() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+    let center = AxialCoords::center();
+
+    // 18 contiguous tiles => strength of 19 with the default rules
+    own_around_center(&mock_redis, &mut con, "first_user_id", 2).await;
+
+    let capped = GameData::new(10, 2).with_rules(ClassicRules {
+        max_strength: 5,
+        ..ClassicRules::default()
+    });
+    let updated = capped
+        .handle_click(&mock_redis, &mut con, &center, "second_user_id", None)
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.strength == 4,
+        "Strength should be capped before damage, got {tile:?}"
+    );
+
+    let clicks = clicks_to_capture(&capped, &mock_redis, &mut con, "second_user_id").await;
+    assert!(
+        clicks == 4,
+        "A capped tile should be captured after as many clicks as the cap, got {clicks} more"
+    );
+
+    mock_redis.flushdb().await.unwrap();
+    own_around_center(&mock_redis, &mut con, "first_user_id", 2).await;
+
+    // only the 6 direct neighbors reinforce the center
+    let close = GameData::new(10, 2).with_rules(ClassicRules {
+        influence_radius: 1,
+        ..ClassicRules::default()
+    });
+    let clicks = clicks_to_capture(&close, &mock_redis, &mut con, "second_user_id").await;
+    assert!(
+        clicks == 7,
+        "Only tiles within the influence radius should count, got {clicks} clicks"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn repair_rate_removes_several_damages() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+    let center = AxialCoords::center();
+    let game_data = GameData::new(10, 2).with_rules(ClassicRules {
+        repair_rate: 2,
+        ..ClassicRules::default()
+    });
+
+    own_around_center(&mock_redis, &mut con, "first_user_id", 1).await;
+
+    for _ in 0..3 {
+        game_data
+            .handle_click(&mock_redis, &mut con, &center, "second_user_id", None)
+            .await
+            .unwrap();
+    }
+
+    let updated = game_data
+        .handle_click(&mock_redis, &mut con, &center, "first_user_id", None)
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.strength == 6,
+        "Repair should remove 2 of the 3 damages, got {tile:?}"
+    );
+
+    let updated = game_data
+        .handle_click(&mock_redis, &mut con, &center, "first_user_id", None)
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.strength == 7,
+        "Repair should not go below 0 damage, got {tile:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn large_influence_radius_saturates_allies() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+    let center = AxialCoords::center();
+
+    // 330 contiguous tiles, more than an u8 can count
+    own_around_center(&mock_redis, &mut con, "first_user_id", 10).await;
+
+    let wide = GameData::new(10, 2).with_rules(ClassicRules {
+        influence_radius: 10,
+        ..ClassicRules::default()
+    });
+    let updated = wide
+        .handle_click(&mock_redis, &mut con, &center, "second_user_id", None)
+        .await
+        .unwrap();
+    let (_, tile) = are_coords_in_vec(&updated, &center).unwrap();
+    assert!(
+        tile.strength == u8::MAX - 1,
+        "Tile should keep the max strength minus the damage, got {tile:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+/// Variant where neighbors do not matter, every tile is captured in two clicks
+#[derive(Debug, Clone)]
+struct FlatRules;
+
+impl GameRules for FlatRules {
+    fn influence_radius(&self) -> u8 {
+        2
+    }
+
+    fn max_strength(&self) -> u8 {
+        2
+    }
+
+    fn repair_rate(&self) -> u8 {
+        1
+    }
+
+    fn full_strength(&self, _allies: u8) -> u8 {
+        2
+    }
+}
+
+#[tokio::test]
+pub async fn rule_variants_are_played_against_the_store() {
+    // only the classic rules can be played against redis
+    let mock_redis = TestRedisClient::Mock(MockRedisHandler::new());
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+    let center = AxialCoords::center();
+    let game_data = GameData::new(10, 2).with_rules(FlatRules);
+
+    // a lone tile is stronger than with the default rules
+    game_data
+        .handle_click(&mock_redis, &mut con, &center, "first_user_id", None)
+        .await
+        .unwrap();
+    let outcome = mock_redis
+        .apply_click(&mut con, &center, "second_user_id", None, &FlatRules)
+        .await
+        .unwrap();
+    assert!(
+        outcome.action == ClickAction::Damage,
+        "Lone tile should survive the first click, got {outcome:?}"
+    );
+
+    mock_redis.flushdb().await.unwrap();
+
+    // a tile surrounded by its owner is weaker
+    own_around_center(&mock_redis, &mut con, "first_user_id", 2).await;
+    let clicks = clicks_to_capture(&game_data, &mock_redis, &mut con, "second_user_id").await;
+    assert!(
+        clicks == 2,
+        "Every tile should be captured in two clicks, got {clicks}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn redis_store_refuses_rules_it_does_not_script() {
+    // refused before any command is sent, the connection is never used
+    let store = RedisStore::new(redis::Client::open("redis://127.0.0.1:6379").unwrap());
+    let mut con = MockRedisConnection::new();
+    let center = AxialCoords::center();
+
+    let res = store
+        .apply_click(&mut con, &center, "first_user_id", None, &FlatRules)
+        .await;
+    assert!(
+        matches!(res, Err(GameError::InvalidInput(_))),
+        "Rules overriding more than the script parameters should be refused, got {res:?}"
+    );
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use actix_web::{test as actix_test, web};
+use pixelstratwar::{
+    config::GameConfig,
+    coords::{axial_to_pixel, AxialCoords},
+    game::{InnerTileData, TileMap},
+    snapshot::{image_size, render_png, MAX_HEX_SIZE},
+    store::RedisHandler,
+    test_utils::{
+        self,
+        utils::{add_test_user, basic_auth, test_app},
+    },
+};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+fn decode(bytes: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+    let mut reader = png::Decoder::new(bytes).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+
+    (info, pixels)
+}
+
+/// RGBA color of the pixel at the center of `coords`
+fn color_at(info: &png::OutputInfo, pixels: &[u8], coords: AxialCoords, hex_size: f64) -> [u8; 4] {
+    let (x, y) = axial_to_pixel(&coords, hex_size);
+    let x = (info.width as f64 / 2.0 + x) as usize;
+    let y = (info.height as f64 / 2.0 + y) as usize;
+    let i = (y * info.width as usize + x) * 4;
+
+    pixels[i..i + 4].try_into().unwrap()
+}
+
+#[test]
+pub fn render_png_fills_tiles_with_owner_color() {
+    let owned = AxialCoords::new(0, 0);
+    let empty = AxialCoords::new(1, 1);
+
+    let tiles = TileMap::from([(
+        owned,
+        InnerTileData {
+            user_id: "user_a".to_string(),
+            damage: 0,
+            team: None,
+        },
+    )]);
+    let colors = HashMap::from([("user_a".to_string(), (0xff, 0x00, 0x00))]);
+
+    let bytes = render_png(&tiles, &colors, 2, 8).unwrap();
+    assert!(
+        bytes.starts_with(&PNG_SIGNATURE),
+        "Snapshot should be a PNG"
+    );
+
+    let (info, pixels) = decode(&bytes);
+    assert!(
+        (info.width, info.height) == image_size(2, 8),
+        "Image should fit the grid, got {}x{}",
+        info.width,
+        info.height
+    );
+
+    let color = color_at(&info, &pixels, owned, 8.0);
+    assert!(
+        color == [0xff, 0x00, 0x00, 0xff],
+        "Owned tile should have the color of its owner, got {color:?}"
+    );
+
+    let color = color_at(&info, &pixels, empty, 8.0);
+    assert!(
+        color == [0xdd, 0xdd, 0xdd, 0xff],
+        "Empty tile should be grey, got {color:?}"
+    );
+
+    assert!(
+        pixels[..4] == [0, 0, 0, 0],
+        "Corners are outside of the grid and should be transparent"
+    );
+}
+
+#[actix_web::test]
+pub async fn snapshot_route_renders_current_grid() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "painter").await;
+
+    let app_config = GameConfig::read_config_from_env();
+    let app = actix_test::init_service(test_app(store.clone(), pool.clone(), app_config)).await;
+
+    let req = actix_test::TestRequest::post()
+        .uri("/tile/0/0")
+        .insert_header(basic_auth(&user))
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(res.status().is_success(), "Click should succeed");
+
+    let req = actix_test::TestRequest::get()
+        .uri("/snapshot.png?size=6")
+        .to_request();
+    let res = actix_test::call_service(&app, req).await;
+    assert!(res.status().is_success(), "Got {}", res.status());
+    assert!(
+        res.headers()
+            .get("content-type")
+            .is_some_and(|v| v == "image/png"),
+        "Snapshot should be served as a PNG"
+    );
+
+    let bytes = actix_test::read_body(res).await;
+    let (info, pixels) = decode(&bytes);
+
+    // test_app grid has a radius of 10
+    assert!((info.width, info.height) == image_size(10, 6));
+
+    let color = color_at(&info, &pixels, AxialCoords::new(0, 0), 6.0);
+    let (r, g, b) = pixelstratwar::utils::hex_to_color(&user.color).unwrap();
+    assert!(
+        color == [r, g, b, 0xff],
+        "Clicked tile should have the color of the user, got {color:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn snapshot_route_reuses_the_last_render() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "painter").await;
+
+    let app_config = GameConfig::read_config_from_env();
+    let app = actix_test::init_service(test_app(store.clone(), pool.clone(), app_config)).await;
+
+    let get_snapshot = |size: u32| {
+        actix_test::TestRequest::get()
+            .uri(&format!("/snapshot.png?size={size}"))
+            .to_request()
+    };
+
+    let bytes = actix_test::call_and_read_body(&app, get_snapshot(100)).await;
+    let (info, _) = decode(&bytes);
+    assert!(
+        (info.width, info.height) == image_size(10, MAX_HEX_SIZE),
+        "Hexagon size should be capped, got {}x{}",
+        info.width,
+        info.height
+    );
+
+    // written behind the change log, the grid is not known to have changed
+    let coords = AxialCoords::new(1, 0);
+    let mut con = store.get_connection(&pool).await.unwrap();
+    let tile = InnerTileData {
+        user_id: user.id.clone(),
+        damage: 0,
+        team: None,
+    };
+    store.set_tile(&mut con, &coords, tile).await.unwrap();
+
+    let cached = actix_test::call_and_read_body(&app, get_snapshot(MAX_HEX_SIZE)).await;
+    assert!(
+        cached == bytes,
+        "Snapshot of an unchanged grid should be the last render"
+    );
+
+    let bytes = actix_test::call_and_read_body(&app, get_snapshot(6)).await;
+    let (info, pixels) = decode(&bytes);
+    let color = color_at(&info, &pixels, coords, 6.0);
+    let (r, g, b) = pixelstratwar::utils::hex_to_color(&user.color).unwrap();
+    assert!(
+        color == [r, g, b, 0xff],
+        "Snapshot of another size should be rendered again, got {color:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
@@ -0,0 +1,83 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use pixelstratwar::{coords::AxialCoords, game::GameData, test_utils};
+use serde_json::Value;
+use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt};
+
+/// Writer appending the logs to a shared buffer
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+pub async fn clicks_run_in_spans_down_to_the_store() {
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_span_events(FmtSpan::CLOSE)
+            .with_span_list(true)
+            .with_writer(move || writer.clone()),
+    );
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let mut con = store.get_connection(&pool).await.unwrap();
+    let game_data = GameData::new(10, 2);
+
+    game_data
+        .handle_click(&store, &mut con, &AxialCoords::new(0, 0), "user_a", None)
+        .await
+        .unwrap();
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let closed: Vec<Value> = logs
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let span_named = |name: &str| {
+        closed
+            .iter()
+            .find(|event| event["span"]["name"] == name)
+            .unwrap_or_else(|| panic!("No {name} span in the logs:\n{logs}"))
+    };
+
+    let click = span_named("handle_click");
+    assert!(
+        click["span"]["user_id"] == "user_a" && click["span"]["action"] == "create",
+        "Click span should record the user and the action, got {click}"
+    );
+
+    for name in [
+        "apply_click",
+        "fetch_within",
+        "computed_tile",
+        "batch_get_tiles",
+    ] {
+        let span = span_named(name);
+        let parents: Vec<&str> = span["spans"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|parent| parent["name"].as_str())
+            .collect();
+
+        assert!(
+            parents.first() == Some(&"handle_click"),
+            "{name} should run within the click span, got {parents:?}"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use pixelstratwar::{
+    store::RedisHandler,
+    test_utils,
+    user::{hash_token, rank_teams, PublicUser, TeamScore, User},
+};
+use redis::{FromRedisValue, ToRedisArgs};
+use std::time::Duration;
+
+#[test]
+pub fn user_redis_args_round_trip() {
+    let user = User::new("round-trip");
+
+    let args = user.to_redis_args();
+    let value = redis::Value::Array(args.into_iter().map(redis::Value::BulkString).collect());
+
+    let parsed = User::from_redis_value(&value).expect("Should parse user written as redis args");
+
+    // the session token is never written in the user hash
+    let user = user.without_token();
+
+    assert!(
+        parsed == user,
+        "User should survive a round trip through redis args, got {parsed:?} instead of {user:?}"
+    );
+}
+
+#[test]
+pub fn user_team_survives_redis_args() {
+    let user = User {
+        team: Some("red".to_string()),
+        ..User::new("teammate")
+    };
+
+    let args = user.to_redis_args();
+    let value = redis::Value::Array(args.into_iter().map(redis::Value::BulkString).collect());
+
+    let parsed = User::from_redis_value(&value).unwrap();
+    assert!(
+        parsed.team.as_deref() == Some("red"),
+        "Team should be saved with the user, got {:?}",
+        parsed.team
+    );
+}
+
+#[test]
+pub fn teams_are_ranked_by_total_score() {
+    let member = |name: &str, team: Option<&str>, score| {
+        PublicUser::new(
+            User {
+                team: team.map(str::to_string),
+                ..User::new(name)
+            },
+            score,
+        )
+    };
+
+    let users = [
+        member("a", Some("red"), 3),
+        member("b", Some("blue"), 4),
+        member("c", Some("red"), 2),
+        member("d", None, 10),
+        member("e", Some("green"), 5),
+    ];
+
+    let teams = rank_teams(&users);
+    let expected = [(1, "green", 5, 1), (2, "red", 5, 2), (3, "blue", 4, 1)].map(
+        |(rank, team, score, members)| TeamScore {
+            rank,
+            team: team.to_string(),
+            score,
+            members,
+        },
+    );
+    assert!(
+        teams == expected,
+        "Teams should be ranked by total score then by name, got {teams:?}"
+    );
+}
+
+#[test]
+pub fn user_from_empty_redis_value_fails() {
+    let value = redis::Value::Array(vec![]);
+
+    assert!(
+        User::from_redis_value(&value).is_err(),
+        "Parsing an empty hash should fail"
+    );
+}
+
+#[tokio::test]
+pub async fn user_store_round_trip() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let user = User::new("stored-user");
+
+    let added = mock_redis
+        .add_user(&mut con, user.clone(), test_utils::mocks::TOKEN_TTL)
+        .await
+        .unwrap();
+    assert!(added, "User should be added");
+
+    let stored = user.without_token();
+
+    let by_id = mock_redis
+        .get_user_by_id(&mut con, &user.id)
+        .await
+        .unwrap()
+        .expect("Should find user by id");
+    assert!(by_id == stored, "Got {by_id:?} instead of {stored:?} by id");
+
+    let by_username = mock_redis
+        .get_user_by_username(&mut con, &user.username)
+        .await
+        .unwrap()
+        .expect("Should find user by username");
+    assert!(
+        by_username == stored,
+        "Got {by_username:?} instead of {stored:?} by username"
+    );
+
+    let public_users = mock_redis.get_public_users(&mut con).await.unwrap();
+    assert!(
+        public_users.iter().any(|u| u.id == user.id),
+        "Public users should list the new user, got {public_users:?}"
+    );
+
+    assert!(
+        mock_redis
+            .is_valid_token_for_user(&mut con, &user.token, &user.id)
+            .await
+            .unwrap(),
+        "User token should be valid"
+    );
+
+    let missing = mock_redis
+        .get_user_by_id(&mut con, "unknown-user-id")
+        .await
+        .unwrap();
+    assert!(missing.is_none(), "Unknown id should not match any user");
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[tokio::test]
+pub async fn usernames_are_unique() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let first = User::new("same-name");
+    let second = User::new("same-name");
+
+    assert!(
+        mock_redis
+            .add_user(&mut con, first.clone(), test_utils::mocks::TOKEN_TTL)
+            .await
+            .unwrap(),
+        "First user should be added"
+    );
+    assert!(
+        !mock_redis
+            .add_user(&mut con, second.clone(), test_utils::mocks::TOKEN_TTL)
+            .await
+            .unwrap(),
+        "Second user with the same username should be refused"
+    );
+
+    let second_by_id = mock_redis
+        .get_user_by_id(&mut con, &second.id)
+        .await
+        .unwrap();
+    assert!(
+        second_by_id.is_none(),
+        "Refused user should not be saved, got {second_by_id:?}"
+    );
+
+    let by_username = mock_redis
+        .get_user_by_username(&mut con, "same-name")
+        .await
+        .unwrap()
+        .expect("Should find first user by username");
+    assert!(
+        by_username.id == first.id,
+        "Username should still point to first user, got {by_username:?}"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
+
+#[test]
+pub fn tokens_are_hashed() {
+    let user = User::new("hashed");
+    let hash = hash_token(&user.token);
+
+    assert!(hash != user.token, "Hash should differ from the token");
+    assert!(
+        hash == hash_token(&user.token),
+        "Hashing the same token should give the same hash"
+    );
+
+    let redis_args = user.to_redis_args().concat();
+    let user_hash = String::from_utf8_lossy(&redis_args);
+    assert!(
+        !user_hash.contains(&user.token),
+        "Token should not be written in the user hash"
+    );
+}
+
+#[tokio::test]
+pub async fn tokens_expire_rotate_and_revoke() {
+    let mock_redis = test_utils::mocks::redis_client_or_mock().await.unwrap();
+    let mut con = test_utils::mocks::get_connection(&mock_redis)
+        .await
+        .unwrap();
+
+    let mut user = User::new("session-user");
+    mock_redis
+        .add_user(&mut con, user.clone(), 1)
+        .await
+        .unwrap();
+
+    assert!(
+        mock_redis
+            .is_valid_token_for_user(&mut con, &user.token, &user.id)
+            .await
+            .unwrap(),
+        "Fresh token should be valid"
+    );
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    assert!(
+        !mock_redis
+            .is_valid_token_for_user(&mut con, &user.token, &user.id)
+            .await
+            .unwrap(),
+        "Token should expire after its ttl"
+    );
+
+    let previous_token = user.token.clone();
+    user.renew_token();
+    mock_redis
+        .set_user_token(
+            &mut con,
+            &user.id,
+            &user.token,
+            test_utils::mocks::TOKEN_TTL,
+        )
+        .await
+        .unwrap();
+
+    assert!(
+        mock_redis
+            .is_valid_token_for_user(&mut con, &user.token, &user.id)
+            .await
+            .unwrap(),
+        "Renewed token should be valid"
+    );
+    assert!(
+        !mock_redis
+            .is_valid_token_for_user(&mut con, &previous_token, &user.id)
+            .await
+            .unwrap(),
+        "Previous token should not be valid anymore"
+    );
+
+    assert!(
+        mock_redis
+            .revoke_user_token(&mut con, &user.id)
+            .await
+            .unwrap(),
+        "Token should be revoked"
+    );
+    assert!(
+        !mock_redis
+            .is_valid_token_for_user(&mut con, &user.token, &user.id)
+            .await
+            .unwrap(),
+        "Revoked token should not be valid"
+    );
+
+    let _ = mock_redis.flushdb().await.unwrap();
+}
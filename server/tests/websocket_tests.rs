@@ -0,0 +1,1057 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix_web::web;
+use awc::ws::{Frame, Message};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use pixelstratwar::{
+    config::GameConfig,
+    coords::AxialCoords,
+    game::GameData,
+    protocol::{ClientMessage, CloseStatus, ErrorCode, ServerMessage, PROTOCOL_VERSION},
+    relay::{RelayBus, RELAY_CHANNEL},
+    store::RedisHandler,
+    test_utils::{
+        self,
+        utils::{add_test_user, basic_auth, test_app, test_app_with_relay},
+    },
+};
+
+async fn send<S>(framed: &mut S, message: ClientMessage)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Debug,
+{
+    let bytes = message.encode().unwrap();
+    framed.send(Message::Binary(bytes.into())).await.unwrap();
+}
+
+async fn next_message<S>(framed: &mut S) -> ServerMessage
+where
+    S: Stream<Item = Result<Frame, awc::error::WsProtocolError>> + Unpin,
+{
+    while let Some(frame) = framed.next().await {
+        if let Frame::Binary(bytes) = frame.unwrap() {
+            return ServerMessage::decode(&bytes).unwrap();
+        }
+    }
+
+    panic!("Websocket closed before sending a message");
+}
+
+// Reads server messages until the reply to a client message, returns the reply
+// and the messages received before it
+async fn messages_until_reply<S>(framed: &mut S) -> (ServerMessage, Vec<ServerMessage>)
+where
+    S: Stream<Item = Result<Frame, awc::error::WsProtocolError>> + Unpin,
+{
+    let mut messages = Vec::new();
+
+    loop {
+        let message = next_message(framed).await;
+
+        if matches!(
+            message,
+            ServerMessage::Ack { .. } | ServerMessage::Error { .. }
+        ) {
+            return (message, messages);
+        }
+        messages.push(message);
+    }
+}
+
+async fn next_reply<S>(framed: &mut S) -> ServerMessage
+where
+    S: Stream<Item = Result<Frame, awc::error::WsProtocolError>> + Unpin,
+{
+    messages_until_reply(framed).await.0
+}
+
+// Skips server messages until a tiles change
+async fn next_tiles_change<S>(framed: &mut S) -> Vec<(AxialCoords, String)>
+where
+    S: Stream<Item = Result<Frame, awc::error::WsProtocolError>> + Unpin,
+{
+    loop {
+        if let ServerMessage::TilesChange { tiles, .. } = next_message(framed).await {
+            return tiles
+                .into_iter()
+                .map(|(coords, tile)| (coords, tile.user_id))
+                .collect();
+        }
+    }
+}
+
+fn is_error(message: &ServerMessage, expected_id: u32, expected_code: ErrorCode) -> bool {
+    matches!(
+        message,
+        ServerMessage::Error { request_id, code, .. }
+            if *request_id == expected_id && *code == expected_code
+    )
+}
+
+// Opens a websocket and completes the protocol handshake
+async fn connect(
+    srv: &mut actix_test::TestServer,
+) -> impl Stream<Item = Result<Frame, awc::error::WsProtocolError>>
+       + Sink<Message, Error = awc::error::WsProtocolError>
+       + Unpin {
+    let mut framed = srv.ws_at("/ws").await.unwrap();
+
+    let hello = next_message(&mut framed).await;
+    assert!(
+        hello
+            == ServerMessage::Hello {
+                version: PROTOCOL_VERSION
+            },
+        "Server should announce its protocol version, got {hello:?}"
+    );
+
+    send(
+        &mut framed,
+        ClientMessage::Hello {
+            request_id: 0,
+            version: PROTOCOL_VERSION,
+        },
+    )
+    .await;
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        reply == ServerMessage::Ack { request_id: 0 },
+        "Hello with the server version should be acked, got {reply:?}"
+    );
+
+    framed
+}
+
+#[actix_web::test]
+pub async fn websocket_requires_protocol_handshake() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+
+    let app_config = GameConfig::read_config_from_env();
+    let mut srv =
+        actix_test::start(move || test_app(store.clone(), pool.clone(), app_config.clone()));
+
+    let mut framed = srv.ws_at("/ws").await.unwrap();
+    assert!(matches!(
+        next_message(&mut framed).await,
+        ServerMessage::Hello { .. }
+    ));
+
+    send(
+        &mut framed,
+        ClientMessage::Subscribe {
+            request_id: 1,
+            batches: vec![0],
+        },
+    )
+    .await;
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        is_error(&reply, 1, ErrorCode::HandshakeRequired),
+        "Messages before hello should be refused, got {reply:?}"
+    );
+
+    send(
+        &mut framed,
+        ClientMessage::Hello {
+            request_id: 2,
+            version: PROTOCOL_VERSION + 1,
+        },
+    )
+    .await;
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        is_error(&reply, 2, ErrorCode::UnsupportedVersion),
+        "Hello with another version should be refused, got {reply:?}"
+    );
+
+    let closed = loop {
+        match framed.next().await {
+            Some(Ok(Frame::Close(_))) | None => break true,
+            Some(Ok(_)) => continue,
+            Some(Err(_)) => break false,
+        }
+    };
+    assert!(
+        closed,
+        "Websocket should be closed after a version mismatch"
+    );
+}
+
+#[actix_web::test]
+pub async fn websocket_click_requires_authentication() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.click_cooldown_ms = 60_000;
+
+    let server_store = store.clone();
+    let server_pool = pool.clone();
+    let mut srv = actix_test::start(move || {
+        test_app(
+            server_store.clone(),
+            server_pool.clone(),
+            app_config.clone(),
+        )
+    });
+
+    let mut framed = connect(&mut srv).await;
+
+    send(
+        &mut framed,
+        ClientMessage::Click {
+            request_id: 1,
+            coords: AxialCoords::new(0, 0),
+        },
+    )
+    .await;
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        is_error(&reply, 1, ErrorCode::Unauthorized),
+        "Click before authenticating should be refused, got {reply:?}"
+    );
+
+    send(
+        &mut framed,
+        ClientMessage::Authenticate {
+            request_id: 2,
+            user_id: user.id.clone(),
+            token: "not-the-token".to_string(),
+        },
+    )
+    .await;
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        is_error(&reply, 2, ErrorCode::Unauthorized),
+        "Wrong token should be refused, got {reply:?}"
+    );
+
+    send(
+        &mut framed,
+        ClientMessage::Authenticate {
+            request_id: 3,
+            user_id: user.id.clone(),
+            token: user.token.clone(),
+        },
+    )
+    .await;
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        reply == ServerMessage::Ack { request_id: 3 },
+        "Valid token should be acked, got {reply:?}"
+    );
+
+    send(
+        &mut framed,
+        ClientMessage::Click {
+            request_id: 4,
+            coords: AxialCoords::new(0, 0),
+        },
+    )
+    .await;
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        reply == ServerMessage::Ack { request_id: 4 },
+        "Authenticated click should be acked, got {reply:?}"
+    );
+
+    let mut con = store.get_connection(&pool).await.unwrap();
+    let tile = store
+        .get_tile(&mut con, &AxialCoords::new(0, 0))
+        .await
+        .unwrap()
+        .expect("Tile should have been created");
+    assert!(
+        tile.user_id == user.id,
+        "Tile should belong to the authenticated user"
+    );
+
+    send(
+        &mut framed,
+        ClientMessage::Click {
+            request_id: 5,
+            coords: AxialCoords::new(1, 0),
+        },
+    )
+    .await;
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        is_error(&reply, 5, ErrorCode::Cooldown),
+        "Click during cooldown should be refused, got {reply:?}"
+    );
+
+    framed
+        .send(Message::Binary(vec![0x02, 6, 0, 0, 0, 1].into()))
+        .await
+        .unwrap();
+    let reply = next_reply(&mut framed).await;
+    assert!(
+        is_error(&reply, 6, ErrorCode::Malformed),
+        "Truncated click should be refused, got {reply:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn tile_changes_are_sent_to_subscribers_only() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+
+    // same grid as `test_app`
+    let game_data = GameData::new(10, 2);
+    let clicked = AxialCoords::new(0, 0);
+    let clicked_batch = game_data.batch_of(&clicked).unwrap() as u16;
+    let other_batch = (clicked_batch + 1) % game_data.batches_count() as u16;
+
+    let app_config = GameConfig::read_config_from_env();
+    let server_store = store.clone();
+    let server_pool = pool.clone();
+    let mut srv = actix_test::start(move || {
+        test_app(
+            server_store.clone(),
+            server_pool.clone(),
+            app_config.clone(),
+        )
+    });
+
+    let mut subscribed = connect(&mut srv).await;
+    let mut elsewhere = connect(&mut srv).await;
+
+    for (framed, batch) in [
+        (&mut subscribed, clicked_batch),
+        (&mut elsewhere, other_batch),
+    ] {
+        send(
+            framed,
+            ClientMessage::Subscribe {
+                request_id: 1,
+                batches: vec![batch],
+            },
+        )
+        .await;
+        let reply = next_reply(framed).await;
+        assert!(
+            reply == ServerMessage::Ack { request_id: 1 },
+            "Subscription should be acked, got {reply:?}"
+        );
+    }
+
+    send(
+        &mut elsewhere,
+        ClientMessage::Subscribe {
+            request_id: 2,
+            batches: vec![game_data.batches_count() as u16],
+        },
+    )
+    .await;
+    let reply = next_reply(&mut elsewhere).await;
+    assert!(
+        is_error(&reply, 2, ErrorCode::Malformed),
+        "Subscription to an unknown batch should be refused, got {reply:?}"
+    );
+
+    let req = srv
+        .post(format!("/tile/{}/{}", clicked.q, clicked.r))
+        .insert_header(basic_auth(&user));
+    let res = req.send().await.unwrap();
+    assert!(res.status().is_success(), "Click should succeed");
+
+    let tiles = next_tiles_change(&mut subscribed).await;
+    assert!(
+        tiles.iter().any(|(c, id)| *c == clicked && *id == user.id),
+        "Subscriber should receive the clicked tile, got {tiles:?}"
+    );
+
+    // the broadcaster sent the tick to every subscriber at once, so it was
+    // received before the reply to this message
+    send(
+        &mut elsewhere,
+        ClientMessage::Unsubscribe {
+            request_id: 3,
+            batches: vec![other_batch],
+        },
+    )
+    .await;
+    let (reply, messages) = messages_until_reply(&mut elsewhere).await;
+    assert!(
+        reply == ServerMessage::Ack { request_id: 3 },
+        "Unsubscription should be acked"
+    );
+    assert!(
+        messages
+            .iter()
+            .all(|m| !matches!(m, ServerMessage::TilesChange { .. })),
+        "Tile change should only reach subscribers of its batch, got {messages:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn cooldowns_are_only_sent_to_their_user() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+    let other = add_test_user(&store, &pool, "other").await;
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.click_cooldown_ms = 60_000;
+    // every client in the same shard, so messages reach them in order
+    app_config.broadcast_shards = 1;
+
+    let server_store = store.clone();
+    let server_pool = pool.clone();
+    let mut srv = actix_test::start(move || {
+        test_app(
+            server_store.clone(),
+            server_pool.clone(),
+            app_config.clone(),
+        )
+    });
+
+    let mut clicking = connect(&mut srv).await;
+    let mut watching = connect(&mut srv).await;
+
+    for (framed, user) in [(&mut clicking, &user), (&mut watching, &other)] {
+        send(
+            framed,
+            ClientMessage::Authenticate {
+                request_id: 1,
+                user_id: user.id.clone(),
+                token: user.token.clone(),
+            },
+        )
+        .await;
+        let reply = next_reply(framed).await;
+        assert!(
+            reply == ServerMessage::Ack { request_id: 1 },
+            "Valid token should be acked, got {reply:?}"
+        );
+    }
+
+    send(
+        &mut clicking,
+        ClientMessage::Click {
+            request_id: 2,
+            coords: AxialCoords::new(0, 0),
+        },
+    )
+    .await;
+
+    let cooldown = loop {
+        if let ServerMessage::Cooldown {
+            user_id,
+            remaining_ms,
+        } = next_message(&mut clicking).await
+        {
+            break (user_id, remaining_ms);
+        }
+    };
+    assert!(
+        cooldown == (user.id.clone(), 60_000),
+        "Clicking user should receive its cooldown, got {cooldown:?}"
+    );
+
+    // the cooldown was sent by the shard before the clicking user received it
+    send(
+        &mut watching,
+        ClientMessage::Unsubscribe {
+            request_id: 2,
+            batches: vec![],
+        },
+    )
+    .await;
+    let (_, messages) = messages_until_reply(&mut watching).await;
+    assert!(
+        messages
+            .iter()
+            .all(|m| !matches!(m, ServerMessage::Cooldown { .. })),
+        "Other users should not receive the cooldown, got {messages:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn tile_changes_are_coalesced_per_tick() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+    let other = add_test_user(&store, &pool, "other").await;
+
+    let game_data = GameData::new(10, 2);
+    let clicked = AxialCoords::new(0, 0);
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.click_cooldown_ms = 0;
+    app_config.tile_broadcast_tick_ms = 300;
+
+    let server_store = store.clone();
+    let server_pool = pool.clone();
+    let mut srv = actix_test::start(move || {
+        test_app(
+            server_store.clone(),
+            server_pool.clone(),
+            app_config.clone(),
+        )
+    });
+
+    let mut framed = connect(&mut srv).await;
+
+    send(
+        &mut framed,
+        ClientMessage::Subscribe {
+            request_id: 1,
+            batches: (0..game_data.batches_count() as u16).collect(),
+        },
+    )
+    .await;
+    assert!(next_reply(&mut framed).await == ServerMessage::Ack { request_id: 1 });
+
+    // the tile changes hands at each click, the tick starts with the first
+    // click and the following ones land in it
+    for player in [&user, &other, &user] {
+        let req = srv
+            .post(format!("/tile/{}/{}", clicked.q, clicked.r))
+            .insert_header(basic_auth(player));
+        let res = req.send().await.unwrap();
+        assert!(res.status().is_success(), "Click should succeed");
+    }
+
+    let tiles = next_tiles_change(&mut framed).await;
+
+    let mut con = store.get_connection(&pool).await.unwrap();
+    let stored = store
+        .get_tile(&mut con, &clicked)
+        .await
+        .unwrap()
+        .expect("Tile should have been created");
+
+    let clicked_updates: Vec<_> = tiles.iter().filter(|(c, _)| *c == clicked).collect();
+    assert!(
+        clicked_updates.len() == 1,
+        "Updates of a tile during a tick should be merged, got {tiles:?}"
+    );
+    assert!(
+        clicked_updates[0].1 == stored.user_id && stored.user_id == user.id,
+        "Merged update should hold the last owner {}, got {}",
+        stored.user_id,
+        clicked_updates[0].1
+    );
+
+    tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+
+    send(
+        &mut framed,
+        ClientMessage::Unsubscribe {
+            request_id: 2,
+            batches: vec![],
+        },
+    )
+    .await;
+    let (_, messages) = messages_until_reply(&mut framed).await;
+    assert!(
+        messages.iter().all(|m| !matches!(
+            m,
+            ServerMessage::TilesChange { .. } | ServerMessage::TileChange { .. }
+        )),
+        "Clicks of a tick should be sent in a single message, got {messages:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn resync_sends_missed_changes_of_subscribed_batches() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+
+    let game_data = GameData::new(10, 2);
+    let clicked = AxialCoords::new(0, 0);
+    let clicked_batch = game_data.batch_of(&clicked).unwrap() as u16;
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.tile_broadcast_tick_ms = 0;
+
+    let server_store = store.clone();
+    let server_pool = pool.clone();
+    let mut srv = actix_test::start(move || {
+        test_app(
+            server_store.clone(),
+            server_pool.clone(),
+            app_config.clone(),
+        )
+    });
+
+    let mut watcher = connect(&mut srv).await;
+    send(
+        &mut watcher,
+        ClientMessage::Subscribe {
+            request_id: 1,
+            batches: vec![clicked_batch],
+        },
+    )
+    .await;
+    assert!(next_reply(&mut watcher).await == ServerMessage::Ack { request_id: 1 });
+
+    // sequence of a client that downloaded the grid then lost its websocket
+    send(
+        &mut watcher,
+        ClientMessage::Resync {
+            request_id: 2,
+            since: 0,
+        },
+    )
+    .await;
+    let reply = next_reply(&mut watcher).await;
+    assert!(
+        is_error(&reply, 2, ErrorCode::ResyncRequired),
+        "Unknown sequence should require a full resync, got {reply:?}"
+    );
+
+    let res = srv.get("/tiles?batch=0").send().await.unwrap();
+    let since: u64 = res
+        .headers()
+        .get("X-Change-Seq")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap();
+
+    let req = srv
+        .post(format!("/tile/{}/{}", clicked.q, clicked.r))
+        .insert_header(basic_auth(&user));
+    assert!(req.send().await.unwrap().status().is_success());
+
+    // the watcher received the change, so it is recorded
+    let (seq, _) = loop {
+        if let ServerMessage::TilesChange { seq, tiles } = next_message(&mut watcher).await {
+            break (seq, tiles);
+        }
+    };
+
+    let mut framed = connect(&mut srv).await;
+    send(
+        &mut framed,
+        ClientMessage::Subscribe {
+            request_id: 1,
+            batches: vec![clicked_batch],
+        },
+    )
+    .await;
+    assert!(next_reply(&mut framed).await == ServerMessage::Ack { request_id: 1 });
+
+    send(
+        &mut framed,
+        ClientMessage::Resync {
+            request_id: 2,
+            since,
+        },
+    )
+    .await;
+    let (reply, messages) = messages_until_reply(&mut framed).await;
+    assert!(reply == ServerMessage::Ack { request_id: 2 });
+
+    let resynced: Vec<_> = messages
+        .into_iter()
+        .filter_map(|m| match m {
+            ServerMessage::TilesChange { seq, tiles } => Some((seq, tiles)),
+            _ => None,
+        })
+        .collect();
+    assert!(
+        resynced.len() == 1
+            && resynced[0].0 == seq
+            && resynced[0]
+                .1
+                .iter()
+                .any(|(c, t)| *c == clicked && t.user_id == user.id),
+        "Resync should send the missed click with the current sequence, got {resynced:?}"
+    );
+
+    send(
+        &mut framed,
+        ClientMessage::Resync {
+            request_id: 3,
+            since: seq,
+        },
+    )
+    .await;
+    let (reply, messages) = messages_until_reply(&mut framed).await;
+    assert!(reply == ServerMessage::Ack { request_id: 3 });
+    assert!(
+        messages
+            .iter()
+            .any(|m| *m == ServerMessage::TilesChange { seq, tiles: vec![] }),
+        "Up to date client should still get the current sequence, got {messages:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+#[actix_web::test]
+pub async fn events_are_relayed_between_instances() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+
+    let game_data = GameData::new(10, 2);
+    let clicked = AxialCoords::new(0, 0);
+    let clicked_batch = game_data.batch_of(&clicked).unwrap() as u16;
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.tile_broadcast_tick_ms = 0;
+    app_config.click_cooldown_ms = 0;
+
+    // two instances sharing the store, as they would share redis
+    let bus = RelayBus::mock();
+    let start_instance = || {
+        let store = store.clone();
+        let pool = pool.clone();
+        let app_config = app_config.clone();
+        let bus = bus.clone();
+
+        actix_test::start(move || {
+            test_app_with_relay(store.clone(), pool.clone(), app_config.clone(), bus.clone())
+        })
+    };
+    let mut first = start_instance();
+    let mut second = start_instance();
+
+    let mut local = connect(&mut first).await;
+    let mut remote = connect(&mut second).await;
+    for framed in [&mut local, &mut remote] {
+        send(
+            framed,
+            ClientMessage::Subscribe {
+                request_id: 1,
+                batches: vec![clicked_batch],
+            },
+        )
+        .await;
+        assert!(next_reply(framed).await == ServerMessage::Ack { request_id: 1 });
+    }
+
+    let req = first
+        .post(format!("/tile/{}/{}", clicked.q, clicked.r))
+        .insert_header(basic_auth(&user));
+    assert!(req.send().await.unwrap().status().is_success());
+
+    // scores are broadcast at once while tiles wait for the broadcaster, in any order
+    let (mut tiles, mut score) = (None, None);
+    while tiles.is_none() || score.is_none() {
+        match next_message(&mut remote).await {
+            ServerMessage::TilesChange { tiles: changed, .. } => tiles = Some(changed),
+            ServerMessage::ScoreChange { user_id, score: s } => score = Some((user_id, s)),
+            _ => {}
+        }
+    }
+
+    let tiles: Vec<_> = tiles
+        .unwrap()
+        .into_iter()
+        .map(|(coords, tile)| (coords, tile.user_id))
+        .collect();
+    assert!(
+        tiles == [(clicked, user.id.clone())],
+        "Click on the first instance should reach clients of the second one, got {tiles:?}"
+    );
+    assert!(
+        score == Some((user.id.clone(), 1)),
+        "Score changes should be relayed too, got {score:?}"
+    );
+
+    let res = second
+        .post("/login")
+        .send_json(&serde_json::json!({ "username": "newcomer" }))
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+
+    let new_user = loop {
+        if let ServerMessage::NewUser { username, .. } = next_message(&mut local).await {
+            break username;
+        }
+    };
+    assert!(
+        new_user == "newcomer",
+        "Users registered on the second instance should be announced on the first one"
+    );
+
+    // events published by an instance are not relayed back to it
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send(
+        &mut local,
+        ClientMessage::Subscribe {
+            request_id: 2,
+            batches: vec![clicked_batch],
+        },
+    )
+    .await;
+    let (_, messages) = messages_until_reply(&mut local).await;
+    let changes = messages
+        .iter()
+        .filter(|m| matches!(m, ServerMessage::TilesChange { .. }))
+        .count();
+    assert!(
+        changes == 0,
+        "Local clients should receive the click once, got {changes} more changes"
+    );
+
+    // a lone tile is captured in one click, both owners get their new score
+    let attacker = add_test_user(&store, &pool, "attacker").await;
+    let req = first
+        .post(format!("/tile/{}/{}", clicked.q, clicked.r))
+        .insert_header(basic_auth(&attacker));
+    assert!(req.send().await.unwrap().status().is_success());
+
+    let mut scores = HashMap::new();
+    while scores.len() < 2 {
+        if let ServerMessage::ScoreChange { user_id, score } = next_message(&mut remote).await {
+            scores.insert(user_id, score);
+        }
+    }
+    assert!(
+        scores == HashMap::from([(attacker.id.clone(), 1), (user.id.clone(), 0)]),
+        "Capture should change the score of both owners, got {scores:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+// Waits until `count` instances listen to the relay channel of redis
+async fn wait_for_relay_subscribers(client: &redis::Client, count: usize) {
+    let mut con = client.get_multiplexed_async_connection().await.unwrap();
+
+    for _ in 0..500 {
+        let (_, subscribers): (String, usize) = redis::cmd("PUBSUB")
+            .arg("NUMSUB")
+            .arg(RELAY_CHANNEL)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+
+        if subscribers == count {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("{count} instances should listen to the relay channel");
+}
+
+#[actix_web::test]
+pub async fn events_are_relayed_through_redis() {
+    let mut app_config = GameConfig::read_config_from_env();
+    // instances only share events through a real redis
+    if !app_config.with_redis_tests {
+        return;
+    }
+    app_config.tile_broadcast_tick_ms = 0;
+    app_config.click_cooldown_ms = 0;
+
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+    let user = add_test_user(&store, &pool, "player").await;
+    let client = redis::Client::open(app_config.redis_url.as_str()).unwrap();
+
+    let game_data = GameData::new(10, 2);
+    let clicked = [AxialCoords::new(0, 0), AxialCoords::new(0, 1)];
+    let batches: Vec<u16> = clicked
+        .iter()
+        .map(|coords| game_data.batch_of(coords).unwrap() as u16)
+        .collect();
+
+    let start_instance = || {
+        let store = store.clone();
+        let pool = pool.clone();
+        let app_config = app_config.clone();
+        let bus = RelayBus::Redis(client.clone());
+
+        actix_test::start(move || {
+            test_app_with_relay(store.clone(), pool.clone(), app_config.clone(), bus.clone())
+        })
+    };
+    let mut first = start_instance();
+    let mut second = start_instance();
+    wait_for_relay_subscribers(&client, 2).await;
+
+    let mut local = connect(&mut first).await;
+    let mut remote = connect(&mut second).await;
+    for framed in [&mut local, &mut remote] {
+        send(
+            framed,
+            ClientMessage::Subscribe {
+                request_id: 1,
+                batches: batches.clone(),
+            },
+        )
+        .await;
+        assert!(next_reply(framed).await == ServerMessage::Ack { request_id: 1 });
+    }
+
+    let click = |coords: AxialCoords| {
+        first
+            .post(format!("/tile/{}/{}", coords.q, coords.r))
+            .insert_header(basic_auth(&user))
+            .send()
+    };
+
+    assert!(click(clicked[0]).await.unwrap().status().is_success());
+    let tiles = next_tiles_change(&mut remote).await;
+    assert!(
+        tiles == [(clicked[0], user.id.clone())],
+        "Click on the first instance should reach clients of the second one, got {tiles:?}"
+    );
+
+    // the first instance received its own event back from redis and ignored it
+    let tiles = next_tiles_change(&mut local).await;
+    assert!(tiles == [(clicked[0], user.id.clone())]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    send(
+        &mut local,
+        ClientMessage::Subscribe {
+            request_id: 2,
+            batches: batches.clone(),
+        },
+    )
+    .await;
+    let (_, messages) = messages_until_reply(&mut local).await;
+    assert!(
+        messages
+            .iter()
+            .all(|m| !matches!(m, ServerMessage::TilesChange { .. })),
+        "Local clients should receive the click once, got {messages:?}"
+    );
+
+    // instances subscribe again once redis dropped their connection
+    let mut con = client.get_multiplexed_async_connection().await.unwrap();
+    let _: usize = redis::cmd("CLIENT")
+        .arg("KILL")
+        .arg("TYPE")
+        .arg("pubsub")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    wait_for_relay_subscribers(&client, 0).await;
+    wait_for_relay_subscribers(&client, 2).await;
+
+    assert!(click(clicked[1]).await.unwrap().status().is_success());
+    let tiles = next_tiles_change(&mut remote).await;
+    assert!(
+        tiles == [(clicked[1], user.id.clone())],
+        "Events should be relayed again after a reconnection, got {tiles:?}"
+    );
+
+    store.flushdb().await.unwrap();
+}
+
+// Skips frames until the server closes the websocket, returns the close code
+async fn close_code<S>(framed: &mut S) -> Option<u16>
+where
+    S: Stream<Item = Result<Frame, awc::error::WsProtocolError>> + Unpin,
+{
+    while let Some(frame) = framed.next().await {
+        if let Frame::Close(reason) = frame.unwrap() {
+            return reason.map(|reason| reason.code.into());
+        }
+    }
+
+    None
+}
+
+#[actix_web::test]
+pub async fn silent_websockets_are_closed_after_the_timeout() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.heartbeat_interval_ms = 20;
+    app_config.client_timeout_ms = 100;
+    let mut srv =
+        actix_test::start(move || test_app(store.clone(), pool.clone(), app_config.clone()));
+
+    // answering pings keeps the connection open past the timeout
+    let mut alive = connect(&mut srv).await;
+    let answered = tokio::time::timeout(Duration::from_millis(300), async {
+        while let Some(frame) = alive.next().await {
+            match frame.unwrap() {
+                Frame::Ping(bytes) => alive.send(Message::Pong(bytes)).await.unwrap(),
+                Frame::Close(reason) => return reason,
+                _ => {}
+            }
+        }
+        None
+    })
+    .await;
+    assert!(
+        answered.is_err(),
+        "Clients answering pings should stay connected, got {answered:?}"
+    );
+
+    let mut silent = connect(&mut srv).await;
+    let code = close_code(&mut silent).await;
+    assert!(
+        code == Some(CloseStatus::Timeout.code()),
+        "Clients not answering pings should be closed, got {code:?}"
+    );
+}
+
+#[actix_web::test]
+pub async fn connections_over_the_limits_are_refused() {
+    let (store, pool) = test_utils::mocks::game_store_or_mock().await.unwrap();
+    let store = web::Data::new(store);
+
+    let mut app_config = GameConfig::read_config_from_env();
+    app_config.max_connections = 3;
+    app_config.max_connections_per_ip = 2;
+    let start_server = |app_config: GameConfig| {
+        let store = store.clone();
+        let pool = pool.clone();
+        actix_test::start(move || test_app(store.clone(), pool.clone(), app_config.clone()))
+    };
+
+    let mut srv = start_server(app_config.clone());
+    let first = connect(&mut srv).await;
+    let _second = connect(&mut srv).await;
+
+    let mut refused = srv.ws_at("/ws").await.unwrap();
+    let code = close_code(&mut refused).await;
+    assert!(
+        code == Some(CloseStatus::TooManyConnections.code()),
+        "Connections over the limit of an address should be refused, got {code:?}"
+    );
+
+    // the connection is released once closed
+    drop(first);
+    let mut accepted = false;
+    for _ in 0..100 {
+        let mut framed = srv.ws_at("/ws").await.unwrap();
+        if let Frame::Binary(_) = framed.next().await.unwrap().unwrap() {
+            accepted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(
+        accepted,
+        "Closed connections should not count towards the limit"
+    );
+
+    app_config.max_connections = 1;
+    app_config.max_connections_per_ip = 0;
+    let mut srv = start_server(app_config);
+    let _only = connect(&mut srv).await;
+
+    let mut refused = srv.ws_at("/ws").await.unwrap();
+    let code = close_code(&mut refused).await;
+    assert!(
+        code == Some(CloseStatus::ServerFull.code()),
+        "Connections over the limit of the server should be refused, got {code:?}"
+    );
+}